@@ -0,0 +1,285 @@
+use image::codecs::gif::GifDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::{AnimationDecoder, Frame, ImageFormat};
+use log::info;
+use std::io::Cursor;
+
+use crate::errors::ImageServerError;
+
+/// 解码后的动画帧序列
+pub struct AnimatedImage {
+    /// 已合成到完整画布上的帧
+    pub frames: Vec<Frame>,
+    /// 循环次数，0 表示无限循环
+    pub loop_count: u32,
+}
+
+/// 解码动画 WebP 或 GIF 的所有帧，其他格式按单帧处理
+pub fn decode_frames(data: &[u8]) -> Result<AnimatedImage, ImageServerError> {
+    let format = image::guess_format(data).map_err(|_| ImageServerError::UnsupportedFormat)?;
+    let decode_error =
+        |e: image::ImageError| ImageServerError::CompressionError(format!("Failed to decode frames: {}", e));
+
+    let (frames, loop_count) = match format {
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(Cursor::new(data)).map_err(decode_error)?;
+            let frames = decoder.into_frames().collect_frames().map_err(decode_error)?;
+            (frames, webp_loop_count(data).unwrap_or(0))
+        }
+        ImageFormat::Gif => {
+            // image 不暴露 GIF 的循环次数，按无限循环处理
+            let decoder = GifDecoder::new(Cursor::new(data)).map_err(decode_error)?;
+            (decoder.into_frames().collect_frames().map_err(decode_error)?, 0)
+        }
+        _ => {
+            let img = image::load_from_memory_with_format(data, format).map_err(decode_error)?;
+            (vec![Frame::new(img.to_rgba8())], 0)
+        }
+    };
+
+    if frames.is_empty() {
+        return Err(ImageServerError::CompressionError("Image has no frames".to_string()));
+    }
+
+    Ok(AnimatedImage { frames, loop_count })
+}
+
+/// 从动画 WebP 的 ANIM 块读取循环次数，非动画 WebP 返回 `None`
+pub fn webp_loop_count(data: &[u8]) -> Option<u32> {
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return None;
+    }
+
+    let mut offset = 12;
+    while offset + 8 <= data.len() {
+        let fourcc = &data[offset..offset + 4];
+        let size = u32::from_le_bytes(data[offset + 4..offset + 8].try_into().ok()?) as usize;
+        let body = offset + 8;
+        if fourcc == b"ANIM" {
+            // ANIM: 4 字节背景色 + 2 字节循环次数
+            let bytes = data.get(body + 4..body + 6)?;
+            return Some(u16::from_le_bytes([bytes[0], bytes[1]]) as u32);
+        }
+        // 块按偶数字节对齐
+        offset = body + size + (size & 1);
+    }
+
+    None
+}
+
+/// 只解析容器结构统计帧数，不解码像素：GIF 数图像描述符，WebP 数 ANMF 块，APNG 读 acTL 中的帧数
+///
+/// 静态图片和其他格式返回 1；数据被截断时返回已读到的帧数（至少为 1）。
+pub fn frame_count(data: &[u8]) -> usize {
+    let count = match image::guess_format(data) {
+        Ok(ImageFormat::Gif) => gif_frame_count(data),
+        Ok(ImageFormat::WebP) => webp_frame_count(data),
+        Ok(ImageFormat::Png) => apng_frame_count(data),
+        _ => 1,
+    };
+    count.max(1)
+}
+
+fn gif_frame_count(data: &[u8]) -> usize {
+    // 6 字节签名 + 7 字节逻辑屏幕描述符，之后可能是全局颜色表
+    let flags = match data.get(10) {
+        Some(&flags) => flags,
+        None => return 0,
+    };
+    let mut offset = 13 + color_table_len(flags);
+    let mut frames = 0;
+
+    // 跳过以 0 长度子块结尾的数据子块序列
+    let skip_sub_blocks = |mut offset: usize| -> Option<usize> {
+        loop {
+            let len = *data.get(offset)? as usize;
+            offset += 1 + len;
+            if len == 0 {
+                return Some(offset);
+            }
+        }
+    };
+
+    while let Some(&block) = data.get(offset) {
+        let next = match block {
+            // 图像描述符：9 字节 + 局部颜色表 + LZW 最小码长 + 图像数据子块
+            0x2C => {
+                frames += 1;
+                data.get(offset + 9)
+                    .and_then(|&flags| skip_sub_blocks(offset + 10 + color_table_len(flags) + 1))
+            }
+            // 扩展块：标签 + 数据子块
+            0x21 => skip_sub_blocks(offset + 2),
+            _ => None,
+        };
+        match next {
+            Some(next) => offset = next,
+            None => break,
+        }
+    }
+    frames
+}
+
+// GIF 颜色表标志位为 1 时，颜色表长度为 3 * 2^(N+1) 字节
+fn color_table_len(flags: u8) -> usize {
+    if flags & 0x80 == 0 {
+        0
+    } else {
+        3 << ((flags & 0x07) + 1)
+    }
+}
+
+fn webp_frame_count(data: &[u8]) -> usize {
+    let mut offset = 12;
+    let mut frames = 0;
+    while offset + 8 <= data.len() {
+        let size = u32::from_le_bytes([data[offset + 4], data[offset + 5], data[offset + 6], data[offset + 7]]) as usize;
+        if &data[offset..offset + 4] == b"ANMF" {
+            frames += 1;
+        }
+        // 块按偶数字节对齐
+        offset = offset + 8 + size + (size & 1);
+    }
+    frames
+}
+
+fn apng_frame_count(data: &[u8]) -> usize {
+    // PNG 签名后依次为 长度 + 类型 + 数据 + CRC，acTL 必须位于第一个 IDAT 之前
+    let mut offset = 8;
+    while offset + 8 <= data.len() {
+        let len = u32::from_be_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]) as usize;
+        match &data[offset + 4..offset + 8] {
+            b"acTL" => {
+                return data
+                    .get(offset + 8..offset + 12)
+                    .map_or(0, |n| u32::from_be_bytes([n[0], n[1], n[2], n[3]]) as usize);
+            }
+            b"IDAT" => return 1,
+            _ => offset += 12 + len,
+        }
+    }
+    1
+}
+
+/// 将所有帧编码为 APNG，保留每帧的延时和循环次数
+pub fn encode_apng(animation: &AnimatedImage) -> Result<Vec<u8>, ImageServerError> {
+    let encode_error =
+        |e: png::EncodingError| ImageServerError::CompressionError(format!("Failed to encode APNG: {}", e));
+
+    let first = animation.frames[0].buffer();
+    let (width, height) = first.dimensions();
+
+    let mut output = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(Cursor::new(&mut output), width, height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_compression(png::Compression::Best);
+        encoder
+            .set_animated(animation.frames.len() as u32, animation.loop_count)
+            .map_err(encode_error)?;
+
+        let mut writer = encoder.write_header().map_err(encode_error)?;
+        for frame in &animation.frames {
+            let buffer = frame.buffer();
+            if buffer.dimensions() != (width, height) {
+                return Err(ImageServerError::CompressionError(
+                    "Animation frames have inconsistent dimensions".to_string(),
+                ));
+            }
+
+            let (numerator, denominator) = frame.delay().numer_denom_ms();
+            let delay_ms = (numerator as f64 / denominator.max(1) as f64).round();
+            writer
+                .set_frame_delay(delay_ms.min(u16::MAX as f64) as u16, 1000)
+                .map_err(encode_error)?;
+            writer.write_image_data(buffer.as_raw()).map_err(encode_error)?;
+        }
+        writer.finish().map_err(encode_error)?;
+    }
+
+    info!(
+        "APNG 编码完成 - {} 帧, 循环次数: {}, 输出大小: {} bytes",
+        animation.frames.len(),
+        animation.loop_count,
+        output.len()
+    );
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::gif::{GifEncoder, Repeat};
+    use image::{Delay, Rgba, RgbaImage};
+
+    fn animated_gif() -> Vec<u8> {
+        let mut data = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut data);
+            encoder.set_repeat(Repeat::Infinite).unwrap();
+            for color in [[255, 0, 0, 255], [0, 0, 255, 255]] {
+                let frame = Frame::from_parts(
+                    RgbaImage::from_pixel(8, 8, Rgba(color)),
+                    0,
+                    0,
+                    Delay::from_numer_denom_ms(100, 1),
+                );
+                encoder.encode_frame(frame).unwrap();
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn test_gif_to_apng() {
+        let animation = decode_frames(&animated_gif()).unwrap();
+        assert_eq!(animation.frames.len(), 2);
+
+        let apng = encode_apng(&animation).unwrap();
+        let reader = png::Decoder::new(Cursor::new(apng)).read_info().unwrap();
+        let control = reader.info().animation_control.unwrap();
+        assert_eq!(control.num_frames, 2);
+        assert_eq!(control.num_plays, 0);
+        let frame_control = reader.info().frame_control.unwrap();
+        assert_eq!((frame_control.delay_num, frame_control.delay_den), (100, 1000));
+    }
+
+    #[test]
+    fn test_frame_count() {
+        let gif = animated_gif();
+        assert_eq!(frame_count(&gif), 2);
+        assert_eq!(frame_count(&encode_apng(&decode_frames(&gif).unwrap()).unwrap()), 2);
+
+        let mut webp = b"RIFF\0\0\0\0WEBP".to_vec();
+        webp.extend_from_slice(b"VP8X");
+        webp.extend_from_slice(&10u32.to_le_bytes());
+        webp.extend_from_slice(&[0; 10]);
+        for _ in 0..3 {
+            webp.extend_from_slice(b"ANMF");
+            webp.extend_from_slice(&3u32.to_le_bytes());
+            webp.extend_from_slice(&[0, 0, 0, 0]);
+        }
+        assert_eq!(frame_count(&webp), 3);
+
+        let mut png = Vec::new();
+        image::DynamicImage::new_rgb8(4, 4)
+            .write_to(&mut Cursor::new(&mut png), image::ImageOutputFormat::Png)
+            .unwrap();
+        assert_eq!(frame_count(&png), 1);
+        assert_eq!(frame_count(b"not an image"), 1);
+    }
+
+    #[test]
+    fn test_webp_loop_count() {
+        let mut data = b"RIFF\0\0\0\0WEBP".to_vec();
+        data.extend_from_slice(b"VP8X");
+        data.extend_from_slice(&10u32.to_le_bytes());
+        data.extend_from_slice(&[0; 10]);
+        data.extend_from_slice(b"ANIM");
+        data.extend_from_slice(&6u32.to_le_bytes());
+        data.extend_from_slice(&[0, 0, 0, 0, 3, 0]);
+        assert_eq!(webp_loop_count(&data), Some(3));
+        assert_eq!(webp_loop_count(b"not a webp"), None);
+    }
+}
@@ -0,0 +1,481 @@
+use log::{info, warn};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::io::{Cursor, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::errors::ImageServerError;
+
+/// 单个 ZIP 请求最多处理的条目数
+pub const MAX_ZIP_ENTRIES: usize = 1000;
+
+/// 输出 ZIP 根目录下的处理报告
+pub const ZIP_REPORT_NAME: &str = "_report.json";
+
+/// 不同条目的输出文件名相同（如 `logo.png` 和 `logo.jpg` 都输出为 `logo.jpg`）时区分后者的方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ZipNaming {
+    /// 追加序号：`logo-1.jpg`、`logo-2.jpg`
+    #[default]
+    Counter,
+    /// 追加输入内容 SHA-256 的前 8 位十六进制：`logo-3f2a9c1b.jpg`，同一输入总是得到相同的名称
+    Hash,
+}
+
+impl ZipNaming {
+    pub const NAMES: [&'static str; 2] = ["counter", "hash"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "counter" => Some(Self::Counter),
+            "hash" => Some(Self::Hash),
+            _ => None,
+        }
+    }
+}
+
+/// ZIP 中一个条目的处理结果
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ZipEntryReport {
+    pub name: String,
+    /// "compressed"、"skipped" 或 "failed"
+    pub status: &'static str,
+    /// 输出 ZIP 中的最终文件名（含冲突时追加的后缀），未输出时为 `None`
+    pub output: Option<String>,
+    pub original_size: u64,
+    pub compressed_size: Option<u64>,
+    pub reason: Option<String>,
+    /// 内容与之前的条目相同、直接复用其结果时为该条目的名称
+    pub duplicate_of: Option<String>,
+}
+
+impl ZipEntryReport {
+    fn skipped(name: &str, original_size: u64, reason: impl Into<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            status: "skipped",
+            output: None,
+            original_size,
+            compressed_size: None,
+            reason: Some(reason.into()),
+            duplicate_of: None,
+        }
+    }
+}
+
+// 已处理条目的结果，供内容相同的后续条目复用
+#[derive(Clone)]
+struct ProcessedEntry {
+    name: String,
+    result: Result<(Vec<u8>, &'static str), String>,
+}
+
+/// 逐个压缩 ZIP 中的图片，返回保持目录结构和文件名（扩展名换成输出格式）的 ZIP
+///
+/// 整个输出保存在内存中；大批量任务使用 `compress_zip_to_file`。
+pub fn compress_zip<F>(
+    data: &[u8],
+    max_entry_size: u64,
+    naming: ZipNaming,
+    compress_entry: F,
+) -> Result<(Vec<u8>, Vec<ZipEntryReport>), ImageServerError>
+where
+    F: FnMut(&[u8]) -> Result<(Vec<u8>, &'static str), ImageServerError>,
+{
+    let (output, reports) = compress_zip_to(data, max_entry_size, naming, Cursor::new(Vec::new()), compress_entry)?;
+    Ok((output.into_inner(), reports))
+}
+
+/// 与 `compress_zip` 相同，但每个条目压缩完成后即写入 `dir` 下的临时文件，内存占用与批量大小无关
+///
+/// 返回临时文件路径，由调用方在发送后删除；处理失败时文件已被删除。
+pub fn compress_zip_to_file<F>(
+    data: &[u8],
+    max_entry_size: u64,
+    naming: ZipNaming,
+    dir: &Path,
+    compress_entry: F,
+) -> Result<(PathBuf, Vec<ZipEntryReport>), ImageServerError>
+where
+    F: FnMut(&[u8]) -> Result<(Vec<u8>, &'static str), ImageServerError>,
+{
+    let path = dir.join(format!("img-server-zip-{}.zip", uuid::Uuid::new_v4().simple()));
+    let file = std::fs::File::create(&path).map_err(|e| {
+        ImageServerError::ProcessingError(format!("Failed to create {}: {}", path.display(), e))
+    })?;
+
+    let result = compress_zip_to(data, max_entry_size, naming, std::io::BufWriter::new(file), compress_entry).and_then(
+        |(output, reports)| {
+            output
+                .into_inner()
+                .map_err(|e| ImageServerError::ProcessingError(format!("Failed to write ZIP: {}", e.error())))?
+                .sync_all()
+                .map_err(|e| ImageServerError::ProcessingError(format!("Failed to write ZIP: {}", e)))?;
+            Ok(reports)
+        },
+    );
+    match result {
+        Ok(reports) => Ok((path, reports)),
+        Err(e) => {
+            if let Err(remove_error) = std::fs::remove_file(&path) {
+                warn!("Failed to remove {}: {}", path.display(), remove_error);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// `compress_zip` 的实现，输出写入任意可定位的 `output`
+///
+/// `compress_entry` 返回压缩结果和输出扩展名。非图片、路径不安全或解压后超过 `max_entry_size`
+/// 的条目跳过，单个条目压缩失败不影响其他条目，结果都记录在 `_report.json` 中。
+///
+/// 输出文件名不区分大小写地去重（解压到 Windows、macOS 时大小写不同的名称同样会覆盖），冲突时按
+/// `naming` 追加后缀，最终名称记录在报告的 `output` 中。
+///
+/// 内容相同（SHA-256 一致）的条目只压缩一次，后续条目复用结果并在报告的 `duplicate_of` 中记录首个条目。
+/// 只有中央目录中 CRC32 和大小都与其他条目相同的条目才计算哈希并暂存结果，同组最后一个条目处理后即释放。
+fn compress_zip_to<W, F>(
+    data: &[u8],
+    max_entry_size: u64,
+    naming: ZipNaming,
+    output: W,
+    mut compress_entry: F,
+) -> Result<(W, Vec<ZipEntryReport>), ImageServerError>
+where
+    W: Write + Seek,
+    F: FnMut(&[u8]) -> Result<(Vec<u8>, &'static str), ImageServerError>,
+{
+    let mut archive = ZipArchive::new(Cursor::new(data))
+        .map_err(|e| ImageServerError::InvalidParameters(format!("Invalid ZIP archive: {}", e)))?;
+    if archive.len() > MAX_ZIP_ENTRIES {
+        return Err(ImageServerError::InvalidParameters(format!(
+            "ZIP archive must contain at most {} entries, got {}",
+            MAX_ZIP_ENTRIES,
+            archive.len()
+        )));
+    }
+
+    let write_error = |e: zip::result::ZipError| ImageServerError::ProcessingError(format!("Failed to write ZIP: {}", e));
+    let mut writer = ZipWriter::new(output);
+    // 图片本身已经压缩过，直接存储
+    let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+    let mut reports = Vec::new();
+
+    // 按中央目录的 CRC32 和大小分组，只有同组超过一个条目时才可能重复
+    let mut remaining_in_group: HashMap<(u32, u64), usize> = HashMap::new();
+    for index in 0..archive.len() {
+        if let Ok(entry) = archive.by_index_raw(index) {
+            if !entry.is_dir() {
+                *remaining_in_group.entry((entry.crc32(), entry.size())).or_default() += 1;
+            }
+        }
+    }
+    remaining_in_group.retain(|_, count| *count > 1);
+    let mut processed: HashMap<(u32, u64), HashMap<[u8; 32], ProcessedEntry>> = HashMap::new();
+    let mut used_names = HashSet::from([ZIP_REPORT_NAME.to_lowercase()]);
+
+    for index in 0..archive.len() {
+        let mut entry = archive
+            .by_index(index)
+            .map_err(|e| ImageServerError::InvalidParameters(format!("Invalid ZIP entry: {}", e)))?;
+        let name = entry.name().to_string();
+        let original_size = entry.size();
+        let group = (entry.crc32(), original_size);
+        let remaining = if entry.is_dir() {
+            None
+        } else {
+            remaining_in_group.get_mut(&group).map(|count| {
+                *count -= 1;
+                *count
+            })
+        };
+
+        // 拒绝绝对路径和包含 .. 的条目，避免解压时写到目标目录之外
+        if entry.enclosed_name().is_none() {
+            reports.push(ZipEntryReport::skipped(&name, original_size, "unsafe path"));
+            continue;
+        }
+        if entry.is_dir() {
+            writer.add_directory(name.as_str(), stored).map_err(write_error)?;
+            continue;
+        }
+
+        // 声明的大小不可信，按上限读取，防止压缩炸弹
+        let mut input = Vec::new();
+        if let Err(e) = (&mut entry).take(max_entry_size + 1).read_to_end(&mut input) {
+            reports.push(ZipEntryReport::skipped(&name, original_size, format!("failed to read entry: {}", e)));
+            continue;
+        }
+        if input.len() as u64 > max_entry_size {
+            reports.push(ZipEntryReport::skipped(
+                &name,
+                original_size,
+                format!("entry exceeds {} bytes", max_entry_size),
+            ));
+            continue;
+        }
+        if image::guess_format(&input).is_err() {
+            reports.push(ZipEntryReport::skipped(&name, original_size, "not an image"));
+            continue;
+        }
+
+        let hash: Option<[u8; 32]> = remaining.map(|_| Sha256::digest(&input).into());
+        let previous = match (hash, remaining) {
+            (Some(hash), Some(0)) => processed.remove(&group).and_then(|mut entries| entries.remove(&hash)),
+            (Some(hash), Some(_)) => processed.get(&group).and_then(|entries| entries.get(&hash)).cloned(),
+            _ => None,
+        };
+        let (result, duplicate_of) = match previous {
+            Some(ProcessedEntry { name: first, result }) => {
+                info!("ZIP 条目 {} 与 {} 内容相同，复用结果", name, first);
+                (result, Some(first))
+            }
+            None => {
+                let result = compress_entry(&input).map_err(|e| e.to_string());
+                if let (Some(hash), true) = (hash, remaining.is_some_and(|count| count > 0)) {
+                    processed
+                        .entry(group)
+                        .or_default()
+                        .insert(hash, ProcessedEntry { name: name.clone(), result: result.clone() });
+                }
+                (result, None)
+            }
+        };
+
+        match result {
+            Ok((output, extension)) => {
+                let output_name = Path::new(&name).with_extension(extension).to_string_lossy().into_owned();
+                let output_name = unique_output_name(output_name, &input, naming, &mut used_names);
+                writer.start_file(output_name.as_str(), stored).map_err(write_error)?;
+                writer
+                    .write_all(&output)
+                    .map_err(|e| ImageServerError::ProcessingError(format!("Failed to write ZIP: {}", e)))?;
+                reports.push(ZipEntryReport {
+                    name,
+                    status: "compressed",
+                    output: Some(output_name),
+                    original_size: input.len() as u64,
+                    compressed_size: Some(output.len() as u64),
+                    reason: None,
+                    duplicate_of,
+                });
+            }
+            Err(reason) => {
+                warn!("Failed to compress ZIP entry {}: {}", name, reason);
+                reports.push(ZipEntryReport {
+                    name,
+                    status: "failed",
+                    output: None,
+                    original_size: input.len() as u64,
+                    compressed_size: None,
+                    reason: Some(reason),
+                    duplicate_of,
+                });
+            }
+        }
+    }
+
+    let report = serde_json::to_vec_pretty(&serde_json::json!({ "entries": reports }))
+        .map_err(|e| ImageServerError::ProcessingError(format!("Failed to write ZIP report: {}", e)))?;
+    writer
+        .start_file(ZIP_REPORT_NAME, FileOptions::default().compression_method(CompressionMethod::Deflated))
+        .map_err(write_error)?;
+    writer
+        .write_all(&report)
+        .map_err(|e| ImageServerError::ProcessingError(format!("Failed to write ZIP: {}", e)))?;
+    let mut output = writer.finish().map_err(write_error)?;
+
+    info!(
+        "ZIP 处理完成 - 条目数: {}, 输出大小: {} bytes",
+        reports.len(),
+        output.stream_position().unwrap_or(0)
+    );
+    Ok((output, reports))
+}
+
+// 返回与已输出文件名都不冲突的名称并记录，冲突时在扩展名前追加后缀
+fn unique_output_name(name: String, input: &[u8], naming: ZipNaming, used: &mut HashSet<String>) -> String {
+    if used.insert(name.to_lowercase()) {
+        return name;
+    }
+
+    let path = Path::new(&name);
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy()).unwrap_or_default();
+    let with_suffix = |suffix: &str| {
+        let file_name = match path.extension() {
+            Some(extension) => format!("{}-{}.{}", stem, suffix, extension.to_string_lossy()),
+            None => format!("{}-{}", stem, suffix),
+        };
+        path.with_file_name(file_name).to_string_lossy().into_owned()
+    };
+
+    if naming == ZipNaming::Hash {
+        let digest = Sha256::digest(input);
+        let hash: String = digest[..4].iter().map(|b| format!("{:02x}", b)).collect();
+        let candidate = with_suffix(&hash);
+        if used.insert(candidate.to_lowercase()) {
+            return candidate;
+        }
+    }
+    // 内容相同的条目哈希后缀也相同，仍然冲突时改用序号
+    let mut counter = 1;
+    loop {
+        let candidate = with_suffix(&counter.to_string());
+        if used.insert(candidate.to_lowercase()) {
+            info!("ZIP 输出文件名 {} 已存在，改为 {}", name, candidate);
+            return candidate;
+        }
+        counter += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zip_of(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in entries {
+            if name.ends_with('/') {
+                writer.add_directory(*name, FileOptions::default()).unwrap();
+            } else {
+                writer.start_file(*name, FileOptions::default()).unwrap();
+                writer.write_all(data).unwrap();
+            }
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_compress_zip() {
+        let mut png = Vec::new();
+        image::DynamicImage::new_rgb8(4, 4)
+            .write_to(&mut Cursor::new(&mut png), image::ImageOutputFormat::Png)
+            .unwrap();
+        let input = zip_of(&[
+            ("assets/", b""),
+            ("assets/logo.png", &png),
+            ("assets/readme.txt", b"hello"),
+            ("../escape.png", &png),
+        ]);
+
+        let (output, reports) = compress_zip(&input, 1024 * 1024, ZipNaming::Counter, |data| Ok((data.to_vec(), "jpg"))).unwrap();
+        let statuses: Vec<(&str, &str)> = reports.iter().map(|r| (r.name.as_str(), r.status)).collect();
+        assert_eq!(
+            statuses,
+            vec![("assets/logo.png", "compressed"), ("assets/readme.txt", "skipped"), ("../escape.png", "skipped")]
+        );
+        assert_eq!(reports[0].output.as_deref(), Some("assets/logo.jpg"));
+        assert_eq!(reports[1].reason.as_deref(), Some("not an image"));
+
+        let mut archive = ZipArchive::new(Cursor::new(output)).unwrap();
+        let names: Vec<&str> = archive.file_names().collect();
+        assert!(names.contains(&"assets/logo.jpg"));
+        assert!(names.contains(&ZIP_REPORT_NAME));
+        assert!(!names.iter().any(|name| name.contains("escape")));
+        let mut logo = Vec::new();
+        archive.by_name("assets/logo.jpg").unwrap().read_to_end(&mut logo).unwrap();
+        assert_eq!(logo, png);
+
+        // 解压后超过上限的条目跳过
+        let (_, reports) = compress_zip(&input, 16, ZipNaming::Counter, |data| Ok((data.to_vec(), "jpg"))).unwrap();
+        assert_eq!(reports[0].status, "skipped");
+
+        assert!(matches!(
+            compress_zip(b"not a zip", 1024, ZipNaming::Counter, |data| Ok((data.to_vec(), "jpg"))),
+            Err(ImageServerError::InvalidParameters(_))
+        ));
+    }
+
+    #[test]
+    fn test_compress_zip_deduplicates_entries() {
+        let mut png = Vec::new();
+        image::DynamicImage::new_rgb8(4, 4)
+            .write_to(&mut Cursor::new(&mut png), image::ImageOutputFormat::Png)
+            .unwrap();
+        let mut other = Vec::new();
+        image::DynamicImage::new_rgb8(8, 8)
+            .write_to(&mut Cursor::new(&mut other), image::ImageOutputFormat::Png)
+            .unwrap();
+        let input = zip_of(&[("a/logo.png", &png), ("other.png", &other), ("b/logo.png", &png), ("c/logo.png", &png)]);
+
+        let mut calls = 0;
+        let (output, reports) = compress_zip(&input, 1024 * 1024, ZipNaming::Counter, |data| {
+            calls += 1;
+            Ok((data.to_vec(), "jpg"))
+        })
+        .unwrap();
+        assert_eq!(calls, 2);
+        let duplicates: Vec<Option<&str>> = reports.iter().map(|r| r.duplicate_of.as_deref()).collect();
+        assert_eq!(duplicates, vec![None, None, Some("a/logo.png"), Some("a/logo.png")]);
+        assert!(reports.iter().all(|r| r.status == "compressed"));
+
+        let mut archive = ZipArchive::new(Cursor::new(output)).unwrap();
+        let mut logo = Vec::new();
+        archive.by_name("c/logo.jpg").unwrap().read_to_end(&mut logo).unwrap();
+        assert_eq!(logo, png);
+
+        // 失败结果同样复用
+        let mut calls = 0;
+        let (_, reports) = compress_zip(&input, 1024 * 1024, ZipNaming::Counter, |_| {
+            calls += 1;
+            Err(ImageServerError::UnsupportedFormat)
+        })
+        .unwrap();
+        assert_eq!(calls, 2);
+        assert_eq!(reports[3].status, "failed");
+        assert_eq!(reports[3].duplicate_of.as_deref(), Some("a/logo.png"));
+        assert_eq!(reports[3].reason, reports[0].reason);
+    }
+
+    #[test]
+    fn test_compress_zip_avoids_name_collisions() {
+        let mut png = Vec::new();
+        image::DynamicImage::new_rgb8(4, 4)
+            .write_to(&mut Cursor::new(&mut png), image::ImageOutputFormat::Png)
+            .unwrap();
+        let mut jpeg = Vec::new();
+        image::DynamicImage::new_rgb8(8, 8)
+            .write_to(&mut Cursor::new(&mut jpeg), image::ImageOutputFormat::Jpeg(80))
+            .unwrap();
+        let input = zip_of(&[("img/logo.png", &png), ("img/logo.jpg", &jpeg), ("img/LOGO.jpeg", &jpeg)]);
+
+        let (output, reports) = compress_zip(&input, 1024 * 1024, ZipNaming::Counter, |data| Ok((data.to_vec(), "jpg"))).unwrap();
+        let names: Vec<Option<&str>> = reports.iter().map(|r| r.output.as_deref()).collect();
+        assert_eq!(names, vec![Some("img/logo.jpg"), Some("img/logo-1.jpg"), Some("img/LOGO-2.jpg")]);
+        let archive = ZipArchive::new(Cursor::new(output)).unwrap();
+        assert_eq!(archive.len(), 4);
+
+        // 哈希后缀取自输入内容；内容相同的第三个条目退回序号
+        let (_, reports) = compress_zip(&input, 1024 * 1024, ZipNaming::Hash, |data| Ok((data.to_vec(), "jpg"))).unwrap();
+        let hash: String = Sha256::digest(&jpeg)[..4].iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(reports[1].output, Some(format!("img/logo-{}.jpg", hash)));
+        assert_eq!(reports[2].output.as_deref(), Some("img/LOGO-1.jpg"));
+
+        assert_eq!(ZipNaming::from_name("Hash"), Some(ZipNaming::Hash));
+        assert_eq!(ZipNaming::from_name("uuid"), None);
+    }
+
+    #[test]
+    fn test_compress_zip_to_file() {
+        let dir = std::env::temp_dir().join(format!("img-server-zip-test-{}", uuid::Uuid::new_v4().simple()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = zip_of(&[("readme.txt", b"hello")]);
+
+        let (path, reports) = compress_zip_to_file(&input, 1024, ZipNaming::Counter, &dir, |data| Ok((data.to_vec(), "jpg"))).unwrap();
+        assert!(path.starts_with(&dir));
+        assert_eq!(reports[0].status, "skipped");
+        let archive = ZipArchive::new(std::fs::File::open(&path).unwrap()).unwrap();
+        assert!(archive.file_names().any(|name| name == ZIP_REPORT_NAME));
+        std::fs::remove_file(&path).unwrap();
+
+        // 失败时不留下临时文件
+        assert!(compress_zip_to_file(b"not a zip", 1024, ZipNaming::Counter, &dir, |data| Ok((data.to_vec(), "jpg"))).is_err());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir(&dir).unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+use std::fs;
+use std::path::PathBuf;
+
+use crate::compression;
+
+/// `compress` 子命令的参数
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompressArgs {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub quality: u8,
+    /// 未指定时由 `output_format` 按输入内容推断
+    pub format: Option<String>,
+    pub algorithm: String,
+}
+
+pub const COMPRESS_USAGE: &str =
+    "Usage: img-server-rs compress <input> <output> [--quality N] [--format F] [--algorithm A]";
+
+impl CompressArgs {
+    /// 解析 `compress` 之后的参数
+    pub fn parse(args: &[String], default_algorithm: &str) -> Result<Self, String> {
+        let mut positional = Vec::new();
+        let mut quality = None;
+        let mut format = None;
+        let mut algorithm = None;
+
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--quality" | "-q" => {
+                    let value = iter.next().ok_or("--quality requires a value")?;
+                    let q = value
+                        .parse::<u8>()
+                        .map_err(|_| format!("Invalid quality: {}", value))?;
+                    quality = Some(q.min(100));
+                }
+                "--format" | "-f" => {
+                    format = Some(iter.next().ok_or("--format requires a value")?.clone());
+                }
+                "--algorithm" | "-a" => {
+                    algorithm = Some(iter.next().ok_or("--algorithm requires a value")?.clone());
+                }
+                other if other.starts_with('-') => {
+                    return Err(format!("Unknown option: {}", other));
+                }
+                _ => positional.push(arg.clone()),
+            }
+        }
+
+        let [input, output]: [String; 2] = positional
+            .try_into()
+            .map_err(|_| "Expected exactly <input> and <output>".to_string())?;
+        let output = PathBuf::from(output);
+
+        Ok(Self {
+            input: PathBuf::from(input),
+            output,
+            quality: quality.unwrap_or(85),
+            format,
+            algorithm: algorithm.unwrap_or_else(|| default_algorithm.to_string()),
+        })
+    }
+
+    /// 输出格式：未指定时与 HTTP 接口相同，按输入内容检测，能编码的格式保持不变，其他输出 JPEG；
+    /// 内容无法识别时才参考输出文件扩展名
+    pub fn output_format(&self, data: &[u8]) -> String {
+        self.format.clone().unwrap_or_else(|| {
+            let extension = self.output.extension().and_then(|extension| extension.to_str());
+            compression::default_format_for_input(data, extension).name().to_string()
+        })
+    }
+
+    /// 按输出格式的最低质量修正后的质量
+    pub fn quality_for(&self, format: &str) -> u8 {
+        self.quality.max(compression::min_quality(format))
+    }
+}
+
+/// 使用与服务端相同的压缩流程处理本地文件
+pub fn run_compress(args: &CompressArgs) -> Result<(), String> {
+    let data = fs::read(&args.input)
+        .map_err(|e| format!("Failed to read {:?}: {}", args.input, e))?;
+
+    let format = args.output_format(&data);
+    let quality = args.quality_for(&format);
+    let (compressed, width, height, _exif_info) =
+        compression::compress_image(&data, &format, quality, &args.algorithm)?;
+
+    fs::write(&args.output, &compressed)
+        .map_err(|e| format!("Failed to write {:?}: {}", args.output, e))?;
+
+    println!(
+        "{} -> {}: {} -> {} bytes ({}x{})",
+        args.input.display(),
+        args.output.display(),
+        data.len(),
+        compressed.len(),
+        width,
+        height
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_defaults() {
+        let parsed = CompressArgs::parse(&args(&["in.jpg", "out.png"]), "mozjpeg").unwrap();
+        assert_eq!(parsed.format, None);
+        assert_eq!(parsed.quality, 85);
+        assert_eq!(parsed.algorithm, "mozjpeg");
+    }
+
+    #[test]
+    fn test_output_format() {
+        let mut png = Vec::new();
+        image::RgbaImage::new(4, 4)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageOutputFormat::Png)
+            .unwrap();
+
+        // 按输入内容检测，与输出扩展名无关
+        let parsed = CompressArgs::parse(&args(&["in.png", "out.jpg"]), "mozjpeg").unwrap();
+        assert_eq!(parsed.output_format(&png), "png");
+        // 内容无法识别时参考输出扩展名
+        let parsed = CompressArgs::parse(&args(&["in.dat", "out.png"]), "mozjpeg").unwrap();
+        assert_eq!(parsed.output_format(b"not an image"), "png");
+        let parsed = CompressArgs::parse(&args(&["in.dat", "out.bin"]), "mozjpeg").unwrap();
+        assert_eq!(parsed.output_format(b"not an image"), "jpeg");
+        // 显式指定的格式优先
+        let parsed = CompressArgs::parse(&args(&["in.png", "out.bin", "-f", "webp"]), "mozjpeg").unwrap();
+        assert_eq!(parsed.output_format(&png), "webp");
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let parsed = CompressArgs::parse(
+            &args(&["in.png", "out.bin", "--quality", "60", "--format", "jpeg", "--algorithm", "jpeg-encoder"]),
+            "mozjpeg",
+        )
+        .unwrap();
+        assert_eq!(parsed.quality, 60);
+        assert_eq!(parsed.format.as_deref(), Some("jpeg"));
+        assert_eq!(parsed.algorithm, "jpeg-encoder");
+
+        // PNG 允许质量 0，JPEG 最低为 1
+        let parsed = CompressArgs::parse(&args(&["in.png", "out.png", "-q", "0"]), "mozjpeg").unwrap();
+        assert_eq!(parsed.quality_for("png"), 0);
+        assert_eq!(parsed.quality_for("jpeg"), 1);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(CompressArgs::parse(&args(&["in.png"]), "mozjpeg").is_err());
+        assert!(CompressArgs::parse(&args(&["in.png", "out.png", "--quality"]), "mozjpeg").is_err());
+        assert!(CompressArgs::parse(&args(&["in.png", "out.png", "--bogus"]), "mozjpeg").is_err());
+    }
+}
@@ -0,0 +1,337 @@
+use image::codecs::jpeg::JpegDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::{DynamicImage, ImageDecoder, ImageFormat};
+use log::info;
+use std::io::Cursor;
+
+/// 输出色彩空间
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputColorSpace {
+    /// 保持源像素不变
+    #[default]
+    Original,
+    /// 按源 ICC 配置文件转换到 sRGB
+    Srgb,
+}
+
+impl OutputColorSpace {
+    pub const NAMES: [&'static str; 2] = ["original", "srgb"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "original" => Some(Self::Original),
+            "srgb" => Some(Self::Srgb),
+            _ => None,
+        }
+    }
+}
+
+// sRGB 原色在 D50 白点下的 XYZ（按列为 R、G、B），与 ICC sRGB 配置文件一致
+const SRGB_D50: [[f64; 3]; 3] = [
+    [0.436_074_7, 0.385_064_9, 0.143_080_4],
+    [0.222_504_5, 0.716_878_6, 0.060_616_9],
+    [0.013_932_2, 0.097_104_5, 0.714_173_3],
+];
+
+/// ICC 色调响应曲线
+#[derive(Debug, Clone, PartialEq)]
+enum ToneCurve {
+    Gamma(f64),
+    Table(Vec<u16>),
+    /// ICC parametricCurveType，参数依次为 g, a, b, c, d, e, f
+    Parametric(u16, [f64; 7]),
+}
+
+impl ToneCurve {
+    fn linearize(&self, v: f64) -> f64 {
+        match self {
+            Self::Gamma(g) => v.powf(*g),
+            Self::Table(table) => {
+                let pos = v * (table.len() - 1) as f64;
+                let i = (pos.floor() as usize).min(table.len() - 1);
+                let j = (i + 1).min(table.len() - 1);
+                let t = pos - i as f64;
+                (table[i] as f64 * (1.0 - t) + table[j] as f64 * t) / 65535.0
+            }
+            Self::Parametric(kind, [g, a, b, c, d, e, f]) => match kind {
+                0 => v.powf(*g),
+                1 if v >= -b / a => (a * v + b).powf(*g),
+                1 => 0.0,
+                2 if v >= -b / a => (a * v + b).powf(*g) + c,
+                2 => *c,
+                3 if v >= *d => (a * v + b).powf(*g),
+                3 => c * v,
+                _ if v >= *d => (a * v + b).powf(*g) + e,
+                _ => c * v + f,
+            },
+        }
+    }
+}
+
+/// 由原色矩阵和每通道色调曲线描述的 RGB 配置文件（绝大多数广色域配置文件属于此类）
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatrixShaperProfile {
+    to_xyz: [[f64; 3]; 3],
+    curves: [ToneCurve; 3],
+}
+
+/// 读取源图嵌入的 ICC 配置文件（支持 JPEG、PNG、WebP）
+pub fn read_icc_profile(data: &[u8]) -> Option<Vec<u8>> {
+    match image::guess_format(data).ok()? {
+        ImageFormat::Jpeg => JpegDecoder::new(Cursor::new(data)).ok()?.icc_profile(),
+        ImageFormat::Png => PngDecoder::new(Cursor::new(data)).ok()?.icc_profile(),
+        ImageFormat::WebP => WebPDecoder::new(Cursor::new(data)).ok()?.icc_profile(),
+        _ => None,
+    }
+}
+
+/// 解析矩阵/曲线型 RGB ICC 配置文件，基于 LUT 的配置文件返回 `None`
+pub fn parse_icc_profile(icc: &[u8]) -> Option<MatrixShaperProfile> {
+    if icc.len() < 132 || &icc[16..20] != b"RGB " {
+        return None;
+    }
+
+    let tag_count = read_u32(icc, 128)? as usize;
+    let find_tag = |signature: &[u8; 4]| -> Option<&[u8]> {
+        (0..tag_count).find_map(|i| {
+            let entry = 132 + i * 12;
+            if icc.get(entry..entry + 4)? != signature {
+                return None;
+            }
+            let offset = read_u32(icc, entry + 4)? as usize;
+            let size = read_u32(icc, entry + 8)? as usize;
+            icc.get(offset..offset.checked_add(size)?)
+        })
+    };
+
+    let mut to_xyz = [[0.0; 3]; 3];
+    for (column, signature) in [b"rXYZ", b"gXYZ", b"bXYZ"].iter().enumerate() {
+        let tag = find_tag(signature)?;
+        if tag.get(0..4)? != b"XYZ " {
+            return None;
+        }
+        for (row, values) in to_xyz.iter_mut().enumerate() {
+            values[column] = read_s15_fixed16(tag, 8 + row * 4)?;
+        }
+    }
+
+    let curves = [
+        parse_tone_curve(find_tag(b"rTRC")?)?,
+        parse_tone_curve(find_tag(b"gTRC")?)?,
+        parse_tone_curve(find_tag(b"bTRC")?)?,
+    ];
+
+    Some(MatrixShaperProfile { to_xyz, curves })
+}
+
+fn parse_tone_curve(tag: &[u8]) -> Option<ToneCurve> {
+    match tag.get(0..4)? {
+        b"curv" => {
+            let count = read_u32(tag, 8)? as usize;
+            match count {
+                0 => Some(ToneCurve::Gamma(1.0)),
+                1 => Some(ToneCurve::Gamma(read_u16(tag, 12)? as f64 / 256.0)),
+                _ => {
+                    let table = (0..count)
+                        .map(|i| read_u16(tag, 12 + i * 2))
+                        .collect::<Option<Vec<u16>>>()?;
+                    Some(ToneCurve::Table(table))
+                }
+            }
+        }
+        b"para" => {
+            let kind = read_u16(tag, 8)?;
+            let param_count = match kind {
+                0 => 1,
+                1 => 3,
+                2 => 4,
+                3 => 5,
+                4 => 7,
+                _ => return None,
+            };
+            let mut params = [0.0; 7];
+            for (i, param) in params.iter_mut().enumerate().take(param_count) {
+                *param = read_s15_fixed16(tag, 12 + i * 4)?;
+            }
+            Some(ToneCurve::Parametric(kind, params))
+        }
+        _ => None,
+    }
+}
+
+/// 按 ICC 配置文件将像素转换到 sRGB，配置文件无法解析时原样返回
+pub fn convert_to_srgb(img: DynamicImage, icc: &[u8]) -> DynamicImage {
+    let profile = match parse_icc_profile(icc) {
+        Some(profile) => profile,
+        None => {
+            info!("ICC 配置文件不是矩阵/曲线型 RGB 配置文件，跳过 sRGB 转换");
+            return img;
+        }
+    };
+
+    // 源 RGB -> XYZ(D50) -> 线性 sRGB
+    let srgb_inverse = match invert(&SRGB_D50) {
+        Some(inverse) => inverse,
+        None => return img,
+    };
+    let matrix = multiply(&srgb_inverse, &profile.to_xyz);
+
+    // 每通道 8 位输入只有 256 种取值，预先计算线性化结果
+    let linear: Vec<[f64; 256]> = profile
+        .curves
+        .iter()
+        .map(|curve| {
+            let mut table = [0.0; 256];
+            for (i, value) in table.iter_mut().enumerate() {
+                *value = curve.linearize(i as f64 / 255.0);
+            }
+            table
+        })
+        .collect();
+
+    let convert = |r: u8, g: u8, b: u8| -> [u8; 3] {
+        let input = [linear[0][r as usize], linear[1][g as usize], linear[2][b as usize]];
+        let mut output = [0u8; 3];
+        for (channel, row) in output.iter_mut().zip(matrix.iter()) {
+            let value = row[0] * input[0] + row[1] * input[1] + row[2] * input[2];
+            *channel = (srgb_encode(value.clamp(0.0, 1.0)) * 255.0).round() as u8;
+        }
+        output
+    };
+
+    info!("按 ICC 配置文件转换到 sRGB");
+    if img.color().has_alpha() {
+        let mut rgba = img.to_rgba8();
+        for pixel in rgba.pixels_mut() {
+            let [r, g, b] = convert(pixel[0], pixel[1], pixel[2]);
+            pixel[0] = r;
+            pixel[1] = g;
+            pixel[2] = b;
+        }
+        DynamicImage::ImageRgba8(rgba)
+    } else {
+        let mut rgb = img.to_rgb8();
+        for pixel in rgb.pixels_mut() {
+            pixel.0 = convert(pixel[0], pixel[1], pixel[2]);
+        }
+        DynamicImage::ImageRgb8(rgb)
+    }
+}
+
+fn srgb_encode(linear: f64) -> f64 {
+    if linear <= 0.003_130_8 {
+        linear * 12.92
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn multiply(a: &[[f64; 3]; 3], b: &[[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    let mut result = [[0.0; 3]; 3];
+    for (i, row) in result.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    result
+}
+
+fn invert(m: &[[f64; 3]; 3]) -> Option<[[f64; 3]; 3]> {
+    let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+
+    Some([
+        [
+            (m[1][1] * m[2][2] - m[1][2] * m[2][1]) / det,
+            (m[0][2] * m[2][1] - m[0][1] * m[2][2]) / det,
+            (m[0][1] * m[1][2] - m[0][2] * m[1][1]) / det,
+        ],
+        [
+            (m[1][2] * m[2][0] - m[1][0] * m[2][2]) / det,
+            (m[0][0] * m[2][2] - m[0][2] * m[2][0]) / det,
+            (m[0][2] * m[1][0] - m[0][0] * m[1][2]) / det,
+        ],
+        [
+            (m[1][0] * m[2][1] - m[1][1] * m[2][0]) / det,
+            (m[0][1] * m[2][0] - m[0][0] * m[2][1]) / det,
+            (m[0][0] * m[1][1] - m[0][1] * m[1][0]) / det,
+        ],
+    ])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn read_s15_fixed16(data: &[u8], offset: usize) -> Option<f64> {
+    Some(read_u32(data, offset)? as i32 as f64 / 65536.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 构造使用 sRGB 原色和单一 gamma 曲线的最小 ICC 配置文件
+    fn test_profile(gamma: f64) -> Vec<u8> {
+        let mut icc = vec![0u8; 128];
+        icc[16..20].copy_from_slice(b"RGB ");
+        let tags: [&[u8; 4]; 6] = [b"rXYZ", b"gXYZ", b"bXYZ", b"rTRC", b"gTRC", b"bTRC"];
+        icc.extend_from_slice(&(tags.len() as u32).to_be_bytes());
+
+        let mut data = Vec::new();
+        let data_start = 132 + tags.len() * 12;
+        for (i, signature) in tags.iter().enumerate() {
+            let body = if i < 3 {
+                let mut body = b"XYZ \0\0\0\0".to_vec();
+                for row in SRGB_D50.iter() {
+                    body.extend_from_slice(&((row[i] * 65536.0).round() as i32).to_be_bytes());
+                }
+                body
+            } else {
+                let mut body = b"curv\0\0\0\0".to_vec();
+                body.extend_from_slice(&1u32.to_be_bytes());
+                body.extend_from_slice(&((gamma * 256.0) as u16).to_be_bytes());
+                body.extend_from_slice(&[0, 0]);
+                body
+            };
+            icc.extend_from_slice(*signature);
+            icc.extend_from_slice(&((data_start + data.len()) as u32).to_be_bytes());
+            icc.extend_from_slice(&(body.len() as u32).to_be_bytes());
+            data.extend_from_slice(&body);
+        }
+        icc.extend_from_slice(&data);
+        icc
+    }
+
+    #[test]
+    fn test_parse_icc_profile() {
+        let profile = parse_icc_profile(&test_profile(2.2)).unwrap();
+        assert_eq!(profile.curves[0], ToneCurve::Gamma(563.0 / 256.0));
+        assert!((profile.to_xyz[0][0] - SRGB_D50[0][0]).abs() < 1e-4);
+        assert!(parse_icc_profile(b"not an icc profile").is_none());
+    }
+
+    #[test]
+    fn test_convert_linear_profile_to_srgb() {
+        // 线性 (gamma 1.0) 的中灰在 sRGB 中更亮
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(2, 2, image::Rgb([128, 128, 128])));
+        let converted = convert_to_srgb(img, &test_profile(1.0)).to_rgb8();
+        let value = converted.get_pixel(0, 0)[0];
+        assert!((186..=190).contains(&value), "got {}", value);
+    }
+
+    #[test]
+    fn test_output_color_space_from_name() {
+        assert_eq!(OutputColorSpace::from_name("sRGB"), Some(OutputColorSpace::Srgb));
+        assert_eq!(OutputColorSpace::from_name("p3"), None);
+    }
+}
@@ -1,8 +1,418 @@
-use log::info;
+use log::{info, warn};
 use image::DynamicImage;
 use std::time::Instant;
+use std::collections::HashMap;
 use std::io::Cursor;
 use exif::{Reader, In, Tag, Value};
+use tracing::info_span;
+
+use crate::animation;
+use crate::color::{self, OutputColorSpace};
+use crate::errors::ImageServerError;
+use crate::heif;
+use crate::jpeg_transform::{self, Transform};
+use crate::pipeline::{self, Operation};
+use crate::quality;
+
+/// 单次压缩请求的参数
+#[derive(Debug, Clone)]
+pub struct CompressionOptions {
+    pub format: String,
+    pub quality: u8,
+    pub algorithm: String,
+    /// 索引 PNG 的位深度（1、2、4、8），为 `None` 时根据调色板大小自动选择
+    pub png_depth: Option<u8>,
+    /// imagequant 的速度 1-10（1 最慢、质量最好），为 `None` 时使用 imagequant 默认值 4
+    pub png_quantize_speed: Option<u8>,
+    /// 量化实际达到的质量低于此值时改为输出无损真彩色 PNG，0 表示不回退
+    pub png_min_quantization_quality: u8,
+    /// JPEG 输出是否强制使用渐进式编码
+    pub progressive: bool,
+    /// 输出为 JPEG 时用于填充透明区域的背景色
+    pub background: Option<[u8; 3]>,
+    /// mozjpeg 使用的量化表
+    pub quant_table: JpegQuantTable,
+    /// mozjpeg 亮度、色度量化表各自使用的质量，为 `None` 时使用 `quality`
+    pub luma_quality: Option<u8>,
+    pub chroma_quality: Option<u8>,
+    /// 目标 SSIM，设置后忽略 `quality` 并搜索满足目标的最低质量
+    pub target_ssim: Option<f64>,
+    /// mozjpeg 的扫描和 Huffman 编码模式，为 `None` 时使用 mozjpeg 默认设置
+    pub jpeg_mode: Option<JpegMode>,
+    /// 索引 PNG 的抖动设置
+    pub dither: Dither,
+    /// JPEG 输出时保留源图的拍摄参数 EXIF（见 `PRESERVED_EXIF_TAGS`）
+    pub preserve_exif: bool,
+    /// 输出宽高都不超过 `thumbnail_max_dimension` 时额外提高的 JPEG 质量，0 表示不提高
+    pub thumbnail_quality_boost: u8,
+    pub thumbnail_max_dimension: u32,
+    /// 输出色彩空间，`Srgb` 时按源 ICC 配置文件转换像素
+    pub color_space: OutputColorSpace,
+    /// 写入输出文件的注释：JPEG 为 COM 段，PNG 为 "Comment" 文本块
+    pub comment: Option<String>,
+    /// 写入输出文件的 XMP 包：JPEG 为 APP1 段，PNG 为关键字 "XML:com.adobe.xmp" 的 iTXt 块
+    pub xmp: Option<String>,
+    /// 允许使用的最高质量，缩略图质量提升和 SSIM 搜索都不会超过它
+    pub max_quality: u8,
+    /// 缩放使用的默认滤镜
+    pub resize_filter: ResizeFilter,
+    /// 宽度缩小到 1/N 及以下时改用 Triangle 滤镜，0 表示总是使用 `resize_filter`
+    pub fast_downscale_factor: u32,
+    /// 索引 PNG 量化单线程执行，相同输入和参数总是得到逐字节相同的输出
+    pub deterministic: bool,
+    /// 编码后重新解码输出，尺寸或方向与预期不符时记录警告
+    pub verify_output: bool,
+    /// 源图为索引 PNG：PNG 输出的颜色数不超过调色板上限时沿用原有颜色，不重新量化
+    pub source_indexed: bool,
+    /// 编码前按顺序执行的预处理操作，APNG 输出不支持
+    pub operations: Vec<Operation>,
+    /// JPEG 输入只需旋转/翻转时在 DCT 域无损变换，保留源图质量和全部元数据，见 `lossless_transform_for`
+    pub lossless_rotate: bool,
+    /// 输出大小上限（字节），设置后忽略 `quality` 并二分查找放得下的最高质量，仅 JPEG
+    pub max_bytes: Option<usize>,
+    /// 按 `max_bytes` 搜索时最多编码的次数，以及提前结束的容差（低于目标的百分比）
+    pub size_search_max_iterations: u32,
+    pub size_search_tolerance_percent: f64,
+}
+
+impl CompressionOptions {
+    pub fn new(format: &str, quality: u8, algorithm: &str) -> Self {
+        Self {
+            format: format.to_string(),
+            quality,
+            algorithm: algorithm.to_string(),
+            png_depth: None,
+            png_quantize_speed: None,
+            png_min_quantization_quality: 0,
+            progressive: false,
+            background: None,
+            quant_table: JpegQuantTable::Default,
+            luma_quality: None,
+            chroma_quality: None,
+            target_ssim: None,
+            jpeg_mode: None,
+            dither: Dither::default(),
+            preserve_exif: false,
+            thumbnail_quality_boost: 0,
+            thumbnail_max_dimension: 0,
+            color_space: OutputColorSpace::Original,
+            comment: None,
+            xmp: None,
+            max_quality: 100,
+            resize_filter: ResizeFilter::default(),
+            fast_downscale_factor: 0,
+            deterministic: false,
+            verify_output: false,
+            source_indexed: false,
+            operations: Vec::new(),
+            lossless_rotate: false,
+            max_bytes: None,
+            size_search_max_iterations: 7,
+            size_search_tolerance_percent: 5.0,
+        }
+    }
+
+    /// 从 `original_width` 缩小到 `width` 时使用的滤镜
+    ///
+    /// 大倍数缩小时 Lanczos 等滤镜的画质优势几乎看不出，而耗时明显更高，因此改用 Triangle。
+    pub fn resize_filter_for(&self, original_width: u32, width: u32) -> ResizeFilter {
+        let large_factor = self.fast_downscale_factor > 0
+            && original_width as u64 >= width as u64 * self.fast_downscale_factor as u64;
+        if large_factor && self.resize_filter.is_slower_than(ResizeFilter::Triangle) {
+            ResizeFilter::Triangle
+        } else {
+            self.resize_filter
+        }
+    }
+
+    /// 按输出尺寸得到实际使用的质量：缩略图体积本来就小，提高质量的代价很低
+    pub fn quality_for(&self, width: u32, height: u32) -> u8 {
+        let is_jpeg = is_format(&self.format, Format::Jpeg);
+        if is_jpeg
+            && self.thumbnail_quality_boost > 0
+            && width <= self.thumbnail_max_dimension
+            && height <= self.thumbnail_max_dimension
+        {
+            self.quality.saturating_add(self.thumbnail_quality_boost).min(self.max_quality.clamp(1, 100)).max(self.quality)
+        } else {
+            self.quality
+        }
+    }
+
+    /// mozjpeg 亮度和色度实际使用的质量，未单独指定的通道使用 `quality`
+    pub fn channel_qualities(&self) -> (u8, u8) {
+        (self.luma_quality.unwrap_or(self.quality), self.chroma_quality.unwrap_or(self.quality))
+    }
+
+    // 尺寸对应的质量与设置不同时返回调整后的副本
+    fn for_dimensions(&self, width: u32, height: u32) -> std::borrow::Cow<'_, Self> {
+        let quality = self.quality_for(width, height);
+        if quality == self.quality {
+            std::borrow::Cow::Borrowed(self)
+        } else {
+            info!("缩略图 {}x{} 质量提升: {} -> {}", width, height, self.quality, quality);
+            let mut options = self.clone();
+            options.quality = quality;
+            std::borrow::Cow::Owned(options)
+        }
+    }
+}
+
+/// `Dither::Auto` 下量化后调色板不超过该颜色数时视为平面图形，关闭抖动
+pub const AUTO_DITHER_MAX_COLORS: usize = 64;
+
+/// 索引 PNG 的抖动设置
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dither {
+    /// 固定抖动强度（0.0 - 1.0）
+    Level(f32),
+    /// 按量化后的颜色数决定：颜色少的平面图形不抖动，照片类内容完全抖动
+    Auto,
+}
+
+impl Default for Dither {
+    fn default() -> Self {
+        Self::Level(1.0)
+    }
+}
+
+impl Dither {
+    /// 解析 `auto`、`none`/`off`、`full`/`on` 或 0.0 - 1.0 之间的强度
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "auto" => Some(Self::Auto),
+            "none" | "off" => Some(Self::Level(0.0)),
+            "full" | "on" => Some(Self::Level(1.0)),
+            other => other
+                .parse::<f32>()
+                .ok()
+                .filter(|level| (0.0..=1.0).contains(level))
+                .map(Self::Level),
+        }
+    }
+
+    /// 根据量化后的调色板大小得到实际抖动强度
+    pub fn level_for(self, palette_len: usize) -> f32 {
+        match self {
+            Self::Level(level) => level,
+            Self::Auto if palette_len <= AUTO_DITHER_MAX_COLORS => 0.0,
+            Self::Auto => 1.0,
+        }
+    }
+}
+
+/// mozjpeg 的编码模式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JpegMode {
+    /// 单次扫描、标准 Huffman 表，编码和解码最快
+    Baseline,
+    /// 多次扫描的渐进式编码
+    Progressive,
+    /// 单次扫描、优化 Huffman 表，适合解码能力较弱的设备
+    Optimized,
+}
+
+impl JpegMode {
+    pub const NAMES: [&'static str; 3] = ["baseline", "progressive", "optimized"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "baseline" => Some(Self::Baseline),
+            "progressive" => Some(Self::Progressive),
+            "optimized" => Some(Self::Optimized),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Baseline => "baseline",
+            Self::Progressive => "progressive",
+            Self::Optimized => "optimized",
+        }
+    }
+}
+
+/// 缩放滤镜，按速度从快到慢排列
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    #[default]
+    Lanczos3,
+}
+
+impl ResizeFilter {
+    pub const NAMES: [&'static str; 5] = ["nearest", "triangle", "catmull-rom", "gaussian", "lanczos3"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "nearest" => Some(Self::Nearest),
+            "triangle" => Some(Self::Triangle),
+            "catmull-rom" => Some(Self::CatmullRom),
+            "gaussian" => Some(Self::Gaussian),
+            "lanczos3" => Some(Self::Lanczos3),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Nearest => "nearest",
+            Self::Triangle => "triangle",
+            Self::CatmullRom => "catmull-rom",
+            Self::Gaussian => "gaussian",
+            Self::Lanczos3 => "lanczos3",
+        }
+    }
+
+    pub fn is_slower_than(self, other: Self) -> bool {
+        self > other
+    }
+
+    pub fn filter_type(self) -> image::imageops::FilterType {
+        match self {
+            Self::Nearest => image::imageops::FilterType::Nearest,
+            Self::Triangle => image::imageops::FilterType::Triangle,
+            Self::CatmullRom => image::imageops::FilterType::CatmullRom,
+            Self::Gaussian => image::imageops::FilterType::Gaussian,
+            Self::Lanczos3 => image::imageops::FilterType::Lanczos3,
+        }
+    }
+}
+
+/// mozjpeg 可选的 JPEG 量化表
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum JpegQuantTable {
+    /// mozjpeg 默认量化表
+    #[default]
+    Default,
+    AnnexK,
+    Flat,
+    MsSsim,
+    ImageMagick,
+    PsnrHvs,
+    KleinSilversteinCarney,
+    WatsonTaylorBorthwick,
+    AhumadaWatsonPeterson,
+    PetersonAhumadaWatson,
+}
+
+impl JpegQuantTable {
+    pub const NAMES: [&'static str; 10] = [
+        "default", "annex-k", "flat", "ms-ssim", "imagemagick", "psnr-hvs",
+        "klein", "watson", "ahumada", "peterson",
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "default" => Some(Self::Default),
+            "annex-k" => Some(Self::AnnexK),
+            "flat" => Some(Self::Flat),
+            "ms-ssim" => Some(Self::MsSsim),
+            "imagemagick" => Some(Self::ImageMagick),
+            "psnr-hvs" => Some(Self::PsnrHvs),
+            "klein" => Some(Self::KleinSilversteinCarney),
+            "watson" => Some(Self::WatsonTaylorBorthwick),
+            "ahumada" => Some(Self::AhumadaWatsonPeterson),
+            "peterson" => Some(Self::PetersonAhumadaWatson),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Default => "default",
+            Self::AnnexK => "annex-k",
+            Self::Flat => "flat",
+            Self::MsSsim => "ms-ssim",
+            Self::ImageMagick => "imagemagick",
+            Self::PsnrHvs => "psnr-hvs",
+            Self::KleinSilversteinCarney => "klein",
+            Self::WatsonTaylorBorthwick => "watson",
+            Self::AhumadaWatsonPeterson => "ahumada",
+            Self::PetersonAhumadaWatson => "peterson",
+        }
+    }
+
+    /// 亮度和色度量化表，`Default` 时返回 `None` 使用 mozjpeg 内置表
+    #[cfg(feature = "mozjpeg")]
+    fn tables(self) -> Option<(&'static mozjpeg::qtable::QTable, &'static mozjpeg::qtable::QTable)> {
+        use mozjpeg::qtable;
+        match self {
+            Self::Default => None,
+            Self::AnnexK => Some((&qtable::AnnexK_Luma, &qtable::AnnexK_Chroma)),
+            Self::Flat => Some((&qtable::Flat, &qtable::Flat)),
+            Self::MsSsim => Some((&qtable::MSSSIM_Luma, &qtable::MSSSIM_Chroma)),
+            Self::ImageMagick => Some((&qtable::NRobidoux, &qtable::NRobidoux)),
+            Self::PsnrHvs => Some((&qtable::PSNRHVS_Luma, &qtable::PSNRHVS_Chroma)),
+            Self::KleinSilversteinCarney => Some((&qtable::KleinSilversteinCarney, &qtable::KleinSilversteinCarney)),
+            Self::WatsonTaylorBorthwick => Some((&qtable::WatsonTaylorBorthwick, &qtable::WatsonTaylorBorthwick)),
+            Self::AhumadaWatsonPeterson => Some((&qtable::AhumadaWatsonPeterson, &qtable::AhumadaWatsonPeterson)),
+            Self::PetersonAhumadaWatson => Some((&qtable::PetersonAhumadaWatson, &qtable::PetersonAhumadaWatson)),
+        }
+    }
+}
+
+/// 解码阶段的 EXIF 处理结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExifInfo {
+    /// 源图片的 EXIF 方向值（1-8），未读取或不存在时为 `None`
+    pub orientation: Option<u16>,
+    /// 是否已按方向值旋转/翻转像素
+    pub orientation_applied: bool,
+    /// 可读的处理说明，用于日志和 X-EXIF-Info
+    pub summary: String,
+}
+
+impl ExifInfo {
+    /// 没有读取或应用 EXIF 方向
+    pub fn unprocessed(summary: impl Into<String>) -> Self {
+        Self {
+            orientation: None,
+            orientation_applied: false,
+            summary: summary.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for ExifInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.summary)
+    }
+}
+
+/// 一次压缩的输出和统计信息
+#[derive(Debug, Clone)]
+pub struct CompressionResult {
+    pub data: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    /// 解码后、缩放前的源图尺寸（已按 EXIF 方向旋转），用于响应式图片计算源图与输出的比例
+    pub source_width: u32,
+    pub source_height: u32,
+    pub exif_info: ExifInfo,
+    /// 按目标 SSIM 或 `max_bytes` 搜索时实际使用的质量
+    pub chosen_quality: Option<u8>,
+    /// 按目标 SSIM 搜索时达到的 SSIM
+    pub achieved_ssim: Option<f64>,
+    /// 实际使用的质量（含缩略图质量提升或 SSIM 搜索结果）
+    pub effective_quality: u8,
+    /// 索引 PNG 量化实际达到的质量，请求的质量无法达到时低于 `effective_quality`
+    pub quantization_quality: Option<u8>,
+    /// 量化质量低于 `png_min_quantization_quality`，输出改为无损真彩色 PNG
+    pub png_lossless_fallback: bool,
+    /// 在 DCT 域无损执行的旋转/翻转，重新编码时为 `None`
+    pub lossless_transform: Option<Transform>,
+    /// `ops` 中各次缩放实际使用的滤镜，按执行顺序
+    pub resize_filters: Vec<ResizeFilter>,
+    /// 按 `max_bytes` 搜索时编码的次数
+    pub size_search_iterations: Option<u32>,
+    /// 按 `max_bytes` 搜索时输出是否放得下；为 `false` 时返回最低质量的结果
+    pub max_bytes_met: Option<bool>,
+}
 
 // 压缩图片的主要函数
 pub fn compress_image(
@@ -11,39 +421,58 @@ pub fn compress_image(
     quality: u8,
     algorithm: &str
 ) -> Result<(Vec<u8>, u32, u32, String), String> {
+    compress_image_with_options(data, &CompressionOptions::new(format, quality, algorithm))
+        .map(|result| (result.data, result.width, result.height, result.exif_info.summary))
+        .map_err(|e| e.to_string())
+}
+
+// 使用完整参数压缩图片
+pub fn compress_image_with_options(
+    data: &[u8],
+    options: &CompressionOptions,
+) -> Result<CompressionResult, ImageServerError> {
     let total_start = Instant::now();
     info!("开始压缩图片 - 目标格式: {}, 质量: {} (保持原始尺寸), 算法: {}", 
-         format, quality, algorithm);
-    
-    // 读取EXIF信息（仅针对JPEG）
-    let exif_orientation = if format.to_lowercase() == "jpeg" || format.to_lowercase() == "jpg" {
-        read_exif_orientation(data)
-    } else {
-        None
-    };
-    
-    if let Some(orientation) = exif_orientation {
-        info!("检测到EXIF方向信息: {}", orientation);
+         options.format, options.quality, options.algorithm);
+
+    // APNG 需要全部帧，不走单帧解码
+    if is_format(&options.format, Format::Apng) {
+        let animation = animation::decode_frames(data)?;
+        let (width, height) = animation.frames[0].buffer().dimensions();
+        let compressed_data = embed_comment(embed_xmp(animation::encode_apng(&animation)?, options), options);
+        info!("APNG 转换完成 - 总时间: {:.2}ms", total_start.elapsed().as_secs_f64() * 1000.0);
+        return Ok(CompressionResult {
+            data: compressed_data,
+            width,
+            height,
+            source_width: width,
+            source_height: height,
+            exif_info: ExifInfo::unprocessed("No EXIF processing"),
+            chosen_quality: None,
+            achieved_ssim: None,
+            effective_quality: options.quality,
+            quantization_quality: None,
+            png_lossless_fallback: false,
+            lossless_transform: None,
+            resize_filters: Vec::new(),
+            size_search_iterations: None,
+            max_bytes_met: None,
+        });
+    }
+
+    if options.lossless_rotate {
+        match lossless_transform_for(data, options).and_then(|transform| compress_lossless(data, transform, options)) {
+            Ok(result) => {
+                info!("无损变换完成 - 总时间: {:.2}ms", total_start.elapsed().as_secs_f64() * 1000.0);
+                return Ok(result);
+            }
+            Err(reason) => info!("无法无损变换，回退到重新编码: {}", reason),
+        }
     }
     
     // 优化的图片加载
     let load_start = Instant::now();
-    
-    // 使用通用解码器加载图片
-    let mut img = image::load_from_memory(data)
-        .map_err(|e| format!("Failed to decode image: {}", e))?;
-    
-    // 应用EXIF方向校正（仅在JPEG压缩时）
-    let exif_info = if format.to_lowercase() == "jpeg" || format.to_lowercase() == "jpg" {
-        if let Some(orientation) = exif_orientation {
-            img = apply_exif_orientation(img, orientation);
-            format!("Applied EXIF orientation: {}", orientation)
-        } else {
-            "No EXIF orientation found".to_string()
-        }
-    } else {
-        "No EXIF processing".to_string()
-    };
+    let (img, exif_info) = decode_image(data, options)?;
     
     let original_width = img.width();
     let original_height = img.height();
@@ -52,49 +481,574 @@ pub fn compress_image(
     info!("图片加载完成 - 尺寸: {}x{}, 加载时间: {:.2}ms, EXIF处理: {}", 
           original_width, original_height, load_duration.as_secs_f64() * 1000.0, exif_info);
     
+    let result = compress_decoded(data, img, exif_info, options)?;
+    info!("性能统计 - 加载时间: {:.2}ms, 总时间: {:.2}ms", 
+         load_duration.as_secs_f64() * 1000.0, 
+         total_start.elapsed().as_secs_f64() * 1000.0);
+    Ok(result)
+}
+
+/// 压缩已解码的图片
+///
+/// `data` 为原始输入，用于保留 EXIF 等需要读取源文件的处理。
+pub fn compress_decoded(
+    data: &[u8],
+    img: DynamicImage,
+    mut exif_info: ExifInfo,
+    options: &CompressionOptions,
+) -> Result<CompressionResult, ImageServerError> {
+    let (source_width, source_height) = (img.width(), img.height());
+    let (img, resize_filters) = pipeline::apply(img, &options.operations, &mut exif_info, options)?;
+    let original_width = img.width();
+    let original_height = img.height();
+    let span = info_span!(
+        "encode",
+        format = %options.format,
+        algorithm = %options.algorithm,
+        width = original_width,
+        height = original_height,
+        output_bytes = tracing::field::Empty,
+    );
+    let _entered = span.enter();
+
     // 不修改尺寸，直接压缩原始尺寸的图片
     let compression_start = Instant::now();
-    let compressed_data = match format.to_lowercase().as_str() {
-        "jpeg" | "jpg" => {
-            info!("进行 JPEG 压缩，保持原始尺寸 {}x{}，使用算法: {}", original_width, original_height, algorithm);
+    let mut size_search_iterations = None;
+    let mut max_bytes_met = None;
+    let (compressed_data, chosen_quality, achieved_ssim, effective_quality, quantization_quality, png_lossless_fallback) = match (options.target_ssim, options.max_bytes) {
+        (Some(target), _) => {
+            let result = quality::search_quality_for_ssim(&img, options, target)?;
+            (result.data, Some(result.quality), Some(result.ssim), result.quality, None, false)
+        }
+        (None, Some(max_bytes)) => {
+            let result = quality::search_quality_for_size(
+                &img,
+                options,
+                max_bytes,
+                options.size_search_max_iterations,
+                options.size_search_tolerance_percent,
+            )?;
+            size_search_iterations = Some(result.iterations);
+            max_bytes_met = Some(result.fits);
+            (result.data, Some(result.quality), None, result.quality, None, false)
+        }
+        (None, None) => {
+            let options = options.for_dimensions(original_width, original_height);
+            let encoded = encode_image_with_details(img, &options)?;
+            (encoded.data, None, None, options.quality, encoded.quantization_quality, encoded.png_lossless_fallback)
+        }
+    };
+    // WebP 总是无损编码，请求的质量不影响输出，如实报告为 100
+    let effective_quality = if is_format(&options.format, Format::Webp) { 100 } else { effective_quality };
+    // XMP 先写入，随后插入的 EXIF 段位于它之前
+    let compressed_data = embed_xmp(compressed_data, options);
+    let compressed_data = if options.preserve_exif && is_format(&options.format, Format::Jpeg) {
+        match build_preserved_exif(data) {
+            Some(tiff) => insert_jpeg_exif(&compressed_data, &tiff),
+            None => compressed_data,
+        }
+    } else {
+        compressed_data
+    };
+    let compressed_data = embed_comment(compressed_data, options);
+    let compression_duration = compression_start.elapsed();
+    span.record("output_bytes", compressed_data.len());
+
+    if options.verify_output {
+        if let Err(mismatch) = verify_output(&compressed_data, original_width, original_height, &exif_info) {
+            warn!("输出校验失败 - 格式: {}, {}", options.format, mismatch);
+        }
+    }
+    
+    info!("压缩完成 - 保持原始尺寸 {}x{}, 最终大小: {} bytes, 压缩时间: {:.2}ms",
+          original_width, original_height, compressed_data.len(), compression_duration.as_secs_f64() * 1000.0);
+    
+    Ok(CompressionResult {
+        data: compressed_data,
+        width: original_width,
+        height: original_height,
+        source_width,
+        source_height,
+        exif_info,
+        chosen_quality,
+        achieved_ssim,
+        effective_quality,
+        quantization_quality,
+        png_lossless_fallback,
+        lossless_transform: None,
+        resize_filters,
+        size_search_iterations,
+        max_bytes_met,
+    })
+}
+
+/// `lossless_rotate` 时判断能否跳过解码、在 DCT 域无损完成请求，返回需要执行的变换
+///
+/// 要求输入和输出都是 JPEG，`ops` 只包含 `auto-orient`、`rotate` 和 `flip`，且没有要求重新量化的参数
+/// （目标 SSIM、`max_bytes`、单独的亮度/色度质量、非默认量化表、扫描模式）或 ICC 转换。EXIF 方向总会先应用，
+/// 与解码路径一致。`Err` 中为不能无损变换的原因。
+pub fn lossless_transform_for(data: &[u8], options: &CompressionOptions) -> Result<Transform, String> {
+    if !is_format(&options.format, Format::Jpeg) {
+        return Err(format!("output format is {}", options.format));
+    }
+    if detect_progressive_jpeg(data) != Some(false) {
+        return Err("source is not a baseline JPEG".to_string());
+    }
+    if options.target_ssim.is_some()
+        || options.max_bytes.is_some()
+        || options.luma_quality.is_some()
+        || options.chroma_quality.is_some()
+        || options.quant_table != JpegQuantTable::Default
+    {
+        return Err("requested re-quantization".to_string());
+    }
+    if options.progressive || options.jpeg_mode.is_some() {
+        return Err("requested a JPEG scan mode".to_string());
+    }
+    if options.color_space == OutputColorSpace::Srgb && color::read_icc_profile(data).is_some() {
+        return Err("requested ICC conversion".to_string());
+    }
+
+    let mut transform = read_exif_orientation(data).map_or(Transform::IDENTITY, Transform::for_orientation);
+    for operation in &options.operations {
+        match (operation, operation.transform()) {
+            // JPEG 输出的 EXIF 方向已经计入
+            (Operation::AutoOrient, _) => {}
+            (_, Some(next)) => transform = transform.then(next),
+            (operation, None) => return Err(format!("operation {:?} requires re-encoding", operation)),
+        }
+    }
+    Ok(transform)
+}
+
+// 无损变换源 JPEG，质量和尺寸信息取自源图，注释和 XMP 照常写入
+fn compress_lossless(data: &[u8], transform: Transform, options: &CompressionOptions) -> Result<CompressionResult, String> {
+    let _entered = info_span!("lossless_transform", transform = transform.name()).entered();
+    let output = jpeg_transform::transform(data, transform)?;
+    let (width, height) = read_dimensions(&output).ok_or("transformed JPEG has no frame header")?;
+    let exif_info = exif_info_for(data, &options.format);
+    let orientation = exif_info.orientation.map_or(Transform::IDENTITY, Transform::for_orientation);
+    let (source_width, source_height) = read_dimensions(data)
+        .map(|(w, h)| orientation.output_size(w, h))
+        .unwrap_or((width, height));
+    let source_quality = quality::estimate_jpeg_quality(data).map(|estimate| estimate.quality).ok();
+    let output = embed_comment(embed_xmp(output, options), options);
+    info!("无损变换 {} - 尺寸: {}x{}, 大小: {} -> {} bytes", transform.name(), width, height, data.len(), output.len());
+
+    Ok(CompressionResult {
+        data: output,
+        width,
+        height,
+        source_width,
+        source_height,
+        exif_info,
+        chosen_quality: None,
+        achieved_ssim: None,
+        effective_quality: source_quality.unwrap_or(options.quality),
+        quantization_quality: None,
+        png_lossless_fallback: false,
+        lossless_transform: Some(transform),
+        resize_filters: Vec::new(),
+        size_search_iterations: None,
+        max_bytes_met: None,
+    })
+}
+
+/// 重新解码压缩输出，确认尺寸为 `width`x`height`，且已旋转像素的输出不再带 EXIF 方向
+///
+/// 像素已按 EXIF 方向旋转后输出仍带非 1 的方向值，查看器会再旋转一次。
+pub fn verify_output(output: &[u8], width: u32, height: u32, exif_info: &ExifInfo) -> Result<(), String> {
+    let decoded = image::load_from_memory(output)
+        .map_err(|e| format!("输出无法重新解码: {}", e))?;
+    if (decoded.width(), decoded.height()) != (width, height) {
+        return Err(format!(
+            "尺寸不符: 预期 {}x{}, 实际 {}x{}",
+            width, height, decoded.width(), decoded.height()
+        ));
+    }
+    if exif_info.orientation_applied {
+        if let Some(orientation) = read_exif_orientation(output).filter(|&o| o != 1) {
+            return Err(format!("像素已旋转但输出仍带 EXIF 方向 {}", orientation));
+        }
+    }
+    Ok(())
+}
+
+/// 确认输出能被重新解码，捕获编码器产生的损坏文件
+pub fn validate_decodable(output: &[u8], options: &CompressionOptions) -> Result<(), ImageServerError> {
+    image::load_from_memory(output)
+        .map(|_| ())
+        .map_err(|e| ImageServerError::OutputValidationFailed {
+            algorithm: options.algorithm.clone(),
+            format: options.format.clone(),
+            reason: e.to_string(),
+        })
+}
+
+/// 请求中的格式名，`jpg` 和 `jpeg` 是同一格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Jpeg,
+    Png,
+    /// 无损动画 PNG
+    Apng,
+    Webp,
+    /// 校验后原样返回上传内容
+    Original,
+}
+
+impl Format {
+    /// 规范名称，`jpg` 规范为 `jpeg`
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpeg",
+            Self::Png => "png",
+            Self::Apng => "apng",
+            Self::Webp => "webp",
+            Self::Original => "original",
+        }
+    }
+
+    /// 输出文件扩展名
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Png | Self::Apng => "png",
+            Self::Webp => "webp",
+            Self::Original => "bin",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::Png => "image/png",
+            Self::Apng => "image/apng",
+            Self::Webp => "image/webp",
+            Self::Original => "application/octet-stream",
+        }
+    }
+
+    /// 输出是否为 PNG 容器（含 APNG）
+    pub fn is_png(self) -> bool {
+        matches!(self, Self::Png | Self::Apng)
+    }
+}
+
+/// 解析格式名：不区分大小写，`jpg` 视为 `jpeg`，无法识别时返回 `None`
+pub fn normalize_format(format: &str) -> Option<Format> {
+    match format.trim().to_lowercase().as_str() {
+        "jpeg" | "jpg" => Some(Format::Jpeg),
+        "png" => Some(Format::Png),
+        "apng" => Some(Format::Apng),
+        "webp" => Some(Format::Webp),
+        "original" => Some(Format::Original),
+        _ => None,
+    }
+}
+
+/// 未指定输出格式时的默认格式：按内容检测输入格式，能编码的 PNG、WebP 保持不变，其他输出 JPEG；
+/// 内容无法识别时才参考文件扩展名 `extension`
+pub fn default_format_for_input(data: &[u8], extension: Option<&str>) -> Format {
+    match image::guess_format(data) {
+        Ok(image::ImageFormat::Png) => Format::Png,
+        Ok(image::ImageFormat::WebP) => Format::Webp,
+        Ok(_) => Format::Jpeg,
+        Err(_) => match extension.and_then(normalize_format) {
+            Some(format @ (Format::Png | Format::Webp)) => format,
+            _ => Format::Jpeg,
+        },
+    }
+}
+
+/// `format` 是否为指定格式，`jpg` 与 `jpeg` 等价
+pub fn is_format(format: &str, expected: Format) -> bool {
+    normalize_format(format) == Some(expected)
+}
+
+/// 输出格式允许的最低质量：PNG 量化质量可以为 0（最大程度量化、体积最小），其他格式为 1
+pub fn min_quality(format: &str) -> u8 {
+    if is_format(format, Format::Png) {
+        0
+    } else {
+        1
+    }
+}
+
+/// 请求可以使用的算法名，其他名称在 JPEG 输出时按 mozjpeg 处理
+pub const ALGORITHMS: [&str; 4] = ["mozjpeg", "jpeg-encoder", "png-quantized", "none"];
+
+/// 原始像素支持的通道数：1 为灰度，3 为 RGB，4 为 RGBA
+pub const RAW_CHANNELS: [u8; 3] = [1, 3, 4];
+
+/// 将按行排列的原始像素构造成图片，数据长度必须等于 `width * height * channels`
+pub fn image_from_raw(data: Vec<u8>, width: u32, height: u32, channels: u8) -> Result<DynamicImage, ImageServerError> {
+    let len = data.len();
+    let mismatch = || ImageServerError::InvalidParameters(format!(
+        "Declared {}x{}x{} does not match body length {}", width, height, channels, len
+    ));
+
+    match channels {
+        1 => image::GrayImage::from_raw(width, height, data).map(DynamicImage::ImageLuma8),
+        3 => image::RgbImage::from_raw(width, height, data).map(DynamicImage::ImageRgb8),
+        4 => image::RgbaImage::from_raw(width, height, data).map(DynamicImage::ImageRgba8),
+        _ => {
+            return Err(ImageServerError::InvalidParameters(format!(
+                "channels must be one of {:?}, got {}", RAW_CHANNELS, channels
+            )))
+        }
+    }
+    .filter(|img| img.as_bytes().len() == len)
+    .ok_or_else(mismatch)
+}
+
+/// 解码图片，输出为 JPEG 时应用 EXIF 方向校正
+///
+/// 返回解码后的图片和 EXIF 处理说明。
+pub fn decode_image(
+    data: &[u8],
+    options: &CompressionOptions,
+) -> Result<(DynamicImage, ExifInfo), ImageServerError> {
+    // 使用通用解码器加载图片
+    let img = info_span!("decode", bytes = data.len()).in_scope(|| {
+        if heif::is_heif(data) {
+            return heif::decode(data);
+        }
+        image::load_from_memory(data)
+            .map_err(|e| ImageServerError::CompressionError(format!("Failed to decode image: {}", e)))
+    })?;
+
+    Ok(finish_decode(data, img, options))
+}
+
+/// 对已解码的像素做色彩空间转换和 EXIF 方向校正，`data` 为原始文件，用于读取 ICC 和 EXIF
+pub fn finish_decode(data: &[u8], mut img: DynamicImage, options: &CompressionOptions) -> (DynamicImage, ExifInfo) {
+    let span = info_span!(
+        "transform",
+        color_space = ?options.color_space,
+        orientation = tracing::field::Empty,
+    );
+    let _entered = span.enter();
+    let exif_info = exif_info_for(data, &options.format);
+    if exif_info.orientation_applied {
+        span.record("orientation", exif_info.orientation);
+    }
+
+    if let Some(orientation) = exif_info.orientation {
+        info!("检测到EXIF方向信息: {}", orientation);
+    }
+
+    // 转换到 sRGB：没有嵌入 ICC 配置文件时视为已是 sRGB
+    if options.color_space == OutputColorSpace::Srgb {
+        if let Some(icc) = color::read_icc_profile(data) {
+            img = color::convert_to_srgb(img, &icc);
+        }
+    }
+
+    // 应用EXIF方向校正（仅在JPEG压缩时）
+    if let (true, Some(orientation)) = (exif_info.orientation_applied, exif_info.orientation) {
+        img = apply_exif_orientation(img, orientation);
+    }
+
+    (img, exif_info)
+}
+
+/// 不解码像素，判断输出为 `format` 时源图片的 EXIF 方向及是否会被应用
+///
+/// 方向值总会读取，只有 JPEG 输出会按方向旋转像素；其他格式的客户端可据此自行处理。
+pub fn exif_info_for(data: &[u8], format: &str) -> ExifInfo {
+    // libheif 解码时已经旋转像素，再按 EXIF 方向处理会转两次
+    if heif::is_heif(data) {
+        return ExifInfo {
+            orientation: None,
+            orientation_applied: false,
+            summary: "Orientation applied by HEIF decoder".to_string(),
+        };
+    }
+    let orientation = read_exif_orientation(data);
+    let is_jpeg_output = is_format(format, Format::Jpeg);
+
+    let summary = match orientation {
+        Some(orientation) if is_jpeg_output => format!("Applied EXIF orientation: {}", orientation),
+        None if is_jpeg_output => "No EXIF orientation found".to_string(),
+        _ => "No EXIF processing".to_string(),
+    };
+
+    ExifInfo {
+        orientation,
+        orientation_applied: is_jpeg_output && orientation.is_some(),
+        summary,
+    }
+}
+
+/// 按目标格式和算法编码已解码的图片
+pub fn encode_image(img: DynamicImage, options: &CompressionOptions) -> Result<Vec<u8>, ImageServerError> {
+    encode_image_with_details(img, options).map(|encoded| encoded.data)
+}
+
+/// `encode_image_with_details` 的结果
+#[derive(Debug, Clone)]
+pub struct EncodedImage {
+    pub data: Vec<u8>,
+    /// 索引 PNG 量化实际达到的质量（0-100），其他格式为 `None`
+    pub quantization_quality: Option<u8>,
+    /// 量化质量低于 `png_min_quantization_quality`，改为输出无损真彩色 PNG
+    pub png_lossless_fallback: bool,
+}
+
+/// 与 `encode_image` 相同，同时返回量化质量和 PNG 实际使用的编码路径
+pub fn encode_image_with_details(
+    img: DynamicImage,
+    options: &CompressionOptions,
+) -> Result<EncodedImage, ImageServerError> {
+    let format = options.format.as_str();
+    let algorithm = options.algorithm.as_str();
+    let (width, height) = (img.width(), img.height());
+
+    // 编码器和量化器都不接受空图片，明确返回 400 而不是在原生库中出错
+    if width == 0 || height == 0 {
+        return Err(ImageServerError::InvalidParameters(format!(
+            "Image has no pixels ({}x{})", width, height
+        )));
+    }
+
+    let mut quantization_quality = None;
+    let mut png_lossless_fallback = false;
+    let compressed_data = match normalize_format(format) {
+        Some(Format::Jpeg) => {
+            // JPEG 无法保存透明度：指定背景色时合成到背景上，否则拒绝而不是静默丢弃 alpha
+            let img = match options.background {
+                Some(background) if img.color().has_alpha() => flatten_alpha(img, background),
+                None if has_transparency(&img) => {
+                    return Err(ImageServerError::InvalidParameters(
+                        "Image has transparency which JPEG cannot store; use format=png or format=webp, or pass a background color (e.g. background=ffffff)".to_string()
+                    ));
+                }
+                _ => img,
+            };
+            info!("进行 JPEG 压缩，保持原始尺寸 {}x{}，使用算法: {}", width, height, algorithm);
             match algorithm.to_lowercase().as_str() {
                 "mozjpeg" => {
                     info!("使用 mozjpeg 进行 JPEG 压缩");
-                    do_mozjpeg_compression(img, quality)?
+                    do_mozjpeg_compression(img, options)
                 },
                 "jpeg-encoder" => {
                     info!("使用 jpeg-encoder 进行 JPEG 压缩");
-                    do_jpeg_encoder_compression(img, quality)?
+                    do_jpeg_encoder_compression(img, options)
                 },
                 _ => {
                     info!("未知算法 '{}', 默认使用 mozjpeg", algorithm);
-                    do_mozjpeg_compression(img, quality)?
+                    do_mozjpeg_compression(img, options)
                 }
-            }
+            }.map_err(ImageServerError::CompressionError)?
         },
-        "png" => {
-            info!("进行 PNG 压缩，保持原始尺寸 {}x{}", original_width, original_height);
-            let result = do_png_compression(&img.to_rgba8().into_raw(), original_width, original_height)?;
-            result.0
+        Some(Format::Png) => {
+            info!("进行 PNG 压缩，保持原始尺寸 {}x{}", width, height);
+            let rgba = img.into_rgba8().into_raw();
+            let compress = || do_png_compression(&rgba, width, height, options.quality, options.png_depth, options.png_quantize_speed, options.dither, options.source_indexed);
+            let result = if options.deterministic {
+                run_single_threaded(compress)?
+            } else {
+                compress()
+            };
+            let (data, _, _, quality) = result.map_err(ImageServerError::CompressionError)?;
+            quantization_quality = quality;
+            // 调色板无法达到质量下限时，宁可输出更大的真彩色 PNG 也不交付明显失真的结果
+            match quality {
+                Some(quality) if quality < options.png_min_quantization_quality => {
+                    info!("PNG 量化质量 {} 低于下限 {}，改为无损真彩色 PNG",
+                          quality, options.png_min_quantization_quality);
+                    png_lossless_fallback = true;
+                    do_lossless_png_compression(&rgba, width, height).map_err(ImageServerError::CompressionError)?
+                }
+                _ => data,
+            }
         },
-        "webp" => {
-            info!("进行 WebP 压缩，保持原始尺寸 {}x{}", original_width, original_height);
-            do_webp_compression(&img.to_rgba8().into_raw(), original_width, original_height, quality)?
+        Some(Format::Webp) => {
+            info!("进行 WebP 压缩，保持原始尺寸 {}x{}", width, height);
+            do_webp_compression(&img.into_rgba8().into_raw(), width, height, options.quality)
+                .map_err(ImageServerError::CompressionError)?
         },
-        _ => return Err(format!("Unsupported format: {}", format))
+        _ => return Err(ImageServerError::CompressionError(format!("Unsupported format: {}", format)))
     };
-    let compression_duration = compression_start.elapsed();
-    
-    let final_size = compressed_data.len();
-    let total_duration = total_start.elapsed();
-    
-    info!("压缩完成 - 保持原始尺寸 {}x{}, 最终大小: {} bytes", original_width, original_height, final_size);
-    info!("性能统计 - 加载时间: {:.2}ms, 压缩时间: {:.2}ms, 总时间: {:.2}ms", 
-         load_duration.as_secs_f64() * 1000.0, 
-         compression_duration.as_secs_f64() * 1000.0,
-         total_duration.as_secs_f64() * 1000.0);
-    
-    Ok((compressed_data, original_width, original_height, exif_info))
+
+    Ok(EncodedImage {
+        data: compressed_data,
+        quantization_quality,
+        png_lossless_fallback,
+    })
+}
+
+/// 只读取图片头部获取尺寸，不解码像素数据
+pub fn read_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    if heif::is_heif(data) {
+        return heif::read_dimensions(data);
+    }
+    image::io::Reader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+/// 完整解码图片以校验其有效性，返回尺寸和识别出的格式
+pub fn validate_image(data: &[u8]) -> Result<(u32, u32, image::ImageFormat), ImageServerError> {
+    let format = image::guess_format(data).map_err(|_| ImageServerError::UnsupportedFormat)?;
+    let img = image::load_from_memory_with_format(data, format)
+        .map_err(|e| ImageServerError::CompressionError(format!("Failed to decode image: {}", e)))?;
+    Ok((img.width(), img.height(), format))
+}
+
+/// 图片金字塔中的一层
+#[derive(Debug, Clone)]
+pub struct PyramidLevel {
+    pub width: u32,
+    pub height: u32,
+    /// 该层实际使用的质量
+    pub quality: u8,
+    /// 缩放使用的滤镜，未缩放时为 `None`
+    pub resize_filter: Option<ResizeFilter>,
+    pub data: Vec<u8>,
+}
+
+/// 只解码一次，按多个宽度等比缩放并分别压缩
+///
+/// 宽度大于原图时按原图尺寸输出，不做放大。
+pub fn compress_pyramid(
+    data: &[u8],
+    options: &CompressionOptions,
+    widths: &[u32],
+) -> Result<(Vec<PyramidLevel>, u32, u32), ImageServerError> {
+    let start = Instant::now();
+    let (img, _exif_info) = decode_image(data, options)?;
+    let (original_width, original_height) = (img.width(), img.height());
+
+    let mut levels = Vec::with_capacity(widths.len());
+    for &width in widths {
+        let (level_img, resize_filter) = if width < original_width {
+            let height = ((original_height as u64 * width as u64) / original_width as u64).max(1) as u32;
+            let filter = options.resize_filter_for(original_width, width);
+            (img.resize_exact(width, height, filter.filter_type()), Some(filter))
+        } else {
+            (img.clone(), None)
+        };
+
+        let (level_width, level_height) = (level_img.width(), level_img.height());
+        let level_options = options.for_dimensions(level_width, level_height);
+        let level_data = encode_image(level_img, &level_options)?;
+        info!("金字塔层 {}x{} 压缩完成, 大小: {} bytes", level_width, level_height, level_data.len());
+
+        levels.push(PyramidLevel {
+            width: level_width,
+            height: level_height,
+            quality: level_options.quality,
+            resize_filter,
+            data: level_data,
+        });
+    }
+
+    info!("金字塔生成完成 - {} 层, 总时间: {:.2}ms", levels.len(), start.elapsed().as_secs_f64() * 1000.0);
+    Ok((levels, original_width, original_height))
 }
 
 // 读取EXIF方向信息
@@ -127,8 +1081,523 @@ fn read_exif_orientation(data: &[u8]) -> Option<u16> {
     }
 }
 
+/// 通过扫描帧头标记判断 JPEG 是否为渐进式编码
+///
+/// 输入不是 JPEG 或在扫描数据之前未找到帧头时返回 `None`。
+pub fn detect_progressive_jpeg(data: &[u8]) -> Option<bool> {
+    if data.len() < 4 || data[0] != 0xFF || data[1] != 0xD8 {
+        return None;
+    }
+
+    let mut pos = 2;
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xFF {
+            return None;
+        }
+        let marker = data[pos + 1];
+        // 标记前允许出现填充字节 0xFF
+        if marker == 0xFF {
+            pos += 1;
+            continue;
+        }
+
+        match marker {
+            // SOF2、SOF6、SOF10、SOF14 为渐进式帧
+            0xC2 | 0xC6 | 0xCA | 0xCE => return Some(true),
+            // 其余 SOF 帧（不含 DHT、JPG、DAC）为顺序式
+            0xC0 | 0xC1 | 0xC3 | 0xC5 | 0xC7 | 0xC9 | 0xCB | 0xCD | 0xCF => return Some(false),
+            // 到达扫描数据或图像结束仍未找到帧头
+            0xDA | 0xD9 => return None,
+            _ => {}
+        }
+
+        let length = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        pos += 2 + length;
+    }
+
+    None
+}
+
+/// JPEG 中每个扫描的熵编码数据结束的位置，即其后第一个标记的偏移
+///
+/// 渐进式 JPEG 在这些位置切分后，每一段都让解码器多渲染一遍更清晰的图像。
+pub fn jpeg_scan_ends(data: &[u8]) -> Vec<usize> {
+    let mut ends = Vec::new();
+    if data.len() < 4 || data[0] != 0xFF || data[1] != 0xD8 {
+        return ends;
+    }
+
+    let mut pos = 2;
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xFF {
+            break;
+        }
+        let marker = data[pos + 1];
+        match marker {
+            0xFF => {
+                pos += 1;
+                continue;
+            }
+            0xD9 => break,
+            // 没有长度字段的标记
+            0x01 | 0xD0..=0xD7 => {
+                pos += 2;
+                continue;
+            }
+            _ => {}
+        }
+
+        let length = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        pos += 2 + length;
+        if marker == 0xDA {
+            // 跳过熵编码数据：0xFF 后跟 0x00（填充）或 RST 标记都属于扫描数据
+            while pos + 1 < data.len()
+                && !(data[pos] == 0xFF && data[pos + 1] != 0x00 && !(0xD0..=0xD7).contains(&data[pos + 1]))
+            {
+                pos += 1;
+            }
+            ends.push(pos.min(data.len()));
+        }
+    }
+
+    ends
+}
+
+/// 生成输出所用的编码器及其参数，用于 X-Encoder / X-Encoder-Options 响应头
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncoderProvenance {
+    /// 编码器名称和服务版本，如 `mozjpeg img-server-rs/1.0.7`
+    pub encoder: String,
+    /// `;` 分隔的 `key=value` 参数，如 `q=82;progressive=false;subsampling=420`
+    pub options: String,
+}
+
+/// 根据请求参数、实际使用的质量和编码输出还原编码方式
+///
+/// 渐进式、色度抽样、调色板大小和位深度从输出文件本身读取，其余参数取自编码时使用的选项。
+pub fn encoder_provenance(options: &CompressionOptions, quality: u8, output: &[u8]) -> EncoderProvenance {
+    let format = normalize_format(&options.format);
+    let (encoder, params): (&str, Vec<(&str, String)>) = match format {
+        Some(Format::Jpeg) => {
+            // 与 encode_image 相同：未知算法按 mozjpeg 处理，未启用 mozjpeg 特性时回退到 jpeg-encoder
+            let use_mozjpeg = cfg!(feature = "mozjpeg") && !options.algorithm.eq_ignore_ascii_case("jpeg-encoder");
+            let mut params = vec![
+                ("q", quality.to_string()),
+                ("progressive", detect_progressive_jpeg(output).unwrap_or(false).to_string()),
+                ("subsampling", jpeg_subsampling(output).unwrap_or_else(|| "unknown".to_string())),
+            ];
+            if use_mozjpeg {
+                let mode = options.jpeg_mode.map(JpegMode::name).unwrap_or("default");
+                params.push(("mode", mode.to_string()));
+                params.push(("quant_table", options.quant_table.name().to_string()));
+                if options.luma_quality.is_some() || options.chroma_quality.is_some() {
+                    let (luma_quality, chroma_quality) = options.channel_qualities();
+                    params.push(("luma_q", luma_quality.to_string()));
+                    params.push(("chroma_q", chroma_quality.to_string()));
+                }
+            }
+            (if use_mozjpeg { "mozjpeg" } else { "jpeg-encoder" }, params)
+        }
+        Some(format @ (Format::Png | Format::Apng)) => {
+            let mut params = Vec::new();
+            if let Ok(reader) = png::Decoder::new(Cursor::new(output)).read_info() {
+                let info = reader.info();
+                params.push(("bit_depth", (info.bit_depth as u8).to_string()));
+                if let Some(palette) = info.palette.as_deref() {
+                    let colors = palette.len() / 3;
+                    params.push(("colors", colors.to_string()));
+                    params.push(("dither", options.dither.level_for(colors).to_string()));
+                }
+                if let Some(control) = info.animation_control {
+                    params.push(("frames", control.num_frames.to_string()));
+                    params.push(("loop", control.num_plays.to_string()));
+                }
+            }
+            params.push(("compression", "best".to_string()));
+            // 量化质量过低回退的真彩色 PNG 没有调色板
+            let quantized = format == Format::Png && params.iter().any(|(key, _)| *key == "colors");
+            (if quantized { "imagequant" } else { "png" }, params)
+        }
+        Some(Format::Webp) => ("webp", vec![("lossless", "true".to_string())]),
+        Some(format) => (format.name(), vec![("q", quality.to_string())]),
+        None => (options.format.as_str(), vec![("q", quality.to_string())]),
+    };
+
+    EncoderProvenance {
+        encoder: format!("{} img-server-rs/{}", encoder, env!("CARGO_PKG_VERSION")),
+        options: params
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>()
+            .join(";"),
+    }
+}
+
+/// 无损变换输出的编码方式：没有重新编码，只记录变换和从输出读取的扫描方式、色度抽样
+pub fn lossless_transform_provenance(transform: Transform, output: &[u8]) -> EncoderProvenance {
+    EncoderProvenance {
+        encoder: format!("lossless-transform img-server-rs/{}", env!("CARGO_PKG_VERSION")),
+        options: format!(
+            "transform={};progressive={};subsampling={}",
+            transform.name(),
+            detect_progressive_jpeg(output).unwrap_or(false),
+            jpeg_subsampling(output).unwrap_or_else(|| "unknown".to_string()),
+        ),
+    }
+}
+
+/// 从 JPEG 帧头读取色度抽样方式，如 `420`、`422`、`444`，单通道返回 `gray`
+pub fn jpeg_subsampling(data: &[u8]) -> Option<String> {
+    if data.len() < 4 || data[0] != 0xFF || data[1] != 0xD8 {
+        return None;
+    }
+
+    let mut pos = 2;
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xFF {
+            return None;
+        }
+        let marker = data[pos + 1];
+        if marker == 0xFF {
+            pos += 1;
+            continue;
+        }
+        if marker == 0xDA || marker == 0xD9 {
+            return None;
+        }
+
+        let length = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        // SOF0-SOF15，不含 DHT、JPG、DAC
+        if (0xC0..=0xCF).contains(&marker) && !matches!(marker, 0xC4 | 0xC8 | 0xCC) {
+            // 精度(1) 高(2) 宽(2) 分量数(1)，随后每个分量: 编号(1) 抽样因子(1) 量化表(1)
+            let segment = data.get(pos + 4..pos + 2 + length)?;
+            let components = *segment.get(5)? as usize;
+            if components == 1 {
+                return Some("gray".to_string());
+            }
+            let luma = *segment.get(7)?;
+            let chroma = *segment.get(10)?;
+            let (luma_h, luma_v) = (luma >> 4, luma & 0x0F);
+            let (chroma_h, chroma_v) = ((chroma >> 4).max(1), (chroma & 0x0F).max(1));
+            return Some(match (luma_h / chroma_h, luma_v / chroma_v) {
+                (1, 1) => "444".to_string(),
+                (2, 1) => "422".to_string(),
+                (2, 2) => "420".to_string(),
+                (1, 2) => "440".to_string(),
+                (h, v) => format!("{}x{}", h, v),
+            });
+        }
+        pos += 2 + length;
+    }
+
+    None
+}
+
+/// 读取 JPEG 在扫描数据之前的所有 DQT 量化表，返回表编号和按 zigzag 顺序排列的 64 个系数
+///
+/// 不是 JPEG 时返回 `None`，同一编号出现多次时保留最后一次定义。
+pub fn read_jpeg_quant_tables(data: &[u8]) -> Option<Vec<(u8, [u16; 64])>> {
+    if data.len() < 4 || data[0] != 0xFF || data[1] != 0xD8 {
+        return None;
+    }
+
+    let mut tables: Vec<(u8, [u16; 64])> = Vec::new();
+    let mut pos = 2;
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xFF {
+            break;
+        }
+        let marker = data[pos + 1];
+        if marker == 0xFF {
+            pos += 1;
+            continue;
+        }
+        if marker == 0xDA || marker == 0xD9 {
+            break;
+        }
+
+        let length = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        let segment = data.get(pos + 4..pos + 2 + length.max(2))?;
+        if marker == 0xDB {
+            // 一个 DQT 段可包含多张表：1 字节精度/编号，随后 64 个 8 位或 16 位系数
+            let mut offset = 0;
+            while offset < segment.len() {
+                let precision = segment[offset] >> 4;
+                let id = segment[offset] & 0x0F;
+                let size = if precision == 0 { 64 } else { 128 };
+                let values = segment.get(offset + 1..offset + 1 + size)?;
+
+                let mut table = [0u16; 64];
+                for (i, value) in table.iter_mut().enumerate() {
+                    *value = if precision == 0 {
+                        values[i] as u16
+                    } else {
+                        u16::from_be_bytes([values[2 * i], values[2 * i + 1]])
+                    };
+                }
+                tables.retain(|(existing, _)| *existing != id);
+                tables.push((id, table));
+                offset += 1 + size;
+            }
+        }
+        pos += 2 + length;
+    }
+
+    Some(tables)
+}
+
+/// 读取指定名称的 EXIF 标签（不区分大小写），返回标签名和可读值
+pub fn read_exif_tags(data: &[u8], tag_names: &[String]) -> Vec<(String, String)> {
+    if tag_names.is_empty() {
+        return Vec::new();
+    }
+
+    let exif = match Reader::new().read_from_container(&mut Cursor::new(data)) {
+        Ok(exif) => exif,
+        Err(_) => return Vec::new(),
+    };
+
+    exif.fields()
+        .filter(|field| field.ifd_num == In::PRIMARY)
+        .filter_map(|field| {
+            let name = field.tag.to_string();
+            tag_names
+                .iter()
+                .any(|wanted| wanted.eq_ignore_ascii_case(&name))
+                .then(|| (name, field.display_value().with_unit(&exif).to_string()))
+        })
+        .collect()
+}
+
+/// `preserve_exif` 时写回输出的拍摄参数；方向已在解码时应用，不再保留
+pub const PRESERVED_EXIF_TAGS: [Tag; 4] = [
+    Tag::WhiteBalance,
+    Tag::ExposureTime,
+    Tag::FNumber,
+    Tag::PhotographicSensitivity,
+];
+
+/// 从源图读取需要保留的 EXIF 字段并编码为最小的 TIFF 结构，没有可保留字段时返回 `None`
+pub fn build_preserved_exif(data: &[u8]) -> Option<Vec<u8>> {
+    let exif = Reader::new().read_from_container(&mut Cursor::new(data)).ok()?;
+    let fields: Vec<&exif::Field> = PRESERVED_EXIF_TAGS
+        .iter()
+        .filter_map(|&tag| exif.get_field(tag, In::PRIMARY))
+        .collect();
+    if fields.is_empty() {
+        return None;
+    }
+
+    let mut writer = exif::experimental::Writer::new();
+    for field in &fields {
+        writer.push_field(field);
+    }
+
+    let mut tiff = Cursor::new(Vec::new());
+    writer.write(&mut tiff, exif.little_endian()).ok()?;
+    info!("保留 EXIF 字段: {:?}", fields.iter().map(|f| f.tag).collect::<Vec<_>>());
+    Some(tiff.into_inner())
+}
+
+/// 将 TIFF 格式的 EXIF 数据作为 APP1 段插入 JPEG，位于 SOI 和 JFIF APP0 之后
+pub fn insert_jpeg_exif(jpeg: &[u8], tiff: &[u8]) -> Vec<u8> {
+    const EXIF_HEADER: &[u8] = b"Exif\0\0";
+    let segment_len = 2 + EXIF_HEADER.len() + tiff.len();
+    if jpeg.len() < 2 || jpeg[0..2] != [0xFF, 0xD8] || segment_len > u16::MAX as usize {
+        return jpeg.to_vec();
+    }
+    let insert_at = jpeg_segment_insert_pos(jpeg);
+
+    let mut output = Vec::with_capacity(jpeg.len() + segment_len + 2);
+    output.extend_from_slice(&jpeg[..insert_at]);
+    output.extend_from_slice(&[0xFF, 0xE1]);
+    output.extend_from_slice(&(segment_len as u16).to_be_bytes());
+    output.extend_from_slice(EXIF_HEADER);
+    output.extend_from_slice(tiff);
+    output.extend_from_slice(&jpeg[insert_at..]);
+    output
+}
+
+// 新段插入位置：SOI 之后，跳过紧随其后的 APP0 (JFIF) 段
+fn jpeg_segment_insert_pos(jpeg: &[u8]) -> usize {
+    let mut insert_at = 2;
+    if jpeg.len() >= 6 && jpeg[2..4] == [0xFF, 0xE0] {
+        insert_at = 4 + u16::from_be_bytes([jpeg[4], jpeg[5]]) as usize;
+    }
+    insert_at.min(jpeg.len())
+}
+
+/// 注释的最大长度（字节）
+pub const MAX_COMMENT_LEN: usize = 1024;
+
+/// 校验写入输出的注释：非空、不超过 `MAX_COMMENT_LEN` 字节且不含控制字符
+pub fn validate_comment(comment: &str) -> Result<(), String> {
+    if comment.is_empty() {
+        return Err("comment must not be empty".to_string());
+    }
+    if comment.len() > MAX_COMMENT_LEN {
+        return Err(format!("comment must be at most {} bytes, got {}", MAX_COMMENT_LEN, comment.len()));
+    }
+    if comment.chars().any(char::is_control) {
+        return Err("comment must not contain control characters".to_string());
+    }
+    Ok(())
+}
+
+// 按输出格式写入注释，其他格式原样返回
+fn embed_comment(data: Vec<u8>, options: &CompressionOptions) -> Vec<u8> {
+    let comment = match options.comment.as_deref() {
+        Some(comment) => comment,
+        None => return data,
+    };
+    match normalize_format(&options.format) {
+        Some(Format::Jpeg) => insert_jpeg_comment(&data, comment),
+        Some(Format::Png | Format::Apng) => insert_png_comment(&data, comment),
+        _ => data,
+    }
+}
+
+/// 在 JPEG 的 SOI（及 APP0）之后插入 COM 注释段
+pub fn insert_jpeg_comment(jpeg: &[u8], comment: &str) -> Vec<u8> {
+    let segment_len = 2 + comment.len();
+    if jpeg.len() < 2 || jpeg[0..2] != [0xFF, 0xD8] || segment_len > u16::MAX as usize {
+        return jpeg.to_vec();
+    }
+    let insert_at = jpeg_segment_insert_pos(jpeg);
+
+    let mut output = Vec::with_capacity(jpeg.len() + segment_len + 2);
+    output.extend_from_slice(&jpeg[..insert_at]);
+    output.extend_from_slice(&[0xFF, 0xFE]);
+    output.extend_from_slice(&(segment_len as u16).to_be_bytes());
+    output.extend_from_slice(comment.as_bytes());
+    output.extend_from_slice(&jpeg[insert_at..]);
+    output
+}
+
+/// 在 PNG 的 IHDR 之后插入关键字为 "Comment" 的文本块
+///
+/// 注释可用 Latin-1 表示时写 tEXt，否则写 UTF-8 编码的 iTXt。
+pub fn insert_png_comment(png_data: &[u8], comment: &str) -> Vec<u8> {
+    use png::text_metadata::{EncodableTextChunk, ITXtChunk, TEXtChunk};
+
+    let mut chunk = Vec::new();
+    let encoded = if comment.chars().all(|c| (c as u32) < 256) {
+        TEXtChunk::new("Comment", comment).encode(&mut chunk)
+    } else {
+        ITXtChunk::new("Comment", comment).encode(&mut chunk)
+    };
+    if encoded.is_err() {
+        return png_data.to_vec();
+    }
+    insert_png_chunk(png_data, &chunk)
+}
+
+// 在 IHDR 之后插入编码好的块，不是 PNG 时原样返回
+fn insert_png_chunk(png_data: &[u8], chunk: &[u8]) -> Vec<u8> {
+    // 8 字节签名 + IHDR（长度 4 + 类型 4 + 数据 13 + CRC 4）
+    const IHDR_END: usize = 8 + 25;
+    if png_data.len() < IHDR_END || &png_data[12..16] != b"IHDR" {
+        return png_data.to_vec();
+    }
+
+    let mut output = Vec::with_capacity(png_data.len() + chunk.len());
+    output.extend_from_slice(&png_data[..IHDR_END]);
+    output.extend_from_slice(chunk);
+    output.extend_from_slice(&png_data[IHDR_END..]);
+    output
+}
+
+/// XMP 包的最大长度（字节），保证 JPEG 中放得进单个 APP1 段
+pub const MAX_XMP_LEN: usize = 60 * 1024;
+
+/// JPEG APP1 段中 XMP 的命名空间标识
+const XMP_JPEG_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+/// PNG 中 XMP 所在 iTXt 块的关键字
+pub const XMP_PNG_KEYWORD: &str = "XML:com.adobe.xmp";
+
+/// 校验写入输出的 XMP：非空、不超过 `MAX_XMP_LEN` 字节，且是只有一个根元素的格式良好的 XML
+pub fn validate_xmp(xmp: &str) -> Result<(), String> {
+    use quick_xml::events::Event;
+
+    if xmp.trim().is_empty() {
+        return Err("xmp must not be empty".to_string());
+    }
+    if xmp.len() > MAX_XMP_LEN {
+        return Err(format!("xmp must be at most {} bytes, got {}", MAX_XMP_LEN, xmp.len()));
+    }
+
+    let mut reader = quick_xml::Reader::from_str(xmp);
+    let mut depth = 0usize;
+    let mut roots = 0usize;
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(_)) => {
+                if depth == 0 {
+                    roots += 1;
+                }
+                depth += 1;
+            }
+            Ok(Event::Empty(_)) if depth == 0 => roots += 1,
+            Ok(Event::End(_)) if depth == 0 => return Err("xmp is not well-formed XML: unexpected end tag".to_string()),
+            Ok(Event::End(_)) => depth -= 1,
+            Ok(Event::Eof) => break,
+            Ok(_) => {}
+            Err(e) => return Err(format!("xmp is not well-formed XML: {}", e)),
+        }
+    }
+
+    match (depth, roots) {
+        (0, 1) => Ok(()),
+        (0, _) => Err("xmp must contain exactly one root element".to_string()),
+        _ => Err("xmp is not well-formed XML: unclosed element".to_string()),
+    }
+}
+
+// 按输出格式写入 XMP，其他格式原样返回
+fn embed_xmp(data: Vec<u8>, options: &CompressionOptions) -> Vec<u8> {
+    let xmp = match options.xmp.as_deref() {
+        Some(xmp) => xmp,
+        None => return data,
+    };
+    match normalize_format(&options.format) {
+        Some(Format::Jpeg) => insert_jpeg_xmp(&data, xmp),
+        Some(Format::Png | Format::Apng) => insert_png_xmp(&data, xmp),
+        _ => data,
+    }
+}
+
+/// 将 XMP 包作为带 Adobe 命名空间标识的 APP1 段插入 JPEG
+pub fn insert_jpeg_xmp(jpeg: &[u8], xmp: &str) -> Vec<u8> {
+    let segment_len = 2 + XMP_JPEG_HEADER.len() + xmp.len();
+    if jpeg.len() < 2 || jpeg[0..2] != [0xFF, 0xD8] || segment_len > u16::MAX as usize {
+        return jpeg.to_vec();
+    }
+    let insert_at = jpeg_segment_insert_pos(jpeg);
+
+    let mut output = Vec::with_capacity(jpeg.len() + segment_len + 2);
+    output.extend_from_slice(&jpeg[..insert_at]);
+    output.extend_from_slice(&[0xFF, 0xE1]);
+    output.extend_from_slice(&(segment_len as u16).to_be_bytes());
+    output.extend_from_slice(XMP_JPEG_HEADER);
+    output.extend_from_slice(xmp.as_bytes());
+    output.extend_from_slice(&jpeg[insert_at..]);
+    output
+}
+
+/// 将 XMP 包作为未压缩的 iTXt 块插入 PNG 的 IHDR 之后
+pub fn insert_png_xmp(png_data: &[u8], xmp: &str) -> Vec<u8> {
+    use png::text_metadata::{EncodableTextChunk, ITXtChunk};
+
+    let mut chunk = Vec::new();
+    if ITXtChunk::new(XMP_PNG_KEYWORD, xmp).encode(&mut chunk).is_err() {
+        return png_data.to_vec();
+    }
+    insert_png_chunk(png_data, &chunk)
+}
+
 // 根据EXIF方向信息旋转图片
-fn apply_exif_orientation(img: DynamicImage, orientation: u16) -> DynamicImage {
+pub(crate) fn apply_exif_orientation(img: DynamicImage, orientation: u16) -> DynamicImage {
     match orientation {
         1 => {
             info!("EXIF方向1: 无需旋转");
@@ -169,21 +1638,60 @@ fn apply_exif_orientation(img: DynamicImage, orientation: u16) -> DynamicImage {
     }
 }
 
+/// 图片是否包含非完全不透明的像素
+pub fn has_transparency(img: &DynamicImage) -> bool {
+    img.color().has_alpha() && img.to_rgba8().pixels().any(|p| p[3] < 255)
+}
+
+/// 将带透明度的图片合成到纯色背景上
+pub fn flatten_alpha(img: DynamicImage, background: [u8; 3]) -> DynamicImage {
+    let mut rgba = img.to_rgba8();
+    for pixel in rgba.pixels_mut() {
+        let alpha = pixel[3] as u32;
+        for c in 0..3 {
+            pixel[c] = ((pixel[c] as u32 * alpha + background[c] as u32 * (255 - alpha) + 127) / 255) as u8;
+        }
+        pixel[3] = 255;
+    }
+    DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(rgba).to_rgb8())
+}
+
 // mozjpeg 压缩函数
-fn do_mozjpeg_compression(img: DynamicImage, quality: u8) -> Result<Vec<u8>, String> {
+#[cfg(feature = "mozjpeg")]
+fn do_mozjpeg_compression(img: DynamicImage, options: &CompressionOptions) -> Result<Vec<u8>, String> {
     info!("开始 mozjpeg 压缩");
     
-    // 转换为 RGB
-    let rgb_img = img.to_rgb8();
-    let (width, height) = (rgb_img.width(), rgb_img.height());
-    let raw_data = rgb_img.into_raw();
+    // 转换为 RGB，已是 RGB8 时直接复用像素缓冲区
+    let (width, height, raw_data) = into_rgb_buffer(img)?;
     
     info!("图片信息 - 宽: {}, 高: {}, 数据长度: {}", width, height, raw_data.len());
     
     // 使用 mozjpeg::Compress API
     let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
     comp.set_size(width as usize, height as usize);
-    comp.set_quality(quality as f32);
+    comp.set_quality(options.quality as f32);
+    // 指定量化表或分别指定亮度、色度质量时按质量缩放后替换默认表，mozjpeg 默认表即 ImageMagick 表
+    if options.quant_table.tables().is_some() || options.luma_quality.is_some() || options.chroma_quality.is_some() {
+        let (luma, chroma) = options.quant_table.tables()
+            .unwrap_or((&mozjpeg::qtable::NRobidoux, &mozjpeg::qtable::NRobidoux));
+        let (luma_quality, chroma_quality) = options.channel_qualities();
+        comp.set_luma_qtable(&luma.scaled(luma_quality as f32, luma_quality as f32));
+        comp.set_chroma_qtable(&chroma.scaled(chroma_quality as f32, chroma_quality as f32));
+    }
+    // mozjpeg 默认即为渐进式并优化 Huffman 表，非渐进模式需要显式关闭扫描脚本
+    match options.jpeg_mode {
+        Some(JpegMode::Baseline) => {
+            comp.set_optimize_scans(false);
+            comp.set_optimize_coding(false);
+        }
+        Some(JpegMode::Optimized) => {
+            comp.set_optimize_scans(false);
+            comp.set_optimize_coding(true);
+        }
+        Some(JpegMode::Progressive) => comp.set_progressive_mode(),
+        None if options.progressive => comp.set_progressive_mode(),
+        None => {}
+    }
     comp.set_mem_dest();
     comp.start_compress();
     
@@ -202,14 +1710,25 @@ fn do_mozjpeg_compression(img: DynamicImage, quality: u8) -> Result<Vec<u8>, Str
     Ok(jpeg_data)
 }
 
+// 未启用 mozjpeg 特性时改用 jpeg-encoder，量化表参数被忽略，编码模式只保留是否渐进式
+#[cfg(not(feature = "mozjpeg"))]
+fn do_mozjpeg_compression(img: DynamicImage, options: &CompressionOptions) -> Result<Vec<u8>, String> {
+    info!("未启用 mozjpeg 特性，改用 jpeg-encoder 压缩");
+    let mut options = options.clone();
+    options.progressive = match options.jpeg_mode {
+        Some(JpegMode::Progressive) => true,
+        Some(JpegMode::Baseline) | Some(JpegMode::Optimized) => false,
+        None => options.progressive,
+    };
+    do_jpeg_encoder_compression(img, &options)
+}
+
 // jpeg-encoder 压缩函数
-fn do_jpeg_encoder_compression(img: DynamicImage, quality: u8) -> Result<Vec<u8>, String> {
+fn do_jpeg_encoder_compression(img: DynamicImage, options: &CompressionOptions) -> Result<Vec<u8>, String> {
     info!("开始 jpeg-encoder 压缩");
     
-    // 转换为 RGB
-    let rgb_img = img.to_rgb8();
-    let (width, height) = (rgb_img.width(), rgb_img.height());
-    let raw_data = rgb_img.into_raw();
+    // 转换为 RGB，已是 RGB8 时直接复用像素缓冲区
+    let (width, height, raw_data) = into_rgb_buffer(img)?;
     
     info!("图片信息 - 宽: {}, 高: {}, 数据长度: {}", width, height, raw_data.len());
     
@@ -217,7 +1736,8 @@ fn do_jpeg_encoder_compression(img: DynamicImage, quality: u8) -> Result<Vec<u8>
     use jpeg_encoder::{Encoder, ColorType};
     
     let mut output = Vec::new();
-    let encoder = Encoder::new(&mut output, quality);
+    let mut encoder = Encoder::new(&mut output, jpeg_encoder_quality(options.quality));
+    encoder.set_progressive(options.progressive);
     encoder.encode(&raw_data, width as u16, height as u16, ColorType::Rgb)
         .map_err(|e| format!("JPEG encoder failed: {:?}", e))?;
     
@@ -225,14 +1745,55 @@ fn do_jpeg_encoder_compression(img: DynamicImage, quality: u8) -> Result<Vec<u8>
     Ok(output)
 }
 
+/// jpeg-encoder 实际使用的质量
+///
+/// `Encoder::new` 只在文档中要求质量为 1-100，0 或大于 100 时的结果依赖库内部实现（量化表按边界处理，
+/// 色度抽样却直接比较原值），因此在调用前收紧到与 mozjpeg 相同的 1-100。
+fn jpeg_encoder_quality(quality: u8) -> u8 {
+    let clamped = quality.clamp(1, 100);
+    if clamped != quality {
+        info!("jpeg-encoder 质量 {} 超出 1-100，按 {} 编码", quality, clamped);
+    }
+    clamped
+}
+
+/// 在只有一个线程的 rayon 线程池中执行 `f`
+///
+/// imagequant 的直方图和 K-means 在 rayon 上并行，各线程的浮点累加顺序随调度变化，
+/// 输出可能逐次不同。单线程执行后量化没有其他随机来源，结果可复现。
+/// 每次调用新建线程池，不与其他请求共享同一个线程。
+fn run_single_threaded<R: Send>(f: impl FnOnce() -> R + Send) -> Result<R, ImageServerError> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(1)
+        .build()
+        .map_err(|e| ImageServerError::ProcessingError(format!("Failed to create quantization thread pool: {}", e)))?;
+    Ok(pool.install(f))
+}
+
 // PNG 压缩函数 - 基于 fast-image 项目的高性能实现
-pub fn do_png_compression(rgba_data: &[u8], width: u32, height: u32) -> Result<(Vec<u8>, u32, u32), String> {
+#[allow(clippy::too_many_arguments)]
+pub fn do_png_compression(
+    rgba_data: &[u8],
+    width: u32,
+    height: u32,
+    quality: u8,
+    png_depth: Option<u8>,
+    speed: Option<u8>,
+    dither: Dither,
+    keep_exact_colors: bool,
+) -> Result<(Vec<u8>, u32, u32, Option<u8>), String> {
     info!("开始 PNG 压缩 - 尺寸: {}x{}, 数据大小: {} bytes", width, height, rgba_data.len());
+
+    // 显式指定位深度时，将调色板限制在该位深度可表示的颜色数内
+    let explicit_depth = match png_depth {
+        Some(depth) => Some(png_bit_depth(depth)?),
+        None => None,
+    };
     
     let start_time = Instant::now();
     
-    // 设置默认质量为85，适用于PNG量化
-    let quality = 85u8;
+    // 量化目标质量 0-100，0 表示最大程度量化、体积最小
+    let quality = quality.min(100);
     
     let width_usize = width as usize;
     let height_usize = height as usize;
@@ -241,68 +1802,123 @@ pub fn do_png_compression(rgba_data: &[u8], width: u32, height: u32) -> Result<(
     let pixel_count = width_usize * height_usize;
     let memory_size_mb = (pixel_count * 4) / (1024 * 1024);
     
-    // 使用 imagequant 进行颜色量化
-    let mut liq = imagequant::new();
-    liq.set_quality(0, quality)
-        .map_err(|e| format!("Failed to set PNG quality: {:?}", e))?;
-    
-    // 优化的 RGBA 转换，支持零拷贝
-    let use_zero_copy = can_use_zero_copy();
-    
-    let mut img_quantize = if use_zero_copy {
-        // 零拷贝路径：直接重新解释内存布局
-        // 这为大图片节省约50%的内存
-        let rgba_pixels = unsafe {
-            std::slice::from_raw_parts(
-                rgba_data.as_ptr() as *const imagequant::RGBA,
-                pixel_count,
-            )
-        };
-        
-        if memory_size_mb > 50 {
-            info!("PNG compression: Using zero-copy optimization for {}MB image ({}x{})",
-                  memory_size_mb, width, height);
-        }
-        
-        liq.new_image(rgba_pixels, width_usize, height_usize, 0.0)
-            .map_err(|e| format!("Failed to create quantized image with zero-copy: {:?}", e))?
+    // 完全透明像素的 RGB 不可见，统一为 0 后不会占用多个调色板条目
+    let normalized: Vec<u8>;
+    let rgba_data = if rgba_data.chunks_exact(4).any(|p| p[3] == 0 && p != [0, 0, 0, 0]) {
+        normalized = rgba_data
+            .chunks_exact(4)
+            .flat_map(|p| if p[3] == 0 { [0; 4] } else { [p[0], p[1], p[2], p[3]] })
+            .collect();
+        normalized.as_slice()
     } else {
-        // 预分配路径：最小化分配开销
-        let mut rgba_pixels = Vec::with_capacity(rgba_data.len() / 4);
-        
-        // 使用 chunks_exact 获得更好的性能（无边界检查）
-        for chunk in rgba_data.chunks_exact(4) {
-            rgba_pixels.push(imagequant::RGBA {
-                r: chunk[0],
-                g: chunk[1],
-                b: chunk[2],
-                a: chunk[3],
-            });
+        rgba_data
+    };
+    // 单一颜色（包括完全透明）的图片无需量化，得到只有一项的调色板和 1 位深度
+    let first = &rgba_data[..rgba_data.len().min(4)];
+    let single_color = rgba_data.chunks_exact(4).all(|p| p == first);
+
+    // 极小图片（如 1x1 跟踪像素）颜色数本就不超过调色板上限，直接精确建调色板，跳过量化器；
+    // 源图已是索引 PNG 时同样沿用原有颜色，重新量化只会让颜色漂移
+    let max_colors = png_depth.map_or(256, |depth| 1usize << depth);
+    let exact = if pixel_count <= EXACT_PALETTE_MAX_PIXELS || keep_exact_colors || single_color {
+        exact_palette(rgba_data, max_colors)
+    } else {
+        None
+    };
+
+    // 第三项为 imagequant 实际达到的量化质量，可能低于请求的质量；精确调色板无损，为 100
+    let (palette, pixels, quantization_quality) = match exact {
+        Some((palette, pixels)) => {
+            info!("PNG 图片 {} 像素、{} 种颜色，跳过量化，使用精确调色板", pixel_count, palette.len());
+            (palette, pixels, Some(100))
         }
-        
-        // 记录回退原因以便调试
-        if memory_size_mb > 50 {
-            info!("PNG compression: Using pre-allocation fallback for {}MB image ({}x{}) - zero-copy not available",
-                  memory_size_mb, width, height);
+        None => {
+            // 使用 imagequant 进行颜色量化
+            let mut liq = imagequant::new();
+            liq.set_quality(0, quality)
+                .map_err(|e| format!("Failed to set PNG quality: {:?}", e))?;
+            if let Some(depth) = png_depth {
+                liq.set_max_colors(1u32 << depth)
+                    .map_err(|e| format!("Failed to set PNG max colors: {:?}", e))?;
+            }
+            // 速度越高量化越快，大图上用少量质量换取明显更低的延迟
+            if let Some(speed) = speed {
+                liq.set_speed(speed as i32)
+                    .map_err(|e| format!("Failed to set PNG quantize speed: {:?}", e))?;
+            }
+
+            // 优化的 RGBA 转换，支持零拷贝
+            let use_zero_copy = can_use_zero_copy();
+
+            let mut img_quantize = if use_zero_copy {
+                // 零拷贝路径：直接重新解释内存布局
+                // 这为大图片节省约50%的内存
+                let rgba_pixels = unsafe {
+                    std::slice::from_raw_parts(
+                        rgba_data.as_ptr() as *const imagequant::RGBA,
+                        pixel_count,
+                    )
+                };
+
+                if memory_size_mb > 50 {
+                    info!("PNG compression: Using zero-copy optimization for {}MB image ({}x{})",
+                          memory_size_mb, width, height);
+                }
+
+                liq.new_image(rgba_pixels, width_usize, height_usize, 0.0)
+                    .map_err(|e| format!("Failed to create quantized image with zero-copy: {:?}", e))?
+            } else {
+                // 预分配路径：最小化分配开销
+                let mut rgba_pixels = Vec::with_capacity(rgba_data.len() / 4);
+
+                // 使用 chunks_exact 获得更好的性能（无边界检查）
+                for chunk in rgba_data.chunks_exact(4) {
+                    rgba_pixels.push(imagequant::RGBA {
+                        r: chunk[0],
+                        g: chunk[1],
+                        b: chunk[2],
+                        a: chunk[3],
+                    });
+                }
+
+                // 记录回退原因以便调试
+                if memory_size_mb > 50 {
+                    info!("PNG compression: Using pre-allocation fallback for {}MB image ({}x{}) - zero-copy not available",
+                          memory_size_mb, width, height);
+                }
+
+                liq.new_image(&rgba_pixels[..], width_usize, height_usize, 0.0)
+                    .map_err(|e| format!("Failed to create quantized image with pre-allocation: {:?}", e))?
+            };
+
+            // 量化图像
+            let mut res = liq
+                .quantize(&mut img_quantize)
+                .map_err(|e| format!("Failed to quantize PNG: {:?}", e))?;
+
+            // 设置抖动级别 (0.0 - 1.0)，auto 模式按量化后的颜色数决定
+            let dithering_level = dither.level_for(res.palette_len());
+            res.set_dithering_level(dithering_level)
+                .map_err(|e| format!("Failed to set dithering: {:?}", e))?;
+            info!("PNG 抖动设置: {:?}, 实际强度: {}", dither, dithering_level);
+
+            let quantization_quality = res.quantization_quality();
+            info!("PNG 量化质量: 请求 {}, 实际 {:?}", quality, quantization_quality);
+
+            // 获取量化数据
+            let (palette, pixels) = res.remapped(&mut img_quantize)
+                .map_err(|e| format!("Failed to remap PNG: {:?}", e))?;
+            (palette, pixels, quantization_quality)
         }
-        
-        liq.new_image(&rgba_pixels[..], width_usize, height_usize, 0.0)
-            .map_err(|e| format!("Failed to create quantized image with pre-allocation: {:?}", e))?
     };
     
-    // 量化图像
-    let mut res = liq
-        .quantize(&mut img_quantize)
-        .map_err(|e| format!("Failed to quantize PNG: {:?}", e))?;
-    
-    // 设置抖动级别 (0.0 - 1.0)
-    res.set_dithering_level(1.0)
-        .map_err(|e| format!("Failed to set dithering: {:?}", e))?;
-    
-    // 获取量化数据
-    let (palette, pixels) = res
-        .remapped(&mut img_quantize)
-        .map_err(|e| format!("Failed to remap PNG: {:?}", e))?;
+    // tRNS 只能覆盖调色板开头的连续条目，先把带透明度的条目移到前面
+    let (palette, pixels) = transparent_first(palette, pixels);
+
+    // 未显式指定时，选择能容纳调色板的最小位深度
+    let bit_depth = explicit_depth.unwrap_or_else(|| minimal_bit_depth(palette.len()));
+    let pixels = pack_indexed_pixels(&pixels, width_usize, bit_depth);
+    info!("PNG 调色板颜色数: {}, 位深度: {:?}", palette.len(), bit_depth);
     
     // 使用量化调色板创建 PNG
     let mut png_data = Vec::new();
@@ -310,7 +1926,7 @@ pub fn do_png_compression(rgba_data: &[u8], width: u32, height: u32) -> Result<(
     {
         let mut encoder = png::Encoder::new(Cursor::new(&mut png_data), width, height);
         encoder.set_color(png::ColorType::Indexed);
-        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_depth(bit_depth);
         
         // 根据质量设置压缩级别（反向：质量越低 = 压缩越高）
         let compression_level = png::Compression::Best;
@@ -326,7 +1942,7 @@ pub fn do_png_compression(rgba_data: &[u8], width: u32, height: u32) -> Result<(
         // 剩余条目假定为完全不透明 (255)
         let mut trns: Vec<u8> = palette.iter().map(|c| c.a).collect();
         
-        // 修剪尾部完全不透明的 alpha 值以保持块最小
+        // 透明条目已排在前面，修剪尾部完全不透明的 alpha 值后块即最小
         while trns.last().copied() == Some(255) {
             trns.pop();
         }
@@ -349,7 +1965,237 @@ pub fn do_png_compression(rgba_data: &[u8], width: u32, height: u32) -> Result<(
     info!("PNG 压缩完成 - 输出大小: {} bytes, 耗时: {:.2}ms", 
           png_data.len(), duration.as_secs_f64() * 1000.0);
     
-    Ok((png_data, width, height))
+    Ok((png_data, width, height, quantization_quality))
+}
+
+/// 无损编码 RGBA 像素为真彩色 PNG，全部不透明时省去 alpha 通道
+pub fn do_lossless_png_compression(rgba_data: &[u8], width: u32, height: u32) -> Result<Vec<u8>, String> {
+    let opaque = rgba_data.chunks_exact(4).all(|pixel| pixel[3] == 255);
+    let rgb_data: Vec<u8>;
+    let (color, pixels) = if opaque {
+        rgb_data = rgba_data.chunks_exact(4).flat_map(|pixel| [pixel[0], pixel[1], pixel[2]]).collect();
+        (png::ColorType::Rgb, rgb_data.as_slice())
+    } else {
+        (png::ColorType::Rgba, rgba_data)
+    };
+
+    let mut png_data = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(Cursor::new(&mut png_data), width, height);
+        encoder.set_color(color);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_compression(png::Compression::Best);
+        let mut writer = encoder.write_header()
+            .map_err(|e| format!("Failed to write PNG header: {}", e))?;
+        writer.write_image_data(pixels)
+            .map_err(|e| format!("Failed to write PNG data: {}", e))?;
+    }
+    Ok(png_data)
+}
+
+/// 索引 PNG 调色板中的一个颜色
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PaletteEntry {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl PaletteEntry {
+    /// `#rrggbb` 形式的颜色值
+    pub fn hex(&self) -> String {
+        format!("#{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+    }
+}
+
+/// 读取索引 PNG 的调色板，即 `do_png_compression` 中量化器 remapped 得到的调色板
+///
+/// alpha 取自 tRNS 块，未覆盖的条目为完全不透明。非索引 PNG 返回 `None`。
+pub fn read_png_palette(data: &[u8]) -> Option<Vec<PaletteEntry>> {
+    let reader = png::Decoder::new(Cursor::new(data)).read_info().ok()?;
+    let info = reader.info();
+    if info.color_type != png::ColorType::Indexed {
+        return None;
+    }
+
+    let palette = info.palette.as_deref()?;
+    let trns = info.trns.as_deref().unwrap_or(&[]);
+    Some(
+        palette
+            .chunks_exact(3)
+            .enumerate()
+            .map(|(i, rgb)| PaletteEntry {
+                r: rgb[0],
+                g: rgb[1],
+                b: rgb[2],
+                a: trns.get(i).copied().unwrap_or(255),
+            })
+            .collect(),
+    )
+}
+
+/// 对每种算法执行一次极小图片的编码，提前完成原生编码器的延迟初始化
+///
+/// 返回每个算法的耗时，编码失败时返回错误信息。
+pub fn warm_up_encoders() -> Vec<(&'static str, Result<std::time::Duration, String>)> {
+    let img = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(8, 8, |x, y| {
+        image::Rgba([(x * 32) as u8, (y * 32) as u8, 128, 255])
+    }));
+
+    let options = CompressionOptions::new("jpeg", 80, "mozjpeg");
+    let mut results = Vec::new();
+
+    #[cfg(feature = "mozjpeg")]
+    {
+        let start = Instant::now();
+        let result = do_mozjpeg_compression(img.clone(), &options).map(|_| start.elapsed());
+        results.push(("mozjpeg", result));
+    }
+
+    let start = Instant::now();
+    let result = do_jpeg_encoder_compression(img.clone(), &options).map(|_| start.elapsed());
+    results.push(("jpeg-encoder", result));
+
+    let start = Instant::now();
+    let result = do_png_compression(&img.to_rgba8().into_raw(), 8, 8, 85, None, None, Dither::default(), false).map(|_| start.elapsed());
+    results.push(("png-quantized", result));
+
+    results
+}
+
+/// 将位深度数值转换为 PNG 位深度，仅支持索引 PNG 可用的 1、2、4、8
+pub fn png_bit_depth(depth: u8) -> Result<png::BitDepth, String> {
+    match depth {
+        1 => Ok(png::BitDepth::One),
+        2 => Ok(png::BitDepth::Two),
+        4 => Ok(png::BitDepth::Four),
+        8 => Ok(png::BitDepth::Eight),
+        _ => Err(format!("Unsupported PNG bit depth: {} (expected 1, 2, 4 or 8)", depth)),
+    }
+}
+
+/// 不超过该像素数的 PNG 跳过 imagequant，直接按原始颜色建立精确调色板
+pub const EXACT_PALETTE_MAX_PIXELS: usize = 64;
+
+/// 颜色数不超过 `max_colors` 时返回精确调色板和每个像素的索引，否则返回 `None`
+pub fn exact_palette(rgba_data: &[u8], max_colors: usize) -> Option<(Vec<imagequant::RGBA>, Vec<u8>)> {
+    let mut palette: Vec<imagequant::RGBA> = Vec::new();
+    // 大尺寸的索引 PNG 也走这里，按颜色查索引避免每个像素线性扫描调色板
+    let mut indices: HashMap<[u8; 4], u8> = HashMap::new();
+    let mut pixels = Vec::with_capacity(rgba_data.len() / 4);
+
+    for chunk in rgba_data.chunks_exact(4) {
+        let key = [chunk[0], chunk[1], chunk[2], chunk[3]];
+        let index = match indices.get(&key) {
+            Some(&index) => index,
+            None if palette.len() < max_colors.min(256) => {
+                palette.push(imagequant::RGBA { r: key[0], g: key[1], b: key[2], a: key[3] });
+                let index = (palette.len() - 1) as u8;
+                indices.insert(key, index);
+                index
+            }
+            None => return None,
+        };
+        pixels.push(index);
+    }
+
+    Some((palette, pixels))
+}
+
+/// 把带透明度的调色板条目稳定地移到前面，并同步改写像素索引
+///
+/// 量化器和 `exact_palette` 都可能让透明条目夹在不透明条目之间，此时 tRNS 必须一直写到
+/// 最后一个透明条目，中间的不透明条目也要各占一个 255。重排后 tRNS 长度等于透明条目数。
+fn transparent_first(palette: Vec<imagequant::RGBA>, pixels: Vec<u8>) -> (Vec<imagequant::RGBA>, Vec<u8>) {
+    let mut order: Vec<usize> = (0..palette.len()).collect();
+    order.sort_by_key(|&i| palette[i].a == 255);
+    if order.iter().enumerate().all(|(new, &old)| new == old) {
+        return (palette, pixels);
+    }
+
+    let mut remap = [0u8; 256];
+    for (new, &old) in order.iter().enumerate() {
+        remap[old] = new as u8;
+    }
+    let sorted = order.iter().map(|&old| palette[old]).collect();
+    let pixels = pixels.into_iter().map(|index| remap[index as usize]).collect();
+    (sorted, pixels)
+}
+
+/// 是否为索引色（调色板）PNG，只读取 IHDR 中的颜色类型
+pub fn is_indexed_png(data: &[u8]) -> bool {
+    // 8 字节签名 + 长度 4 + 类型 4 + 宽高 8 + 位深度 1，颜色类型 3 为索引色
+    data.len() > 25 && data.starts_with(b"\x89PNG\r\n\x1a\n") && &data[12..16] == b"IHDR" && data[25] == 3
+}
+
+/// 能容纳给定调色板大小的最小位深度
+pub fn minimal_bit_depth(palette_len: usize) -> png::BitDepth {
+    match palette_len {
+        0..=2 => png::BitDepth::One,
+        3..=4 => png::BitDepth::Two,
+        5..=16 => png::BitDepth::Four,
+        _ => png::BitDepth::Eight,
+    }
+}
+
+/// 按位深度打包索引像素，每行按 PNG 规范从高位开始填充并补齐到字节边界
+pub fn pack_indexed_pixels(pixels: &[u8], width: usize, bit_depth: png::BitDepth) -> Vec<u8> {
+    let bits = bit_depth as usize;
+    if bits == 8 || width == 0 {
+        return pixels.to_vec();
+    }
+
+    let per_byte = 8 / bits;
+    let row_bytes = width.div_ceil(per_byte);
+    let mut packed = Vec::with_capacity(row_bytes * (pixels.len() / width));
+
+    for row in pixels.chunks_exact(width) {
+        for group in row.chunks(per_byte) {
+            let mut byte = 0u8;
+            for (i, &index) in group.iter().enumerate() {
+                byte |= index << (8 - bits * (i + 1));
+            }
+            packed.push(byte);
+        }
+    }
+
+    packed
+}
+
+/// 取出按行紧密排列的 RGB8 像素
+///
+/// `DynamicImage::to_rgb8` 总会复制整幅图片；`into_rgb8` 对 `ImageRgb8` 只移动底层 `Vec`，
+/// 其他像素格式才转换。这里不做任何类型重解释，编码器按 `width * 3` 切分扫描线，
+/// 因此仍校验缓冲区长度与 RGB 布局，不符时返回错误而不是越界。
+fn into_rgb_buffer(img: DynamicImage) -> Result<(u32, u32, Vec<u8>), String> {
+    let zero_copy = matches!(img, DynamicImage::ImageRgb8(_));
+    let rgb_img = img.into_rgb8();
+    let (width, height) = rgb_img.dimensions();
+    let raw_data = rgb_img.into_raw();
+
+    let expected_len = width as usize * height as usize * 3;
+    if !is_rgb_layout_compatible() || raw_data.len() != expected_len {
+        return Err(format!(
+            "Unexpected RGB buffer layout: {} bytes for {}x{}", raw_data.len(), width, height
+        ));
+    }
+    if zero_copy && expected_len > 50 * 1024 * 1024 {
+        info!("JPEG compression: Reusing RGB buffer without copy for {}MB image ({}x{})",
+              expected_len / (1024 * 1024), width, height);
+    }
+
+    Ok((width, height, raw_data))
+}
+
+/// image::Rgb<u8> 内存布局的运行时验证
+/// 确认像素按 R,G,B 三个字节紧密排列，扫描线长度即为 `width * 3`
+pub fn is_rgb_layout_compatible() -> bool {
+    use image::Pixel;
+
+    std::mem::size_of::<image::Rgb<u8>>() == 3
+        && std::mem::align_of::<image::Rgb<u8>>() == 1
+        && image::Rgb([0x12u8, 0x34, 0x56]).channels() == [0x12, 0x34, 0x56]
 }
 
 /// 检查 imagequant::RGBA 的零拷贝转换是否安全
@@ -373,8 +2219,773 @@ pub fn is_rgba_layout_compatible() -> bool {
 }
 
 // WebP 压缩函数
-pub fn do_webp_compression(_data: &[u8], _width: u32, _height: u32, _quality: u8) -> Result<Vec<u8>, String> {
-    // 简单的 WebP 压缩实现
-    // 这里可以使用 webp 库，但为了简化，我们先返回错误
-    Err("WebP compression not yet implemented".to_string())
+// image 自带的纯 Rust 编码器只支持无损 VP8L，quality 不影响输出；
+// 对 BMP 这类未压缩输入仍能大幅缩小体积
+pub fn do_webp_compression(data: &[u8], width: u32, height: u32, _quality: u8) -> Result<Vec<u8>, String> {
+    let mut output = Vec::new();
+    image::codecs::webp::WebPEncoder::new_lossless(&mut output)
+        .encode(data, width, height, image::ColorType::Rgba8)
+        .map_err(|e| format!("WebP encoding failed: {}", e))?;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_minimal_bit_depth() {
+        assert_eq!(minimal_bit_depth(2), png::BitDepth::One);
+        assert_eq!(minimal_bit_depth(4), png::BitDepth::Two);
+        assert_eq!(minimal_bit_depth(16), png::BitDepth::Four);
+        assert_eq!(minimal_bit_depth(17), png::BitDepth::Eight);
+    }
+
+    #[test]
+    fn test_png_bit_depth_rejects_invalid() {
+        assert!(png_bit_depth(4).is_ok());
+        assert!(png_bit_depth(3).is_err());
+        assert!(png_bit_depth(16).is_err());
+    }
+
+    #[test]
+    fn test_pack_indexed_pixels() {
+        // 3x2 image at 2-bit depth: each row padded to one byte
+        let pixels = [1, 2, 3, 0, 1, 2];
+        let packed = pack_indexed_pixels(&pixels, 3, png::BitDepth::Two);
+        assert_eq!(packed, vec![0b0110_1100, 0b0001_1000]);
+    }
+
+    #[test]
+    fn test_png_compression_uses_minimal_depth() {
+        // Two-color image should be encoded at 1-bit depth
+        let mut rgba = Vec::new();
+        for i in 0..64 {
+            let v = if i % 2 == 0 { 0 } else { 255 };
+            rgba.extend_from_slice(&[v, v, v, 255]);
+        }
+
+        let (png_data, _, _, quantization_quality) = do_png_compression(&rgba, 8, 8, 85, None, None, Dither::default(), false).unwrap();
+        let decoder = png::Decoder::new(Cursor::new(png_data));
+        let reader = decoder.read_info().unwrap();
+        assert_eq!(reader.info().bit_depth, png::BitDepth::One);
+        // 两种颜色可以无损量化
+        assert_eq!(quantization_quality, Some(100));
+    }
+
+    #[test]
+    fn test_warm_up_encoders() {
+        let results = warm_up_encoders();
+        let expected = if cfg!(feature = "mozjpeg") { 3 } else { 2 };
+        assert_eq!(results.len(), expected);
+        assert!(results.iter().all(|(_, result)| result.is_ok()));
+    }
+
+    fn encode_test_jpeg(progressive: bool) -> Vec<u8> {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(16, 16, |x, y| {
+            image::Rgb([(x * 16) as u8, (y * 16) as u8, 64])
+        }));
+        let mut options = CompressionOptions::new("jpeg", 80, "jpeg-encoder");
+        options.progressive = progressive;
+        do_jpeg_encoder_compression(img, &options).unwrap()
+    }
+
+    #[test]
+    fn test_detect_progressive_jpeg() {
+        assert_eq!(detect_progressive_jpeg(&encode_test_jpeg(true)), Some(true));
+        assert_eq!(detect_progressive_jpeg(&encode_test_jpeg(false)), Some(false));
+        assert_eq!(detect_progressive_jpeg(b"\x89PNG\r\n\x1a\n"), None);
+    }
+
+    #[test]
+    fn test_jpeg_rejects_transparency_without_background() {
+        let img = image::RgbaImage::from_fn(4, 4, |x, _| image::Rgba([255, 0, 0, if x < 2 { 0 } else { 255 }]));
+        let mut png = Vec::new();
+        DynamicImage::ImageRgba8(img)
+            .write_to(&mut Cursor::new(&mut png), image::ImageOutputFormat::Png)
+            .unwrap();
+
+        let mut options = CompressionOptions::new("jpeg", 80, "mozjpeg");
+        assert!(matches!(
+            compress_image_with_options(&png, &options),
+            Err(ImageServerError::InvalidParameters(_))
+        ));
+
+        options.background = Some([255, 255, 255]);
+        assert!(compress_image_with_options(&png, &options).is_ok());
+    }
+
+    #[test]
+    fn test_flatten_alpha() {
+        let img = image::RgbaImage::from_pixel(1, 1, image::Rgba([0, 0, 0, 0]));
+        let flattened = flatten_alpha(DynamicImage::ImageRgba8(img), [255, 128, 0]);
+        assert_eq!(flattened.to_rgb8().get_pixel(0, 0).0, [255, 128, 0]);
+    }
+
+    #[test]
+    fn test_mozjpeg_quant_tables() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(16, 16, |x, y| {
+            image::Rgb([(x * 16) as u8, (y * 16) as u8, 64])
+        }));
+
+        for name in JpegQuantTable::NAMES {
+            let mut options = CompressionOptions::new("jpeg", 75, "mozjpeg");
+            options.quant_table = JpegQuantTable::from_name(name).unwrap();
+            let jpeg = do_mozjpeg_compression(img.clone(), &options).unwrap();
+            assert!(image::load_from_memory(&jpeg).is_ok(), "{} produced invalid JPEG", name);
+        }
+
+        assert!(JpegQuantTable::from_name("unknown").is_none());
+    }
+
+    #[test]
+    fn test_jpeg_encoder_quality_bounds() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(32, 32, |x, y| {
+            image::Rgb([(x * 8) as u8, (y * 8) as u8, ((x + y) * 4) as u8])
+        }));
+        let encode = |quality| {
+            do_jpeg_encoder_compression(img.clone(), &CompressionOptions::new("jpeg", quality, "jpeg-encoder")).unwrap()
+        };
+
+        let lowest = encode(1);
+        let highest = encode(100);
+        for jpeg in [&lowest, &highest] {
+            let decoded = image::load_from_memory(jpeg).unwrap();
+            assert_eq!((decoded.width(), decoded.height()), (32, 32));
+        }
+        assert!(lowest.len() < highest.len());
+
+        // 超出范围时按边界编码
+        assert_eq!(jpeg_encoder_quality(0), 1);
+        assert_eq!(jpeg_encoder_quality(255), 100);
+        assert_eq!(encode(0), lowest);
+        assert_eq!(encode(255), highest);
+    }
+
+    #[test]
+    fn test_mozjpeg_channel_qualities() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 4) as u8, ((x + y) * 2) as u8])
+        }));
+        let mut options = CompressionOptions::new("jpeg", 90, "mozjpeg");
+        assert_eq!(options.channel_qualities(), (90, 90));
+        let uniform = do_mozjpeg_compression(img.clone(), &options).unwrap();
+
+        // 只降低色度质量，输出更小且仍可解码
+        options.chroma_quality = Some(30);
+        assert_eq!(options.channel_qualities(), (90, 30));
+        let weighted = do_mozjpeg_compression(img, &options).unwrap();
+        assert!(image::load_from_memory(&weighted).is_ok());
+        if cfg!(feature = "mozjpeg") {
+            assert!(weighted.len() < uniform.len());
+        }
+    }
+
+    #[test]
+    fn test_compress_pyramid() {
+        let img = image::RgbImage::from_fn(64, 32, |x, y| image::Rgb([(x * 4) as u8, (y * 8) as u8, 0]));
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(img)
+            .write_to(&mut Cursor::new(&mut png), image::ImageOutputFormat::Png)
+            .unwrap();
+
+        let options = CompressionOptions::new("jpeg", 80, "mozjpeg");
+        let (levels, width, height) = compress_pyramid(&png, &options, &[16, 32, 128]).unwrap();
+        assert_eq!((width, height), (64, 32));
+        let sizes: Vec<(u32, u32)> = levels.iter().map(|l| (l.width, l.height)).collect();
+        assert_eq!(sizes, vec![(16, 8), (32, 16), (64, 32)]);
+        let filters: Vec<Option<ResizeFilter>> = levels.iter().map(|l| l.resize_filter).collect();
+        assert_eq!(filters, vec![Some(ResizeFilter::Lanczos3), Some(ResizeFilter::Lanczos3), None]);
+
+        // 缩小到 1/4 及以下时改用 Triangle
+        let mut options = options;
+        options.fast_downscale_factor = 4;
+        let (levels, _, _) = compress_pyramid(&png, &options, &[16, 32]).unwrap();
+        let filters: Vec<Option<ResizeFilter>> = levels.iter().map(|l| l.resize_filter).collect();
+        assert_eq!(filters, vec![Some(ResizeFilter::Triangle), Some(ResizeFilter::Lanczos3)]);
+
+        // 本身比 Triangle 快的滤镜不受影响
+        options.resize_filter = ResizeFilter::Nearest;
+        assert_eq!(options.resize_filter_for(64, 8), ResizeFilter::Nearest);
+    }
+
+    #[test]
+    fn test_mozjpeg_jpeg_modes() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(32, 32, |x, y| {
+            image::Rgb([(x * 8) as u8, (y * 8) as u8, 128])
+        }));
+
+        let encode = |mode| {
+            let mut options = CompressionOptions::new("jpeg", 80, "mozjpeg");
+            options.jpeg_mode = Some(mode);
+            do_mozjpeg_compression(img.clone(), &options).unwrap()
+        };
+
+        assert_eq!(detect_progressive_jpeg(&encode(JpegMode::Progressive)), Some(true));
+        let baseline = encode(JpegMode::Baseline);
+        let optimized = encode(JpegMode::Optimized);
+        assert_eq!(detect_progressive_jpeg(&baseline), Some(false));
+        assert_eq!(detect_progressive_jpeg(&optimized), Some(false));
+        assert!(optimized.len() <= baseline.len());
+
+        assert!(JpegMode::from_name("unknown").is_none());
+    }
+
+    #[test]
+    fn test_dither_from_name() {
+        assert_eq!(Dither::from_name("auto"), Some(Dither::Auto));
+        assert_eq!(Dither::from_name("off"), Some(Dither::Level(0.0)));
+        assert_eq!(Dither::from_name("FULL"), Some(Dither::Level(1.0)));
+        assert_eq!(Dither::from_name("0.5"), Some(Dither::Level(0.5)));
+        assert_eq!(Dither::from_name("1.5"), None);
+        assert_eq!(Dither::from_name("noisy"), None);
+    }
+
+    #[test]
+    fn test_dither_auto_level() {
+        assert_eq!(Dither::Auto.level_for(2), 0.0);
+        assert_eq!(Dither::Auto.level_for(AUTO_DITHER_MAX_COLORS + 1), 1.0);
+        assert_eq!(Dither::Level(0.3).level_for(2), 0.3);
+    }
+
+    #[test]
+    fn test_preserve_exif_shooting_parameters() {
+        let f_number = exif::Field {
+            tag: Tag::FNumber,
+            ifd_num: In::PRIMARY,
+            value: Value::Rational(vec![exif::Rational { num: 28, denom: 10 }]),
+        };
+        let iso = exif::Field {
+            tag: Tag::PhotographicSensitivity,
+            ifd_num: In::PRIMARY,
+            value: Value::Short(vec![400]),
+        };
+        let orientation = exif::Field {
+            tag: Tag::Orientation,
+            ifd_num: In::PRIMARY,
+            value: Value::Short(vec![1]),
+        };
+        let mut writer = exif::experimental::Writer::new();
+        writer.push_field(&f_number);
+        writer.push_field(&iso);
+        writer.push_field(&orientation);
+        let mut tiff = Cursor::new(Vec::new());
+        writer.write(&mut tiff, false).unwrap();
+
+        let source = insert_jpeg_exif(&encode_test_jpeg(false), &tiff.into_inner());
+        let mut options = CompressionOptions::new("jpeg", 80, "mozjpeg");
+        options.preserve_exif = true;
+        let output = compress_image_with_options(&source, &options).unwrap().data;
+
+        let wanted = vec!["FNumber".to_string(), "PhotographicSensitivity".to_string(), "Orientation".to_string()];
+        let tags = read_exif_tags(&output, &wanted);
+        let names: Vec<&str> = tags.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["FNumber", "PhotographicSensitivity"]);
+
+        options.preserve_exif = false;
+        let output = compress_image_with_options(&source, &options).unwrap().data;
+        assert!(read_exif_tags(&output, &wanted).is_empty());
+    }
+
+    #[test]
+    fn test_lossless_rotate() {
+        let orientation = exif::Field {
+            tag: Tag::Orientation,
+            ifd_num: In::PRIMARY,
+            value: Value::Short(vec![6]),
+        };
+        let mut writer = exif::experimental::Writer::new();
+        writer.push_field(&orientation);
+        let mut tiff = Cursor::new(Vec::new());
+        writer.write(&mut tiff, false).unwrap();
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(48, 32, |x, y| image::Rgb([(x * 5) as u8, (y * 7) as u8, 64])));
+        let jpeg = do_jpeg_encoder_compression(img, &CompressionOptions::new("jpeg", 80, "jpeg-encoder")).unwrap();
+        let source = insert_jpeg_exif(&jpeg, &tiff.into_inner());
+
+        // EXIF 方向 6 与 rotate:90 合成旋转 180 度，输出保留 EXIF 且方向为 1
+        let mut options = CompressionOptions::new("jpeg", 80, "mozjpeg");
+        options.lossless_rotate = true;
+        options.operations = vec![Operation::Rotate { degrees: 90 }];
+        let result = compress_image_with_options(&source, &options).unwrap();
+        assert_eq!(result.lossless_transform, Some(Transform::ROTATE_180));
+        assert_eq!((result.width, result.height), (48, 32));
+        assert_eq!((result.source_width, result.source_height), (32, 48));
+        assert_eq!(read_exif_orientation(&result.data), Some(1));
+        assert!(result.exif_info.orientation_applied);
+
+        // 交换宽高的旋转：量化表随系数转置，解码结果与像素域旋转一致
+        options.operations = vec![Operation::Rotate { degrees: 90 }];
+        let result = compress_image_with_options(&jpeg, &options).unwrap();
+        assert_eq!(result.lossless_transform, Some(Transform::ROTATE_90));
+        assert_eq!((result.width, result.height), (32, 48));
+        let rotated = image::load_from_memory(&result.data).unwrap().to_rgb8();
+        let expected = image::load_from_memory(&jpeg).unwrap().rotate90().to_rgb8();
+        let max_difference = rotated.as_raw().iter().zip(expected.as_raw()).map(|(&a, &b)| a.abs_diff(b)).max().unwrap();
+        assert!(max_difference <= 2, "max difference {}", max_difference);
+
+        // 其他操作需要重新编码
+        options.operations.push(Operation::Grayscale);
+        let result = compress_image_with_options(&source, &options).unwrap();
+        assert_eq!(result.lossless_transform, None);
+        assert_eq!((result.width, result.height), (48, 32));
+    }
+
+    #[test]
+    fn test_exif_info_orientation() {
+        let orientation = exif::Field {
+            tag: Tag::Orientation,
+            ifd_num: In::PRIMARY,
+            value: Value::Short(vec![6]),
+        };
+        let mut writer = exif::experimental::Writer::new();
+        writer.push_field(&orientation);
+        let mut tiff = Cursor::new(Vec::new());
+        writer.write(&mut tiff, false).unwrap();
+        let source = insert_jpeg_exif(&encode_test_jpeg(false), &tiff.into_inner());
+
+        let options = CompressionOptions::new("jpeg", 80, "jpeg-encoder");
+        let exif_info = compress_image_with_options(&source, &options).unwrap().exif_info;
+        assert_eq!(exif_info.orientation, Some(6));
+        assert!(exif_info.orientation_applied);
+
+        // 非 JPEG 输出不旋转像素，但仍报告源图片的方向值
+        let exif_info = exif_info_for(&source, "png");
+        assert_eq!(exif_info.orientation, Some(6));
+        assert!(!exif_info.orientation_applied);
+
+        let exif_info = exif_info_for(&encode_test_jpeg(false), "jpeg");
+        assert_eq!(exif_info, ExifInfo::unprocessed("No EXIF orientation found"));
+    }
+
+    #[test]
+    fn test_verify_output() {
+        let orientation = exif::Field {
+            tag: Tag::Orientation,
+            ifd_num: In::PRIMARY,
+            value: Value::Short(vec![6]),
+        };
+        let mut writer = exif::experimental::Writer::new();
+        writer.push_field(&orientation);
+        let mut tiff = Cursor::new(Vec::new());
+        writer.write(&mut tiff, false).unwrap();
+        let tiff = tiff.into_inner();
+        let source = insert_jpeg_exif(&encode_test_jpeg(false), &tiff);
+
+        let mut options = CompressionOptions::new("jpeg", 80, "jpeg-encoder");
+        options.verify_output = true;
+        let result = compress_image_with_options(&source, &options).unwrap();
+        assert!(result.exif_info.orientation_applied);
+        assert_eq!(verify_output(&result.data, result.width, result.height, &result.exif_info), Ok(()));
+
+        assert!(verify_output(&result.data, 16, 8, &result.exif_info).unwrap_err().contains("16x8"));
+        assert!(verify_output(b"not an image", 16, 16, &result.exif_info).is_err());
+
+        // 方向被重复应用：像素已旋转，输出却仍带方向 6
+        let double_rotated = insert_jpeg_exif(&result.data, &tiff);
+        assert!(verify_output(&double_rotated, 16, 16, &result.exif_info).is_err());
+        assert_eq!(verify_output(&double_rotated, 16, 16, &ExifInfo::unprocessed("")), Ok(()));
+    }
+
+    #[test]
+    fn test_thumbnail_quality_boost() {
+        let mut options = CompressionOptions::new("jpeg", 80, "mozjpeg");
+        assert_eq!(options.quality_for(64, 64), 80);
+
+        options.thumbnail_quality_boost = 10;
+        options.thumbnail_max_dimension = 256;
+        assert_eq!(options.quality_for(256, 128), 90);
+        assert_eq!(options.quality_for(512, 128), 80);
+
+        options.quality = 95;
+        assert_eq!(options.quality_for(64, 64), 100);
+
+        options.format = "png".to_string();
+        assert_eq!(options.quality_for(64, 64), 95);
+    }
+
+    #[test]
+    fn test_read_png_palette() {
+        let img = image::RgbaImage::from_fn(4, 4, |x, _| {
+            if x < 2 { image::Rgba([255, 0, 0, 255]) } else { image::Rgba([0, 0, 255, 0]) }
+        });
+        let (png, _, _, _) = do_png_compression(img.as_raw(), 4, 4, 85, None, None, Dither::Level(0.0), false).unwrap();
+
+        let palette = read_png_palette(&png).unwrap();
+        assert_eq!(palette.len(), 2);
+        assert!(palette.iter().any(|c| c.hex() == "#ff0000" && c.a == 255));
+        assert!(palette.iter().any(|c| c.a == 0));
+
+        let opaque = DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(img).to_rgb8());
+        let jpeg = encode_image(opaque, &CompressionOptions::new("jpeg", 80, "jpeg-encoder")).unwrap();
+        assert!(read_png_palette(&jpeg).is_none());
+    }
+
+    #[test]
+    fn test_encoder_provenance() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(16, 16, |x, y| {
+            image::Rgb([(x * 16) as u8, (y * 16) as u8, 128])
+        }));
+
+        let options = CompressionOptions::new("jpeg", 82, "jpeg-encoder");
+        let jpeg = encode_image(img.clone(), &options).unwrap();
+        let provenance = encoder_provenance(&options, 82, &jpeg);
+        assert!(provenance.encoder.starts_with("jpeg-encoder img-server-rs/"));
+        assert_eq!(provenance.options, "q=82;progressive=false;subsampling=420");
+
+        let options = CompressionOptions::new("jpeg", 95, "jpeg-encoder");
+        let jpeg = encode_image(img.clone(), &options).unwrap();
+        assert_eq!(jpeg_subsampling(&jpeg).as_deref(), Some("444"));
+
+        let options = CompressionOptions::new("png", 80, "png-quantized");
+        let png = encode_image(img, &options).unwrap();
+        let provenance = encoder_provenance(&options, 80, &png);
+        assert!(provenance.encoder.starts_with("imagequant "));
+        assert!(provenance.options.starts_with("bit_depth="));
+        assert!(provenance.options.contains(";colors="));
+    }
+
+    #[test]
+    fn test_embed_comment() {
+        assert!(validate_comment("source: camera-42").is_ok());
+        assert!(validate_comment("").is_err());
+        assert!(validate_comment("line\nbreak").is_err());
+        assert!(validate_comment(&"x".repeat(MAX_COMMENT_LEN + 1)).is_err());
+
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(8, 8, image::Rgb([10, 20, 30])));
+        let mut options = CompressionOptions::new("jpeg", 80, "jpeg-encoder");
+        options.comment = Some("source: camera-42".to_string());
+        let jpeg = embed_comment(encode_image(img.clone(), &options).unwrap(), &options);
+        let com = jpeg.windows(2).position(|w| w == [0xFF, 0xFE]).unwrap();
+        assert_eq!(&jpeg[com + 4..com + 4 + 17], b"source: camera-42");
+        assert!(image::load_from_memory(&jpeg).is_ok());
+
+        options.format = "png".to_string();
+        for comment in ["source: camera-42", "来源: 相机"] {
+            options.comment = Some(comment.to_string());
+            let png_data = embed_comment(encode_image(img.clone(), &options).unwrap(), &options);
+            let reader = png::Decoder::new(Cursor::new(png_data)).read_info().unwrap();
+            let info = reader.info();
+            let text: Vec<String> = info.uncompressed_latin1_text.iter().map(|t| t.text.clone())
+                .chain(info.utf8_text.iter().map(|t| t.get_text().unwrap()))
+                .collect();
+            assert_eq!(text, vec![comment.to_string()]);
+        }
+    }
+
+    #[test]
+    fn test_jpeg_scan_ends() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| image::Rgb([x as u8 * 4, y as u8 * 4, 128])));
+        let mut options = CompressionOptions::new("jpeg", 80, "mozjpeg");
+
+        options.jpeg_mode = Some(JpegMode::Baseline);
+        let baseline = encode_image(img.clone(), &options).unwrap();
+        let ends = jpeg_scan_ends(&baseline);
+        assert_eq!(ends.len(), 1);
+        assert_eq!(&baseline[ends[0]..], &[0xFF, 0xD9]);
+
+        options.jpeg_mode = Some(JpegMode::Progressive);
+        let progressive = encode_image(img, &options).unwrap();
+        let ends = jpeg_scan_ends(&progressive);
+        assert!(ends.len() > 1, "{:?}", ends);
+        assert!(ends.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(&progressive[*ends.last().unwrap()..], &[0xFF, 0xD9]);
+
+        assert!(jpeg_scan_ends(b"not a jpeg").is_empty());
+    }
+
+    #[test]
+    fn test_embed_xmp() {
+        let xmp = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#"/></x:xmpmeta>"#;
+        assert!(validate_xmp(xmp).is_ok());
+        assert!(validate_xmp(&format!("<?xpacket begin=\"\"?>{}<?xpacket end=\"w\"?>", xmp)).is_ok());
+        assert!(validate_xmp("").is_err());
+        assert!(validate_xmp("<a><b></a>").is_err());
+        assert!(validate_xmp("<a>").is_err());
+        assert!(validate_xmp("<a/><b/>").is_err());
+        assert!(validate_xmp("plain text").is_err());
+        assert!(validate_xmp(&format!("<a>{}</a>", "x".repeat(MAX_XMP_LEN))).is_err());
+
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(8, 8, image::Rgb([10, 20, 30])));
+        let mut options = CompressionOptions::new("jpeg", 80, "jpeg-encoder");
+        options.xmp = Some(xmp.to_string());
+        let jpeg = embed_xmp(encode_image(img.clone(), &options).unwrap(), &options);
+        let app1 = jpeg.windows(2).position(|w| w == [0xFF, 0xE1]).unwrap();
+        let payload = &jpeg[app1 + 4..];
+        assert!(payload.starts_with(XMP_JPEG_HEADER));
+        assert!(payload[XMP_JPEG_HEADER.len()..].starts_with(xmp.as_bytes()));
+        assert!(image::load_from_memory(&jpeg).is_ok());
+
+        options.format = "png".to_string();
+        let png_data = embed_xmp(encode_image(img, &options).unwrap(), &options);
+        let reader = png::Decoder::new(Cursor::new(png_data)).read_info().unwrap();
+        let chunk = &reader.info().utf8_text[0];
+        assert_eq!(chunk.keyword, XMP_PNG_KEYWORD);
+        assert!(!chunk.compressed);
+        assert_eq!(chunk.get_text().unwrap(), xmp);
+    }
+
+    #[test]
+    fn test_png_quality_reaches_quantizer() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 4) as u8, ((x + y) * 2) as u8])
+        }));
+
+        let colors_at = |quality: u8| {
+            let options = CompressionOptions::new("png", quality, "png-quantized");
+            let png = encode_image(img.clone(), &options).unwrap();
+            read_png_palette(&png).unwrap().len()
+        };
+        // 质量越低量化越激进，0 表示最小体积
+        assert!(colors_at(0) < colors_at(100));
+        assert_eq!(min_quality("png"), 0);
+        assert_eq!(min_quality("jpeg"), 1);
+    }
+
+    #[test]
+    fn test_png_quality_changes_output_size() {
+        let img = image::RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 4) as u8, ((x + y) * 2) as u8])
+        });
+        let mut source = Vec::new();
+        img.write_to(&mut Cursor::new(&mut source), image::ImageOutputFormat::Png).unwrap();
+
+        let (low, _, _, _) = compress_image(&source, "png", 40, "png-quantized").unwrap();
+        let (high, _, _, _) = compress_image(&source, "png", 90, "png-quantized").unwrap();
+        assert!(low.len() < high.len(), "quality 40: {} bytes, quality 90: {} bytes", low.len(), high.len());
+    }
+
+    #[test]
+    fn test_into_rgb_buffer_reuses_rgb8() {
+        assert!(is_rgb_layout_compatible());
+
+        let rgb = image::RgbImage::from_pixel(4, 2, image::Rgb([1, 2, 3]));
+        let ptr = rgb.as_raw().as_ptr();
+        let (width, height, data) = into_rgb_buffer(DynamicImage::ImageRgb8(rgb)).unwrap();
+        assert_eq!((width, height, data.len()), (4, 2, 24));
+        assert_eq!(data.as_ptr(), ptr);
+
+        // 其他像素格式转换为 RGB
+        let rgba = image::RgbaImage::from_pixel(4, 2, image::Rgba([1, 2, 3, 255]));
+        let (_, _, data) = into_rgb_buffer(DynamicImage::ImageRgba8(rgba)).unwrap();
+        assert_eq!(&data[..3], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn test_tiny_images() {
+        let cases = [
+            ("jpeg", "mozjpeg"),
+            ("jpeg", "jpeg-encoder"),
+            ("png", "png-quantized"),
+            ("webp", "mozjpeg"),
+            ("apng", "mozjpeg"),
+        ];
+        for (width, height) in [(1, 1), (2, 1), (1, 2)] {
+            let img = image::RgbImage::from_pixel(width, height, image::Rgb([200, 100, 50]));
+            let mut source = Vec::new();
+            img.write_to(&mut Cursor::new(&mut source), image::ImageOutputFormat::Png).unwrap();
+
+            for (format, algorithm) in cases {
+                let options = CompressionOptions::new(format, 80, algorithm);
+                let result = compress_image_with_options(&source, &options)
+                    .unwrap_or_else(|e| panic!("{}x{} {} {}: {}", width, height, format, algorithm, e));
+                assert!(!result.data.is_empty());
+                assert_eq!((result.width, result.height), (width, height));
+                let decoded = image::load_from_memory(&result.data).unwrap();
+                assert_eq!((decoded.width(), decoded.height()), (width, height));
+            }
+
+            let mut options = CompressionOptions::new("jpeg", 80, "mozjpeg");
+            options.target_ssim = Some(0.95);
+            assert!(compress_image_with_options(&source, &options).unwrap().chosen_quality.is_some());
+
+            let mut options = CompressionOptions::new("png", 0, "png-quantized");
+            options.png_depth = Some(1);
+            options.dither = Dither::Auto;
+            assert!(compress_image_with_options(&source, &options).is_ok());
+
+            let transparent = DynamicImage::ImageRgba8(image::RgbaImage::new(width, height));
+            let png = encode_image(transparent, &CompressionOptions::new("png", 80, "png-quantized")).unwrap();
+            assert_eq!(image::load_from_memory(&png).unwrap().to_rgba8().get_pixel(0, 0)[3], 0);
+        }
+
+        // 空图片明确拒绝
+        for (format, algorithm) in cases.into_iter().filter(|(format, _)| *format != "apng") {
+            let options = CompressionOptions::new(format, 80, algorithm);
+            assert!(matches!(
+                encode_image(DynamicImage::new_rgb8(0, 0), &options),
+                Err(ImageServerError::InvalidParameters(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_exact_palette() {
+        let rgba = [255, 0, 0, 255, 0, 0, 255, 0, 255, 0, 0, 255];
+        let (palette, pixels) = exact_palette(&rgba, 256).unwrap();
+        assert_eq!(palette.len(), 2);
+        assert_eq!(pixels, vec![0, 1, 0]);
+        assert_eq!(palette[1].a, 0);
+
+        // 颜色数超过上限时交给量化器
+        assert!(exact_palette(&rgba, 1).is_none());
+    }
+
+    #[test]
+    fn test_indexed_png_round_trip() {
+        // 200 种相近的颜色，重新量化容易合并或偏移
+        let (width, height) = (128u32, 128u32);
+        let palette: Vec<u8> = (0..200u32).flat_map(|i| [i as u8, (i / 2) as u8, 255 - i as u8]).collect();
+        let indices: Vec<u8> = (0..width * height).map(|i| ((i / 3) % 200) as u8).collect();
+        let mut data = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut data, width, height);
+            encoder.set_color(png::ColorType::Indexed);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.set_palette(palette);
+            encoder.write_header().unwrap().write_image_data(&indices).unwrap();
+        }
+        assert!(is_indexed_png(&data));
+        assert!(!is_indexed_png(b"not a png"));
+
+        let mut options = CompressionOptions::new("png", 60, "png-quantized");
+        options.source_indexed = is_indexed_png(&data);
+        let result = compress_image_with_options(&data, &options).unwrap();
+        assert_eq!(result.quantization_quality, Some(100));
+        assert_eq!(read_png_palette(&result.data).unwrap().len(), 200);
+        let input = image::load_from_memory(&data).unwrap().to_rgba8();
+        let output = image::load_from_memory(&result.data).unwrap().to_rgba8();
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn test_single_color_images() {
+        let (width, height) = (300u32, 200u32);
+        let transparent = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(width, height, |x, y| {
+            // 编辑器常在完全透明的区域留下杂乱的 RGB
+            image::Rgba([x as u8, y as u8, (x ^ y) as u8, 0])
+        }));
+        let opaque = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(width, height, image::Rgba([30, 120, 200, 255])));
+
+        // PNG：一项调色板、1 位深度，像素与输入一致
+        for (img, expected) in [(&transparent, [0, 0, 0, 0]), (&opaque, [30, 120, 200, 255])] {
+            let png = encode_image(img.clone(), &CompressionOptions::new("png", 80, "png-quantized")).unwrap();
+            let palette = read_png_palette(&png).unwrap();
+            assert_eq!(palette, vec![PaletteEntry { r: expected[0], g: expected[1], b: expected[2], a: expected[3] }]);
+            let reader = png::Decoder::new(Cursor::new(&png)).read_info().unwrap();
+            assert_eq!(reader.info().bit_depth, png::BitDepth::One);
+            let decoded = image::load_from_memory(&png).unwrap().to_rgba8();
+            assert!(decoded.pixels().all(|p| p.0 == expected));
+        }
+
+        // WebP 无损，alpha 保持不变
+        let webp = encode_image(transparent.clone(), &CompressionOptions::new("webp", 80, "webp")).unwrap();
+        assert!(image::load_from_memory(&webp).unwrap().to_rgba8().pixels().all(|p| p[3] == 0));
+
+        // JPEG：完全透明时得到纯背景色，不透明时保留原色（容许编码误差）
+        for algorithm in ["mozjpeg", "jpeg-encoder"] {
+            let mut options = CompressionOptions::new("jpeg", 90, algorithm);
+            assert!(matches!(encode_image(transparent.clone(), &options), Err(ImageServerError::InvalidParameters(_))));
+            options.background = Some([255, 255, 255]);
+            for (img, expected) in [(&transparent, [255, 255, 255]), (&opaque, [30, 120, 200])] {
+                let jpeg = encode_image(img.clone(), &options).unwrap();
+                let decoded = image::load_from_memory(&jpeg).unwrap().to_rgb8();
+                assert!(
+                    decoded.pixels().all(|p| p.0.iter().zip(expected).all(|(&a, b)| a.abs_diff(b) <= 3)),
+                    "{} output drifted from {:?}", algorithm, expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_png_trns_with_transparency_mid_palette() {
+        // 按出现顺序建调色板：不透明、全透明、不透明、半透明、不透明
+        let colors = [[255, 0, 0, 255], [0, 0, 0, 0], [0, 255, 0, 255], [0, 0, 255, 128], [255, 255, 0, 255]];
+        let rgba: Vec<u8> = (0..16).flat_map(|i| colors[i % colors.len()]).collect();
+
+        let (png_data, _, _, _) = do_png_compression(&rgba, 4, 4, 80, None, None, Dither::Level(0.0), true).unwrap();
+
+        // 每个像素的 alpha 都与输入一致
+        let output = image::load_from_memory(&png_data).unwrap().to_rgba8();
+        assert_eq!(output.into_raw(), rgba);
+
+        // tRNS 只包含两个透明条目，不透明条目都在其后
+        let reader = png::Decoder::new(Cursor::new(&png_data)).read_info().unwrap();
+        assert_eq!(reader.info().trns.as_deref(), Some(&[0u8, 128][..]));
+        let palette = read_png_palette(&png_data).unwrap();
+        assert_eq!(palette.len(), 5);
+        assert!(palette[2..].iter().all(|entry| entry.a == 255));
+    }
+
+    #[test]
+    fn test_deterministic_png() {
+        // 颜色远多于 256 种，走 imagequant 量化和抖动
+        let img = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(256, 256, |x, y| {
+            image::Rgba([x as u8, y as u8, (x ^ y) as u8, 255 - (x / 2) as u8])
+        }));
+        let mut options = CompressionOptions::new("png", 70, "png-quantized");
+        options.deterministic = true;
+
+        let first = encode_image(img.clone(), &options).unwrap();
+        for _ in 0..3 {
+            assert_eq!(encode_image(img.clone(), &options).unwrap(), first);
+        }
+    }
+
+    #[test]
+    fn test_png_lossless_fallback() {
+        // 颜色远多于 256 种，调色板达不到 100 的量化质量
+        let img = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(128, 128, |x, y| {
+            image::Rgba([(x * 2) as u8, (y * 2) as u8, (x ^ y) as u8, 255])
+        }));
+        let mut options = CompressionOptions::new("png", 100, "png-quantized");
+        let encoded = encode_image_with_details(img.clone(), &options).unwrap();
+        assert!(!encoded.png_lossless_fallback);
+        assert!(read_png_palette(&encoded.data).is_some());
+
+        options.png_min_quantization_quality = 100;
+        let encoded = encode_image_with_details(img.clone(), &options).unwrap();
+        assert!(encoded.quantization_quality.unwrap() < 100);
+        assert!(encoded.png_lossless_fallback);
+        assert!(read_png_palette(&encoded.data).is_none());
+        let decoded = image::load_from_memory(&encoded.data).unwrap();
+        assert_eq!(decoded.color(), image::ColorType::Rgb8);
+        assert_eq!(decoded.to_rgba8(), img.to_rgba8());
+        assert!(encoder_provenance(&options, 100, &encoded.data).encoder.starts_with("png "));
+    }
+
+    #[test]
+    fn test_png_quantize_speed() {
+        let img = image::RgbaImage::from_fn(128, 128, |x, y| {
+            image::Rgba([(x * 2) as u8, (y * 2) as u8, (x ^ y) as u8, 255])
+        });
+        for speed in [1, 10] {
+            let (png, width, height, quantization_quality) =
+                do_png_compression(img.as_raw(), 128, 128, 70, None, Some(speed), Dither::default(), false).unwrap();
+            assert_eq!((width, height), (128, 128));
+            assert!(quantization_quality.is_some());
+            let decoded = image::load_from_memory(&png).unwrap();
+            assert_eq!((decoded.width(), decoded.height()), (128, 128));
+        }
+        assert!(do_png_compression(img.as_raw(), 128, 128, 70, None, Some(11), Dither::default(), false).is_err());
+    }
+
+    #[test]
+    fn test_bmp_to_webp() {
+        let img = image::RgbImage::from_fn(256, 256, |x, y| image::Rgb([x as u8, y as u8, 128]));
+        let mut bmp = Vec::new();
+        img.write_to(&mut Cursor::new(&mut bmp), image::ImageOutputFormat::Bmp).unwrap();
+        assert!(bmp.starts_with(b"BM"));
+
+        let options = CompressionOptions::new("webp", 80, "webp");
+        let result = compress_image_with_options(&bmp, &options).unwrap();
+        assert_eq!(result.effective_quality, 100);
+        let webp = result.data;
+        assert_eq!(image::guess_format(&webp).unwrap(), image::ImageFormat::WebP);
+        assert!(webp.len() * 10 < bmp.len(), "{} -> {} bytes", bmp.len(), webp.len());
+
+        // 无损编码，像素与原图一致
+        let decoded = image::load_from_memory(&webp).unwrap().to_rgb8();
+        assert_eq!(decoded, img);
+    }
 }
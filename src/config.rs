@@ -1,12 +1,19 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Config {
     pub server: ServerConfig,
     pub compression: CompressionConfig,
     pub logging: LoggingConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub static_files: StaticFilesConfig,
+    #[serde(default)]
+    pub load_shedding: LoadSheddingConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -16,6 +23,83 @@ pub struct ServerConfig {
     pub max_file_size_mb: usize,
     pub worker_threads: Option<usize>,
     pub enable_cors: bool,
+    /// Exact request paths that get CORS headers when `enable_cors` is on; admin endpoints
+    /// such as /metrics are left out by default so browsers cannot read them cross-origin
+    #[serde(default = "default_cors_endpoints")]
+    pub cors_endpoints: Vec<String>,
+    /// Multipart field names accepted as the uploaded image
+    #[serde(default = "default_file_field_names")]
+    pub file_field_names: Vec<String>,
+    /// Maximum size in bytes of each non-file multipart field (e.g. comment, xmp); larger fields get 413
+    #[serde(default = "default_max_text_field_bytes")]
+    pub max_text_field_bytes: usize,
+    /// Directory an upload proxy may reference via the X-File-Path header (disabled when unset)
+    #[serde(default)]
+    pub proxy_upload_dir: Option<String>,
+    /// Directory where /compress/zip writes its output before streaming it back (system temp dir when unset)
+    #[serde(default)]
+    pub zip_temp_dir: Option<String>,
+    /// How /compress/zip renames an output whose name is already taken (e.g. logo.png and logo.jpg
+    /// both becoming logo.jpg): "counter" appends -1, -2, ...; "hash" appends the input's short SHA-256
+    #[serde(default = "default_zip_name_collision")]
+    pub zip_name_collision: String,
+    /// Maximum width or height of an input image in pixels, checked per axis (0 disables the check)
+    #[serde(default = "default_max_image_dimension", alias = "max_dimension_single_axis")]
+    pub max_image_dimension: u32,
+    /// Maximum total pixel count of an input image, guards against decompression bombs (0 disables the check)
+    #[serde(default = "default_max_image_pixels")]
+    pub max_image_pixels: u64,
+    /// Maximum frame count of an animated input decoded for format=apng (0 disables the check);
+    /// the frame count times the canvas area is also checked against max_image_pixels
+    #[serde(default = "default_max_animation_frames")]
+    pub max_animation_frames: u32,
+    /// Maximum declared pixel count for /compress/raw bodies (0 disables the check)
+    #[serde(default = "default_max_raw_pixels")]
+    pub max_raw_pixels: u64,
+    /// Upload types rejected with 415 when detected by magic bytes, e.g. ["application/pdf"]; see `magic::MAGIC_TYPES`
+    #[serde(default)]
+    pub denied_types: Vec<String>,
+    /// API keys accepted via `X-Api-Key` or `Authorization: Bearer`; requests without a valid key are anonymous
+    #[serde(default)]
+    pub api_keys: Vec<String>,
+    /// Highest quality anonymous requests may use (0 disables the cap)
+    #[serde(default)]
+    pub anonymous_max_quality: u8,
+    /// Upload size limit for anonymous requests in MB (0 uses max_file_size_mb)
+    #[serde(default)]
+    pub anonymous_max_file_size_mb: usize,
+}
+
+fn default_cors_endpoints() -> Vec<String> {
+    crate::cors::default_endpoints()
+}
+
+fn default_file_field_names() -> Vec<String> {
+    vec!["file".to_string()]
+}
+
+fn default_max_text_field_bytes() -> usize {
+    64 * 1024
+}
+
+fn default_zip_name_collision() -> String {
+    "counter".to_string()
+}
+
+fn default_max_image_dimension() -> u32 {
+    30_000
+}
+
+fn default_max_image_pixels() -> u64 {
+    100_000_000
+}
+
+fn default_max_animation_frames() -> u32 {
+    1000
+}
+
+fn default_max_raw_pixels() -> u64 {
+    25_000_000
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -25,6 +109,278 @@ pub struct CompressionConfig {
     pub enable_cache: bool,
     pub cache_ttl_minutes: u32,
     pub max_concurrent_jobs: usize,
+    /// How long a request waits for a free compression slot before responding 503 (0 rejects immediately)
+    #[serde(default = "default_queue_wait_timeout_ms")]
+    pub queue_wait_timeout_ms: u64,
+    /// Default dithering for indexed PNG output: "auto", "none", "full" or a level between 0.0 and 1.0
+    #[serde(default = "default_dither")]
+    pub dither: String,
+    /// Inputs smaller than this many bytes are returned unchanged without compression (0 disables)
+    #[serde(default)]
+    pub skip_below_bytes: usize,
+    /// JPEG quality added when both output dimensions are at most `thumbnail_max_dimension` (0 disables)
+    #[serde(default = "default_thumbnail_quality_boost")]
+    pub thumbnail_quality_boost: u8,
+    #[serde(default = "default_thumbnail_max_dimension")]
+    pub thumbnail_max_dimension: u32,
+    /// Default output color space: "original" or "srgb" (convert using the source ICC profile)
+    #[serde(default = "default_color_space")]
+    pub color_space: String,
+    /// Maximum time spent decoding an input before responding 504, separate from encoding (0 disables)
+    #[serde(default = "default_decode_timeout_secs")]
+    pub decode_timeout_secs: u64,
+    /// Maximum time spent encoding before responding 504, for algorithms without an
+    /// `algorithm_timeout_secs` entry (0 disables)
+    #[serde(default)]
+    pub encode_timeout_secs: u64,
+    /// Encode time limit per algorithm, e.g. { mozjpeg = 10, png-quantized = 30 }, so slow encoders
+    /// get room without letting fast ones run away (0 disables the limit for that algorithm)
+    #[serde(default)]
+    pub algorithm_timeout_secs: HashMap<String, u64>,
+    /// Start decoding JPEG/PNG uploads while they are still arriving when the request's
+    /// Content-Length is at least this many bytes (0 disables)
+    #[serde(default)]
+    pub streaming_decode_min_bytes: u64,
+    /// Encode JPEG output with the faster jpeg-encoder when the upload is at least this many
+    /// bytes and the request names no algorithm; mozjpeg gets slow on very large inputs (0 disables)
+    #[serde(default)]
+    pub fast_jpeg_encoder_min_bytes: usize,
+    /// Default quality per output format when the request gives none, e.g. { webp = 75 }
+    #[serde(default)]
+    pub format_quality: HashMap<String, u8>,
+    /// Default algorithm per detected input format when the request gives none, e.g. { png = "png-quantized" }
+    #[serde(default)]
+    pub default_algorithm_by_input_format: HashMap<String, String>,
+    /// Algorithms requests may not use, e.g. ["mozjpeg"]; they are also omitted from /info
+    #[serde(default)]
+    pub disabled_algorithms: Vec<String>,
+    /// Use `default_algorithm` instead of responding 400 when a request asks for a disabled algorithm
+    #[serde(default)]
+    pub disabled_algorithm_fallback: bool,
+    /// Respond 400 listing the valid algorithms instead of treating an unknown algorithm as mozjpeg
+    #[serde(default)]
+    pub strict_algorithm: bool,
+    /// Resize filter for downscaled outputs: "nearest", "triangle", "catmull-rom", "gaussian" or "lanczos3"
+    #[serde(default = "default_resize_filter")]
+    pub resize_filter_default: String,
+    /// Use the faster triangle filter when downscaling to 1/N of the width or less (0 always uses resize_filter_default)
+    #[serde(default = "default_resize_fast_downscale_factor")]
+    pub resize_fast_downscale_factor: u32,
+    /// Cap JPEG output quality at the source JPEG's estimated quality (from its DQT tables)
+    /// plus `source_quality_margin`, so re-encodes don't grow files without gaining quality
+    #[serde(default)]
+    pub clamp_to_source_quality: bool,
+    #[serde(default = "default_source_quality_margin")]
+    pub source_quality_margin: u8,
+    /// User-Agent substrings (case-insensitive) of clients that get the legacy format from
+    /// format=auto even when their Accept header lists image/webp; empty honors Accept only
+    #[serde(default)]
+    pub legacy_user_agents: Vec<String>,
+    /// Encode indexed PNG output as lossless true-color PNG instead when imagequant's achieved
+    /// quality is below this floor (0 disables)
+    #[serde(default)]
+    pub png_min_quantization_quality: u8,
+    /// Re-decode every output and log a warning when its dimensions or EXIF orientation
+    /// are not what the encoder should have produced (costs an extra decode per request)
+    #[serde(default)]
+    pub verify_output: bool,
+    /// Algorithm used to re-encode when a request with validate_output=true gets an output
+    /// that cannot be decoded (respond 500 instead when unset)
+    #[serde(default)]
+    pub validate_output_fallback_algorithm: Option<String>,
+    /// Most encodes a max_bytes request may spend searching for the highest JPEG quality that
+    /// fits, so a pathological image cannot keep the encoder busy (7 covers the full 1-100 range)
+    #[serde(default = "default_size_search_max_iterations")]
+    pub size_search_max_iterations: u32,
+    /// Stop the max_bytes search as soon as an output fits within this percentage below the
+    /// target, e.g. 5 accepts 95-100% of max_bytes (0 keeps searching until the range is exhausted)
+    #[serde(default = "default_size_search_tolerance_percent")]
+    pub size_search_tolerance_percent: f64,
+    /// Named parameter bundles selectable with `profile=<name>`, e.g. [compression.profiles.web-thumbnail]
+    #[serde(default)]
+    pub profiles: HashMap<String, CompressionProfile>,
+}
+
+/// A named set of /compress parameters; parameters given in the request override the profile's values
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CompressionProfile {
+    pub format: Option<String>,
+    pub quality: Option<u8>,
+    pub algorithm: Option<String>,
+    pub png_depth: Option<u8>,
+    pub png_quantize_speed: Option<u8>,
+    pub dither: Option<String>,
+    pub color_space: Option<String>,
+    pub quant_table: Option<String>,
+    pub jpeg_mode: Option<String>,
+    pub background: Option<String>,
+    pub target_ssim: Option<f64>,
+    pub preserve_exif: Option<bool>,
+    pub preserve_progressive: Option<bool>,
+    pub deterministic: Option<bool>,
+}
+
+impl CompressionProfile {
+    /// Values other than `format` as request parameter names and strings, in the form the handler parses them
+    pub fn params(&self) -> Vec<(&'static str, String)> {
+        let mut params = Vec::new();
+        let mut push = |name, value: Option<String>| {
+            if let Some(value) = value {
+                params.push((name, value));
+            }
+        };
+        push("quality", self.quality.map(|v| v.to_string()));
+        push("algorithm", self.algorithm.clone());
+        push("png_depth", self.png_depth.map(|v| v.to_string()));
+        push("png_quantize_speed", self.png_quantize_speed.map(|v| v.to_string()));
+        push("dither", self.dither.clone());
+        push("color_space", self.color_space.clone());
+        push("quant_table", self.quant_table.clone());
+        push("jpeg_mode", self.jpeg_mode.clone());
+        push("background", self.background.clone());
+        push("target_ssim", self.target_ssim.map(|v| v.to_string()));
+        push("preserve_exif", self.preserve_exif.map(|v| v.to_string()));
+        push("preserve_progressive", self.preserve_progressive.map(|v| v.to_string()));
+        push("deterministic", self.deterministic.map(|v| v.to_string()));
+        params
+    }
+
+    /// Check the values that can be validated without an input image
+    fn validate(&self) -> Result<(), String> {
+        use crate::compression;
+
+        if let Some(format) = self.format.as_deref() {
+            let auto = format.eq_ignore_ascii_case(crate::negotiation::AUTO_FORMAT);
+            if !auto && compression::normalize_format(format).is_none() {
+                return Err(format!("unknown format {}", format));
+            }
+        }
+        if let Some(quality) = self.quality {
+            if quality > 100 {
+                return Err(format!("quality must be between 0 and 100, got {}", quality));
+            }
+        }
+        if let Some(algorithm) = self.algorithm.as_deref() {
+            if !compression::ALGORITHMS.iter().any(|known| known.eq_ignore_ascii_case(algorithm)) {
+                return Err(format!("algorithm must be one of {:?}, got {}", compression::ALGORITHMS, algorithm));
+            }
+        }
+        if let Some(depth) = self.png_depth {
+            if !matches!(depth, 1 | 2 | 4 | 8) {
+                return Err(format!("png_depth must be 1, 2, 4 or 8, got {}", depth));
+            }
+        }
+        if let Some(speed) = self.png_quantize_speed {
+            if !(1..=10).contains(&speed) {
+                return Err(format!("png_quantize_speed must be between 1 and 10, got {}", speed));
+            }
+        }
+        if let Some(dither) = self.dither.as_deref() {
+            if compression::Dither::from_name(dither).is_none() {
+                return Err(format!("invalid dither {}", dither));
+            }
+        }
+        if let Some(color_space) = self.color_space.as_deref() {
+            if crate::color::OutputColorSpace::from_name(color_space).is_none() {
+                return Err(format!("color_space must be one of {:?}, got {}", crate::color::OutputColorSpace::NAMES, color_space));
+            }
+        }
+        if let Some(quant_table) = self.quant_table.as_deref() {
+            if compression::JpegQuantTable::from_name(quant_table).is_none() {
+                return Err(format!("quant_table must be one of {:?}, got {}", compression::JpegQuantTable::NAMES, quant_table));
+            }
+        }
+        if let Some(jpeg_mode) = self.jpeg_mode.as_deref() {
+            if compression::JpegMode::from_name(jpeg_mode).is_none() {
+                return Err(format!("jpeg_mode must be one of {:?}, got {}", compression::JpegMode::NAMES, jpeg_mode));
+            }
+        }
+        if let Some(target) = self.target_ssim {
+            if !(target > 0.0 && target < 1.0) {
+                return Err(format!("target_ssim must be between 0 and 1 (exclusive), got {}", target));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CompressionConfig {
+    /// Configured default quality for an output format ("jpg" is treated as "jpeg")
+    pub fn quality_for_format(&self, format: &str) -> Option<u8> {
+        self.format_quality.get(normalize_format(format).as_str()).copied()
+    }
+
+    /// Whether `algorithm` is listed in `disabled_algorithms` (case-insensitive)
+    pub fn is_algorithm_disabled(&self, algorithm: &str) -> bool {
+        self.disabled_algorithms.iter().any(|disabled| disabled.eq_ignore_ascii_case(algorithm))
+    }
+
+    /// Encode time limit in seconds for `algorithm` (case-insensitive), falling back to `encode_timeout_secs`; 0 means no limit
+    pub fn encode_timeout_secs_for(&self, algorithm: &str) -> u64 {
+        self.algorithm_timeout_secs
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(algorithm))
+            .map_or(self.encode_timeout_secs, |(_, secs)| *secs)
+    }
+
+    /// Default algorithm for an input format, falling back to `default_algorithm` when none is configured
+    pub fn algorithm_for_input_format(&self, format: &str) -> &str {
+        self.default_algorithm_by_input_format
+            .get(normalize_format(format).as_str())
+            .unwrap_or(&self.default_algorithm)
+    }
+}
+
+fn normalize_format(format: &str) -> String {
+    match crate::compression::normalize_format(format) {
+        Some(format) => format.name().to_string(),
+        None => format.to_lowercase(),
+    }
+}
+
+fn default_queue_wait_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_decode_timeout_secs() -> u64 {
+    10
+}
+
+fn default_color_space() -> String {
+    "original".to_string()
+}
+
+fn default_thumbnail_quality_boost() -> u8 {
+    10
+}
+
+fn default_thumbnail_max_dimension() -> u32 {
+    256
+}
+
+fn default_resize_filter() -> String {
+    "lanczos3".to_string()
+}
+
+fn default_source_quality_margin() -> u8 {
+    5
+}
+
+fn default_resize_fast_downscale_factor() -> u32 {
+    4
+}
+
+fn default_size_search_max_iterations() -> u32 {
+    7
+}
+
+fn default_size_search_tolerance_percent() -> f64 {
+    5.0
+}
+
+fn default_dither() -> String {
+    "full".to_string()
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -32,18 +388,80 @@ pub struct LoggingConfig {
     pub level: String,
     pub enable_request_logging: bool,
     pub log_compression_stats: bool,
+    /// EXIF tags to log for each request (e.g. "Make", "Model", "LensModel")
+    #[serde(default)]
+    pub log_exif_tags: Vec<String>,
+    /// Log output format: "text" (human-readable) or "json" (one object per line)
+    #[serde(default = "default_log_format")]
+    pub format: String,
+    /// Write a tracing span per request and per decode/transform/encode phase to stdout when it
+    /// closes, with its busy and idle time; uses `format` for text or JSON output
+    #[serde(default = "default_trace_spans")]
+    pub trace_spans: bool,
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Self {
-            server: ServerConfig::default(),
-            compression: CompressionConfig::default(),
-            logging: LoggingConfig::default(),
-        }
+fn default_log_format() -> String {
+    "text".to_string()
+}
+
+fn default_trace_spans() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StorageConfig {
+    /// Storage backend: "none", "filesystem" or "s3"
+    pub backend: String,
+    pub path: String,
+    pub s3_bucket: String,
+    pub s3_region: String,
+    pub s3_endpoint: Option<String>,
+    pub s3_prefix: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StaticFilesConfig {
+    /// Directory served at `/` for the bundled web UI
+    pub dir: String,
+    /// Cache-Control max-age for static assets; HTML is always revalidated (0 sends no-cache for everything)
+    pub cache_max_age_secs: u64,
+    /// Cache assets with a content hash in their file name (e.g. app.3f2a9c1b.js) for a year as immutable
+    pub immutable_hashed_assets: bool,
+    /// Send ETag and answer If-None-Match with 304
+    pub use_etag: bool,
+    /// Send Last-Modified and answer If-Modified-Since with 304
+    pub use_last_modified: bool,
+    /// Response for `/` when the static directory is missing: "json" describes the service,
+    /// "redirect" redirects to `root_redirect`, "not_found" returns 404
+    pub root_response: String,
+    /// Redirect target used when `root_response = "redirect"`
+    pub root_redirect: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoadSheddingConfig {
+    /// Degrade /compress output while the compression queue is busy instead of letting latency grow
+    pub enabled: bool,
+    /// Jobs already running (out of `compression.max_concurrent_jobs`) at which a request is degraded
+    pub min_active_jobs: usize,
+    /// Quality cap for degraded requests
+    pub max_quality: u8,
+    /// Encode degraded JPEG output with the faster jpeg-encoder instead of mozjpeg
+    pub fast_jpeg_encoder: bool,
+}
+
+impl LoadSheddingConfig {
+    /// Whether a request arriving while `active_jobs` jobs are running gets degraded
+    pub fn engaged(&self, active_jobs: usize) -> bool {
+        self.enabled && active_jobs >= self.min_active_jobs
     }
 }
 
+/// Accepted values for `static_files.root_response`
+pub const ROOT_RESPONSES: [&str; 3] = ["json", "redirect", "not_found"];
+
 impl Default for ServerConfig {
     fn default() -> Self {
         Self {
@@ -52,6 +470,20 @@ impl Default for ServerConfig {
             max_file_size_mb: 100,
             worker_threads: None, // Use system default
             enable_cors: true,
+            cors_endpoints: default_cors_endpoints(),
+            file_field_names: default_file_field_names(),
+            max_text_field_bytes: default_max_text_field_bytes(),
+            proxy_upload_dir: None,
+            zip_temp_dir: None,
+            zip_name_collision: default_zip_name_collision(),
+            max_image_dimension: default_max_image_dimension(),
+            max_image_pixels: default_max_image_pixels(),
+            max_animation_frames: default_max_animation_frames(),
+            max_raw_pixels: default_max_raw_pixels(),
+            denied_types: Vec::new(),
+            api_keys: Vec::new(),
+            anonymous_max_quality: 0,
+            anonymous_max_file_size_mb: 0,
         }
     }
 }
@@ -64,6 +496,33 @@ impl Default for CompressionConfig {
             enable_cache: false,
             cache_ttl_minutes: 60,
             max_concurrent_jobs: 10,
+            queue_wait_timeout_ms: default_queue_wait_timeout_ms(),
+            dither: default_dither(),
+            skip_below_bytes: 0,
+            thumbnail_quality_boost: default_thumbnail_quality_boost(),
+            thumbnail_max_dimension: default_thumbnail_max_dimension(),
+            color_space: default_color_space(),
+            decode_timeout_secs: default_decode_timeout_secs(),
+            encode_timeout_secs: 0,
+            algorithm_timeout_secs: HashMap::new(),
+            streaming_decode_min_bytes: 0,
+            fast_jpeg_encoder_min_bytes: 0,
+            format_quality: HashMap::new(),
+            default_algorithm_by_input_format: HashMap::new(),
+            disabled_algorithms: Vec::new(),
+            disabled_algorithm_fallback: false,
+            strict_algorithm: false,
+            resize_filter_default: default_resize_filter(),
+            resize_fast_downscale_factor: default_resize_fast_downscale_factor(),
+            clamp_to_source_quality: false,
+            source_quality_margin: default_source_quality_margin(),
+            legacy_user_agents: Vec::new(),
+            png_min_quantization_quality: 0,
+            verify_output: false,
+            validate_output_fallback_algorithm: None,
+            size_search_max_iterations: default_size_search_max_iterations(),
+            size_search_tolerance_percent: default_size_search_tolerance_percent(),
+            profiles: HashMap::new(),
         }
     }
 }
@@ -74,6 +533,47 @@ impl Default for LoggingConfig {
             level: "info".to_string(),
             enable_request_logging: true,
             log_compression_stats: true,
+            log_exif_tags: Vec::new(),
+            format: default_log_format(),
+            trace_spans: default_trace_spans(),
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: "none".to_string(),
+            path: "./storage".to_string(),
+            s3_bucket: String::new(),
+            s3_region: "us-east-1".to_string(),
+            s3_endpoint: None,
+            s3_prefix: "compressed".to_string(),
+        }
+    }
+}
+
+impl Default for StaticFilesConfig {
+    fn default() -> Self {
+        Self {
+            dir: "./static".to_string(),
+            cache_max_age_secs: 3600,
+            immutable_hashed_assets: true,
+            use_etag: true,
+            use_last_modified: true,
+            root_response: "json".to_string(),
+            root_redirect: "/info".to_string(),
+        }
+    }
+}
+
+impl Default for LoadSheddingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_active_jobs: 8,
+            max_quality: 70,
+            fast_jpeg_encoder: true,
         }
     }
 }
@@ -134,7 +634,7 @@ impl Config {
 
         if let Ok(quality) = std::env::var("IMG_SERVER_DEFAULT_QUALITY") {
             if let Ok(q) = quality.parse::<u8>() {
-                if q >= 1 && q <= 100 {
+                if (1..=100).contains(&q) {
                     self.compression.default_quality = q;
                 }
             }
@@ -144,35 +644,236 @@ impl Config {
             self.compression.default_algorithm = algorithm;
         }
 
+        if let Ok(max_pixels) = std::env::var("IMG_SERVER_MAX_IMAGE_PIXELS") {
+            if let Ok(pixels) = max_pixels.parse::<u64>() {
+                self.server.max_image_pixels = pixels;
+            }
+        }
+
+        if let Ok(max_pixels) = std::env::var("IMG_SERVER_MAX_RAW_PIXELS") {
+            if let Ok(pixels) = max_pixels.parse::<u64>() {
+                self.server.max_raw_pixels = pixels;
+            }
+        }
+
+        if let Ok(keys) = std::env::var("IMG_SERVER_API_KEYS") {
+            self.server.api_keys = keys
+                .split(',')
+                .map(str::trim)
+                .filter(|key| !key.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+
+        if let Ok(algorithms) = std::env::var("IMG_SERVER_DISABLED_ALGORITHMS") {
+            self.compression.disabled_algorithms = algorithms
+                .split(',')
+                .map(str::trim)
+                .filter(|algorithm| !algorithm.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+
+        if let Ok(dir) = std::env::var("IMG_SERVER_PROXY_UPLOAD_DIR") {
+            self.server.proxy_upload_dir = Some(dir);
+        }
+
+        if let Ok(backend) = std::env::var("IMG_SERVER_STORAGE_BACKEND") {
+            self.storage.backend = backend;
+        }
+
+        if let Ok(path) = std::env::var("IMG_SERVER_STORAGE_PATH") {
+            self.storage.path = path;
+        }
+
+        if let Ok(format) = std::env::var("IMG_SERVER_LOG_FORMAT") {
+            self.logging.format = format;
+        }
+
         if let Ok(log_level) = std::env::var("RUST_LOG") {
             self.logging.level = log_level;
         }
     }
 
-    /// Validate configuration values
+    /// Validate configuration values, collecting every problem so a config file can be fixed in one pass
     fn validate(&self) -> Result<(), ConfigError> {
+        let mut errors = Vec::new();
+
         if self.server.port == 0 {
-            return Err(ConfigError::ValidationError("Port cannot be 0".to_string()));
+            errors.push("Port cannot be 0".to_string());
         }
 
         if self.server.max_file_size_mb == 0 {
-            return Err(ConfigError::ValidationError("Max file size cannot be 0".to_string()));
+            errors.push("Max file size cannot be 0".to_string());
         }
 
-        if !(1..=100).contains(&self.compression.default_quality) {
-            return Err(ConfigError::ValidationError(
-                "Default quality must be between 1 and 100".to_string()
+        if self.server.file_field_names.is_empty() {
+            errors.push("file_field_names must contain at least one name".to_string());
+        }
+
+        if self.server.max_text_field_bytes == 0 {
+            errors.push("max_text_field_bytes cannot be 0".to_string());
+        }
+
+        for endpoint in self.server.cors_endpoints.iter().filter(|endpoint| !endpoint.starts_with('/')) {
+            errors.push(format!("cors_endpoints entry {} must be a path starting with /", endpoint));
+        }
+
+        for unknown in self.server.denied_types.iter().filter(|mime_type| {
+            !crate::magic::MAGIC_TYPES.iter().any(|known| known.eq_ignore_ascii_case(mime_type))
+        }) {
+            errors.push(format!(
+                "Unknown denied type {}. Must be one of: {:?}", unknown, crate::magic::MAGIC_TYPES
             ));
         }
 
+        if !(1..=100).contains(&self.compression.default_quality) {
+            errors.push("Default quality must be between 1 and 100".to_string());
+        }
+
         let valid_algorithms = ["mozjpeg", "jpeg-encoder", "png-quantized"];
         if !valid_algorithms.contains(&self.compression.default_algorithm.as_str()) {
-            return Err(ConfigError::ValidationError(
-                format!("Invalid default algorithm. Must be one of: {:?}", valid_algorithms)
+            errors.push(format!("Invalid default algorithm. Must be one of: {:?}", valid_algorithms));
+        }
+
+        // Falling back needs an enabled default algorithm
+        if self.compression.is_algorithm_disabled(&self.compression.default_algorithm) {
+            errors.push(format!(
+                "default_algorithm {} cannot be listed in disabled_algorithms",
+                self.compression.default_algorithm
             ));
         }
 
-        Ok(())
+        for (format, algorithm) in &self.compression.default_algorithm_by_input_format {
+            if self.compression.is_algorithm_disabled(algorithm) {
+                errors.push(format!(
+                    "Default algorithm {} for input format {} is listed in disabled_algorithms",
+                    algorithm, format
+                ));
+            }
+            if !valid_algorithms.contains(&algorithm.as_str()) {
+                errors.push(format!(
+                    "Invalid default algorithm {} for input format {}. Must be one of: {:?}",
+                    algorithm, format, valid_algorithms
+                ));
+            }
+        }
+
+        if let Some(algorithm) = self.compression.validate_output_fallback_algorithm.as_deref() {
+            let known = crate::compression::ALGORITHMS.iter().any(|known| known.eq_ignore_ascii_case(algorithm));
+            if !known || algorithm.eq_ignore_ascii_case("none") || self.compression.is_algorithm_disabled(algorithm) {
+                errors.push(format!(
+                    "validate_output_fallback_algorithm must be an enabled encoding algorithm, got {}", algorithm
+                ));
+            }
+        }
+
+        for algorithm in self.compression.algorithm_timeout_secs
+            .keys()
+            .filter(|algorithm| !crate::compression::ALGORITHMS.iter().any(|known| known.eq_ignore_ascii_case(algorithm)))
+        {
+            errors.push(format!(
+                "Unknown algorithm {} in algorithm_timeout_secs. Must be one of: {:?}",
+                algorithm, crate::compression::ALGORITHMS
+            ));
+        }
+
+        if !(1..=crate::quality::MAX_SIZE_SEARCH_ITERATIONS).contains(&self.compression.size_search_max_iterations) {
+            errors.push(format!(
+                "size_search_max_iterations must be between 1 and {}",
+                crate::quality::MAX_SIZE_SEARCH_ITERATIONS
+            ));
+        }
+
+        if !(0.0..=50.0).contains(&self.compression.size_search_tolerance_percent) {
+            errors.push("size_search_tolerance_percent must be between 0 and 50".to_string());
+        }
+
+        if self.compression.max_concurrent_jobs == 0 {
+            errors.push("max_concurrent_jobs must be at least 1".to_string());
+        }
+
+        if self.server.anonymous_max_quality > 100 {
+            errors.push("anonymous_max_quality must be between 0 and 100".to_string());
+        }
+
+        if self.compression.png_min_quantization_quality > 100 {
+            errors.push("png_min_quantization_quality must be between 0 and 100".to_string());
+        }
+
+        // An empty pattern would match every User-Agent and disable format=auto entirely
+        if self.compression.legacy_user_agents.iter().any(|pattern| pattern.trim().is_empty()) {
+            errors.push("legacy_user_agents cannot contain empty patterns".to_string());
+        }
+
+        for (format, _) in self.compression.format_quality.iter().filter(|(_, q)| !(1..=100).contains(*q)) {
+            errors.push(format!("format_quality for {} must be between 1 and 100", format));
+        }
+
+        if !ROOT_RESPONSES.contains(&self.static_files.root_response.as_str()) {
+            errors.push(format!("Invalid root_response. Must be one of: {:?}", ROOT_RESPONSES));
+        }
+
+        if self.compression.fast_jpeg_encoder_min_bytes > 0 && self.compression.is_algorithm_disabled("jpeg-encoder") {
+            errors.push("fast_jpeg_encoder_min_bytes requires jpeg-encoder, which is disabled".to_string());
+        }
+
+        if self.load_shedding.enabled {
+            if self.load_shedding.min_active_jobs == 0 {
+                errors.push("load_shedding.min_active_jobs must be at least 1".to_string());
+            }
+            if !(1..=100).contains(&self.load_shedding.max_quality) {
+                errors.push("load_shedding.max_quality must be between 1 and 100".to_string());
+            }
+            if self.load_shedding.fast_jpeg_encoder && self.compression.is_algorithm_disabled("jpeg-encoder") {
+                errors.push("load_shedding.fast_jpeg_encoder requires jpeg-encoder, which is disabled".to_string());
+            }
+        }
+
+        if crate::compression::Dither::from_name(&self.compression.dither).is_none() {
+            errors.push("dither must be \"auto\", \"none\", \"full\" or a level between 0.0 and 1.0".to_string());
+        }
+
+        if crate::archive::ZipNaming::from_name(&self.server.zip_name_collision).is_none() {
+            errors.push(format!(
+                "Invalid zip_name_collision. Must be one of: {:?}", crate::archive::ZipNaming::NAMES
+            ));
+        }
+
+        if crate::compression::ResizeFilter::from_name(&self.compression.resize_filter_default).is_none() {
+            errors.push(format!(
+                "Invalid resize_filter_default. Must be one of: {:?}", crate::compression::ResizeFilter::NAMES
+            ));
+        }
+
+        if crate::color::OutputColorSpace::from_name(&self.compression.color_space).is_none() {
+            errors.push(format!("Invalid color space. Must be one of: {:?}", crate::color::OutputColorSpace::NAMES));
+        }
+
+        for (name, profile) in &self.compression.profiles {
+            if let Err(e) = profile.validate() {
+                errors.push(format!("Invalid compression profile {}: {}", name, e));
+            }
+        }
+
+        if !crate::logging::LOG_FORMATS.contains(&self.logging.format.as_str()) {
+            errors.push(format!("Invalid log format. Must be one of: {:?}", crate::logging::LOG_FORMATS));
+        }
+
+        let valid_backends = ["none", "filesystem", "s3"];
+        if !valid_backends.contains(&self.storage.backend.as_str()) {
+            errors.push(format!("Invalid storage backend. Must be one of: {:?}", valid_backends));
+        }
+
+        if self.storage.backend == "s3" && self.storage.s3_bucket.is_empty() {
+            errors.push("S3 storage requires s3_bucket to be set".to_string());
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::ValidationError(errors))
+        }
     }
 
     /// Generate a sample configuration file
@@ -196,6 +897,22 @@ impl Config {
     pub fn max_file_size_bytes(&self) -> usize {
         self.server.max_file_size_mb * 1024 * 1024
     }
+
+    /// Directory for spooled /compress/zip output
+    pub fn zip_temp_dir(&self) -> std::path::PathBuf {
+        match &self.server.zip_temp_dir {
+            Some(dir) => std::path::PathBuf::from(dir),
+            None => std::env::temp_dir(),
+        }
+    }
+
+    /// Upload size limit for anonymous requests, never above the normal limit
+    pub fn anonymous_max_file_size_bytes(&self) -> usize {
+        match self.server.anonymous_max_file_size_mb {
+            0 => self.max_file_size_bytes(),
+            mb => (mb * 1024 * 1024).min(self.max_file_size_bytes()),
+        }
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -206,8 +923,9 @@ pub enum ConfigError {
     #[error("Parse error: {0}")]
     ParseError(String),
     
-    #[error("Validation error: {0}")]
-    ValidationError(String),
+    /// Every problem found in the configuration, not just the first
+    #[error("Validation error: {}", .0.join("; "))]
+    ValidationError(Vec<String>),
     
     #[error("Serialization error: {0}")]
     SerializeError(String),
@@ -224,6 +942,9 @@ mod tests {
         assert_eq!(config.server.host, "0.0.0.0");
         assert_eq!(config.server.port, 3030);
         assert_eq!(config.compression.default_quality, 80);
+        assert_eq!(config.compression.decode_timeout_secs, 10);
+        assert_eq!(config.server.file_field_names, vec!["file".to_string()]);
+        assert!(config.logging.trace_spans);
     }
 
     #[test]
@@ -244,6 +965,124 @@ mod tests {
         config.compression.default_quality = 80;
         config.compression.default_algorithm = "invalid".to_string();
         assert!(config.validate().is_err());
+
+        // Empty file field names should fail
+        config.compression.default_algorithm = "mozjpeg".to_string();
+        config.server.file_field_names.clear();
+        assert!(config.validate().is_err());
+        config.server.file_field_names = vec!["file".to_string()];
+
+        // Text fields need a non-zero size limit
+        config.server.max_text_field_bytes = 0;
+        assert!(config.validate().is_err());
+        config.server.max_text_field_bytes = 65536;
+
+        // CORS endpoints are matched against request paths
+        config.server.cors_endpoints.push("metrics".to_string());
+        assert!(config.validate().is_err());
+        config.server.cors_endpoints = crate::cors::default_endpoints();
+
+        // Profiles are validated like request parameters
+        config.compression.profiles.insert(
+            "thumb".to_string(),
+            CompressionProfile { format: Some("jpg".to_string()), quality: Some(70), ..Default::default() },
+        );
+        assert!(config.validate().is_ok());
+        config.compression.profiles.get_mut("thumb").unwrap().jpeg_mode = Some("interlaced".to_string());
+        assert!(config.validate().is_err());
+        config.compression.profiles.clear();
+
+        // Denied types must be detectable
+        config.server.denied_types = vec!["Application/PDF".to_string()];
+        assert!(config.validate().is_ok());
+        config.server.denied_types = vec!["application/msword".to_string()];
+        assert!(config.validate().is_err());
+        config.server.denied_types.clear();
+
+        // Invalid dither should fail
+        config.compression.dither = "noisy".to_string();
+        assert!(config.validate().is_err());
+        config.compression.dither = "auto".to_string();
+        assert!(config.validate().is_ok());
+
+        // Unknown resize filter should fail
+        config.compression.resize_filter_default = "bicubic".to_string();
+        assert!(config.validate().is_err());
+        config.compression.resize_filter_default = "Triangle".to_string();
+        assert!(config.validate().is_ok());
+
+        // Unknown ZIP name collision strategy should fail
+        config.server.zip_name_collision = "uuid".to_string();
+        assert!(config.validate().is_err());
+        config.server.zip_name_collision = "hash".to_string();
+        assert!(config.validate().is_ok());
+
+        // Invalid log format should fail
+        config.logging.format = "xml".to_string();
+        assert!(config.validate().is_err());
+        config.logging.format = "json".to_string();
+        assert!(config.validate().is_ok());
+
+        // Per-format quality out of range should fail
+        config.compression.format_quality.insert("webp".to_string(), 0);
+        assert!(config.validate().is_err());
+        config.compression.format_quality.insert("webp".to_string(), 75);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.compression.quality_for_format("WebP"), Some(75));
+        assert_eq!(config.compression.quality_for_format("png"), None);
+
+        // Per-input-format algorithm must be a known algorithm
+        config.compression.default_algorithm_by_input_format.insert("png".to_string(), "zopfli".to_string());
+        assert!(config.validate().is_err());
+        config.compression.default_algorithm_by_input_format.insert("png".to_string(), "png-quantized".to_string());
+        assert!(config.validate().is_ok());
+        assert_eq!(config.compression.algorithm_for_input_format("PNG"), "png-quantized");
+        assert_eq!(config.compression.algorithm_for_input_format("jpg"), "mozjpeg");
+
+        // Disabling the default algorithm leaves nothing to fall back to
+        config.compression.disabled_algorithms = vec!["MozJPEG".to_string()];
+        assert!(config.validate().is_err());
+        config.compression.disabled_algorithms = vec!["png-quantized".to_string()];
+        assert!(config.validate().is_err());
+        config.compression.default_algorithm_by_input_format.clear();
+        assert!(config.validate().is_ok());
+        assert!(config.compression.is_algorithm_disabled("PNG-Quantized"));
+        config.compression.disabled_algorithms.clear();
+
+        // Unknown root response should fail
+        config.static_files.root_response = "html".to_string();
+        assert!(config.validate().is_err());
+        config.static_files.root_response = "redirect".to_string();
+        assert!(config.validate().is_ok());
+
+        // Invalid storage backend should fail
+        config.compression.default_algorithm = "mozjpeg".to_string();
+        config.storage.backend = "ftp".to_string();
+        assert!(config.validate().is_err());
+
+        // S3 without a bucket should fail
+        config.storage.backend = "s3".to_string();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_config_validation_reports_all_errors() {
+        let mut config = Config::default();
+        config.server.port = 0;
+        config.compression.default_quality = 0;
+        config.server.cors_endpoints = vec!["metrics".to_string(), "health".to_string()];
+        config.storage.backend = "ftp".to_string();
+
+        match config.validate() {
+            Err(ConfigError::ValidationError(errors)) => {
+                assert_eq!(errors.len(), 5, "{:?}", errors);
+                let message = ConfigError::ValidationError(errors).to_string();
+                for expected in ["Port cannot be 0", "Default quality", "metrics", "health", "storage backend"] {
+                    assert!(message.contains(expected), "{} missing from {}", expected, message);
+                }
+            }
+            other => panic!("expected validation errors, got {:?}", other),
+        }
     }
 
     #[test]
@@ -276,4 +1115,95 @@ mod tests {
         let config = Config::default();
         assert_eq!(config.max_file_size_bytes(), 100 * 1024 * 1024);
     }
+
+    #[test]
+    fn test_anonymous_max_file_size_bytes() {
+        let mut config = Config::default();
+        assert_eq!(config.anonymous_max_file_size_bytes(), config.max_file_size_bytes());
+
+        config.server.anonymous_max_file_size_mb = 5;
+        assert_eq!(config.anonymous_max_file_size_bytes(), 5 * 1024 * 1024);
+
+        // 匿名上限不会超过常规上限
+        config.server.anonymous_max_file_size_mb = 500;
+        assert_eq!(config.anonymous_max_file_size_bytes(), config.max_file_size_bytes());
+    }
+
+    #[test]
+    fn test_encode_timeout_secs_for() {
+        let mut config = Config::default();
+        assert_eq!(config.compression.encode_timeout_secs_for("mozjpeg"), 0);
+
+        config.compression.encode_timeout_secs = 5;
+        config.compression.algorithm_timeout_secs.insert("png-quantized".to_string(), 30);
+        assert_eq!(config.compression.encode_timeout_secs_for("mozjpeg"), 5);
+        assert_eq!(config.compression.encode_timeout_secs_for("PNG-Quantized"), 30);
+        assert!(config.validate().is_ok());
+
+        config.compression.algorithm_timeout_secs.insert("avif".to_string(), 60);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_output_fallback_algorithm() {
+        let mut config = Config::default();
+        config.compression.validate_output_fallback_algorithm = Some("jpeg-encoder".to_string());
+        assert!(config.validate().is_ok());
+
+        config.compression.disabled_algorithms = vec!["jpeg-encoder".to_string()];
+        assert!(config.validate().is_err());
+
+        for algorithm in ["none", "avif"] {
+            config.compression.validate_output_fallback_algorithm = Some(algorithm.to_string());
+            assert!(config.validate().is_err(), "{}", algorithm);
+        }
+    }
+
+    #[test]
+    fn test_load_shedding() {
+        let mut config = Config::default();
+        assert!(!config.load_shedding.engaged(100));
+
+        config.load_shedding.enabled = true;
+        config.load_shedding.min_active_jobs = 4;
+        assert!(!config.load_shedding.engaged(3));
+        assert!(config.load_shedding.engaged(4));
+        assert!(config.validate().is_ok());
+
+        config.compression.disabled_algorithms = vec!["jpeg-encoder".to_string()];
+        assert!(config.validate().is_err());
+        config.load_shedding.fast_jpeg_encoder = false;
+        assert!(config.validate().is_ok());
+
+        config.load_shedding.min_active_jobs = 0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_size_search_bounds() {
+        let mut config = Config::default();
+        assert_eq!(config.compression.size_search_max_iterations, 7);
+        assert!(config.validate().is_ok());
+
+        config.compression.size_search_max_iterations = 0;
+        config.compression.size_search_tolerance_percent = -1.0;
+        match config.validate() {
+            Err(ConfigError::ValidationError(errors)) => assert_eq!(errors.len(), 2, "{:?}", errors),
+            other => panic!("expected validation errors, got {:?}", other),
+        }
+
+        config.compression.size_search_max_iterations = 3;
+        config.compression.size_search_tolerance_percent = 0.0;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_fast_jpeg_encoder_min_bytes() {
+        let mut config = Config::default();
+        config.compression.fast_jpeg_encoder_min_bytes = 10 * 1024 * 1024;
+        assert!(config.validate().is_ok());
+
+        config.compression.disabled_algorithms = vec!["jpeg-encoder".to_string()];
+        assert!(config.validate().is_err());
+    }
 }
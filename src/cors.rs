@@ -0,0 +1,77 @@
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header::{
+    HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+};
+use std::future::Future;
+
+use crate::config::ServerConfig;
+use crate::handlers::{CORS_ALLOW_HEADERS, CORS_ALLOW_METHODS};
+
+/// 需要处理浏览器预检请求的上传接口
+pub const PREFLIGHT_ENDPOINTS: [&str; 8] = [
+    "/compress",
+    "/compress/raw",
+    "/compress/zip",
+    "/pyramid",
+    "/analyze",
+    "/estimate-quality",
+    "/compare",
+    "/sprite",
+];
+
+/// 默认允许跨域访问的接口：上传接口和只读的服务信息，不含 `/metrics` 等运维接口
+pub fn default_endpoints() -> Vec<String> {
+    PREFLIGHT_ENDPOINTS
+        .iter()
+        .chain(&["/health", "/info"])
+        .map(|path| path.to_string())
+        .collect()
+}
+
+/// `path` 是否返回 CORS 响应头：`enable_cors` 关闭时所有接口都不返回
+pub fn allows(path: &str, config: &ServerConfig) -> bool {
+    config.enable_cors && config.cors_endpoints.iter().any(|endpoint| endpoint == path)
+}
+
+/// 为允许跨域的接口加上 CORS 响应头，已有的（如预检响应）保持不变
+pub async fn with_cors_headers<B, E>(
+    enabled: bool,
+    fut: impl Future<Output = Result<ServiceResponse<B>, E>>,
+) -> Result<ServiceResponse<B>, E> {
+    let mut response = fut.await?;
+    if enabled {
+        let headers = response.headers_mut();
+        for (name, value) in [
+            (ACCESS_CONTROL_ALLOW_ORIGIN, "*"),
+            (ACCESS_CONTROL_ALLOW_METHODS, CORS_ALLOW_METHODS),
+            (ACCESS_CONTROL_ALLOW_HEADERS, CORS_ALLOW_HEADERS),
+        ] {
+            if !headers.contains_key(&name) {
+                headers.insert(name, HeaderValue::from_static(value));
+            }
+        }
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_allows() {
+        let mut config = Config::default().server;
+        assert!(allows("/compress", &config));
+        assert!(allows("/info", &config));
+        assert!(!allows("/metrics", &config));
+        assert!(!allows("/compress/", &config));
+
+        config.cors_endpoints = vec!["/metrics".to_string()];
+        assert!(allows("/metrics", &config));
+        assert!(!allows("/compress", &config));
+
+        config.enable_cors = false;
+        assert!(!allows("/metrics", &config));
+    }
+}
@@ -20,8 +20,68 @@ pub enum ImageServerError {
     #[error("Invalid parameters: {0}")]
     InvalidParameters(String),
     
+    #[error("Storage error: {0}")]
+    StorageError(String),
+    
+    #[error("Access denied: {0}")]
+    AccessDenied(String),
+    
+    #[error("Request deadline exceeded")]
+    DeadlineExceeded,
+    
+    #[error("Server busy: no compression slot became free within {waited_ms}ms")]
+    Overloaded { waited_ms: u64 },
+    
+    #[error("Image decoding exceeded {secs}s")]
+    DecodeTimeout { secs: u64 },
+    
+    #[error("Encoding with {algorithm} exceeded {secs}s")]
+    EncodeTimeout { algorithm: String, secs: u64 },
+    
+    #[error("Input is animated with {frames} frames, but reject_animated requires a still image")]
+    AnimatedInput { frames: usize },
+    
+    #[error("Animation with {frames} frames of {width}x{height} exceeds {limit_name} of {limit}")]
+    AnimationTooLarge { frames: usize, width: u32, height: u32, limit_name: &'static str, limit: u64 },
+    
+    #[error("Upload type {mime_type} is not allowed")]
+    DeniedType { mime_type: &'static str },
+    
+    #[error("Output of {algorithm} ({format}) could not be decoded: {reason}")]
+    OutputValidationFailed { algorithm: String, format: String, reason: String },
+    
+    #[error("Upload interrupted: {0}")]
+    UploadInterrupted(String),
+    
     #[error("File too large: maximum size is {max_size} bytes")]
     FileTooLarge { max_size: usize },
+    
+    #[error("Form field \"{field}\" too large: maximum size is {max_size} bytes")]
+    FieldTooLarge { field: String, max_size: usize },
+    
+    #[error("{}", dimensions_message(*.width, *.height, *.axis, .limit_name, *.limit))]
+    DimensionsTooLarge {
+        width: u32,
+        height: u32,
+        /// 超出单轴限制的方向（"width" 或 "height"），总像素超限时为 `None`
+        axis: Option<&'static str>,
+        limit_name: &'static str,
+        limit: u64,
+    },
+}
+
+// 单轴超限时指明是宽还是高，便于区分全景图/横幅与像素炸弹
+fn dimensions_message(width: u32, height: u32, axis: Option<&str>, limit_name: &str, limit: u64) -> String {
+    match axis {
+        Some(axis) => {
+            let value = if axis == "width" { width } else { height };
+            format!(
+                "Image {} {} exceeds {} of {} (image is {}x{})",
+                axis, value, limit_name, limit, width, height
+            )
+        }
+        None => format!("Image dimensions {}x{} exceed {} of {}", width, height, limit_name, limit),
+    }
 }
 
 impl actix_web::ResponseError for ImageServerError {
@@ -41,6 +101,31 @@ impl actix_web::ResponseError for ImageServerError {
                     "message": self.to_string()
                 }))
             }
+            ImageServerError::AnimatedInput { frames } => {
+                HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": "animated_input",
+                    "message": self.to_string(),
+                    "frames": frames
+                }))
+            }
+            ImageServerError::AnimationTooLarge { frames, width, height, limit_name, limit } => {
+                HttpResponse::PayloadTooLarge().json(serde_json::json!({
+                    "error": "animation_too_large",
+                    "message": self.to_string(),
+                    "frames": frames,
+                    "width": width,
+                    "height": height,
+                    "limit_name": limit_name,
+                    "limit": limit
+                }))
+            }
+            ImageServerError::DeniedType { mime_type } => {
+                HttpResponse::UnsupportedMediaType().json(serde_json::json!({
+                    "error": "denied_type",
+                    "message": self.to_string(),
+                    "type": mime_type
+                }))
+            }
             ImageServerError::InvalidParameters(_) => {
                 HttpResponse::BadRequest().json(serde_json::json!({
                     "error": "invalid_parameters",
@@ -54,6 +139,77 @@ impl actix_web::ResponseError for ImageServerError {
                     "max_size_bytes": max_size
                 }))
             }
+            ImageServerError::OutputValidationFailed { algorithm, format, reason } => {
+                HttpResponse::InternalServerError().json(serde_json::json!({
+                    "error": "output_validation_failed",
+                    "message": self.to_string(),
+                    "algorithm": algorithm,
+                    "format": format,
+                    "reason": reason
+                }))
+            }
+            ImageServerError::UploadInterrupted(_) => {
+                HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": "upload_interrupted",
+                    "message": self.to_string(),
+                    "retryable": true
+                }))
+            }
+            ImageServerError::FieldTooLarge { field, max_size } => {
+                HttpResponse::PayloadTooLarge().json(serde_json::json!({
+                    "error": "field_too_large",
+                    "message": self.to_string(),
+                    "field": field,
+                    "max_size_bytes": max_size
+                }))
+            }
+            ImageServerError::DimensionsTooLarge { width, height, axis, limit_name, limit } => {
+                HttpResponse::PayloadTooLarge().json(serde_json::json!({
+                    "error": "dimensions_too_large",
+                    "message": self.to_string(),
+                    "width": width,
+                    "height": height,
+                    "axis": axis,
+                    "limit_name": limit_name,
+                    "limit": limit
+                }))
+            }
+            ImageServerError::AccessDenied(_) => {
+                HttpResponse::Forbidden().json(serde_json::json!({
+                    "error": "access_denied",
+                    "message": self.to_string()
+                }))
+            }
+            ImageServerError::DecodeTimeout { secs } => {
+                HttpResponse::GatewayTimeout().json(serde_json::json!({
+                    "error": "decode_timeout",
+                    "message": self.to_string(),
+                    "decode_timeout_secs": secs
+                }))
+            }
+            ImageServerError::EncodeTimeout { algorithm, secs } => {
+                HttpResponse::GatewayTimeout().json(serde_json::json!({
+                    "error": "encode_timeout",
+                    "message": self.to_string(),
+                    "algorithm": algorithm,
+                    "encode_timeout_secs": secs
+                }))
+            }
+            ImageServerError::Overloaded { waited_ms } => {
+                HttpResponse::ServiceUnavailable()
+                    .insert_header(("Retry-After", "1"))
+                    .json(serde_json::json!({
+                        "error": "overloaded",
+                        "message": self.to_string(),
+                        "waited_ms": waited_ms
+                    }))
+            }
+            ImageServerError::DeadlineExceeded => {
+                HttpResponse::GatewayTimeout().json(serde_json::json!({
+                    "error": "deadline_exceeded",
+                    "message": self.to_string()
+                }))
+            }
             _ => {
                 HttpResponse::InternalServerError().json(serde_json::json!({
                     "error": "internal_error",
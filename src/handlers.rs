@@ -1,26 +1,73 @@
-use actix_multipart::{Field, Multipart};
-use actix_web::{web, HttpResponse, Result};
+use actix_multipart::{Field, Multipart, MultipartError};
+use actix_web::error::PayloadError;
+use actix_web::http::header::{ContentEncoding, HeaderName, HeaderValue, LOCATION};
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use base64::Engine;
 use futures::TryStreamExt;
-use log::{error, info};
+use log::{error, info, warn};
 use serde::Deserialize;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
 use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::OwnedSemaphorePermit;
 
 // Import the compression module
-use crate::compression;
+use crate::animation;
+use crate::archive;
+use crate::magic;
+use crate::color;
+use crate::compression::{self, Format};
 use crate::errors::ImageServerError;
-use crate::config::Config;
+use crate::logging;
+use crate::negotiation;
+use crate::pipeline;
+use crate::sprite;
+use crate::config::{CompressionProfile, Config};
+use crate::queue::JobQueue;
+use crate::metrics::{CompressionMetrics, SavingsStats};
+use crate::storage::{self, StorageBackend};
+use crate::streaming::{self, StreamingDecode};
 
 #[derive(Debug, Deserialize)]
 pub struct CompressionQuery {
     pub quality: Option<u8>,
     pub format: Option<String>,
     pub algorithm: Option<String>,
+    pub png_depth: Option<u8>,
+    pub png_quantize_speed: Option<u8>,
+    pub preserve_progressive: Option<bool>,
+    pub background: Option<String>,
+    pub quant_table: Option<String>,
+    pub luma_quality: Option<u8>,
+    pub chroma_quality: Option<u8>,
+    pub target_ssim: Option<f64>,
+    pub jpeg_mode: Option<String>,
+    pub dither: Option<String>,
+    pub preserve_exif: Option<bool>,
+    pub color_space: Option<String>,
+    pub include_palette: Option<bool>,
+    pub comment: Option<String>,
+    pub xmp: Option<String>,
+    pub sidecar: Option<bool>,
+    pub deterministic: Option<bool>,
+    pub stream: Option<bool>,
+    pub profile: Option<String>,
+    pub reject_animated: Option<bool>,
+    pub validate_output: Option<bool>,
+    pub ops: Option<String>,
+    pub lossless_rotate: Option<bool>,
+    pub max_bytes: Option<usize>,
 }
 
 pub struct FileUpload {
     pub data: Vec<u8>,
     pub filename: Option<String>,
     pub content_type: Option<String>,
+    /// 上传过程中已开始的解码，见 `streaming_decode_min_bytes`
+    pub streamed_decode: Option<StreamingDecode>,
+    /// 随上传分块增量计算的 `data` 的 SHA-256
+    pub sha256: Option<[u8; 32]>,
 }
 
 impl FileUpload {
@@ -29,121 +76,2203 @@ impl FileUpload {
             data: Vec::new(),
             filename: None,
             content_type: None,
+            streamed_decode: None,
+            sha256: None,
         }
     }
+
+    /// 输入内容的 SHA-256，上传时未计算（如从磁盘读取）才遍历 `data`
+    pub fn content_sha256(&self) -> [u8; 32] {
+        self.sha256.unwrap_or_else(|| Sha256::digest(&self.data).into())
+    }
+}
+
+impl Default for FileUpload {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// CORS 允许的方法和请求头
+pub const CORS_ALLOW_METHODS: &str = "GET, POST, OPTIONS";
+pub const CORS_ALLOW_HEADERS: &str = "Content-Type, Authorization, X-Api-Key, X-Request-Deadline-Ms, X-File-Path, X-Request-Id, X-Param-Quality, X-Param-Format, X-Param-Algorithm";
+
+/// 预检结果的缓存时间（秒）
+pub const CORS_MAX_AGE_SECS: u32 = 86400;
+
+/// 上传代理已保存文件的路径，必须位于配置的 proxy_upload_dir 内
+pub const FILE_PATH_HEADER: &str = "X-File-Path";
+
+/// 客户端传入的请求截止时间（Unix 毫秒时间戳）
+pub const DEADLINE_HEADER: &str = "X-Request-Deadline-Ms";
+
+/// 携带 API Key 的请求头，也可使用 `Authorization: Bearer <key>`
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+
+/// 按请求身份生效的上限：携带有效 API Key 的请求使用常规上限，其余按匿名配置收紧
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestLimits {
+    pub max_quality: u8,
+    pub max_file_size: usize,
+}
+
+impl RequestLimits {
+    pub fn for_request(req: &HttpRequest, config: &Config) -> Self {
+        if has_valid_api_key(req, config) {
+            return Self {
+                max_quality: 100,
+                max_file_size: config.max_file_size_bytes(),
+            };
+        }
+
+        let max_quality = match config.server.anonymous_max_quality {
+            0 => 100,
+            quality => quality,
+        };
+        Self {
+            max_quality,
+            max_file_size: config.anonymous_max_file_size_bytes(),
+        }
+    }
+}
+
+pub async fn compress_endpoint(
+    req: HttpRequest,
+    mut payload: Multipart,
+    query: web::Query<CompressionQuery>,
+    config: web::Data<Config>,
+    storage: Option<web::Data<dyn StorageBackend>>,
+    queue: Option<web::Data<JobQueue>>,
+    metrics: Option<web::Data<CompressionMetrics>>,
+) -> Result<HttpResponse> {
+    // 截止时间在到达时已过期则直接返回 504
+    let deadline = parse_deadline(&req)?;
+    if let Some(deadline) = deadline {
+        remaining_budget(deadline)?;
+    }
+
+    let limits = RequestLimits::for_request(&req, &config);
+
+    // 上传代理通过 X-File-Path 指定已保存的文件时直接从磁盘读取，请求体只用于其他表单字段
+    let (file_upload, mut form_params) = match req.headers().get(FILE_PATH_HEADER) {
+        Some(path) => {
+            let path = path.to_str().map_err(|_| {
+                ImageServerError::InvalidParameters(format!("{} must be valid UTF-8", FILE_PATH_HEADER))
+            })?;
+            let file_upload = read_proxied_file(path, &config, limits.max_file_size).await?;
+            let form_params = if is_multipart(&req) {
+                read_multipart(&mut payload, &config, limits.max_file_size, false).await?.1
+            } else {
+                HashMap::new()
+            };
+            (Some(file_upload), form_params)
+        }
+        None => {
+            // 大文件边上传边解码，上传结束时像素通常已解码完成
+            let min_bytes = config.compression.streaming_decode_min_bytes;
+            let stream_decode = min_bytes > 0 && request_content_length(&req).is_some_and(|len| len >= min_bytes);
+            read_multipart(&mut payload, &config, limits.max_file_size, stream_decode).await?
+        }
+    };
+
+    let mut file_upload = match file_upload {
+        Some(upload) => upload,
+        None => {
+            return Ok(missing_file_response(&config));
+        }
+    };
+
+    check_denied_types(&file_upload.data, &config)?;
+    check_image_dimensions(&file_upload.data, &config)?;
+    let input_sha256 = file_upload.content_sha256();
+    let input_sha256_hex = hex_digest(&input_sha256);
+
+    // 只接受静态图片的流水线可以拒绝动画输入，而不是静默只取第一帧
+    let reject_animated = query.reject_animated
+        .or_else(|| {
+            form_params.get("reject_animated")
+                .and_then(|s| s.parse::<bool>().ok())
+        })
+        .unwrap_or(false);
+    if reject_animated {
+        let frames = animation::frame_count(&file_upload.data);
+        if frames > 1 {
+            return Err(ImageServerError::AnimatedInput { frames }.into());
+        }
+    }
+
+    // 配置的参数组合作为表单参数的默认值，请求中显式给出的查询参数和表单字段优先
+    let profile_name = query.profile.clone().or_else(|| form_params.get("profile").cloned());
+    let profile = match profile_name.as_deref() {
+        Some(name) => Some(resolve_profile(name, &config)?),
+        None => None,
+    };
+    if let Some(profile) = profile {
+        for (name, value) in profile.params() {
+            form_params.entry(name.to_string()).or_insert(value);
+        }
+    }
+
+    // 根据目标格式确定输出格式，未指定时与输入格式保持一致
+    // jpg 等别名统一为规范名，缓存键、指标标签和响应头保持一致
+    let requested_format = resolve_param(query.format.clone(), &req, &form_params, "format", parse_text_param)
+        .or_else(|| profile.and_then(|p| p.format.clone()));
+    // format=auto 按 Accept 和 User-Agent 在 WebP 与默认格式之间选择，旧客户端不会拿到无法解码的图片；
+    // WebP 输入的默认格式就是 WebP，回退时仍输出 JPEG
+    let auto_fallback = match default_output_format(&file_upload) {
+        "webp" => "jpeg",
+        format => format,
+    };
+    let auto_format = requested_format
+        .as_deref()
+        .filter(|f| f.eq_ignore_ascii_case(negotiation::AUTO_FORMAT))
+        .map(|_| negotiation::select_format(&req, auto_fallback, &config.compression));
+    let target_format = match (&auto_format, requested_format.as_deref()) {
+        (Some(selection), _) => selection.format,
+        (None, Some(f)) => canonical_format(f),
+        (None, None) => default_output_format(&file_upload),
+    };
+    if let Some(selection) = &auto_format {
+        info!("format=auto selected {} ({})", selection.format, selection.reason);
+    }
+    // APNG 输出一次性解码全部帧，解码前按帧数和帧数×画布面积拒绝过大的动画
+    if compression::is_format(target_format, Format::Apng) {
+        check_animation_limits(&file_upload.data, &config)?;
+    }
+    
+    // 设置质量
+    let quality = resolve_param(query.quality, &req, &form_params, "quality", parse_form_quality)
+        .or_else(|| config.compression.quality_for_format(target_format))
+        .unwrap_or(85)
+        .clamp(compression::min_quality(target_format), limits.max_quality);
+
+    // JPEG 重新编码时质量不超过源图估算质量加余量：更高的质量只增加体积，找不回已丢失的细节
+    let source_quality = if config.compression.clamp_to_source_quality && compression::is_format(target_format, Format::Jpeg) {
+        crate::quality::estimate_jpeg_quality(&file_upload.data).ok().map(|estimate| estimate.quality)
+    } else {
+        None
+    };
+    let max_quality = match source_quality {
+        Some(source_quality) => {
+            limits.max_quality.min(source_quality.saturating_add(config.compression.source_quality_margin).min(100))
+        }
+        None => limits.max_quality,
+    };
+    if quality > max_quality {
+        info!("Clamping quality {} to {} (estimated source quality {:?})", quality, max_quality, source_quality);
+    }
+    let quality = quality.min(max_quality);
+
+    // 设置算法，未指定时按输入格式选择默认算法
+    let requested_algorithm = resolve_param(query.algorithm.clone(), &req, &form_params, "algorithm", parse_text_param);
+    let algorithm_explicit = requested_algorithm.is_some();
+    let algorithm = requested_algorithm.unwrap_or_else(|| default_algorithm(&file_upload, &config));
+    let algorithm = resolve_enabled_algorithm(algorithm, &config)?;
+
+    // 大文件的 JPEG 输出未指定算法时改用更快的 jpeg-encoder，mozjpeg 在大图上耗时过长
+    let fast_min_bytes = config.compression.fast_jpeg_encoder_min_bytes;
+    let algorithm_override = (!algorithm_explicit
+        && fast_min_bytes > 0
+        && file_upload.data.len() >= fast_min_bytes
+        && compression::is_format(target_format, Format::Jpeg)
+        && !["jpeg-encoder", "none"].iter().any(|name| algorithm.eq_ignore_ascii_case(name)))
+        .then(|| "jpeg-encoder".to_string());
+    let algorithm = match algorithm_override.clone() {
+        Some(fast) => {
+            info!("Input is {} bytes (>= {}), using {} instead of {}", file_upload.data.len(), fast_min_bytes, fast, algorithm);
+            fast
+        }
+        None => algorithm,
+    };
+
+    // algorithm=none 或 format=original 时只校验图片，原样返回上传内容
+    if algorithm.eq_ignore_ascii_case("none") || compression::is_format(target_format, Format::Original) {
+        return passthrough_response(file_upload).await.map(|response| with_input_sha256(response, &input_sha256_hex));
+    }
+
+    // 小于阈值的图片压缩收益很小，校验后原样返回
+    let skip_below_bytes = config.compression.skip_below_bytes;
+    if skip_below_bytes > 0 && file_upload.data.len() < skip_below_bytes {
+        info!("Skipping compression for {} byte input (below {} bytes)", file_upload.data.len(), skip_below_bytes);
+        let mut response = passthrough_response(file_upload).await?;
+        response.headers_mut().insert(
+            HeaderName::from_static("x-skipped"),
+            HeaderValue::from_static("too-small"),
+        );
+        return Ok(with_input_sha256(response, &input_sha256_hex));
+    }
+
+    // 运行中的任务达到阈值时降低质量、改用更快的 JPEG 编码器，以少量画质换取高峰期的延迟
+    let load_shed = queue.as_ref().is_some_and(|queue| config.load_shedding.engaged(queue.stats().in_flight));
+    let (quality, max_quality, algorithm) = if load_shed {
+        let max_quality = max_quality.min(config.load_shedding.max_quality);
+        let algorithm = if config.load_shedding.fast_jpeg_encoder && compression::is_format(target_format, Format::Jpeg) {
+            "jpeg-encoder".to_string()
+        } else {
+            algorithm
+        };
+        info!("Load shedding engaged: quality {} -> {}, algorithm {}", quality, quality.min(max_quality), algorithm);
+        (quality.min(max_quality), max_quality, algorithm)
+    } else {
+        (quality, max_quality, algorithm)
+    };
+
+    // 设置 PNG 位深度（未指定时自动选择）
+    let png_depth = query.png_depth
+        .or_else(|| {
+            form_params.get("png_depth")
+                .and_then(|s| s.parse::<u8>().ok())
+        });
+    if let Some(depth) = png_depth {
+        if !matches!(depth, 1 | 2 | 4 | 8) {
+            return Err(ImageServerError::InvalidParameters(
+                format!("png_depth must be 1, 2, 4 or 8, got {}", depth)
+            ).into());
+        }
+    }
+
+    // 设置 PNG 量化速度（未指定时使用 imagequant 默认值）
+    let png_quantize_speed = query.png_quantize_speed
+        .or_else(|| {
+            form_params.get("png_quantize_speed")
+                .and_then(|s| s.parse::<u8>().ok())
+        });
+    if let Some(speed) = png_quantize_speed {
+        if !(1..=10).contains(&speed) {
+            return Err(ImageServerError::InvalidParameters(
+                format!("png_quantize_speed must be between 1 and 10, got {}", speed)
+            ).into());
+        }
+    }
+
+    // 设置 JPEG 透明区域的背景色
+    let background = match query.background.clone().or_else(|| form_params.get("background").cloned()) {
+        Some(color) => Some(parse_hex_color(&color).ok_or_else(|| {
+            ImageServerError::InvalidParameters(format!(
+                "background must be a hex color like ffffff or #fff, got {}", color
+            ))
+        })?),
+        None => None,
+    };
+
+    // 设置索引 PNG 的抖动，未指定时使用配置的默认值
+    let dither = match query.dither.clone().or_else(|| form_params.get("dither").cloned()) {
+        Some(value) => compression::Dither::from_name(&value).ok_or_else(|| {
+            ImageServerError::InvalidParameters(format!(
+                "dither must be auto, none, full or a level between 0.0 and 1.0, got {}", value
+            ))
+        })?,
+        None => compression::Dither::from_name(&config.compression.dither).unwrap_or_default(),
+    };
+
+    // 设置输出色彩空间，未指定时使用配置的默认值
+    let color_space = query.color_space.clone()
+        .or_else(|| form_params.get("color_space").cloned())
+        .unwrap_or_else(|| config.compression.color_space.clone());
+    let color_space = color::OutputColorSpace::from_name(&color_space).ok_or_else(|| {
+        ImageServerError::InvalidParameters(format!(
+            "color_space must be one of {:?}, got {}", color::OutputColorSpace::NAMES, color_space
+        ))
+    })?;
+
+    // 设置 mozjpeg 量化表
+    let quant_table = match query.quant_table.clone().or_else(|| form_params.get("quant_table").cloned()) {
+        Some(name) => compression::JpegQuantTable::from_name(&name).ok_or_else(|| {
+            ImageServerError::InvalidParameters(format!(
+                "quant_table must be one of {:?}, got {}", compression::JpegQuantTable::NAMES, name
+            ))
+        })?,
+        None => compression::JpegQuantTable::Default,
+    };
+
+    // 设置 mozjpeg 亮度、色度各自的质量，未指定的通道使用 quality
+    let luma_quality = query.luma_quality
+        .or_else(|| {
+            form_params.get("luma_quality")
+                .and_then(|s| s.parse::<u8>().ok())
+        });
+    let chroma_quality = query.chroma_quality
+        .or_else(|| {
+            form_params.get("chroma_quality")
+                .and_then(|s| s.parse::<u8>().ok())
+        });
+    for (name, value) in [("luma_quality", luma_quality), ("chroma_quality", chroma_quality)] {
+        if let Some(value) = value {
+            if !(1..=100).contains(&value) {
+                return Err(ImageServerError::InvalidParameters(
+                    format!("{} must be between 1 and 100, got {}", name, value)
+                ).into());
+            }
+        }
+    }
+
+    // 设置 mozjpeg 编码模式，显式指定时优先于 preserve_progressive
+    let jpeg_mode = match query.jpeg_mode.clone().or_else(|| form_params.get("jpeg_mode").cloned()) {
+        Some(name) => Some(compression::JpegMode::from_name(&name).ok_or_else(|| {
+            ImageServerError::InvalidParameters(format!(
+                "jpeg_mode must be one of {:?}, got {}", compression::JpegMode::NAMES, name
+            ))
+        })?),
+        None => None,
+    };
+
+    // 设置目标 SSIM（仅 JPEG 输出）
+    let target_ssim = query.target_ssim
+        .or_else(|| {
+            form_params.get("target_ssim")
+                .and_then(|s| s.parse::<f64>().ok())
+        });
+    if let Some(target) = target_ssim {
+        if !(target > 0.0 && target < 1.0) {
+            return Err(ImageServerError::InvalidParameters(
+                format!("target_ssim must be between 0 and 1 (exclusive), got {}", target)
+            ).into());
+        }
+        if !compression::is_format(target_format, Format::Jpeg) {
+            return Err(ImageServerError::InvalidParameters(
+                "target_ssim is only supported for JPEG output".to_string()
+            ).into());
+        }
+    }
+
+    // 设置输出大小上限（仅 JPEG 输出），按配置的次数上限二分查找质量
+    let max_bytes = query.max_bytes
+        .or_else(|| {
+            form_params.get("max_bytes")
+                .and_then(|s| s.parse::<usize>().ok())
+        });
+    if let Some(max_bytes) = max_bytes {
+        if max_bytes == 0 {
+            return Err(ImageServerError::InvalidParameters(
+                "max_bytes must be greater than 0".to_string()
+            ).into());
+        }
+        if !compression::is_format(target_format, Format::Jpeg) {
+            return Err(ImageServerError::InvalidParameters(
+                "max_bytes is only supported for JPEG output".to_string()
+            ).into());
+        }
+        if target_ssim.is_some() {
+            return Err(ImageServerError::InvalidParameters(
+                "max_bytes cannot be combined with target_ssim".to_string()
+            ).into());
+        }
+    }
+
+    // 按配置记录指定的 EXIF 标签，便于统计上传设备
+    let exif_tags = compression::read_exif_tags(&file_upload.data, &config.logging.log_exif_tags);
+    if !exif_tags.is_empty() {
+        let tags: Vec<String> = exif_tags
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        info!("EXIF tags: {}", tags.join(", "));
+    }
+
+    // 检测源 JPEG 是否为渐进式，按需在输出中保留
+    let source_progressive = compression::detect_progressive_jpeg(&file_upload.data);
+    let preserve_progressive = query.preserve_progressive
+        .or_else(|| {
+            form_params.get("preserve_progressive")
+                .and_then(|s| s.parse::<bool>().ok())
+        })
+        .unwrap_or(false);
+
+    let preserve_exif = query.preserve_exif
+        .or_else(|| {
+            form_params.get("preserve_exif")
+                .and_then(|s| s.parse::<bool>().ok())
+        })
+        .unwrap_or(false);
+
+    // 量化单线程执行，相同输入和参数得到逐字节相同的输出，便于可复现构建和内容寻址存储
+    let deterministic = query.deterministic
+        .or_else(|| {
+            form_params.get("deterministic")
+                .and_then(|s| s.parse::<bool>().ok())
+        })
+        .unwrap_or(false);
+
+    // 编码前按顺序执行的预处理操作，如先裁剪再缩放最后锐化
+    let operations = match query.ops.clone().or_else(|| form_params.get("ops").cloned()) {
+        Some(ops) => pipeline::parse_operations(&ops)?,
+        None => Vec::new(),
+    };
+    if !operations.is_empty() && compression::is_format(target_format, Format::Apng) {
+        return Err(ImageServerError::InvalidParameters(
+            "ops is not supported for APNG output".to_string()
+        ).into());
+    }
+
+    // 只需旋转/翻转的 JPEG 在 DCT 域无损变换，保留源图质量和元数据；条件不满足时照常重新编码
+    let lossless_rotate = query.lossless_rotate
+        .or_else(|| {
+            form_params.get("lossless_rotate")
+                .and_then(|s| s.parse::<bool>().ok())
+        })
+        .unwrap_or(false);
+    if lossless_rotate && !compression::is_format(target_format, Format::Jpeg) {
+        return Err(ImageServerError::InvalidParameters(format!(
+            "lossless_rotate requires format=jpeg, got {}", target_format
+        )).into());
+    }
+
+    // 返回前重新解码输出，确保不会交付编码器产生的损坏文件
+    let validate_output = query.validate_output
+        .or_else(|| {
+            form_params.get("validate_output")
+                .and_then(|s| s.parse::<bool>().ok())
+        })
+        .unwrap_or(false);
+
+    // 以 JSON 返回压缩结果及量化得到的调色板，仅适用于索引 PNG 输出
+    let include_palette = query.include_palette
+        .or_else(|| {
+            form_params.get("include_palette")
+                .and_then(|s| s.parse::<bool>().ok())
+        })
+        .unwrap_or(false);
+    if include_palette && !compression::is_format(target_format, Format::Png) {
+        return Err(ImageServerError::InvalidParameters(format!(
+            "include_palette requires format=png, got {}", target_format
+        )).into());
+    }
+
+    // 以 multipart/mixed 同时返回图片和 JSON 元数据，便于归档系统一次取回
+    let sidecar = query.sidecar
+        .or_else(|| {
+            form_params.get("sidecar")
+                .and_then(|s| s.parse::<bool>().ok())
+        })
+        .unwrap_or(false);
+    if sidecar && include_palette {
+        return Err(ImageServerError::InvalidParameters(
+            "sidecar and include_palette cannot be combined".to_string()
+        ).into());
+    }
+
+    // 以渐进式 JPEG 编码并按扫描分块流式返回，前端可以先显示模糊的图像再逐步变清晰
+    let stream = query.stream
+        .or_else(|| {
+            form_params.get("stream")
+                .and_then(|s| s.parse::<bool>().ok())
+        })
+        .unwrap_or(false);
+    if stream {
+        if !compression::is_format(target_format, Format::Jpeg) {
+            return Err(ImageServerError::InvalidParameters(format!(
+                "stream requires format=jpeg, got {}", target_format
+            )).into());
+        }
+        if sidecar || include_palette {
+            return Err(ImageServerError::InvalidParameters(
+                "stream cannot be combined with sidecar or include_palette".to_string()
+            ).into());
+        }
+        if jpeg_mode.is_some_and(|mode| mode != compression::JpegMode::Progressive) {
+            return Err(ImageServerError::InvalidParameters(
+                "stream requires progressive encoding, remove jpeg_mode or set it to progressive".to_string()
+            ).into());
+        }
+    }
+    let jpeg_mode = if stream { Some(compression::JpegMode::Progressive) } else { jpeg_mode };
+
+    // 写入输出文件的注释，仅 JPEG 和 PNG 支持
+    let comment = query.comment.clone()
+        .or_else(|| form_params.get("comment").cloned());
+    if let Some(comment) = comment.as_deref() {
+        compression::validate_comment(comment).map_err(ImageServerError::InvalidParameters)?;
+        if !matches!(compression::normalize_format(target_format), Some(Format::Jpeg | Format::Png | Format::Apng)) {
+            return Err(ImageServerError::InvalidParameters(format!(
+                "comment is only supported for jpeg and png output, got {}", target_format
+            )).into());
+        }
+    }
+
+    // 写入输出文件的 XMP 包，仅 JPEG 和 PNG 支持；通常较长，更适合放在表单字段中
+    let xmp = query.xmp.clone()
+        .or_else(|| form_params.get("xmp").cloned());
+    if let Some(xmp) = xmp.as_deref() {
+        compression::validate_xmp(xmp).map_err(ImageServerError::InvalidParameters)?;
+        if !matches!(compression::normalize_format(target_format), Some(Format::Jpeg | Format::Png | Format::Apng)) {
+            return Err(ImageServerError::InvalidParameters(format!(
+                "xmp is only supported for jpeg and png output, got {}", target_format
+            )).into());
+        }
+    }
+
+    info!(
+        "Processing file: {} ({} bytes) with quality: {}, format: {} (保持原始尺寸), algorithm: {}",
+        file_upload.filename.as_deref().unwrap_or("unknown"),
+        file_upload.data.len(),
+        quality,
+        target_format,
+        algorithm
+    );
+
+    // 检查持久化存储中是否已有相同输入和参数的压缩结果
+    let mut options = compression::CompressionOptions::new(target_format, quality, &algorithm);
+    options.png_depth = png_depth;
+    options.png_quantize_speed = png_quantize_speed;
+    options.png_min_quantization_quality = config.compression.png_min_quantization_quality;
+    options.dither = dither;
+    options.progressive = preserve_progressive && source_progressive == Some(true);
+    options.source_indexed = compression::is_indexed_png(&file_upload.data);
+    options.background = background;
+    options.quant_table = quant_table;
+    options.luma_quality = luma_quality;
+    options.chroma_quality = chroma_quality;
+    options.target_ssim = target_ssim;
+    options.max_bytes = max_bytes;
+    options.size_search_max_iterations = config.compression.size_search_max_iterations;
+    options.size_search_tolerance_percent = config.compression.size_search_tolerance_percent;
+    options.jpeg_mode = jpeg_mode;
+    options.preserve_exif = preserve_exif;
+    options.color_space = color_space;
+    options.comment = comment;
+    options.xmp = xmp;
+    options.deterministic = deterministic;
+    options.operations = operations;
+    options.lossless_rotate = lossless_rotate;
+    options.verify_output = config.compression.verify_output;
+    options.max_quality = max_quality;
+    options.thumbnail_quality_boost = config.compression.thumbnail_quality_boost;
+    options.thumbnail_max_dimension = config.compression.thumbnail_max_dimension;
+    options.resize_filter = compression::ResizeFilter::from_name(&config.compression.resize_filter_default)
+        .unwrap_or_default();
+    options.fast_downscale_factor = config.compression.resize_fast_downscale_factor;
+
+    let storage_key = storage.as_ref().map(|_| storage::storage_key(&input_sha256, &options));
+
+    if let (Some(storage), Some(key)) = (storage.as_ref(), storage_key.as_deref()) {
+        match storage.get(key).await {
+            Ok(Some(stored_data)) => {
+                info!("Storage hit for {}, {} bytes", key, stored_data.len());
+                if include_palette {
+                    let response = palette_response(&file_upload, target_format, file_upload.data.len(), &stored_data, None);
+                    return Ok(with_load_shed_header(negotiation::with_auto_format_headers(with_input_sha256(response, &input_sha256_hex), auto_format.as_ref()), load_shed));
+                }
+                if sidecar {
+                    // 存储命中时没有本次编码的参数，encoder 相关字段为 null
+                    let (width, height) = compression::read_dimensions(&stored_data).unwrap_or((0, 0));
+                    let exif_info = compression::exif_info_for(&file_upload.data, target_format);
+                    let metadata = serde_json::json!({
+                        "width": width,
+                        "height": height,
+                        "quality": null,
+                        "quantization_quality": null,
+                        "encoder": null,
+                        "encoder_options": null,
+                        "exif": exif_metadata(&exif_info),
+                        "storage_cache": "HIT",
+                    });
+                    let response = sidecar_response(&file_upload, target_format, file_upload.data.len(), stored_data, metadata);
+                    return Ok(with_load_shed_header(negotiation::with_auto_format_headers(with_input_sha256(response, &input_sha256_hex), auto_format.as_ref()), load_shed));
+                }
+                let response = stored_response(&file_upload, target_format, source_progressive, stored_data, stream);
+                return Ok(with_load_shed_header(negotiation::with_auto_format_headers(with_input_sha256(response, &input_sha256_hex), auto_format.as_ref()), load_shed));
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Storage lookup failed, compressing instead: {}", e),
+        }
+    }
+    
+    // 解码和压缩占用一个任务名额，名额占满时排队等待
+    let permit = acquire_job_slot(queue.as_ref()).await?;
+
+    // 解码单独限时，构造的图片解码过慢时返回 504，而不是悄悄耗尽整个压缩预算
+    let original_size = file_upload.data.len();
+    let input = std::mem::take(&mut file_upload.data);
+    let streamed_decode = file_upload.streamed_decode.take();
+    let lossless = lossless_rotate && compression::lossless_transform_for(&input, &options).is_ok();
+    let (input, decoded) = if compression::is_format(target_format, Format::Apng) || lossless {
+        // APNG 需要解码全部帧，无损变换不解码像素，都由压缩阶段处理
+        (input, None)
+    } else {
+        let (input, img, exif_info) = decode_with_timeout(input, streamed_decode, &options, &config, deadline).await?;
+        (input, Some((img, exif_info)))
+    };
+
+    // Perform compression on the blocking pool, bounded by the encode timeout and request deadline
+    let compression_options = options.clone();
+    let fallback_algorithm = config.compression.validate_output_fallback_algorithm.clone()
+        .filter(|fallback| !fallback.eq_ignore_ascii_case(&options.algorithm));
+    // 超时返回后阻塞任务仍会跑完，名额随任务一起释放
+    let compression_result = encode_with_timeout(move || {
+        let _permit = permit;
+        let result = match decoded {
+            Some((img, exif_info)) => {
+                compression::compress_decoded(&input, img, exif_info, &compression_options)
+            }
+            None => compression::compress_image_with_options(&input, &compression_options),
+        }?;
+        if !validate_output {
+            return Ok((result, None));
+        }
+        match (compression::validate_decodable(&result.data, &compression_options), fallback_algorithm) {
+            (Ok(()), _) => Ok((result, None)),
+            (Err(err), Some(fallback)) => {
+                warn!("{}, re-encoding with {}", err, fallback);
+                let mut fallback_options = compression_options.clone();
+                fallback_options.algorithm = fallback;
+                let result = compression::compress_image_with_options(&input, &fallback_options)?;
+                compression::validate_decodable(&result.data, &fallback_options)?;
+                Ok((result, Some(fallback_options.algorithm)))
+            }
+            (Err(err), None) => Err(err),
+        }
+    }, &options.algorithm, &config, deadline).await?;
+
+    match compression_result {
+        Ok((result, validation_fallback)) => {
+            // 回退后编码器信息和指标都按实际使用的算法记录
+            if let Some(fallback) = &validation_fallback {
+                options.algorithm = fallback.clone();
+            }
+            let compression::CompressionResult {
+                data: compressed_data,
+                width,
+                height,
+                source_width,
+                source_height,
+                exif_info,
+                chosen_quality,
+                achieved_ssim,
+                effective_quality,
+                quantization_quality,
+                png_lossless_fallback,
+                lossless_transform,
+                resize_filters,
+                size_search_iterations,
+                max_bytes_met,
+            } = result;
+            // 只有经过量化的 PNG 输出才有编码路径可报告
+            let png_path = quantization_quality.map(|_| if png_lossless_fallback { "lossless" } else { "quantized" });
+            let output_size = compressed_data.len();
+            let provenance = match lossless_transform {
+                Some(transform) => compression::lossless_transform_provenance(transform, &compressed_data),
+                None => compression::encoder_provenance(&options, effective_quality, &compressed_data),
+            };
+            
+            info!("Compression successful, size: {} bytes, dimensions: {}x{}, EXIF: {}", 
+                  output_size, width, height, exif_info);
+            if let Some(metrics) = metrics.as_ref() {
+                metrics.record(target_format, &options.algorithm, original_size, output_size);
+            }
+
+            if let (Some(storage), Some(key)) = (storage.as_ref(), storage_key.as_deref()) {
+                if let Err(e) = storage.put(key, &compressed_data).await {
+                    warn!("Failed to persist compressed output {}: {}", key, e);
+                }
+            }
+
+            if include_palette {
+                let response = palette_response(&file_upload, target_format, original_size, &compressed_data, quantization_quality);
+                return Ok(with_load_shed_header(negotiation::with_auto_format_headers(with_input_sha256(response, &input_sha256_hex), auto_format.as_ref()), load_shed));
+            }
+
+            if sidecar {
+                let metadata = serde_json::json!({
+                    "width": width,
+                    "height": height,
+                    "quality": effective_quality,
+                    "chosen_quality": chosen_quality,
+                    "achieved_ssim": achieved_ssim,
+                    "quantization_quality": quantization_quality,
+                    "png_path": png_path,
+                    "encoder": provenance.encoder,
+                    "encoder_options": provenance.options,
+                    "lossless_transform": lossless_transform.map(|transform| transform.name()),
+                    "size_search_iterations": size_search_iterations,
+                    "max_bytes_met": max_bytes_met,
+                    "exif": exif_metadata(&exif_info),
+                });
+                let response = sidecar_response(&file_upload, target_format, original_size, compressed_data, metadata);
+                return Ok(with_load_shed_header(negotiation::with_auto_format_headers(with_input_sha256(response, &input_sha256_hex), auto_format.as_ref()), load_shed));
+            }
+            
+            let mut response = HttpResponse::Ok();
+            if storage.is_some() {
+                response.insert_header(("X-Storage-Cache", "MISS"));
+            }
+            if let Some(progressive) = source_progressive {
+                response.insert_header(("X-Source-Progressive", progressive.to_string()));
+            }
+            if let Some(source_quality) = source_quality {
+                response.insert_header(("X-Source-Quality", source_quality.to_string()));
+            }
+            if let Some(chosen_quality) = chosen_quality {
+                response.insert_header(("X-Chosen-Quality", chosen_quality.to_string()));
+            }
+            if let Some(achieved_ssim) = achieved_ssim {
+                response.insert_header(("X-Achieved-SSIM", format!("{:.4}", achieved_ssim)));
+            }
+            if let Some(iterations) = size_search_iterations {
+                response.insert_header(("X-Size-Search-Iterations", iterations.to_string()));
+            }
+            if let Some(met) = max_bytes_met {
+                response.insert_header(("X-Max-Bytes-Met", met.to_string()));
+            }
+            if let Some(quantization_quality) = quantization_quality {
+                response.insert_header(("X-Quantization-Quality", quantization_quality.to_string()));
+            }
+            if let Some(png_path) = png_path {
+                response.insert_header(("X-PNG-Path", png_path));
+            }
+            if let Some(algorithm) = algorithm_override {
+                response.insert_header(("X-Algorithm-Override", algorithm));
+            }
+            if validate_output {
+                response.insert_header(("X-Output-Validated", "true"));
+            }
+            if let Some(fallback) = validation_fallback {
+                response.insert_header(("X-Validation-Fallback", fallback));
+            }
+            if let Some(transform) = lossless_transform {
+                response.insert_header(("X-Lossless-Transform", transform.name()));
+            }
+            // 与金字塔接口相同，逗号分隔每次缩放使用的滤镜
+            if !resize_filters.is_empty() {
+                let names: Vec<&str> = resize_filters.iter().map(|filter| filter.name()).collect();
+                response.insert_header(("X-Resize-Filter", names.join(",")));
+            }
+            insert_orientation_headers(&mut response, &exif_info);
+            response
+                .insert_header(("Content-Type", determine_output_content_type(target_format)))
+                .insert_header(("X-Effective-Quality", effective_quality.to_string()))
+                .insert_header(("X-Encoder", provenance.encoder))
+                .insert_header(("X-Encoder-Options", provenance.options))
+                // 图片已经是压缩格式，避免 Compress 中间件重复压缩
+                .insert_header(ContentEncoding::Identity)
+                // Add compression statistics to response headers
+                .insert_header(("X-Original-Size", original_size.to_string()))
+                .insert_header(("X-Compressed-Size", output_size.to_string()))
+                .insert_header(("X-Compression-Ratio", compression_ratio(original_size, output_size)))
+                .insert_header(("X-Image-Width", width.to_string()))
+                .insert_header(("X-Image-Height", height.to_string()))
+                .insert_header(("X-Source-Width", source_width.to_string()))
+                .insert_header(("X-Source-Height", source_height.to_string()))
+                .insert_header(("X-EXIF-Info", exif_info.summary.clone()))
+                .insert_header((
+                    "Content-Disposition",
+                    content_disposition(&generate_output_filename(&file_upload.filename, target_format)),
+                ));
+            let response = with_input_sha256(image_body(response, compressed_data, stream), &input_sha256_hex);
+            let response = negotiation::with_auto_format_headers(response, auto_format.as_ref());
+            let response = with_load_shed_header(response, load_shed);
+
+            info!(
+                "Successfully compressed file: {} -> {} bytes ({}x{}), EXIF: {}",
+                original_size, output_size, width, height, exif_info
+            );
+
+            Ok(response)
+        }
+        Err(err @ ImageServerError::InvalidParameters(_)) => {
+            info!("Rejected compression request: {}", err);
+            Err(err.into())
+        }
+        Err(err @ ImageServerError::OutputValidationFailed { .. }) => {
+            error!("Output validation failed: {}", err);
+            Err(err.into())
+        }
+        Err(ImageServerError::CompressionError(err)) => {
+            error!("Compression failed: {}", err);
+            Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Compression failed: {}", err)
+            })))
+        }
+        // 其他错误由 ResponseError 决定状态码，如无法识别的格式返回 415
+        Err(err) => {
+            error!("Compression failed: {}", err);
+            Err(err.into())
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PyramidQuery {
+    pub widths: Option<String>,
+    pub quality: Option<u8>,
+    pub format: Option<String>,
+    pub algorithm: Option<String>,
+}
+
+/// 单次请求允许的最大金字塔层数
+pub const MAX_PYRAMID_LEVELS: usize = 16;
+
+/// 生成多分辨率图片金字塔，只解码一次并按每个宽度分别压缩
+pub async fn pyramid_endpoint(
+    req: HttpRequest,
+    mut payload: Multipart,
+    query: web::Query<PyramidQuery>,
+    config: web::Data<Config>,
+    queue: Option<web::Data<JobQueue>>,
+) -> Result<HttpResponse> {
+    let limits = RequestLimits::for_request(&req, &config);
+    let (file_upload, form_params) = read_multipart(&mut payload, &config, limits.max_file_size, false).await?;
+
+    let file_upload = match file_upload {
+        Some(upload) => upload,
+        None => {
+            return Ok(missing_file_response(&config));
+        }
+    };
+
+    check_denied_types(&file_upload.data, &config)?;
+    check_image_dimensions(&file_upload.data, &config)?;
+
+    let widths_param = query.widths.clone()
+        .or_else(|| form_params.get("widths").cloned())
+        .ok_or_else(|| ImageServerError::InvalidParameters(
+            "widths is required, e.g. widths=256,512,1024".to_string()
+        ))?;
+    let widths = parse_widths(&widths_param)?;
+
+    let target_format = resolve_param(query.format.clone(), &req, &form_params, "format", parse_text_param)
+        .map(|format| canonical_format(&format).to_string())
+        .unwrap_or_else(|| default_output_format(&file_upload).to_string());
+
+    let quality = resolve_param(query.quality, &req, &form_params, "quality", parse_form_quality)
+        .or_else(|| config.compression.quality_for_format(&target_format))
+        .unwrap_or(85)
+        .clamp(compression::min_quality(&target_format), limits.max_quality);
+
+    let algorithm = resolve_param(query.algorithm.clone(), &req, &form_params, "algorithm", parse_text_param)
+        .unwrap_or_else(|| default_algorithm(&file_upload, &config));
+    let algorithm = resolve_enabled_algorithm(algorithm, &config)?;
+
+    info!(
+        "Generating pyramid for {} ({} bytes), widths: {:?}, format: {}, quality: {}",
+        file_upload.filename.as_deref().unwrap_or("unknown"),
+        file_upload.data.len(),
+        widths,
+        target_format,
+        quality
+    );
+
+    let mut options = compression::CompressionOptions::new(&target_format, quality, &algorithm);
+    options.thumbnail_quality_boost = config.compression.thumbnail_quality_boost;
+    options.thumbnail_max_dimension = config.compression.thumbnail_max_dimension;
+    options.resize_filter = compression::ResizeFilter::from_name(&config.compression.resize_filter_default)
+        .unwrap_or_default();
+    options.fast_downscale_factor = config.compression.resize_fast_downscale_factor;
+    let data = file_upload.data;
+    let permit = acquire_job_slot(queue.as_ref()).await?;
+    let result = web::block(logging::in_current_span(move || {
+        let _permit = permit;
+        compression::compress_pyramid(&data, &options, &widths)
+    }))
+        .await
+        .map_err(|e| ImageServerError::ProcessingError(format!("Pyramid task failed: {}", e)))?;
+
+    match result {
+        Ok((levels, original_width, original_height)) => {
+            // 每层实际使用的缩放滤镜，按层顺序逗号分隔，未缩放的层为 none
+            let resize_filters = levels
+                .iter()
+                .map(|level| level.resize_filter.map_or("none", compression::ResizeFilter::name))
+                .collect::<Vec<_>>()
+                .join(",");
+            let levels: Vec<serde_json::Value> = levels
+                .iter()
+                .map(|level| serde_json::json!({
+                    "width": level.width,
+                    "height": level.height,
+                    "quality": level.quality,
+                    "resize_filter": level.resize_filter.map(compression::ResizeFilter::name),
+                    "size": level.data.len(),
+                    "data": base64::engine::general_purpose::STANDARD.encode(&level.data),
+                }))
+                .collect();
+
+            Ok(HttpResponse::Ok()
+                .insert_header(("X-Resize-Filter", resize_filters))
+                .insert_header(("X-Source-Width", original_width.to_string()))
+                .insert_header(("X-Source-Height", original_height.to_string()))
+                .json(serde_json::json!({
+                    "original_width": original_width,
+                    "original_height": original_height,
+                    "format": target_format,
+                    "content_type": determine_output_content_type(&target_format),
+                    "levels": levels,
+                })))
+        }
+        Err(err @ ImageServerError::InvalidParameters(_)) => Err(err.into()),
+        Err(err) => {
+            error!("Pyramid generation failed: {}", err);
+            Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Pyramid generation failed: {}", err)
+            })))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ZipCompressionQuery {
+    pub format: Option<String>,
+    pub quality: Option<u8>,
+    pub algorithm: Option<String>,
+}
+
+/// 压缩请求体 ZIP 中的每张图片，返回保持目录结构的 ZIP，附带逐条目的 `_report.json`
+///
+/// 未指定 `format` 时 PNG 输出 PNG、其他图片输出 JPEG；未指定 `algorithm` 时按输入格式选择默认算法。
+pub async fn compress_zip_endpoint(
+    req: HttpRequest,
+    body: web::Bytes,
+    query: web::Query<ZipCompressionQuery>,
+    config: web::Data<Config>,
+    queue: Option<web::Data<JobQueue>>,
+    metrics: Option<web::Data<CompressionMetrics>>,
+) -> Result<HttpResponse> {
+    let limits = RequestLimits::for_request(&req, &config);
+    if body.len() > limits.max_file_size {
+        return Err(ImageServerError::FileTooLarge { max_size: limits.max_file_size }.into());
+    }
+
+    // 请求体是 ZIP 而不是表单，参数来自查询参数和请求头
+    let no_form = HashMap::new();
+    let requested_format = resolve_param(query.format.clone(), &req, &no_form, "format", parse_text_param);
+    if let Some(format) = requested_format.as_deref() {
+        if !matches!(compression::normalize_format(format), Some(Format::Jpeg | Format::Png | Format::Webp)) {
+            return Err(ImageServerError::InvalidParameters(format!(
+                "format must be jpeg, png or webp for ZIP entries, got {}", format
+            )).into());
+        }
+    }
+    let requested_quality = resolve_param(query.quality, &req, &no_form, "quality", parse_form_quality);
+    // 显式指定的算法在处理前校验，避免每个条目都失败
+    let algorithm = match resolve_param(query.algorithm.clone(), &req, &no_form, "algorithm", parse_text_param) {
+        Some(algorithm) => Some(resolve_enabled_algorithm(algorithm, &config)?),
+        None => None,
+    };
+
+    info!(
+        "Processing ZIP archive ({} bytes), format: {:?}, quality: {:?}, algorithm: {:?}",
+        body.len(), requested_format, requested_quality, algorithm
+    );
+
+    let original_size = body.len();
+    let entry_config = config.clone();
+    let temp_dir = config.zip_temp_dir();
+    let naming = archive::ZipNaming::from_name(&config.server.zip_name_collision).unwrap_or_default();
+    let permit = acquire_job_slot(queue.as_ref()).await?;
+    let result = web::block(logging::in_current_span(move || {
+        let _permit = permit;
+        let config = entry_config;
+        archive::compress_zip_to_file(&body, limits.max_file_size as u64, naming, &temp_dir, |data| {
+            check_denied_types(data, &config)?;
+            check_image_dimensions(data, &config)?;
+            let input_format = image::guess_format(data)
+                .ok()
+                .and_then(|format| format.extensions_str().first().copied())
+                .unwrap_or("");
+            let target_format = match requested_format.as_deref() {
+                Some(format) => canonical_format(format),
+                None => compression::default_format_for_input(data, None).name(),
+            }
+            .to_string();
+            let quality = requested_quality
+                .or_else(|| config.compression.quality_for_format(&target_format))
+                .unwrap_or(85)
+                .clamp(compression::min_quality(&target_format), limits.max_quality);
+            let algorithm = match algorithm.clone() {
+                Some(algorithm) => algorithm,
+                None => resolve_enabled_algorithm(
+                    config.compression.algorithm_for_input_format(input_format).to_string(),
+                    &config,
+                )?,
+            };
+
+            let mut options = compression::CompressionOptions::new(&target_format, quality, &algorithm);
+            options.dither = compression::Dither::from_name(&config.compression.dither).unwrap_or_default();
+            options.source_indexed = compression::is_indexed_png(data);
+            options.max_quality = limits.max_quality;
+            options.thumbnail_quality_boost = config.compression.thumbnail_quality_boost;
+            options.thumbnail_max_dimension = config.compression.thumbnail_max_dimension;
+
+            let result = compression::compress_image_with_options(data, &options)?;
+            if let Some(metrics) = metrics.as_ref() {
+                metrics.record(&target_format, &algorithm, data.len(), result.data.len());
+            }
+            Ok((result.data, output_extension(&target_format)))
+        })
+    }))
+    .await
+    .map_err(|e| ImageServerError::ProcessingError(format!("ZIP task failed: {}", e)))?;
+
+    let (path, reports) = result?;
+    let count = |status: &str| reports.iter().filter(|report| report.status == status).count().to_string();
+
+    // 打开后立即删除路径：已打开的句柄仍可读取，响应发送完关闭句柄时释放磁盘空间
+    let file = std::fs::File::open(&path);
+    if let Err(e) = std::fs::remove_file(&path) {
+        warn!("Failed to remove spooled ZIP {}: {}", path.display(), e);
+    }
+    let file = file.map_err(|e| ImageServerError::ProcessingError(format!("Failed to open spooled ZIP: {}", e)))?;
+    let output = actix_files::NamedFile::from_file(file, &path)
+        .map_err(|e| ImageServerError::ProcessingError(format!("Failed to open spooled ZIP: {}", e)))?
+        .use_etag(false)
+        .use_last_modified(false);
+    info!("Successfully processed ZIP archive: {} -> {} bytes", original_size, output.metadata().len());
+
+    let mut response = output.into_response(&req);
+    let headers = response.headers_mut();
+    for (name, value) in [
+        ("content-type", "application/zip".to_string()),
+        ("content-disposition", content_disposition("compressed.zip")),
+        ("content-encoding", "identity".to_string()),
+        ("x-zip-compressed", count("compressed")),
+        ("x-zip-skipped", count("skipped")),
+        ("x-zip-failed", count("failed")),
+        ("x-zip-deduplicated", reports.iter().filter(|report| report.duplicate_of.is_some()).count().to_string()),
+    ] {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(HeaderName::from_static(name), value);
+        }
+    }
+    Ok(response)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AnalyzeQuery {
+    pub quality: Option<u8>,
+}
+
+/// 试压缩但不返回图片，对比 JPEG 和量化 PNG 的体积与画质并给出推荐格式
+pub async fn analyze_endpoint(
+    req: HttpRequest,
+    mut payload: Multipart,
+    query: web::Query<AnalyzeQuery>,
+    config: web::Data<Config>,
+    queue: Option<web::Data<JobQueue>>,
+) -> Result<HttpResponse> {
+    let limits = RequestLimits::for_request(&req, &config);
+    let (file_upload, form_params) = read_multipart(&mut payload, &config, limits.max_file_size, false).await?;
+
+    let file_upload = match file_upload {
+        Some(upload) => upload,
+        None => {
+            return Ok(missing_file_response(&config));
+        }
+    };
+
+    check_denied_types(&file_upload.data, &config)?;
+    check_image_dimensions(&file_upload.data, &config)?;
+
+    let quality = resolve_param(query.quality, &req, &form_params, "quality", parse_form_quality)
+        .unwrap_or(config.compression.default_quality)
+        .clamp(1, limits.max_quality);
+
+    info!(
+        "Analyzing {} ({} bytes), quality: {}",
+        file_upload.filename.as_deref().unwrap_or("unknown"),
+        file_upload.data.len(),
+        quality
+    );
+
+    let original_size = file_upload.data.len();
+    let data = file_upload.data;
+    let permit = acquire_job_slot(queue.as_ref()).await?;
+    let result = web::block(logging::in_current_span(move || {
+        let _permit = permit;
+        crate::quality::analyze_formats(&data, quality)
+    }))
+        .await
+        .map_err(|e| ImageServerError::ProcessingError(format!("Analyze task failed: {}", e)))?;
+
+    match result {
+        Ok(analysis) => {
+            let candidates: Vec<serde_json::Value> = analysis
+                .candidates
+                .iter()
+                .map(|candidate| serde_json::json!({
+                    "format": candidate.format,
+                    "algorithm": candidate.algorithm,
+                    "size": candidate.size,
+                    "ssim": candidate.ssim,
+                    "compression_ratio": compression_ratio(original_size, candidate.size),
+                }))
+                .collect();
+
+            Ok(HttpResponse::Ok().json(serde_json::json!({
+                "width": analysis.width,
+                "height": analysis.height,
+                "original_size": original_size,
+                "quality": quality,
+                "has_transparency": analysis.has_transparency,
+                "candidates": candidates,
+                "recommended_format": analysis.recommended_format,
+            })))
+        }
+        Err(err) => {
+            error!("Analysis failed: {}", err);
+            Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Analysis failed: {}", err)
+            })))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RawCompressionQuery {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub channels: Option<u8>,
+    pub format: Option<String>,
+    pub quality: Option<u8>,
+    pub algorithm: Option<String>,
+}
+
+/// 压缩请求体中未编码的原始像素（按行排列的灰度、RGB 或 RGBA），尺寸和通道数由查询参数声明
+pub async fn compress_raw_endpoint(
+    req: HttpRequest,
+    body: web::Bytes,
+    query: web::Query<RawCompressionQuery>,
+    config: web::Data<Config>,
+    queue: Option<web::Data<JobQueue>>,
+    metrics: Option<web::Data<CompressionMetrics>>,
+) -> Result<HttpResponse> {
+    let limits = RequestLimits::for_request(&req, &config);
+    if body.len() > limits.max_file_size {
+        return Err(ImageServerError::FileTooLarge { max_size: limits.max_file_size }.into());
+    }
+
+    let (width, height) = match (query.width, query.height) {
+        (Some(width), Some(height)) => (width, height),
+        _ => {
+            return Err(ImageServerError::InvalidParameters(
+                "width and height query parameters are required".to_string(),
+            ).into());
+        }
+    };
+    let channels = query.channels.unwrap_or(4);
+    check_raw_dimensions(body.len(), width, height, channels, &config)?;
+
+    // 请求体是像素数据而不是表单，参数来自查询参数和请求头
+    let no_form = HashMap::new();
+    let target_format = resolve_param(query.format.clone(), &req, &no_form, "format", parse_text_param)
+        .map_or_else(|| "png".to_string(), |format| canonical_format(&format).to_string());
+    if !matches!(compression::normalize_format(&target_format), Some(Format::Jpeg | Format::Png | Format::Webp)) {
+        return Err(ImageServerError::InvalidParameters(format!(
+            "format must be jpeg, png or webp for raw pixels, got {}", target_format
+        )).into());
+    }
+    let quality = resolve_param(query.quality, &req, &no_form, "quality", parse_form_quality)
+        .or_else(|| config.compression.quality_for_format(&target_format))
+        .unwrap_or(config.compression.default_quality)
+        .clamp(compression::min_quality(&target_format), limits.max_quality);
+    let algorithm = resolve_param(query.algorithm.clone(), &req, &no_form, "algorithm", parse_text_param)
+        .unwrap_or_else(|| config.compression.default_algorithm.clone());
+    let algorithm = resolve_enabled_algorithm(algorithm, &config)?;
+
+    info!(
+        "Processing raw pixels: {}x{}x{} ({} bytes), quality: {}, format: {}, algorithm: {}",
+        width, height, channels, body.len(), quality, target_format, algorithm
+    );
+
+    let mut options = compression::CompressionOptions::new(&target_format, quality, &algorithm);
+    options.dither = compression::Dither::from_name(&config.compression.dither).unwrap_or_default();
+    options.max_quality = limits.max_quality;
+    options.thumbnail_quality_boost = config.compression.thumbnail_quality_boost;
+    options.thumbnail_max_dimension = config.compression.thumbnail_max_dimension;
+
+    let original_size = body.len();
+    let data = body.to_vec();
+    let compression_options = options.clone();
+    let permit = acquire_job_slot(queue.as_ref()).await?;
+    let result = encode_with_timeout(move || {
+        let _permit = permit;
+        let img = compression::image_from_raw(data, width, height, channels)?;
+        compression::compress_decoded(&[], img, compression::ExifInfo::unprocessed("Raw pixels"), &compression_options)
+    }, &algorithm, &config, None).await?;
+
+    match result {
+        Ok(result) => {
+            let output_size = result.data.len();
+            let provenance = compression::encoder_provenance(&options, result.effective_quality, &result.data);
+            info!("Successfully compressed raw pixels: {} -> {} bytes ({}x{})",
+                  original_size, output_size, result.width, result.height);
+            if let Some(metrics) = metrics.as_ref() {
+                metrics.record(&target_format, &algorithm, original_size, output_size);
+            }
+
+            Ok(HttpResponse::Ok()
+                .insert_header(("Content-Type", determine_output_content_type(&target_format)))
+                .insert_header(("Content-Length", output_size.to_string()))
+                .insert_header(("X-Effective-Quality", result.effective_quality.to_string()))
+                .insert_header(("X-Encoder", provenance.encoder))
+                .insert_header(("X-Encoder-Options", provenance.options))
+                .insert_header(ContentEncoding::Identity)
+                .insert_header(("X-Original-Size", original_size.to_string()))
+                .insert_header(("X-Compressed-Size", output_size.to_string()))
+                .insert_header(("X-Compression-Ratio", compression_ratio(original_size, output_size)))
+                .insert_header(("X-Image-Width", result.width.to_string()))
+                .insert_header(("X-Image-Height", result.height.to_string()))
+                .body(result.data))
+        }
+        Err(err @ ImageServerError::InvalidParameters(_)) => {
+            info!("Rejected raw compression request: {}", err);
+            Err(err.into())
+        }
+        Err(err) => {
+            error!("Raw compression failed: {}", err);
+            Ok(HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Compression failed: {}", err)
+            })))
+        }
+    }
+}
+
+/// 根据 DQT 量化表估算上传 JPEG 的原始编码质量，不解码像素
+pub async fn estimate_quality_endpoint(
+    req: HttpRequest,
+    mut payload: Multipart,
+    config: web::Data<Config>,
+) -> Result<HttpResponse> {
+    let limits = RequestLimits::for_request(&req, &config);
+    let (file_upload, _) = read_multipart(&mut payload, &config, limits.max_file_size, false).await?;
+
+    let file_upload = match file_upload {
+        Some(upload) => upload,
+        None => {
+            return Ok(missing_file_response(&config));
+        }
+    };
+
+    let estimate = crate::quality::estimate_jpeg_quality(&file_upload.data)?;
+    info!(
+        "Estimated quality of {} ({} bytes): {}",
+        file_upload.filename.as_deref().unwrap_or("unknown"),
+        file_upload.data.len(),
+        estimate.quality
+    );
+
+    let tables: Vec<serde_json::Value> = estimate
+        .tables
+        .iter()
+        .map(|(id, quality)| serde_json::json!({
+            "id": id,
+            "estimated_quality": quality,
+        }))
+        .collect();
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "estimated_quality": estimate.quality,
+        "tables": tables,
+    })))
+}
+
+/// `/compare` 接受的两个文件字段：原图和待评估的候选图
+pub const COMPARE_FILE_FIELDS: [&str; 2] = ["original", "candidate"];
+
+#[derive(Debug, Deserialize)]
+pub struct CompareQuery {
+    pub resize: Option<bool>,
+}
+
+/// 对比原图和候选图（如压缩后的结果），返回 PSNR、SSIM 和各通道平均误差
+pub async fn compare_endpoint(
+    req: HttpRequest,
+    mut payload: Multipart,
+    query: web::Query<CompareQuery>,
+    config: web::Data<Config>,
+    queue: Option<web::Data<JobQueue>>,
+) -> Result<HttpResponse> {
+    let limits = RequestLimits::for_request(&req, &config);
+    let mut uploads: HashMap<String, FileUpload> = HashMap::new();
+    let mut form_params = HashMap::new();
+
+    while let Some(field) = payload.try_next().await.map_err(multipart_error)? {
+        let field_name = field.name().to_string();
+        if COMPARE_FILE_FIELDS.contains(&field_name.as_str()) {
+            if uploads.contains_key(&field_name) {
+                return Err(ImageServerError::InvalidParameters(format!(
+                    "File field \"{}\" was sent more than once", field_name
+                )).into());
+            }
+            let upload = process_file_field(field, limits.max_file_size, None).await?;
+            uploads.insert(field_name, upload);
+        } else {
+            let value = process_text_field(field, &field_name, config.server.max_text_field_bytes).await?;
+            form_params.insert(field_name, value);
+        }
+    }
+
+    let (original, candidate) = match (uploads.remove("original"), uploads.remove("candidate")) {
+        (Some(original), Some(candidate)) => (original, candidate),
+        _ => {
+            return Err(ImageServerError::InvalidParameters(
+                "Both \"original\" and \"candidate\" file fields are required".to_string()
+            ).into());
+        }
+    };
+
+    for upload in [&original, &candidate] {
+        check_denied_types(&upload.data, &config)?;
+        check_image_dimensions(&upload.data, &config)?;
+    }
+
+    // 尺寸不同时是否把候选图缩放到原图尺寸再比较，默认直接拒绝
+    let resize = query.resize
+        .or_else(|| {
+            form_params.get("resize")
+                .and_then(|s| s.parse::<bool>().ok())
+        })
+        .unwrap_or(false);
+
+    info!(
+        "Comparing {} ({} bytes) with {} ({} bytes)",
+        original.filename.as_deref().unwrap_or("unknown"),
+        original.data.len(),
+        candidate.filename.as_deref().unwrap_or("unknown"),
+        candidate.data.len()
+    );
+
+    let (original_size, candidate_size) = (original.data.len(), candidate.data.len());
+    let permit = acquire_job_slot(queue.as_ref()).await?;
+    let comparison = web::block(logging::in_current_span(move || {
+        let _permit = permit;
+        crate::quality::compare_images(&original.data, &candidate.data, resize)
+    }))
+        .await
+        .map_err(|e| ImageServerError::ProcessingError(format!("Compare task failed: {}", e)))??;
+
+    let [r, g, b, a] = comparison.mean_error;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "width": comparison.width,
+        "height": comparison.height,
+        "original_size": original_size,
+        "candidate_size": candidate_size,
+        "candidate_resized": comparison.candidate_resized,
+        "psnr": comparison.psnr,
+        "ssim": comparison.ssim,
+        "mean_error": {
+            "r": r,
+            "g": g,
+            "b": b,
+            "a": a,
+        },
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SpriteQuery {
+    pub layout: Option<String>,
+    pub columns: Option<u32>,
+    pub padding: Option<u32>,
+    pub format: Option<String>,
+    pub quality: Option<u8>,
+    pub algorithm: Option<String>,
+    pub background: Option<String>,
+}
+
+/// 把多张上传图片按顺序合成一张 CSS 雪碧图，返回 base64 编码的图片和每张源图的位置
+///
+/// 所有文件字段（`file_field_names` 中的字段，可重复）按上传顺序排列，总大小受上传大小上限约束。
+pub async fn sprite_endpoint(
+    req: HttpRequest,
+    mut payload: Multipart,
+    query: web::Query<SpriteQuery>,
+    config: web::Data<Config>,
+    queue: Option<web::Data<JobQueue>>,
+) -> Result<HttpResponse> {
+    let limits = RequestLimits::for_request(&req, &config);
+    let mut uploads: Vec<FileUpload> = Vec::new();
+    let mut form_params = HashMap::new();
+    let mut total_size = 0;
+
+    while let Some(field) = payload.try_next().await.map_err(multipart_error)? {
+        let field_name = field.name().to_string();
+        if config.server.file_field_names.contains(&field_name) {
+            if uploads.len() == sprite::MAX_SPRITE_IMAGES {
+                return Err(ImageServerError::InvalidParameters(format!(
+                    "A sprite sheet can combine at most {} images", sprite::MAX_SPRITE_IMAGES
+                )).into());
+            }
+            let upload = process_file_field(field, limits.max_file_size, None).await?;
+            total_size += upload.data.len();
+            if total_size > limits.max_file_size {
+                return Err(ImageServerError::FileTooLarge { max_size: limits.max_file_size }.into());
+            }
+            uploads.push(upload);
+        } else {
+            let value = process_text_field(field, &field_name, config.server.max_text_field_bytes).await?;
+            form_params.insert(field_name, value);
+        }
+    }
+
+    if uploads.is_empty() {
+        return Ok(missing_file_response(&config));
+    }
+    for upload in &uploads {
+        check_denied_types(&upload.data, &config)?;
+        check_image_dimensions(&upload.data, &config)?;
+    }
+
+    let layout = match query.layout.clone().or_else(|| form_params.get("layout").cloned()) {
+        Some(name) => sprite::SpriteLayout::from_name(&name).ok_or_else(|| {
+            ImageServerError::InvalidParameters(format!(
+                "layout must be one of {:?}, got {}", sprite::SpriteLayout::NAMES, name
+            ))
+        })?,
+        None => sprite::SpriteLayout::default(),
+    };
+    let columns = query.columns
+        .or_else(|| {
+            form_params.get("columns")
+                .and_then(|s| s.parse::<u32>().ok())
+        });
+    if let Some(columns) = columns {
+        if layout != sprite::SpriteLayout::Grid || columns == 0 {
+            return Err(ImageServerError::InvalidParameters(format!(
+                "columns must be at least 1 and requires layout=grid, got {} with layout={}", columns, layout.name()
+            )).into());
+        }
+    }
+    let padding = query.padding
+        .or_else(|| {
+            form_params.get("padding")
+                .and_then(|s| s.parse::<u32>().ok())
+        })
+        .unwrap_or(0);
+    if padding > sprite::MAX_SPRITE_PADDING {
+        return Err(ImageServerError::InvalidParameters(format!(
+            "padding must be at most {}, got {}", sprite::MAX_SPRITE_PADDING, padding
+        )).into());
+    }
+
+    // 雪碧图本身就是需要透明度的场景，默认输出 PNG
+    let target_format = resolve_param(query.format.clone(), &req, &form_params, "format", parse_text_param)
+        .unwrap_or_else(|| "png".to_string());
+    if !matches!(compression::normalize_format(&target_format), Some(Format::Jpeg | Format::Png | Format::Webp)) {
+        return Err(ImageServerError::InvalidParameters(format!(
+            "format must be jpeg, png or webp for sprite sheets, got {}", target_format
+        )).into());
+    }
+    let target_format = canonical_format(&target_format).to_string();
+    let quality = resolve_param(query.quality, &req, &form_params, "quality", parse_form_quality)
+        .or_else(|| config.compression.quality_for_format(&target_format))
+        .unwrap_or(85)
+        .clamp(compression::min_quality(&target_format), limits.max_quality);
+    let algorithm = resolve_param(query.algorithm.clone(), &req, &form_params, "algorithm", parse_text_param)
+        .unwrap_or_else(|| config.compression.algorithm_for_input_format(&target_format).to_string());
+    let algorithm = resolve_enabled_algorithm(algorithm, &config)?;
+    // JPEG 无法保存间距和图片本身的透明区域，未指定时合成到白色背景上
+    let background = match query.background.clone().or_else(|| form_params.get("background").cloned()) {
+        Some(value) => Some(parse_hex_color(&value).ok_or_else(|| {
+            ImageServerError::InvalidParameters(format!("background must be a hex color such as ffffff, got {}", value))
+        })?),
+        None => compression::is_format(&target_format, Format::Jpeg).then_some([255, 255, 255]),
+    };
+
+    info!(
+        "Packing {} images ({} bytes) into a {} sprite sheet, format: {}, quality: {}",
+        uploads.len(),
+        total_size,
+        layout.name(),
+        target_format,
+        quality
+    );
+
+    let mut options = compression::CompressionOptions::new(&target_format, quality, &algorithm);
+    options.dither = compression::Dither::from_name(&config.compression.dither).unwrap_or_default();
+    options.max_quality = limits.max_quality;
+    options.background = background;
+    let names: Vec<String> = uploads
+        .iter()
+        .enumerate()
+        .map(|(index, upload)| upload.filename.clone().unwrap_or_else(|| format!("image-{}", index)))
+        .collect();
+    let sheet_config = config.clone();
+    let permit = acquire_job_slot(queue.as_ref()).await?;
+    let (data, plan) = web::block(logging::in_current_span(move || {
+        let _permit = permit;
+        let images = uploads
+            .iter()
+            .map(|upload| compression::decode_image(&upload.data, &options).map(|(img, _)| img))
+            .collect::<Result<Vec<_>, _>>()?;
+        let sizes: Vec<(u32, u32)> = images.iter().map(|img| (img.width(), img.height())).collect();
+        let plan = sprite::plan(&sizes, layout, columns, padding);
+        // 合成前检查雪碧图尺寸，避免按超大尺寸分配画布
+        check_dimensions(plan.width, plan.height, &sheet_config)?;
+        let sheet = sprite::compose(&images, &plan);
+        let data = compression::encode_image(image::DynamicImage::ImageRgba8(sheet), &options)?;
+        Ok::<_, ImageServerError>((data, plan))
+    }))
+        .await
+        .map_err(|e| ImageServerError::ProcessingError(format!("Sprite task failed: {}", e)))??;
+
+    let sprites: Vec<serde_json::Value> = names
+        .iter()
+        .zip(&plan.rects)
+        .map(|(name, rect)| serde_json::json!({
+            "name": name,
+            "x": rect.x,
+            "y": rect.y,
+            "width": rect.width,
+            "height": rect.height,
+        }))
+        .collect();
+    info!("Sprite sheet {}x{}, {} bytes", plan.width, plan.height, data.len());
+
+    Ok(HttpResponse::Ok()
+        .insert_header(("X-Image-Width", plan.width.to_string()))
+        .insert_header(("X-Image-Height", plan.height.to_string()))
+        .json(serde_json::json!({
+            "width": plan.width,
+            "height": plan.height,
+            "layout": layout.name(),
+            "format": target_format,
+            "content_type": determine_output_content_type(&target_format),
+            "size": data.len(),
+            "data": base64::engine::general_purpose::STANDARD.encode(&data),
+            "sprites": sprites,
+        })))
+}
+
+// 解析逗号分隔的宽度列表
+fn parse_widths(value: &str) -> Result<Vec<u32>, ImageServerError> {
+    let widths = value
+        .split(',')
+        .map(str::trim)
+        .filter(|w| !w.is_empty())
+        .map(|w| match w.parse::<u32>() {
+            Ok(width) if width > 0 => Ok(width),
+            _ => Err(ImageServerError::InvalidParameters(format!("Invalid width: {}", w))),
+        })
+        .collect::<Result<Vec<u32>, _>>()?;
+
+    if widths.is_empty() || widths.len() > MAX_PYRAMID_LEVELS {
+        return Err(ImageServerError::InvalidParameters(format!(
+            "widths must contain between 1 and {} values", MAX_PYRAMID_LEVELS
+        )));
+    }
+
+    Ok(widths)
+}
+
+// 请求头中声明的请求体长度，分块传输等未声明时为 None
+fn request_content_length(req: &HttpRequest) -> Option<u64> {
+    req.headers()
+        .get(actix_web::http::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+// 解析请求截止时间头
+fn parse_deadline(req: &HttpRequest) -> Result<Option<SystemTime>, ImageServerError> {
+    let value = match req.headers().get(DEADLINE_HEADER) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+
+    let millis = value
+        .to_str()
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .ok_or_else(|| {
+            ImageServerError::InvalidParameters(format!(
+                "{} must be a Unix timestamp in milliseconds", DEADLINE_HEADER
+            ))
+        })?;
+
+    Ok(Some(UNIX_EPOCH + Duration::from_millis(millis)))
+}
+
+// 距截止时间的剩余时长，已过期时返回 DeadlineExceeded
+fn remaining_budget(deadline: SystemTime) -> Result<Duration, ImageServerError> {
+    match deadline.duration_since(SystemTime::now()) {
+        Ok(remaining) if !remaining.is_zero() => Ok(remaining),
+        _ => Err(ImageServerError::DeadlineExceeded),
+    }
+}
+
+// 校验图片后原样返回上传的字节
+// 在阻塞线程池中解码，受 decode_timeout_secs 和请求截止时间中较早者限制
+//
+// 上传时已开始流式解码的直接等待其结果，流式解码失败时再从内存完整解码一次。
+// 超时后阻塞线程上的解码无法中断，只是不再等待其结果。
+async fn decode_with_timeout(
+    input: Vec<u8>,
+    streamed_decode: Option<StreamingDecode>,
+    options: &compression::CompressionOptions,
+    config: &Config,
+    deadline: Option<SystemTime>,
+) -> Result<(Vec<u8>, image::DynamicImage, compression::ExifInfo), ImageServerError> {
+    let decode_options = options.clone();
+    let decode_task = web::block(logging::in_current_span(move || {
+        let decoded = match streamed_decode.map(StreamingDecode::wait) {
+            Some(Ok(img)) => Ok(compression::finish_decode(&input, img, &decode_options)),
+            Some(Err(e)) => {
+                warn!("Streaming decode failed, decoding from memory: {}", e);
+                compression::decode_image(&input, &decode_options)
+            }
+            None => compression::decode_image(&input, &decode_options),
+        };
+        decoded.map(|(img, exif_info)| (input, img, exif_info))
+    }));
+
+    let decode_timeout_secs = config.compression.decode_timeout_secs;
+    let result = await_bounded(decode_task, decode_timeout_secs, deadline, "Decoding", || {
+        warn!("Decoding exceeded decode_timeout_secs ({}s)", decode_timeout_secs);
+        ImageServerError::DecodeTimeout { secs: decode_timeout_secs }
+    })
+    .await?;
+
+    result.map_err(|e| ImageServerError::ProcessingError(format!("Decode task failed: {}", e)))?
+}
+
+// 在阻塞线程池中编码，受 `algorithm` 的编码超时（见 `algorithm_timeout_secs`）和请求截止时间中较早者限制
+async fn encode_with_timeout<T: Send + 'static>(
+    encode: impl FnOnce() -> T + Send + 'static,
+    algorithm: &str,
+    config: &Config,
+    deadline: Option<SystemTime>,
+) -> Result<T, ImageServerError> {
+    let encode_task = web::block(logging::in_current_span(encode));
+    let encode_timeout_secs = config.compression.encode_timeout_secs_for(algorithm);
+    let result = await_bounded(encode_task, encode_timeout_secs, deadline, "Compression", || {
+        warn!("Encoding with {} exceeded {}s", algorithm, encode_timeout_secs);
+        ImageServerError::EncodeTimeout { algorithm: algorithm.to_string(), secs: encode_timeout_secs }
+    })
+    .await?;
+
+    result.map_err(|e| ImageServerError::ProcessingError(format!("Compression task failed: {}", e)))
+}
+
+// 等待 `task`，受 `limit_secs`（0 表示不限制）和请求截止时间中较早者限制，先到 `limit_secs` 时返回 `on_limit()`
+async fn await_bounded<T>(
+    task: impl std::future::Future<Output = T>,
+    limit_secs: u64,
+    deadline: Option<SystemTime>,
+    stage: &str,
+    on_limit: impl FnOnce() -> ImageServerError,
+) -> Result<T, ImageServerError> {
+    let limit = (limit_secs > 0).then(|| Duration::from_secs(limit_secs));
+    let deadline_budget = match deadline {
+        Some(deadline) => Some(remaining_budget(deadline)?),
+        None => None,
+    };
+
+    match (limit, deadline_budget) {
+        (None, None) => Ok(task.await),
+        (limit, budget) => {
+            // 取两者中较早到期的一个，并记住是哪一个以返回对应的错误
+            let (timeout, deadline_first) = match (limit, budget) {
+                (Some(limit), Some(budget)) if budget < limit => (budget, true),
+                (Some(limit), _) => (limit, false),
+                (None, budget) => (budget.unwrap_or_default(), true),
+            };
+            match tokio::time::timeout(timeout, task).await {
+                Ok(result) => Ok(result),
+                Err(_) if deadline_first => {
+                    warn!("{} exceeded request deadline after {}ms", stage, timeout.as_millis());
+                    Err(ImageServerError::DeadlineExceeded)
+                }
+                Err(_) => Err(on_limit()),
+            }
+        }
+    }
+}
+
+// 获取压缩任务名额；未注册队列时（如单元测试）不限制并发
+async fn acquire_job_slot(queue: Option<&web::Data<JobQueue>>) -> Result<Option<OwnedSemaphorePermit>, ImageServerError> {
+    match queue {
+        Some(queue) => queue.acquire().await.map(Some),
+        None => Ok(None),
+    }
+}
+
+async fn passthrough_response(file_upload: FileUpload) -> Result<HttpResponse> {
+    let data = file_upload.data;
+    let (data, validation) = web::block(logging::in_current_span(move || {
+        let validation = compression::validate_image(&data);
+        (data, validation)
+    }))
+    .await
+    .map_err(|e| ImageServerError::ProcessingError(format!("Validation task failed: {}", e)))?;
+
+    let (width, height, format) = match validation {
+        Ok(result) => result,
+        Err(err @ ImageServerError::UnsupportedFormat) => return Err(err.into()),
+        Err(err) => {
+            info!("Passthrough validation failed: {}", err);
+            return Err(ImageServerError::InvalidParameters(err.to_string()).into());
+        }
+    };
+
+    info!("Passthrough {:?} image {}x{}, {} bytes", format, width, height, data.len());
+
+    let filename = file_upload.filename.unwrap_or_else(|| {
+        format!("original.{}", format.extensions_str().first().copied().unwrap_or("bin"))
+    });
+
+    Ok(HttpResponse::Ok()
+        .insert_header(("Content-Type", format.to_mime_type()))
+        .insert_header(("Content-Length", data.len().to_string()))
+        .insert_header(ContentEncoding::Identity)
+        .insert_header(("X-Original-Size", data.len().to_string()))
+        .insert_header(("X-Compressed-Size", data.len().to_string()))
+        .insert_header(("X-Compression-Ratio", compression_ratio(data.len(), data.len())))
+        .insert_header(("X-Image-Width", width.to_string()))
+        .insert_header(("X-Image-Height", height.to_string()))
+        .insert_header(("X-Passthrough", "true"))
+        .insert_header(("Content-Disposition", content_disposition(&filename)))
+        .body(data))
+}
+
+// 构建存储命中时的响应，尺寸仅从图片头部读取而不完整解码
+// include_palette=true 时以 JSON 返回 base64 编码的图片和调色板
+fn palette_response(
+    file_upload: &FileUpload,
+    target_format: &str,
+    original_size: usize,
+    data: &[u8],
+    quantization_quality: Option<u8>,
+) -> HttpResponse {
+    // 量化质量过低回退为真彩色 PNG 时没有调色板
+    let palette = compression::read_png_palette(data);
+    let (width, height) = compression::read_dimensions(data).unwrap_or((0, 0));
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "filename": generate_output_filename(&file_upload.filename, target_format),
+        "content_type": determine_output_content_type(target_format),
+        "width": width,
+        "height": height,
+        "original_size": original_size,
+        "compressed_size": data.len(),
+        "quantization_quality": quantization_quality,
+        "png_path": if palette.is_some() { "quantized" } else { "lossless" },
+        "palette": palette.map(|palette| palette.iter().map(|entry| serde_json::json!({
+            "color": entry.hex(),
+            "alpha": entry.a,
+        })).collect::<Vec<_>>()),
+        "data": base64::engine::general_purpose::STANDARD.encode(data),
+    }))
+}
+
+fn hex_digest(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// 附上输入内容的 SHA-256，缓存命中和各种响应形式都带同一个值
+fn with_input_sha256(mut response: HttpResponse, input_sha256_hex: &str) -> HttpResponse {
+    if let Ok(value) = HeaderValue::from_str(input_sha256_hex) {
+        response.headers_mut().insert(HeaderName::from_static("x-content-sha256"), value);
+    }
+    response
+}
+
+// 负载降级时标记响应，包括存储命中：缓存键含降级后的参数，命中的同样是降级结果
+fn with_load_shed_header(mut response: HttpResponse, load_shed: bool) -> HttpResponse {
+    if load_shed {
+        response.headers_mut().insert(HeaderName::from_static("x-load-shed"), HeaderValue::from_static("true"));
+    }
+    response
+}
+
+// 图片和 JSON 元数据各占一个部分的 multipart/mixed 响应，metadata 中补充尺寸、压缩率和校验和等通用字段
+fn sidecar_response(
+    file_upload: &FileUpload,
+    target_format: &str,
+    original_size: usize,
+    data: Vec<u8>,
+    mut metadata: serde_json::Value,
+) -> HttpResponse {
+    let filename = generate_output_filename(&file_upload.filename, target_format);
+    let content_type = determine_output_content_type(target_format);
+    let sha1 = hex_digest(&Sha1::digest(&data));
+
+    if let Some(fields) = metadata.as_object_mut() {
+        fields.insert("filename".to_string(), serde_json::json!(filename));
+        fields.insert("content_type".to_string(), serde_json::json!(content_type));
+        fields.insert("original_size".to_string(), serde_json::json!(original_size));
+        fields.insert("compressed_size".to_string(), serde_json::json!(data.len()));
+        fields.insert("compression_ratio".to_string(), serde_json::json!(compression_ratio(original_size, data.len())));
+        fields.insert("sha1".to_string(), serde_json::json!(sha1));
+    }
+
+    let boundary = format!("img-server-{}", uuid::Uuid::new_v4().simple());
+    let mut body = Vec::with_capacity(data.len() + 1024);
+    body.extend_from_slice(format!(
+        "--{}\r\nContent-Type: {}\r\nContent-Disposition: {}\r\nContent-Length: {}\r\n\r\n",
+        boundary, content_type, content_disposition(&filename), data.len()
+    ).as_bytes());
+    body.extend_from_slice(&data);
+    body.extend_from_slice(format!(
+        "\r\n--{}\r\nContent-Type: application/json\r\nContent-Disposition: {}\r\n\r\n{}\r\n--{}--\r\n",
+        boundary, content_disposition(&format!("{}.json", filename)), metadata, boundary
+    ).as_bytes());
+
+    HttpResponse::Ok()
+        .insert_header(("Content-Type", format!("multipart/mixed; boundary={}", boundary)))
+        // 包含已压缩的图片，避免 Compress 中间件重复压缩
+        .insert_header(ContentEncoding::Identity)
+        .body(body)
+}
+
+fn exif_metadata(exif_info: &compression::ExifInfo) -> serde_json::Value {
+    serde_json::json!({
+        "summary": exif_info.summary,
+        "orientation": exif_info.orientation,
+        "orientation_applied": exif_info.orientation_applied,
+    })
 }
 
-pub async fn compress_endpoint(
-    mut payload: Multipart,
-    query: web::Query<CompressionQuery>,
-    config: web::Data<Config>,
-) -> Result<HttpResponse> {
+fn stored_response(
+    file_upload: &FileUpload,
+    target_format: &str,
+    source_progressive: Option<bool>,
+    stored_data: Vec<u8>,
+    stream: bool,
+) -> HttpResponse {
+    let (width, height) = image::io::Reader::new(std::io::Cursor::new(&stored_data))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_dimensions().ok())
+        .unwrap_or((0, 0));
+
+    let mut response = HttpResponse::Ok();
+    if let Some(progressive) = source_progressive {
+        response.insert_header(("X-Source-Progressive", progressive.to_string()));
+    }
+    insert_orientation_headers(&mut response, &compression::exif_info_for(&file_upload.data, target_format));
+
+    response
+        .insert_header(("Content-Type", determine_output_content_type(target_format)))
+        .insert_header(ContentEncoding::Identity)
+        .insert_header(("X-Original-Size", file_upload.data.len().to_string()))
+        .insert_header(("X-Compressed-Size", stored_data.len().to_string()))
+        .insert_header(("X-Compression-Ratio", compression_ratio(file_upload.data.len(), stored_data.len())))
+        .insert_header(("X-Image-Width", width.to_string()))
+        .insert_header(("X-Image-Height", height.to_string()))
+        .insert_header(("X-Storage-Cache", "HIT"))
+        .insert_header((
+            "Content-Disposition",
+            content_disposition(&generate_output_filename(&file_upload.filename, target_format)),
+        ));
+    image_body(response, stored_data, stream)
+}
+
+// 设置图片响应体：`stream` 时按 JPEG 扫描分块流式发送，否则一次性发送并带上 Content-Length
+// 分块前 `data` 已完整编码，调用方设置的大小和压缩比响应头对流式响应同样准确
+fn image_body(mut response: actix_web::HttpResponseBuilder, data: Vec<u8>, stream: bool) -> HttpResponse {
+    if !stream {
+        return response
+            .insert_header(("Content-Length", data.len().to_string()))
+            .body(data);
+    }
+
+    // 每块以一个扫描结尾，最后一块带上 EOI
+    let data = web::Bytes::from(data);
+    let mut ends = compression::jpeg_scan_ends(&data);
+    ends.pop();
+    let mut start = 0;
+    let mut chunks = Vec::new();
+    for end in ends.into_iter().chain(std::iter::once(data.len())) {
+        if end > start {
+            chunks.push(Ok::<_, actix_web::Error>(data.slice(start..end)));
+            start = end;
+        }
+    }
+    info!("Streaming progressive JPEG in {} chunks", chunks.len());
+
+    response
+        .insert_header(("X-Progressive-Scans", chunks.len().to_string()))
+        .streaming(futures::stream::iter(chunks))
+}
+
+// 结构化的 EXIF 方向信息，客户端无需解析 X-EXIF-Info 即可判断像素是否已被旋转
+fn insert_orientation_headers(response: &mut actix_web::HttpResponseBuilder, exif_info: &compression::ExifInfo) {
+    response.insert_header(("X-Orientation-Applied", exif_info.orientation_applied.to_string()));
+    if let Some(orientation) = exif_info.orientation {
+        response.insert_header(("X-Orientation-Value", orientation.to_string()));
+    }
+}
+
+// 读取 multipart 请求中的文件和其他文本字段
+async fn read_multipart(
+    payload: &mut Multipart,
+    config: &Config,
+    max_file_size: usize,
+    stream_decode: bool,
+) -> Result<(Option<FileUpload>, HashMap<String, String>)> {
     let mut file_upload: Option<FileUpload> = None;
     let mut form_params = HashMap::new();
 
     // Process multipart data
-    while let Some(field) = payload.try_next().await? {
+    while let Some(field) = payload.try_next().await.map_err(multipart_error)? {
         let field_name = field.name().to_string();
         
-        if field_name == "file" {
-            file_upload = Some(process_file_field(field, config.max_file_size_bytes()).await?);
+        if config.server.file_field_names.contains(&field_name) {
+            // 只处理一张图片，静默保留最后一个文件会让客户端难以排查；批量压缩使用 /compress/zip
+            if file_upload.is_some() {
+                return Err(ImageServerError::InvalidParameters(format!(
+                    "Multiple file fields are not allowed (second file in field \"{}\"); send one image per request or use /compress/zip for batches",
+                    field_name
+                )).into());
+            }
+            let stream_config = stream_decode.then_some(config);
+            file_upload = Some(process_file_field(field, max_file_size, stream_config).await?);
         } else {
             // Process other form fields (quality, algorithm, etc.)
-            let value = process_text_field(field).await?;
+            let value = process_text_field(field, &field_name, config.server.max_text_field_bytes).await?;
             form_params.insert(field_name, value);
         }
     }
 
-    let file_upload = match file_upload {
-        Some(upload) => upload,
-        None => {
-            return Ok(HttpResponse::BadRequest().json(serde_json::json!({
-                "error": "No file provided in 'file' field"
-            })));
+    Ok((file_upload, form_params))
+}
+
+// 区分上传中断（客户端断开、连接重置或请求体在结束分隔符前被截断，可以重试）和格式错误的请求体
+//
+// 已读取的流无法在服务端重读，中断时返回带 `retryable` 标记的 400，由客户端重新上传。
+fn multipart_error(e: MultipartError) -> ImageServerError {
+    match e {
+        MultipartError::Incomplete
+        | MultipartError::Payload(PayloadError::Incomplete(_))
+        | MultipartError::Payload(PayloadError::Io(_)) => {
+            warn!("Upload interrupted: {}", e);
+            ImageServerError::UploadInterrupted(e.to_string())
         }
+        e => ImageServerError::InvalidParameters(format!("Malformed multipart body: {}", e)),
+    }
+}
+
+fn is_multipart(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(actix_web::http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_lowercase().starts_with("multipart/form-data"))
+        .unwrap_or(false)
+}
+
+// 读取上传代理保存在共享目录中的文件，规范化路径后严格校验目录前缀以防止路径穿越
+async fn read_proxied_file(path: &str, config: &Config, max_size: usize) -> Result<FileUpload, ImageServerError> {
+    let allowed_dir = config.server.proxy_upload_dir.as_deref().ok_or_else(|| {
+        ImageServerError::AccessDenied(format!("{} uploads are not enabled", FILE_PATH_HEADER))
+    })?;
+
+    let allowed_dir = tokio::fs::canonicalize(allowed_dir).await.map_err(|e| {
+        ImageServerError::ProcessingError(format!("Invalid proxy_upload_dir: {}", e))
+    })?;
+
+    let requested = std::path::Path::new(path);
+    let requested = if requested.is_absolute() {
+        requested.to_path_buf()
+    } else {
+        allowed_dir.join(requested)
     };
 
-    // 根据目标格式确定输出格式
-    let target_format = match query.format.as_deref() {
-        Some(f) => f,
-        None => if file_upload.filename.as_deref().unwrap_or("").to_lowercase().ends_with(".png") {
-            "png"
-        } else {
-            "jpeg"
+    let resolved = tokio::fs::canonicalize(&requested).await.map_err(|_| {
+        ImageServerError::InvalidParameters(format!("File not found: {}", path))
+    })?;
+
+    if !resolved.starts_with(&allowed_dir) {
+        return Err(ImageServerError::AccessDenied(format!(
+            "{} is outside the allowed upload directory", path
+        )));
+    }
+
+    let metadata = tokio::fs::metadata(&resolved).await?;
+    if !metadata.is_file() {
+        return Err(ImageServerError::InvalidParameters(format!("Not a file: {}", path)));
+    }
+    if metadata.len() > max_size as u64 {
+        return Err(ImageServerError::FileTooLarge { max_size });
+    }
+
+    let mut upload = FileUpload::new();
+    upload.data = tokio::fs::read(&resolved).await?;
+    upload.filename = resolved
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.to_string());
+
+    info!("Read proxied upload {:?} ({} bytes)", resolved, upload.data.len());
+    Ok(upload)
+}
+
+// 解码前按图片头部的尺寸检查配置的上限，防止超大图片耗尽内存
+// 按魔数拒绝配置禁止的上传类型，在解码前执行；多语言文件命中任一类型即拒绝
+fn check_denied_types(data: &[u8], config: &Config) -> Result<(), ImageServerError> {
+    if config.server.denied_types.is_empty() {
+        return Ok(());
+    }
+    let denied = magic::detect_magic_types(data).into_iter().find(|mime_type| {
+        config.server.denied_types.iter().any(|denied| denied.eq_ignore_ascii_case(mime_type))
+    });
+    match denied {
+        Some(mime_type) => {
+            warn!("Rejected upload of denied type {}", mime_type);
+            Err(ImageServerError::DeniedType { mime_type })
         }
+        None => Ok(()),
+    }
+}
+
+fn check_image_dimensions(data: &[u8], config: &Config) -> Result<(), ImageServerError> {
+    match compression::read_dimensions(data) {
+        Some((width, height)) => check_dimensions(width, height, config),
+        None => Ok(()),
+    }
+}
+
+fn check_animation_limits(data: &[u8], config: &Config) -> Result<(), ImageServerError> {
+    let frames = animation::frame_count(data);
+    let (width, height) = match compression::read_dimensions(data) {
+        Some(dimensions) => dimensions,
+        None => return Ok(()),
     };
-    
-    // 设置质量
-    let quality = query.quality
-        .or_else(|| {
-            form_params.get("quality")
-                .and_then(|s| s.parse::<u8>().ok())
-        })
-        .unwrap_or(85)
-        .clamp(1, 100);
 
-    // 设置算法
-    let algorithm = query.algorithm.clone()
-        .or_else(|| form_params.get("algorithm").cloned())
-        .unwrap_or_else(|| config.compression.default_algorithm.clone());
+    let max_frames = config.server.max_animation_frames;
+    if max_frames > 0 && frames > max_frames as usize {
+        return Err(ImageServerError::AnimationTooLarge {
+            frames,
+            width,
+            height,
+            limit_name: "max_animation_frames",
+            limit: max_frames as u64,
+        });
+    }
 
-    info!(
-        "Processing file: {} ({} bytes) with quality: {}, format: {} (保持原始尺寸), algorithm: {}",
-        file_upload.filename.as_deref().unwrap_or("unknown"),
-        file_upload.data.len(),
-        quality,
-        target_format,
-        algorithm
-    );
-    
-    // Perform compression
-    match compression::compress_image(
-        &file_upload.data, 
-        target_format, 
-        quality,
-        &algorithm
-    ) {
-        Ok((compressed_data, width, height, exif_info)) => {
-            let output_size = compressed_data.len();
-            
-            info!("Compression successful, size: {} bytes, dimensions: {}x{}, EXIF: {}", 
-                  output_size, width, height, exif_info);
-            
-            let response = HttpResponse::Ok()
-                .insert_header(("Content-Type", determine_output_content_type(target_format)))
-                .insert_header(("Content-Length", output_size.to_string()))
-                // Add compression statistics to response headers
-                .insert_header(("X-Original-Size", file_upload.data.len().to_string()))
-                .insert_header(("X-Compressed-Size", output_size.to_string()))
-                .insert_header(("X-Image-Width", width.to_string()))
-                .insert_header(("X-Image-Height", height.to_string()))
-                .insert_header(("X-EXIF-Info", exif_info.clone()))
-                .insert_header((
-                    "Content-Disposition",
-                    format!(
-                        "attachment; filename=\"{}\"",
-                        generate_output_filename(&file_upload.filename, target_format)
-                    ),
-                ))
-                .body(compressed_data);
+    let max_pixels = config.server.max_image_pixels;
+    let total_pixels = (frames as u64).saturating_mul(width as u64 * height as u64);
+    if max_pixels > 0 && total_pixels > max_pixels {
+        return Err(ImageServerError::AnimationTooLarge {
+            frames,
+            width,
+            height,
+            limit_name: "max_image_pixels",
+            limit: max_pixels,
+        });
+    }
 
-            info!(
-                "Successfully compressed file: {} -> {} bytes ({}x{}), EXIF: {}",
-                file_upload.data.len(), output_size, width, height, exif_info
-            );
+    Ok(())
+}
 
-            Ok(response)
-        }
-        Err(err) => {
-            error!("Compression failed: {}", err);
-            Ok(HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": format!("Compression failed: {}", err)
-            })))
-        }
+fn check_dimensions(width: u32, height: u32, config: &Config) -> Result<(), ImageServerError> {
+    // 宽和高分别检查，错误中指明超限的方向
+    let max_dimension = config.server.max_image_dimension;
+    let oversized_axis = if max_dimension == 0 {
+        None
+    } else if width > max_dimension {
+        Some("width")
+    } else if height > max_dimension {
+        Some("height")
+    } else {
+        None
+    };
+    if let Some(axis) = oversized_axis {
+        return Err(ImageServerError::DimensionsTooLarge {
+            width,
+            height,
+            axis: Some(axis),
+            limit_name: "max_image_dimension",
+            limit: max_dimension as u64,
+        });
+    }
+
+    let max_pixels = config.server.max_image_pixels;
+    if max_pixels > 0 && width as u64 * height as u64 > max_pixels {
+        return Err(ImageServerError::DimensionsTooLarge {
+            width,
+            height,
+            axis: None,
+            limit_name: "max_image_pixels",
+            limit: max_pixels,
+        });
+    }
+
+    Ok(())
+}
+
+// 校验原始像素声明的尺寸：先限制像素数防止像素炸弹，再要求与请求体长度严格一致，避免按错误的步长解释数据
+fn check_raw_dimensions(
+    len: usize,
+    width: u32,
+    height: u32,
+    channels: u8,
+    config: &Config,
+) -> Result<(), ImageServerError> {
+    if !compression::RAW_CHANNELS.contains(&channels) {
+        return Err(ImageServerError::InvalidParameters(format!(
+            "channels must be one of {:?}, got {}", compression::RAW_CHANNELS, channels
+        )));
+    }
+    if width == 0 || height == 0 {
+        return Err(ImageServerError::InvalidParameters(format!(
+            "width and height must be greater than 0, got {}x{}", width, height
+        )));
+    }
+
+    let pixels = width as u64 * height as u64;
+    let max_pixels = config.server.max_raw_pixels;
+    if max_pixels > 0 && pixels > max_pixels {
+        return Err(ImageServerError::InvalidParameters(format!(
+            "Declared {}x{} is {} pixels, exceeding max_raw_pixels ({})", width, height, pixels, max_pixels
+        )));
+    }
+
+    let expected = pixels * channels as u64;
+    if expected != len as u64 {
+        return Err(ImageServerError::InvalidParameters(format!(
+            "Declared {}x{}x{} requires {} bytes, got {}", width, height, channels, expected, len
+        )));
+    }
+
+    Ok(())
+}
+
+// 请求是否携带了配置中的 API Key（X-Api-Key 或 Authorization: Bearer）
+fn has_valid_api_key(req: &HttpRequest, config: &Config) -> bool {
+    if config.server.api_keys.is_empty() {
+        return false;
+    }
+
+    let headers = req.headers();
+    let presented = headers
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .or_else(|| {
+            headers
+                .get(actix_web::http::header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+        })
+        .map(str::trim);
+
+    match presented {
+        Some(key) if !key.is_empty() => config
+            .server
+            .api_keys
+            .iter()
+            .any(|expected| constant_time_eq(expected.as_bytes(), key.as_bytes())),
+        _ => false,
+    }
+}
+
+// 逐字节比较全部内容，避免按前缀匹配长度泄露 API Key
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
     }
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn missing_file_response(config: &Config) -> HttpResponse {
+    HttpResponse::BadRequest().json(serde_json::json!({
+        "error": format!(
+            "No file provided in any of the fields: {}",
+            config.server.file_field_names.join(", ")
+        )
+    }))
 }
 
-async fn process_file_field(mut field: Field, max_size_bytes: usize) -> Result<FileUpload> {
+// `stream_config` 不为空时，识别出可流式解码的格式后把后续分块同时送入解码器
+async fn process_file_field(mut field: Field, max_size_bytes: usize, stream_config: Option<&Config>) -> Result<FileUpload> {
     let mut upload = FileUpload::new();
     
     // Get metadata
@@ -159,8 +2288,12 @@ async fn process_file_field(mut field: Field, max_size_bytes: usize) -> Result<F
     // Stream file data efficiently to handle large files
     let mut data = Vec::new();
     let mut total_size = 0;
+    // 分块到达时顺带计算摘要，缓存查找不必再遍历整个输入
+    let mut hasher = Sha256::new();
+    let mut streamed_decode: Option<StreamingDecode> = None;
+    let mut stream_config = stream_config;
     
-    while let Some(chunk) = field.try_next().await? {
+    while let Some(chunk) = field.try_next().await.map_err(multipart_error)? {
         total_size += chunk.len();
         
         // Use configured file size limit
@@ -169,23 +2302,48 @@ async fn process_file_field(mut field: Field, max_size_bytes: usize) -> Result<F
         }
         
         data.extend_from_slice(&chunk);
+        hasher.update(&chunk);
+
+        if let Some(decode) = &streamed_decode {
+            decode.push(chunk).await;
+        } else if let Some(config) = stream_config.filter(|_| data.len() >= streaming::STREAMING_SNIFF_LEN) {
+            // 只判断一次格式，其他格式仍在上传结束后解码
+            stream_config = None;
+            if let Ok(format) = image::guess_format(&data) {
+                if streaming::supports_streaming(format) {
+                    let config = config.clone();
+                    let decode = StreamingDecode::start(format, move |width, height| {
+                        check_dimensions(width, height, &config)
+                    });
+                    decode.push(web::Bytes::copy_from_slice(&data)).await;
+                    streamed_decode = Some(decode);
+                }
+            }
+        }
     }
 
+    if let Some(decode) = streamed_decode.as_mut() {
+        decode.finish_input();
+    }
     upload.data = data;
+    upload.sha256 = Some(hasher.finalize().into());
+    upload.streamed_decode = streamed_decode;
     Ok(upload)
 }
 
-async fn process_text_field(mut field: Field) -> Result<String> {
+/// 读取文本表单字段：超过 `max_len` 字节返回 413（与文件过大一致），内容不是 UTF-8 返回 400
+async fn process_text_field(mut field: Field, field_name: &str, max_len: usize) -> Result<String> {
     let mut data = Vec::new();
     
-    while let Some(chunk) = field.try_next().await? {
+    while let Some(chunk) = field.try_next().await.map_err(multipart_error)? {
         data.extend_from_slice(&chunk);
         
         // Prevent excessively long text fields
-        if data.len() > 1024 {
-            return Err(ImageServerError::InvalidParameters(
-                "Text field too long".to_string()
-            ).into());
+        if data.len() > max_len {
+            return Err(ImageServerError::FieldTooLarge {
+                field: field_name.to_string(),
+                max_size: max_len,
+            }.into());
         }
     }
     
@@ -195,15 +2353,155 @@ async fn process_text_field(mut field: Field) -> Result<String> {
         ).into())
 }
 
-fn determine_output_content_type(format: &str) -> &'static str {
-    match format.to_lowercase().as_str() {
-        "jpeg" | "jpg" => "image/jpeg",
-        "png" => "image/png",
-        "webp" => "image/webp",
-        _ => "application/octet-stream",
+/// 通过请求头传递参数时的前缀，如 `X-Param-Quality: 70`
+pub const PARAM_HEADER_PREFIX: &str = "X-Param-";
+
+/// 按统一的优先级读取 `name` 参数：查询参数 > 请求头 `X-Param-<name>` > 表单字段
+///
+/// 查询参数已由 serde 解析；请求头和表单字段用 `parse` 解析，无法解析的值视为未提供。
+/// 都没有时返回 `None`，由调用方依次使用参数组合和配置中的默认值。
+pub fn resolve_param<T>(
+    query: Option<T>,
+    req: &HttpRequest,
+    form_params: &HashMap<String, String>,
+    name: &str,
+    parse: impl Fn(&str) -> Option<T>,
+) -> Option<T> {
+    query
+        .or_else(|| {
+            req.headers()
+                .get(format!("{}{}", PARAM_HEADER_PREFIX, name).as_str())
+                .and_then(|value| value.to_str().ok())
+                .and_then(&parse)
+        })
+        .or_else(|| form_params.get(name).and_then(|value| parse(value)))
+}
+
+/// 文本参数：去掉首尾空白，空值视为未提供
+pub fn parse_text_param(value: &str) -> Option<String> {
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// 解析表单中的质量值，超出范围时收敛到 0-100 而不是被忽略，无法解析时返回 `None`
+///
+/// 各格式的最低质量（PNG 为 0，其他为 1）在确定输出格式后再收敛。
+pub fn parse_form_quality(value: &str) -> Option<u8> {
+    value
+        .trim()
+        .parse::<i32>()
+        .ok()
+        .map(|quality| quality.clamp(0, 100) as u8)
+}
+
+/// 压缩后大小占原始大小的百分比，保留两位小数
+///
+/// actix-web 不支持 HTTP trailer。`stream=true` 时输出在分块发送前已经完整编码，`X-Compressed-Size`、
+/// `X-Compression-Ratio` 与非流式响应一样在开始发送前写入响应头。
+pub fn compression_ratio(original_size: usize, compressed_size: usize) -> String {
+    if original_size == 0 {
+        return "0.00".to_string();
+    }
+    format!("{:.2}", compressed_size as f64 / original_size as f64 * 100.0)
+}
+
+/// 解析 `rrggbb`、`rgb` 形式的十六进制颜色，允许带 `#` 前缀
+pub fn parse_hex_color(value: &str) -> Option<[u8; 3]> {
+    let hex = value.trim().trim_start_matches('#');
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    match hex.len() {
+        6 => Some([
+            u8::from_str_radix(&hex[0..2], 16).ok()?,
+            u8::from_str_radix(&hex[2..4], 16).ok()?,
+            u8::from_str_radix(&hex[4..6], 16).ok()?,
+        ]),
+        3 => {
+            let mut rgb = [0u8; 3];
+            for (i, c) in hex.chars().enumerate() {
+                let v = c.to_digit(16)? as u8;
+                rgb[i] = v * 17;
+            }
+            Some(rgb)
+        }
+        _ => None,
+    }
+}
+
+/// 未指定输出格式时按检测到的输入格式输出，输入格式没有编码器时回退到 JPEG
+///
+/// 无法识别输入格式时沿用按文件扩展名判断的旧规则。
+/// 请求未指定算法时按检测到的输入格式选择默认算法，未配置或无法识别时使用全局 `default_algorithm`
+pub fn default_algorithm(file_upload: &FileUpload, config: &Config) -> String {
+    let input_format = image::guess_format(&file_upload.data)
+        .ok()
+        .and_then(|format| format.extensions_str().first().copied());
+    match input_format {
+        Some(format) => config.compression.algorithm_for_input_format(format).to_string(),
+        None => config.compression.default_algorithm.clone(),
+    }
+}
+
+/// 请求的算法被禁用时按配置返回 400，或改用 `default_algorithm`（校验保证其未被禁用）
+///
+/// 开启 `strict_algorithm` 时未知算法同样返回 400，而不是按 mozjpeg 处理。
+pub fn resolve_enabled_algorithm(algorithm: String, config: &Config) -> Result<String, ImageServerError> {
+    if config.compression.strict_algorithm
+        && !compression::ALGORITHMS.iter().any(|known| known.eq_ignore_ascii_case(&algorithm))
+    {
+        return Err(ImageServerError::InvalidParameters(format!(
+            "Unknown algorithm {}. Must be one of: {:?}",
+            algorithm, compression::ALGORITHMS
+        )));
+    }
+
+    if !config.compression.is_algorithm_disabled(&algorithm) {
+        return Ok(algorithm);
+    }
+
+    if config.compression.disabled_algorithm_fallback {
+        warn!(
+            "Algorithm {} is disabled, falling back to {}",
+            algorithm, config.compression.default_algorithm
+        );
+        Ok(config.compression.default_algorithm.clone())
+    } else {
+        Err(ImageServerError::InvalidParameters(format!("algorithm {} is disabled on this server", algorithm)))
+    }
+}
+
+// 按名称查找配置的压缩参数组合，未知名称返回 400 并列出可用的名称
+fn resolve_profile<'a>(name: &str, config: &'a Config) -> Result<&'a CompressionProfile, ImageServerError> {
+    config.compression.profiles.get(name).ok_or_else(|| {
+        let mut names: Vec<&str> = config.compression.profiles.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        ImageServerError::InvalidParameters(format!("Unknown profile {}. Available profiles: {:?}", name, names))
+    })
+}
+
+/// 规范化请求中的格式名（`jpg` → `jpeg`），无法识别的格式原样返回，由编码阶段报错
+fn canonical_format(format: &str) -> &str {
+    match compression::normalize_format(format) {
+        Some(format) => format.name(),
+        None => format,
     }
 }
 
+pub fn default_output_format(file_upload: &FileUpload) -> &'static str {
+    let extension = file_upload
+        .filename
+        .as_deref()
+        .and_then(|name| std::path::Path::new(name).extension())
+        .and_then(|extension| extension.to_str());
+    compression::default_format_for_input(&file_upload.data, extension).name()
+}
+
+fn determine_output_content_type(format: &str) -> &'static str {
+    compression::normalize_format(format).map_or("application/octet-stream", Format::content_type)
+}
+
 fn generate_output_filename(
     original_filename: &Option<String>,
     format: &str,
@@ -219,14 +2517,48 @@ fn generate_output_filename(
         })
         .unwrap_or_else(|| format!("compressed_{}", uuid::Uuid::new_v4()));
 
-    let extension = match format.to_lowercase().as_str() {
-        "jpeg" | "jpg" => "jpg",
-        "png" => "png",
-        "webp" => "webp",
-        _ => "bin",
-    };
+    format!("{}_compressed.{}", base_name, output_extension(format))
+}
+
+/// 下载附件的 Content-Disposition
+///
+/// 响应头只能包含 ASCII：`filename` 为把非 ASCII、引号和反斜杠替换成 `_` 的回退名，
+/// 文件名需要替换时再按 RFC 5987 追加 UTF-8 百分号编码的 `filename*`，支持的浏览器优先使用后者。
+pub fn content_disposition(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| if c == ' ' || (c.is_ascii_graphic() && c != '"' && c != '\\') { c } else { '_' })
+        .collect();
+    if fallback == filename {
+        return format!("attachment; filename=\"{}\"", filename);
+    }
+
+    // RFC 5987 attr-char 之外的字节都按 %XX 编码
+    let encoded: String = filename
+        .bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{:02X}", b)
+            }
+        })
+        .collect();
+    format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback, encoded)
+}
+
+fn output_extension(format: &str) -> &'static str {
+    compression::normalize_format(format).map_or("bin", Format::extension)
+}
 
-    format!("{}_compressed.{}", base_name, extension)
+/// 处理上传接口的 CORS 预检请求，避免预检请求进入 multipart 解析
+pub async fn preflight() -> HttpResponse {
+    HttpResponse::NoContent()
+        .insert_header(("Access-Control-Allow-Origin", "*"))
+        .insert_header(("Access-Control-Allow-Methods", CORS_ALLOW_METHODS))
+        .insert_header(("Access-Control-Allow-Headers", CORS_ALLOW_HEADERS))
+        .insert_header(("Access-Control-Max-Age", CORS_MAX_AGE_SECS.to_string()))
+        .finish()
 }
 
 pub async fn health_check() -> Result<HttpResponse> {
@@ -237,45 +2569,424 @@ pub async fn health_check() -> Result<HttpResponse> {
     })))
 }
 
+/// 未提供静态文件时 `/` 的响应，按 `static_files.root_response` 返回服务说明、重定向或 404
+pub async fn root_endpoint(config: web::Data<Config>) -> Result<HttpResponse> {
+    let static_config = &config.static_files;
+    Ok(match static_config.root_response.as_str() {
+        "redirect" => HttpResponse::Found()
+            .insert_header((LOCATION, static_config.root_redirect.as_str()))
+            .finish(),
+        "not_found" => HttpResponse::NotFound().finish(),
+        _ => HttpResponse::Ok().json(serde_json::json!({
+            "service": "image-compression-server",
+            "version": env!("CARGO_PKG_VERSION"),
+            "endpoints": {
+                "health": "/health",
+                "info": "/info",
+                "metrics": "/metrics",
+                "compress": "/compress",
+                "compress_raw": "/compress/raw",
+                "compress_zip": "/compress/zip",
+                "pyramid": "/pyramid",
+                "analyze": "/analyze",
+                "estimate_quality": "/estimate-quality",
+                "compare": "/compare",
+                "sprite": "/sprite"
+            }
+        })),
+    })
+}
+
+// 压缩收益指标：名称、类型、说明和从每组统计中取值的函数
+type SavingsSeries = (&'static str, &'static str, &'static str, fn(&SavingsStats) -> i64);
+
+/// Prometheus 文本格式的运行指标，目前包含压缩队列状态
+pub async fn metrics_endpoint(
+    queue: Option<web::Data<JobQueue>>,
+    metrics: Option<web::Data<CompressionMetrics>>,
+) -> Result<HttpResponse> {
+    let mut body = String::new();
+    if let Some(queue) = queue {
+        let stats = queue.stats();
+        let metrics: [(&str, &str, &str, u64); 4] = [
+            ("img_server_queue_depth", "gauge", "Requests waiting for a compression slot", stats.depth as u64),
+            ("img_server_jobs_in_flight", "gauge", "Compression jobs currently running", stats.in_flight as u64),
+            ("img_server_max_concurrent_jobs", "gauge", "Configured compression slots", stats.max_jobs as u64),
+            ("img_server_queue_rejected_total", "counter", "Requests rejected with 503 after waiting for a slot", stats.rejected),
+        ];
+        for (name, kind, help, value) in metrics {
+            body.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n{} {}\n", name, help, name, kind, name, value));
+        }
+    }
+
+    // 按输出格式和算法拆分的压缩收益，节省字节数在输出变大时可能为负，因此是 gauge
+    if let Some(metrics) = metrics {
+        let savings = metrics.savings();
+        let series: [SavingsSeries; 4] = [
+            ("img_server_compressions_total", "counter", "Successful compressions", |s| s.compressions as i64),
+            ("img_server_original_bytes_total", "counter", "Input bytes of successful compressions", |s| s.original_bytes as i64),
+            ("img_server_compressed_bytes_total", "counter", "Output bytes of successful compressions", |s| s.compressed_bytes as i64),
+            ("img_server_bytes_saved", "gauge", "Input bytes minus output bytes, negative when outputs grew", |s| s.bytes_saved()),
+        ];
+        for (name, kind, help, value) in series {
+            body.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+            for ((format, algorithm), stats) in &savings {
+                body.push_str(&format!(
+                    "{}{{format=\"{}\",algorithm=\"{}\"}} {}\n",
+                    name, format, algorithm, value(stats)
+                ));
+            }
+        }
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body))
+}
+
+// 未启用 mozjpeg 特性时 mozjpeg 算法会退回 jpeg-encoder，因此不对外列出
+fn supported_algorithms(config: &Config) -> Vec<serde_json::Value> {
+    let mut algorithms = Vec::new();
+    if cfg!(feature = "mozjpeg") {
+        algorithms.push(serde_json::json!({
+            "name": "mozjpeg",
+            "description": "Mozilla JPEG encoder (high quality)",
+            "output_format": "image/jpeg"
+        }));
+    }
+    algorithms.push(serde_json::json!({
+        "name": "jpeg-encoder",
+        "description": "Fast JPEG encoder",
+        "output_format": "image/jpeg"
+    }));
+    algorithms.push(serde_json::json!({
+        "name": "png-quantized",
+        "description": "PNG with color quantization",
+        "output_format": "image/png"
+    }));
+    algorithms.push(serde_json::json!({
+        "name": "none",
+        "description": "Validate the image and return the original bytes unchanged (same as format=original)",
+        "output_format": "original"
+    }));
+    algorithms.retain(|algorithm| {
+        !algorithm["name"].as_str().is_some_and(|name| config.compression.is_algorithm_disabled(name))
+    });
+    algorithms
+}
+
 pub async fn info_endpoint(config: web::Data<Config>) -> Result<HttpResponse> {
+    // 分成几个较小的 json! 调用，单个宏展开过深会超出编译器的递归上限
+    let config_info = serde_json::json!({
+        "max_file_size_mb": config.server.max_file_size_mb,
+        "cors_endpoints": if config.server.enable_cors { config.server.cors_endpoints.clone() } else { Vec::new() },
+        "file_field_names": config.server.file_field_names,
+        "max_text_field_bytes": config.server.max_text_field_bytes,
+        "max_image_dimension": config.server.max_image_dimension,
+        "max_image_pixels": config.server.max_image_pixels,
+        "max_animation_frames": config.server.max_animation_frames,
+        "denied_types": config.server.denied_types,
+        "default_quality": config.compression.default_quality,
+        "default_algorithm": config.compression.default_algorithm,
+        "skip_below_bytes": config.compression.skip_below_bytes,
+        "format_quality": config.compression.format_quality,
+        "default_algorithm_by_input_format": config.compression.default_algorithm_by_input_format,
+        "disabled_algorithms": config.compression.disabled_algorithms,
+        "strict_algorithm": config.compression.strict_algorithm,
+        "decode_timeout_secs": config.compression.decode_timeout_secs,
+        "streaming_decode_min_bytes": config.compression.streaming_decode_min_bytes,
+        "fast_jpeg_encoder_min_bytes": config.compression.fast_jpeg_encoder_min_bytes,
+        "resize_filter_default": config.compression.resize_filter_default,
+        "resize_fast_downscale_factor": config.compression.resize_fast_downscale_factor,
+        "verify_output": config.compression.verify_output,
+        "validate_output_fallback_algorithm": config.compression.validate_output_fallback_algorithm,
+        "clamp_to_source_quality": config.compression.clamp_to_source_quality,
+        "source_quality_margin": config.compression.source_quality_margin,
+        "legacy_user_agents": config.compression.legacy_user_agents,
+        "png_min_quantization_quality": config.compression.png_min_quantization_quality,
+        "size_search_max_iterations": config.compression.size_search_max_iterations,
+        "size_search_tolerance_percent": config.compression.size_search_tolerance_percent,
+        "profiles": config.compression.profiles,
+        "max_concurrent_jobs": config.compression.max_concurrent_jobs,
+        "queue_wait_timeout_ms": config.compression.queue_wait_timeout_ms,
+        "anonymous_max_quality": config.server.anonymous_max_quality,
+        "anonymous_max_file_size_mb": config.server.anonymous_max_file_size_mb,
+        "load_shedding": config.load_shedding
+    });
+    let query_parameters = serde_json::json!({
+        "format": "Output format: jpeg, png, webp, original, apng (lossless animated PNG from animated WebP/GIF, keeping frame delays), or auto (webp when Accept lists image/webp and the User-Agent is not in legacy_user_agents, otherwise the default; reported in X-Auto-Format and X-Auto-Format-Reason) (optional, default: same as the input when it can be encoded, otherwise jpeg)",
+        "quality": "Alternative way to specify quality",
+        "algorithm": "Alternative way to specify algorithm",
+        "png_depth": "Indexed PNG bit depth: 1, 2, 4 or 8 (optional, default: smallest fitting the palette)",
+        "png_quantize_speed": "Indexed PNG quantization speed from 1 (slowest, best palette) to 10 (fastest); higher speeds trade a little quality for much faster quantization of large images (optional, default: 4)",
+        "dither": format!("Indexed PNG dithering: auto, none, full or a level 0.0-1.0; auto disables it when the quantized palette has at most {} colors (optional, default: {})", compression::AUTO_DITHER_MAX_COLORS, config.compression.dither),
+        "preserve_progressive": "Keep progressive encoding when the source JPEG is progressive (optional, default: false)",
+        "color_space": format!("Output color space: original or srgb (converts wide-gamut pixels using the embedded ICC profile; images without one are assumed sRGB) (optional, default: {})", config.compression.color_space),
+        "comment": format!("Text embedded as a JPEG COM marker or PNG \"Comment\" text chunk; at most {} bytes, no control characters (optional)", compression::MAX_COMMENT_LEN),
+        "xmp": format!("XMP packet embedded as a JPEG APP1 segment or PNG \"XML:com.adobe.xmp\" iTXt chunk; must be well-formed XML with one root element, at most {} bytes (optional)", compression::MAX_XMP_LEN),
+        "include_palette": "Return JSON with base64 image data and the quantized palette as #rrggbb colors with alpha; requires format=png (optional, default: false)",
+        "stream": "Encode a progressive JPEG and stream it chunked with one scan per chunk so clients can render blurry-then-sharp as data arrives; X-Compressed-Size and X-Compression-Ratio are still sent as headers because the output is fully encoded before streaming starts; requires format=jpeg, cannot be combined with sidecar or include_palette, and jpeg_mode must be unset or progressive (optional, default: false)",
+        "sidecar": "Return multipart/mixed with the image part followed by a JSON part holding sizes, ratio, dimensions, quality, encoder, encoder options, EXIF summary and the output SHA-1; cannot be combined with include_palette (optional, default: false)",
+        "reject_animated": "Respond 400 with error animated_input and the frame count when the input is an animated GIF, WebP or APNG instead of compressing its first frame (optional, default: false)",
+        "validate_output": "Decode the output before returning it and respond 500 with error output_validation_failed if it is unreadable, or re-encode with validate_output_fallback_algorithm when configured (optional, default: false)",
+        "ops": "Ordered pre-processing applied before encoding, separated by ';' or as a JSON array of strings: auto-orient, rotate:90|180|270, flip:h|v, crop:x,y,width,height, resize:WIDTHxHEIGHT (fit inside, either side optional, never enlarges), sharpen[:sigma[,threshold]], grayscale, flatten[:rrggbb] (optional, at most 16 operations; not supported for APNG)",
+        "lossless_rotate": "For baseline JPEG input and output whose ops only contain auto-orient, rotate and flip, rotate/flip in the DCT domain without re-encoding: keeps the source quality and all metadata, ignores quality and algorithm, and reports X-Lossless-Transform. Falls back to re-encoding when other operations or re-quantizing parameters are requested, or the flipped side is not a whole number of MCUs (optional, default: false; requires format=jpeg)",
+        "profile": "Name of a compression profile from [compression.profiles] supplying defaults for format, quality, algorithm and other parameters; parameters given in the request override it (optional)",
+        "deterministic": "Quantize indexed PNG output on a single thread so the same input and parameters always produce byte-identical output on the same server version; JPEG and WebP output is always deterministic (optional, default: false)",
+        "preserve_exif": "Copy WhiteBalance, ExposureTime, FNumber and ISOSpeedRatings from the source EXIF into JPEG output (optional, default: false)",
+        "background": "Hex color used to fill transparent areas for JPEG output, e.g. ffffff (optional; without it transparent images are rejected for JPEG)",
+        "quant_table": "mozjpeg quantization table (optional, default: default)",
+        "luma_quality": "mozjpeg quality (1-100) for the luma quantization table (optional, default: quality)",
+        "chroma_quality": "mozjpeg quality (1-100) for the chroma quantization table; lower than luma_quality keeps detail while compressing color harder (optional, default: quality)",
+        "jpeg_mode": "mozjpeg encode mode: baseline (single scan, standard Huffman tables), progressive, or optimized (single scan, optimized Huffman tables); overrides preserve_progressive (optional, default: mozjpeg defaults)",
+        "target_ssim": "Target SSIM between 0 and 1; searches for the lowest JPEG quality that reaches it and ignores quality (optional)",
+        "max_bytes": format!("Maximum output size in bytes; binary-searches for the highest JPEG quality that fits in at most {} encodes, stopping early within {}% below the target, and ignores quality. Reports X-Chosen-Quality, X-Size-Search-Iterations and X-Max-Bytes-Met (false when even quality 1 is too large; the smallest output is returned) (optional; requires format=jpeg, cannot be combined with target_ssim)", config.compression.size_search_max_iterations, config.compression.size_search_tolerance_percent)
+    });
     Ok(HttpResponse::Ok().json(serde_json::json!({
         "service": "Image Compression Server",
         "version": env!("CARGO_PKG_VERSION"),
-        "config": {
-            "max_file_size_mb": config.server.max_file_size_mb,
-            "default_quality": config.compression.default_quality,
-            "default_algorithm": config.compression.default_algorithm,
+        "config": config_info,
+        "supported_algorithms": supported_algorithms(&config),
+        "heif_input": cfg!(feature = "libheif"),
+        "pyramid": {
+            "endpoint": "/pyramid",
+            "method": "POST",
+            "content_type": "multipart/form-data",
+            "parameters": {
+                "file": "Image file (required)",
+                "widths": format!("Comma-separated output widths, up to {} (required)", MAX_PYRAMID_LEVELS),
+                "quality": "Compression quality 1-100 (optional)",
+                "format": "Output format (optional)",
+                "algorithm": "Compression algorithm (optional)"
+            },
+            "response": "JSON with base64-encoded image per width"
         },
-        "supported_algorithms": [
-            {
-                "name": "mozjpeg",
-                "description": "Mozilla JPEG encoder (high quality)",
-                "output_format": "image/jpeg"
+        "analyze": {
+            "endpoint": "/analyze",
+            "method": "POST",
+            "content_type": "multipart/form-data",
+            "parameters": {
+                "file": "Image file (required)",
+                "quality": format!("Compression quality 1-100 (optional, default: {})", config.compression.default_quality)
             },
-            {
-                "name": "jpeg-encoder",
-                "description": "Fast JPEG encoder",
-                "output_format": "image/jpeg"
+            "response": "JSON comparing JPEG and quantized PNG size and SSIM, with recommended_format"
+        },
+        "estimate_quality": {
+            "endpoint": "/estimate-quality",
+            "method": "POST",
+            "content_type": "multipart/form-data",
+            "parameters": {
+                "file": "JPEG file (required)"
             },
-            {
-                "name": "png-quantized",
-                "description": "PNG with color quantization",
-                "output_format": "image/png"
-            }
-        ],
+            "response": "JSON with estimated_quality (1-100) derived from the quantization tables, plus a per-table estimate"
+        },
+        "compare": {
+            "endpoint": "/compare",
+            "method": "POST",
+            "content_type": "multipart/form-data",
+            "parameters": {
+                "original": "Reference image file (required)",
+                "candidate": "Image to measure against the original, e.g. its compressed version (required)",
+                "resize": "Scale the candidate to the original's dimensions when they differ instead of returning 400 (optional, default: false)"
+            },
+            "response": "JSON with psnr in dB over RGB (null when identical), luma ssim and per-channel mean_error (r, g, b, a)"
+        },
+        "sprite": {
+            "endpoint": "/sprite",
+            "method": "POST",
+            "content_type": "multipart/form-data",
+            "parameters": {
+                "file": format!("Image files in sprite order; repeat the file field, up to {} images whose combined size counts against the upload limit (required)", sprite::MAX_SPRITE_IMAGES),
+                "layout": format!("One of {:?}: a single top-aligned row, a single left-aligned column, or equal cells sized to the largest image (optional, default: horizontal)", sprite::SpriteLayout::NAMES),
+                "columns": "Grid columns (optional, default: ceil(sqrt(n)); requires layout=grid)",
+                "padding": format!("Transparent gap between images in pixels, at most {} (optional, default: 0)", sprite::MAX_SPRITE_PADDING),
+                "format": "Output format: png, jpeg or webp (optional, default: png)",
+                "quality": "Compression quality 1-100 (optional)",
+                "algorithm": "Compression algorithm (optional)",
+                "background": "Hex color filling transparent areas for JPEG output (optional, default: ffffff)"
+            },
+            "response": "JSON with the base64-encoded sheet, its width and height, and sprites listing each file's name, x, y, width and height in upload order"
+        },
+        "raw": {
+            "endpoint": "/compress/raw",
+            "method": "POST",
+            "content_type": "application/octet-stream",
+            "parameters": {
+                "width": "Declared width in pixels (required)",
+                "height": "Declared height in pixels (required)",
+                "channels": "1 (grayscale), 3 (RGB) or 4 (RGBA); the body must be exactly width*height*channels bytes (optional, default: 4)",
+                "format": "Output format: jpeg, png or webp (optional, default: png)",
+                "quality": format!("Compression quality 1-100, or 0-100 for png (optional, default: {})", config.compression.default_quality),
+                "algorithm": format!("Compression algorithm (optional, default: {})", config.compression.default_algorithm)
+            },
+            "max_raw_pixels": config.server.max_raw_pixels
+        },
+        "zip": {
+            "endpoint": "/compress/zip",
+            "method": "POST",
+            "content_type": "application/zip",
+            "parameters": {
+                "format": "Output format for every image: jpeg, png or webp (optional, default: png for PNG inputs, otherwise jpeg)",
+                "quality": "Compression quality 1-100, or 0-100 for png (optional)",
+                "algorithm": "Compression algorithm (optional, default: by input format)"
+            },
+            "max_entries": archive::MAX_ZIP_ENTRIES,
+            "name_collision": config.server.zip_name_collision,
+            "response": format!("ZIP with the compressed images at their original paths plus {} listing each entry as compressed, skipped or failed; entries identical to an earlier one (by SHA-256) reuse its result, name it in duplicate_of and are counted in X-Zip-Deduplicated", archive::ZIP_REPORT_NAME)
+        },
         "usage": {
             "endpoint": "/compress",
             "method": "POST",
             "content_type": "multipart/form-data",
             "parameters": {
                 "file": "Image file to compress (required)",
-                "quality": format!("Compression quality 1-100 (optional, default: {})", config.compression.default_quality),
-                "algorithm": format!("Compression algorithm (optional, default: {})", config.compression.default_algorithm)
+                "quality": format!("Compression quality 1-100; png accepts 0 (maximum quantization, smallest output) (optional, default: {})", config.compression.default_quality),
+                "algorithm": format!("Compression algorithm (optional, default: per detected input format from default_algorithm_by_input_format, otherwise {})", config.compression.default_algorithm)
             },
-            "query_parameters": {
-                "quality": "Alternative way to specify quality",
-                "algorithm": "Alternative way to specify algorithm"
+            "query_parameters": query_parameters,
+            "quant_tables": {
+                "default": "mozjpeg built-in tables",
+                "annex-k": "JPEG Annex K reference tables",
+                "flat": "Flat table, suits graphics and sharp edges",
+                "ms-ssim": "Tuned for MS-SSIM",
+                "imagemagick": "N. Robidoux table used by ImageMagick",
+                "psnr-hvs": "Tuned for PSNR-HVS, suits photos",
+                "klein": "Klein, Silverstein and Carney",
+                "watson": "Watson, Taylor and Borthwick",
+                "ahumada": "Ahumada, Watson and Peterson",
+                "peterson": "Peterson, Ahumada and Watson"
             }
         }
     })))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_form_quality() {
+        assert_eq!(parse_form_quality("85"), Some(85));
+        assert_eq!(parse_form_quality("0"), Some(0));
+        assert_eq!(parse_form_quality("300"), Some(100));
+        assert_eq!(parse_form_quality("-5"), Some(0));
+        assert_eq!(parse_form_quality("abc"), None);
+    }
+
+    #[test]
+    fn test_resolve_param_precedence() {
+        let form = HashMap::from([("quality".to_string(), "50".to_string())]);
+        let with_header = actix_web::test::TestRequest::default()
+            .insert_header(("X-Param-Quality", "60"))
+            .to_http_request();
+        let without_header = actix_web::test::TestRequest::default().to_http_request();
+
+        // 查询参数 > 请求头 > 表单字段
+        assert_eq!(resolve_param(Some(70), &with_header, &form, "quality", parse_form_quality), Some(70));
+        assert_eq!(resolve_param(None, &with_header, &form, "quality", parse_form_quality), Some(60));
+        assert_eq!(resolve_param(None, &without_header, &form, "quality", parse_form_quality), Some(50));
+        assert_eq!(resolve_param(None, &without_header, &HashMap::new(), "quality", parse_form_quality), None);
+
+        // 无法解析的请求头视为未提供
+        let invalid_header = actix_web::test::TestRequest::default()
+            .insert_header(("X-Param-Quality", "high"))
+            .to_http_request();
+        assert_eq!(resolve_param(None, &invalid_header, &form, "quality", parse_form_quality), Some(50));
+        assert_eq!(parse_text_param("  "), None);
+    }
+
+    #[test]
+    fn test_content_disposition() {
+        use actix_web::http::header::ContentDisposition;
+
+        assert_eq!(content_disposition("photo_compressed.jpg"), "attachment; filename=\"photo_compressed.jpg\"");
+
+        let filename = generate_output_filename(&Some("风景 照片.png".to_string()), "jpeg");
+        let header = content_disposition(&filename);
+        assert_eq!(
+            header,
+            "attachment; filename=\"__ ___compressed.jpg\"; filename*=UTF-8''%E9%A3%8E%E6%99%AF%20%E7%85%A7%E7%89%87_compressed.jpg"
+        );
+
+        // 头部值合法，按 RFC 5987 解码后还原为原始文件名
+        let value = HeaderValue::from_str(&header).unwrap();
+        let parsed = ContentDisposition::from_raw(&value).unwrap();
+        let ext = parsed.get_filename_ext().unwrap();
+        assert_eq!(String::from_utf8(ext.value.clone()).unwrap(), filename);
+        assert_eq!(parsed.get_filename(), Some("__ ___compressed.jpg"));
+
+        // 引号和反斜杠不能出现在回退名中
+        assert!(content_disposition("a\"b\\c.png").starts_with("attachment; filename=\"a_b_c.png\"; filename*="));
+    }
+
+    #[test]
+    fn test_format_aliases() {
+        for alias in ["jpg", "JPG", "jpeg", "Jpeg"] {
+            assert_eq!(canonical_format(alias), "jpeg");
+            assert_eq!(determine_output_content_type(alias), "image/jpeg");
+            assert_eq!(generate_output_filename(&Some("photo.png".to_string()), alias), "photo_compressed.jpg");
+        }
+        assert_eq!(canonical_format("gif"), "gif");
+        assert_eq!(determine_output_content_type("gif"), "application/octet-stream");
+
+        // 内容无法识别时按扩展名选择默认输出格式
+        let mut upload = FileUpload::new();
+        upload.data = b"not an image".to_vec();
+        upload.filename = Some("logo.PNG".to_string());
+        assert_eq!(default_output_format(&upload), "png");
+        upload.filename = Some("photo.JPG".to_string());
+        assert_eq!(default_output_format(&upload), "jpeg");
+
+        // 能编码的输入格式保持不变，透明 WebP 不会丢失 alpha
+        let mut webp = Vec::new();
+        image::RgbaImage::new(4, 4)
+            .write_to(&mut std::io::Cursor::new(&mut webp), image::ImageOutputFormat::WebP)
+            .unwrap();
+        upload.data = webp;
+        assert_eq!(default_output_format(&upload), "webp");
+    }
+
+    #[test]
+    fn test_check_raw_dimensions() {
+        let mut config = Config::default();
+        assert!(check_raw_dimensions(2 * 3 * 4, 2, 3, 4, &config).is_ok());
+        assert!(check_raw_dimensions(2 * 3, 2, 3, 1, &config).is_ok());
+
+        // 长度不一致、通道数非法或尺寸为 0
+        assert!(check_raw_dimensions(2 * 3 * 4 - 1, 2, 3, 4, &config).is_err());
+        assert!(check_raw_dimensions(2 * 3 * 2, 2, 3, 2, &config).is_err());
+        assert!(check_raw_dimensions(0, 0, 3, 4, &config).is_err());
+
+        // 超过像素上限时不论长度是否匹配都拒绝，且不会溢出
+        config.server.max_raw_pixels = 5;
+        assert!(check_raw_dimensions(2 * 3 * 4, 2, 3, 4, &config).is_err());
+        assert!(check_raw_dimensions(16, u32::MAX, u32::MAX, 4, &config).is_err());
+    }
+
+    #[test]
+    fn test_check_image_dimensions_names_axis() {
+        fn png(width: u32, height: u32) -> Vec<u8> {
+            let mut data = Vec::new();
+            image::RgbImage::new(width, height)
+                .write_to(&mut std::io::Cursor::new(&mut data), image::ImageOutputFormat::Png)
+                .unwrap();
+            data
+        }
+
+        let mut config = Config::default();
+        config.server.max_image_dimension = 100;
+        assert!(check_image_dimensions(&png(100, 10), &config).is_ok());
+
+        // 横幅只在宽度方向超限，像素总数并不大
+        match check_image_dimensions(&png(400, 4), &config) {
+            Err(err @ ImageServerError::DimensionsTooLarge { axis: Some("width"), .. }) => {
+                assert_eq!(err.to_string(), "Image width 400 exceeds max_image_dimension of 100 (image is 400x4)");
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(matches!(
+            check_image_dimensions(&png(4, 400), &config),
+            Err(ImageServerError::DimensionsTooLarge { axis: Some("height"), .. })
+        ));
+    }
+}
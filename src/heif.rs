@@ -0,0 +1,90 @@
+use image::DynamicImage;
+
+use crate::errors::ImageServerError;
+
+/// ftyp 中表示 HEIC/HEIF 静态图片或图片序列的主品牌
+///
+/// AVIF 同样使用 HEIF 容器，但品牌为 `avif`/`avis`，不在此列。
+const HEIF_BRANDS: [&[u8; 4]; 8] = [b"heic", b"heix", b"hevc", b"hevx", b"heim", b"heis", b"mif1", b"msf1"];
+
+/// 是否为 HEIC/HEIF 文件（iPhone 默认的拍照格式），`image` 无法解码这类输入
+pub fn is_heif(data: &[u8]) -> bool {
+    data.len() >= 12 && &data[4..8] == b"ftyp" && HEIF_BRANDS.iter().any(|brand| &data[8..12] == *brand)
+}
+
+/// 用 libheif 解码主图像，有 alpha 通道时输出 RGBA，否则输出 RGB
+///
+/// libheif 解码时已按容器中的旋转和镜像（`irot`/`imir`）变换像素，EXIF 方向值只是记录，
+/// 不能再次应用。
+#[cfg(feature = "libheif")]
+pub fn decode(data: &[u8]) -> Result<DynamicImage, ImageServerError> {
+    use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+    let decode_error = |e: libheif_rs::HeifError| {
+        ImageServerError::CompressionError(format!("Failed to decode HEIF image: {}", e))
+    };
+    let lib_heif = LibHeif::new();
+    let context = HeifContext::read_from_bytes(data).map_err(decode_error)?;
+    let handle = context.primary_image_handle().map_err(decode_error)?;
+    let (chroma, channels) = if handle.has_alpha_channel() { (RgbChroma::Rgba, 4) } else { (RgbChroma::Rgb, 3) };
+    let image = lib_heif.decode(&handle, ColorSpace::Rgb(chroma), None).map_err(decode_error)?;
+
+    let planes = image.planes();
+    let plane = planes.interleaved.ok_or_else(|| {
+        ImageServerError::CompressionError("HEIF decoder returned no interleaved plane".to_string())
+    })?;
+    let (width, height) = (plane.width, plane.height);
+
+    // 每行末尾可能有对齐填充，按 stride 逐行拷贝
+    let row_len = width as usize * channels;
+    let mut pixels = Vec::with_capacity(row_len * height as usize);
+    for row in plane.data.chunks(plane.stride).take(height as usize) {
+        pixels.extend_from_slice(&row[..row_len]);
+    }
+
+    let img = if channels == 4 {
+        image::RgbaImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgba8)
+    } else {
+        image::RgbImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8)
+    };
+    img.ok_or_else(|| ImageServerError::CompressionError("HEIF decoder returned a truncated plane".to_string()))
+}
+
+/// 未启用 libheif 特性时无法解码 HEIC/HEIF，返回 `415`
+#[cfg(not(feature = "libheif"))]
+pub fn decode(_data: &[u8]) -> Result<DynamicImage, ImageServerError> {
+    log::warn!("HEIC/HEIF input requires building with the libheif feature");
+    Err(ImageServerError::UnsupportedFormat)
+}
+
+/// 只读取容器头获取主图像变换后的尺寸，不解码像素
+#[cfg(feature = "libheif")]
+pub fn read_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let context = libheif_rs::HeifContext::read_from_bytes(data).ok()?;
+    let handle = context.primary_image_handle().ok()?;
+    Some((handle.width(), handle.height()))
+}
+
+#[cfg(not(feature = "libheif"))]
+pub fn read_dimensions(_data: &[u8]) -> Option<(u32, u32)> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_heif() {
+        assert!(is_heif(b"\0\0\0\x18ftypheic\0\0\0\0mif1heic"));
+        assert!(is_heif(b"\0\0\0\x18ftypmif1\0\0\0\0mif1heic"));
+        assert!(!is_heif(b"\0\0\0\x1cftypavif\0\0\0\0avifmif1"));
+        assert!(!is_heif(b"\0\0\0\x18ftyp"));
+        assert!(!is_heif(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"));
+
+        // 不支持时返回 415 而不是笼统的解码失败
+        if !cfg!(feature = "libheif") {
+            assert!(matches!(decode(b"\0\0\0\x18ftypheic\0\0\0\0"), Err(ImageServerError::UnsupportedFormat)));
+        }
+    }
+}
@@ -0,0 +1,1035 @@
+/// 像素网格的 8 种旋转/翻转组合，以作用于居中坐标（y 轴向下）的有符号置换矩阵表示
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transform {
+    matrix: [[i8; 2]; 2],
+}
+
+impl Transform {
+    pub const IDENTITY: Self = Self { matrix: [[1, 0], [0, 1]] };
+    pub const FLIP_HORIZONTAL: Self = Self { matrix: [[-1, 0], [0, 1]] };
+    pub const FLIP_VERTICAL: Self = Self { matrix: [[1, 0], [0, -1]] };
+    pub const ROTATE_90: Self = Self { matrix: [[0, -1], [1, 0]] };
+    pub const ROTATE_180: Self = Self { matrix: [[-1, 0], [0, -1]] };
+    pub const ROTATE_270: Self = Self { matrix: [[0, 1], [-1, 0]] };
+    pub const TRANSPOSE: Self = Self { matrix: [[0, 1], [1, 0]] };
+    pub const TRANSVERSE: Self = Self { matrix: [[0, -1], [-1, 0]] };
+
+    const NAMED: [(Self, &'static str); 8] = [
+        (Self::IDENTITY, "none"),
+        (Self::FLIP_HORIZONTAL, "flip-horizontal"),
+        (Self::FLIP_VERTICAL, "flip-vertical"),
+        (Self::ROTATE_90, "rotate-90"),
+        (Self::ROTATE_180, "rotate-180"),
+        (Self::ROTATE_270, "rotate-270"),
+        (Self::TRANSPOSE, "transpose"),
+        (Self::TRANSVERSE, "transverse"),
+    ];
+
+    /// 校正 EXIF 方向所需的变换，与 `apply_exif_orientation` 的旋转/翻转顺序一致
+    pub fn for_orientation(orientation: u16) -> Self {
+        match orientation {
+            2 => Self::FLIP_HORIZONTAL,
+            3 => Self::ROTATE_180,
+            4 => Self::FLIP_VERTICAL,
+            5 => Self::ROTATE_270.then(Self::FLIP_HORIZONTAL),
+            6 => Self::ROTATE_90,
+            7 => Self::ROTATE_90.then(Self::FLIP_HORIZONTAL),
+            8 => Self::ROTATE_270,
+            _ => Self::IDENTITY,
+        }
+    }
+
+    /// 先执行 `self` 再执行 `next` 的组合变换
+    pub fn then(self, next: Self) -> Self {
+        let (a, b) = (next.matrix, self.matrix);
+        let mut matrix = [[0; 2]; 2];
+        for (row, out) in matrix.iter_mut().enumerate() {
+            for (col, value) in out.iter_mut().enumerate() {
+                *value = a[row][0] * b[0][col] + a[row][1] * b[1][col];
+            }
+        }
+        Self { matrix }
+    }
+
+    /// 用于日志和 X-Lossless-Transform 的名称，如 `rotate-90`
+    pub fn name(self) -> &'static str {
+        Self::NAMED
+            .iter()
+            .find(|(transform, _)| *transform == self)
+            .map_or("none", |(_, name)| name)
+    }
+
+    /// 是否交换宽高
+    pub fn swaps_axes(self) -> bool {
+        self.matrix[0][0] == 0
+    }
+
+    /// 变换后的尺寸
+    pub fn output_size(self, width: u32, height: u32) -> (u32, u32) {
+        if self.swaps_axes() { (height, width) } else { (width, height) }
+    }
+
+    // 源图的水平、垂直方向是否被翻转，翻转的方向上边缘不完整的 MCU 无法无损移动
+    fn flips_source(self) -> (bool, bool) {
+        let [[a, b], [c, d]] = self.matrix;
+        (a + c < 0, b + d < 0)
+    }
+
+    // 输出网格中 (dx, dy) 处的块来自源网格的哪个块，源网格为 `width`x`height` 个块
+    fn source_block(self, dx: usize, dy: usize, width: usize, height: usize) -> (usize, usize) {
+        let [[a, b], [c, d]] = self.matrix;
+        let (sx, sy, sign_x, sign_y) = if self.swaps_axes() { (dy, dx, c, b) } else { (dx, dy, a, d) };
+        (
+            if sign_x < 0 { width - 1 - sx } else { sx },
+            if sign_y < 0 { height - 1 - sy } else { sy },
+        )
+    }
+
+    // 在 DCT 域变换一个 8x8 块（自然顺序，下标为 `v * 8 + u`）：转置交换频率，翻转使奇数频率变号
+    fn transform_block(self, block: &[i16; 64]) -> [i16; 64] {
+        let [[a, b], [c, d]] = self.matrix;
+        let (sign_x, sign_y) = if self.swaps_axes() { (c, b) } else { (a, d) };
+        let mut out = [0i16; 64];
+        for v in 0..8 {
+            for u in 0..8 {
+                let (su, sv) = if self.swaps_axes() { (v, u) } else { (u, v) };
+                let mut value = block[sv * 8 + su];
+                if sign_x < 0 && su % 2 == 1 {
+                    value = -value;
+                }
+                if sign_y < 0 && sv % 2 == 1 {
+                    value = -value;
+                }
+                out[v * 8 + u] = value;
+            }
+        }
+        out
+    }
+}
+
+/// zigzag 顺序到 8x8 块自然顺序的下标
+const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20, 13, 6, 7, 14, 21,
+    28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59, 52, 45, 38, 31, 39, 46, 53, 60, 61,
+    54, 47, 55, 62, 63,
+];
+
+struct Component {
+    id: u8,
+    h: usize,
+    v: usize,
+    quant_table: u8,
+    dc_table: usize,
+    ac_table: usize,
+    /// 按 MCU 补齐后的块网格尺寸
+    blocks_wide: usize,
+    blocks_high: usize,
+    blocks: Vec<[i16; 64]>,
+}
+
+struct Frame {
+    marker: u8,
+    width: usize,
+    height: usize,
+    components: Vec<Component>,
+    max_h: usize,
+    max_v: usize,
+}
+
+impl Frame {
+    fn mcus_wide(&self) -> usize {
+        self.width.div_ceil(8 * self.max_h)
+    }
+
+    fn mcus_high(&self) -> usize {
+        self.height.div_ceil(8 * self.max_v)
+    }
+}
+
+/// 解码用的 Huffman 表（JPEG 标准附录 C/F 的 maxcode/valptr 形式）
+struct DecodeTable {
+    max_code: [i32; 18],
+    val_ptr: [i32; 17],
+    min_code: [i32; 17],
+    values: Vec<u8>,
+}
+
+impl DecodeTable {
+    fn new(counts: &[u8; 16], values: Vec<u8>) -> Self {
+        let mut table = Self { max_code: [-1; 18], val_ptr: [0; 17], min_code: [0; 17], values };
+        let (mut code, mut k) = (0i32, 0i32);
+        for (length, &count) in (1..=16).zip(counts.iter()) {
+            let count = count as i32;
+            if count > 0 {
+                table.val_ptr[length] = k;
+                table.min_code[length] = code;
+                code += count;
+                k += count;
+                table.max_code[length] = code - 1;
+            }
+            code <<= 1;
+        }
+        // 哨兵，保证解码循环在 16 位内结束
+        table.max_code[17] = i32::MAX;
+        table
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bits: u32,
+    count: u32,
+}
+
+impl BitReader<'_> {
+    fn bit(&mut self) -> Result<u32, String> {
+        if self.count == 0 {
+            let byte = *self.data.get(self.pos).ok_or("truncated entropy-coded data")?;
+            if byte == 0xFF {
+                match self.data.get(self.pos + 1) {
+                    Some(0x00) => self.pos += 1,
+                    _ => return Err("unexpected marker in entropy-coded data".to_string()),
+                }
+            }
+            self.pos += 1;
+            self.bits = byte as u32;
+            self.count = 8;
+        }
+        self.count -= 1;
+        Ok((self.bits >> self.count) & 1)
+    }
+
+    fn receive(&mut self, length: u32) -> Result<i32, String> {
+        let mut value = 0;
+        for _ in 0..length {
+            value = (value << 1) | self.bit()? as i32;
+        }
+        Ok(value)
+    }
+
+    fn receive_extend(&mut self, length: u32) -> Result<i32, String> {
+        let value = self.receive(length)?;
+        Ok(if length > 0 && value < 1 << (length - 1) { value - (1 << length) + 1 } else { value })
+    }
+
+    fn decode(&mut self, table: &DecodeTable) -> Result<u8, String> {
+        let mut code = self.bit()? as i32;
+        let mut length = 1;
+        while code > table.max_code[length] {
+            code = (code << 1) | self.bit()? as i32;
+            length += 1;
+        }
+        if length > 16 {
+            return Err("invalid Huffman code".to_string());
+        }
+        let index = table.val_ptr[length] + code - table.min_code[length];
+        table.values.get(index as usize).copied().ok_or_else(|| "invalid Huffman code".to_string())
+    }
+
+    // 重启间隔结束：丢弃字节内剩余的位并跳过 RSTn 标记
+    fn restart(&mut self) -> Result<(), String> {
+        self.count = 0;
+        match self.data.get(self.pos..self.pos + 2) {
+            Some([0xFF, 0xD0..=0xD7]) => {
+                self.pos += 2;
+                Ok(())
+            }
+            _ => Err("missing restart marker".to_string()),
+        }
+    }
+}
+
+/// 对基线（顺序式 Huffman）JPEG 在 DCT 域执行旋转/翻转，不解码像素也不重新量化
+///
+/// 量化后的系数原样搬移，输出与源图逐像素等价（IDCT 舍入差异除外）。APP、DQT、COM 等段按原顺序保留，
+/// EXIF 方向改为 1；交换宽高的变换会同时转置量化表（行列不对称的量化表否则会错配到搬移后的系数上），
+/// 并交换 EXIF 中的像素宽高；Huffman 表按新的系数重新统计，不写重启标记。
+///
+/// 渐进式、算术编码、12 位精度或分多次扫描的 JPEG，以及翻转方向上尺寸不是 MCU 整数倍的图片
+/// （边缘不完整的 MCU 移动后会错位）返回 `Err`，其中为不能无损变换的原因，调用方应回退到重新编码。
+pub fn transform(data: &[u8], transform: Transform) -> Result<Vec<u8>, String> {
+    if data.len() < 4 || data[0..2] != [0xFF, 0xD8] {
+        return Err("source is not a JPEG".to_string());
+    }
+
+    let mut kept_segments: Vec<&[u8]> = Vec::new();
+    let mut dc_tables: [Option<DecodeTable>; 4] = Default::default();
+    let mut ac_tables: [Option<DecodeTable>; 4] = Default::default();
+    let mut restart_interval = 0usize;
+    let mut frame: Option<Frame> = None;
+
+    let mut pos = 2;
+    let scan_start = loop {
+        let header = data.get(pos..pos + 4).ok_or("no scan found")?;
+        if header[0] != 0xFF {
+            return Err("malformed marker".to_string());
+        }
+        let marker = header[1];
+        if marker == 0xFF {
+            pos += 1;
+            continue;
+        }
+        let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+        // 长度字段包含自身的 2 字节
+        if length < 2 {
+            return Err("invalid segment length".to_string());
+        }
+        let segment = data.get(pos..pos + 2 + length).ok_or("truncated segment")?;
+        let body = &segment[4..];
+
+        match marker {
+            0xC0 | 0xC1 => frame = Some(parse_frame(marker, body)?),
+            0xC2 | 0xC6 | 0xCA | 0xCE => return Err("progressive JPEG".to_string()),
+            0xC3 | 0xC5 | 0xC7 | 0xC9 | 0xCB | 0xCD | 0xCF => {
+                return Err("lossless, hierarchical or arithmetic-coded JPEG".to_string())
+            }
+            0xC4 => parse_huffman_tables(body, &mut dc_tables, &mut ac_tables)?,
+            0xDD => {
+                let interval = body.get(0..2).ok_or("truncated DRI segment")?;
+                restart_interval = u16::from_be_bytes([interval[0], interval[1]]) as usize;
+            }
+            0xDA => break pos,
+            0xD9 => return Err("no scan found".to_string()),
+            _ => kept_segments.push(segment),
+        }
+        pos += 2 + length;
+    };
+
+    let mut frame = frame.ok_or("no baseline frame header")?;
+    let scan_length = u16::from_be_bytes([data[scan_start + 2], data[scan_start + 3]]) as usize;
+    let scan = data.get(scan_start + 4..scan_start + 2 + scan_length).ok_or("truncated scan header")?;
+    parse_scan_header(scan, &mut frame)?;
+
+    let (flips_x, flips_y) = transform.flips_source();
+    let (mcu_width, mcu_height) = (8 * frame.max_h, 8 * frame.max_v);
+    if flips_x && frame.width % mcu_width != 0 {
+        return Err(format!("width {} is not a multiple of the {}-pixel MCU", frame.width, mcu_width));
+    }
+    if flips_y && frame.height % mcu_height != 0 {
+        return Err(format!("height {} is not a multiple of the {}-pixel MCU", frame.height, mcu_height));
+    }
+
+    let mut reader = BitReader { data, pos: scan_start + 2 + scan_length, bits: 0, count: 0 };
+    decode_scan(&mut reader, &mut frame, &dc_tables, &ac_tables, restart_interval)?;
+
+    let frame = transform_frame(frame, transform);
+    let swapped = transform.swaps_axes();
+    let mut output = Vec::with_capacity(data.len());
+    output.extend_from_slice(&[0xFF, 0xD8]);
+    for segment in kept_segments {
+        if segment[1] == 0xE1 && segment[4..].starts_with(b"Exif\0\0") {
+            let mut segment = segment.to_vec();
+            reset_exif_orientation(&mut segment[10..], swapped);
+            output.extend_from_slice(&segment);
+        } else if segment[1] == 0xDB && swapped {
+            let mut segment = segment.to_vec();
+            transpose_quant_tables(&mut segment[4..])?;
+            output.extend_from_slice(&segment);
+        } else {
+            output.extend_from_slice(segment);
+        }
+    }
+    encode_frame(&frame, &mut output);
+    Ok(output)
+}
+
+fn parse_frame(marker: u8, body: &[u8]) -> Result<Frame, String> {
+    if body.len() < 6 {
+        return Err("truncated frame header".to_string());
+    }
+    if body[0] != 8 {
+        return Err(format!("{}-bit precision", body[0]));
+    }
+    let height = u16::from_be_bytes([body[1], body[2]]) as usize;
+    let width = u16::from_be_bytes([body[3], body[4]]) as usize;
+    if width == 0 || height == 0 {
+        return Err("frame without explicit dimensions".to_string());
+    }
+    let count = body[5] as usize;
+    let specs = body.get(6..6 + 3 * count).ok_or("truncated frame header")?;
+    let mut components: Vec<Component> = specs
+        .chunks(3)
+        .map(|spec| Component {
+            id: spec[0],
+            h: (spec[1] >> 4) as usize,
+            v: (spec[1] & 0x0F) as usize,
+            quant_table: spec[2],
+            dc_table: 0,
+            ac_table: 0,
+            blocks_wide: 0,
+            blocks_high: 0,
+            blocks: Vec::new(),
+        })
+        .collect();
+    if components.is_empty() || components.iter().any(|c| !(1..=4).contains(&c.h) || !(1..=4).contains(&c.v)) {
+        return Err("invalid sampling factors".to_string());
+    }
+    // 单分量扫描不交织，MCU 就是一个块，抽样因子不起作用
+    if components.len() == 1 {
+        components[0].h = 1;
+        components[0].v = 1;
+    }
+
+    let mut frame = Frame {
+        marker,
+        width,
+        height,
+        max_h: components.iter().map(|c| c.h).max().unwrap_or(1),
+        max_v: components.iter().map(|c| c.v).max().unwrap_or(1),
+        components,
+    };
+    let (mcus_wide, mcus_high) = (frame.mcus_wide(), frame.mcus_high());
+    for component in &mut frame.components {
+        component.blocks_wide = mcus_wide * component.h;
+        component.blocks_high = mcus_high * component.v;
+        component.blocks = vec![[0; 64]; component.blocks_wide * component.blocks_high];
+    }
+    Ok(frame)
+}
+
+fn parse_huffman_tables(
+    mut body: &[u8],
+    dc_tables: &mut [Option<DecodeTable>; 4],
+    ac_tables: &mut [Option<DecodeTable>; 4],
+) -> Result<(), String> {
+    while !body.is_empty() {
+        let header = body.get(0..17).ok_or("truncated DHT segment")?;
+        let (class, id) = (header[0] >> 4, (header[0] & 0x0F) as usize);
+        let mut counts = [0u8; 16];
+        counts.copy_from_slice(&header[1..17]);
+        let total: usize = counts.iter().map(|&count| count as usize).sum();
+        let values = body.get(17..17 + total).ok_or("truncated DHT segment")?.to_vec();
+        let table = Some(DecodeTable::new(&counts, values));
+        match (class, id) {
+            (0, 0..=3) => dc_tables[id] = table,
+            (1, 0..=3) => ac_tables[id] = table,
+            _ => return Err("invalid Huffman table".to_string()),
+        }
+        body = &body[17 + total..];
+    }
+    Ok(())
+}
+
+fn parse_scan_header(scan: &[u8], frame: &mut Frame) -> Result<(), String> {
+    let count = *scan.first().ok_or("truncated scan header")? as usize;
+    if count != frame.components.len() {
+        return Err("JPEG with more than one scan".to_string());
+    }
+    let specs = scan.get(1..1 + 2 * count).ok_or("truncated scan header")?;
+    for (spec, component) in specs.chunks(2).zip(frame.components.iter_mut()) {
+        if spec[0] != component.id {
+            return Err("scan components are not in frame order".to_string());
+        }
+        component.dc_table = (spec[1] >> 4) as usize;
+        component.ac_table = (spec[1] & 0x0F) as usize;
+        if component.dc_table > 3 || component.ac_table > 3 {
+            return Err("invalid Huffman table selector".to_string());
+        }
+    }
+    Ok(())
+}
+
+// 按 MCU 顺序访问扫描中的每个块：(分量序号, 块在分量网格中的下标)
+fn for_each_block(frame: &Frame, mut visit: impl FnMut(usize, usize, usize) -> Result<(), String>) -> Result<(), String> {
+    let mut mcu = 0;
+    for mcu_y in 0..frame.mcus_high() {
+        for mcu_x in 0..frame.mcus_wide() {
+            for (index, component) in frame.components.iter().enumerate() {
+                for by in 0..component.v {
+                    for bx in 0..component.h {
+                        let (x, y) = (mcu_x * component.h + bx, mcu_y * component.v + by);
+                        visit(mcu, index, y * component.blocks_wide + x)?;
+                    }
+                }
+            }
+            mcu += 1;
+        }
+    }
+    Ok(())
+}
+
+fn decode_scan(
+    reader: &mut BitReader,
+    frame: &mut Frame,
+    dc_tables: &[Option<DecodeTable>; 4],
+    ac_tables: &[Option<DecodeTable>; 4],
+    restart_interval: usize,
+) -> Result<(), String> {
+    let tables = frame
+        .components
+        .iter()
+        .map(|c| match (&dc_tables[c.dc_table], &ac_tables[c.ac_table]) {
+            (Some(dc), Some(ac)) => Ok((dc, ac)),
+            _ => Err("missing Huffman table".to_string()),
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let mut decoded = vec![Vec::new(); frame.components.len()];
+    let mut predictions = vec![0i32; frame.components.len()];
+    let mut last_mcu = None;
+    let mut mcu_count = 0;
+    for_each_block(frame, |mcu, index, block| {
+        if last_mcu != Some(mcu) {
+            if restart_interval > 0 && mcu_count > 0 && mcu_count % restart_interval == 0 {
+                reader.restart()?;
+                predictions.iter_mut().for_each(|prediction| *prediction = 0);
+            }
+            last_mcu = Some(mcu);
+            mcu_count += 1;
+        }
+
+        let (dc, ac) = tables[index];
+        let mut coefficients = [0i16; 64];
+        let category = reader.decode(dc)? as u32;
+        if category > 11 {
+            return Err("invalid DC coefficient".to_string());
+        }
+        predictions[index] = predictions[index]
+            .checked_add(reader.receive_extend(category)?)
+            .ok_or("DC coefficient overflow")?;
+        coefficients[0] = predictions[index] as i16;
+        let mut k = 1;
+        while k < 64 {
+            let symbol = reader.decode(ac)?;
+            let (run, size) = ((symbol >> 4) as usize, (symbol & 0x0F) as u32);
+            if size == 0 {
+                if run != 15 {
+                    break;
+                }
+                k += 16;
+                continue;
+            }
+            k += run;
+            if k > 63 {
+                return Err("invalid AC coefficient".to_string());
+            }
+            coefficients[ZIGZAG[k]] = reader.receive_extend(size)? as i16;
+            k += 1;
+        }
+        decoded[index].push((block, coefficients));
+        Ok(())
+    })?;
+
+    for (component, blocks) in frame.components.iter_mut().zip(decoded) {
+        for (block, coefficients) in blocks {
+            component.blocks[block] = coefficients;
+        }
+    }
+    Ok(())
+}
+
+fn transform_frame(frame: Frame, transform: Transform) -> Frame {
+    let swapped = transform.swaps_axes();
+    let components = frame
+        .components
+        .into_iter()
+        .map(|component| {
+            let (blocks_wide, blocks_high) = if swapped {
+                (component.blocks_high, component.blocks_wide)
+            } else {
+                (component.blocks_wide, component.blocks_high)
+            };
+            let mut blocks = Vec::with_capacity(component.blocks.len());
+            for dy in 0..blocks_high {
+                for dx in 0..blocks_wide {
+                    let (sx, sy) = transform.source_block(dx, dy, component.blocks_wide, component.blocks_high);
+                    blocks.push(transform.transform_block(&component.blocks[sy * component.blocks_wide + sx]));
+                }
+            }
+            let (h, v) = if swapped { (component.v, component.h) } else { (component.h, component.v) };
+            Component { h, v, blocks_wide, blocks_high, blocks, ..component }
+        })
+        .collect();
+    let (width, height) = if swapped { (frame.height, frame.width) } else { (frame.width, frame.height) };
+    let (max_h, max_v) = if swapped { (frame.max_v, frame.max_h) } else { (frame.max_h, frame.max_v) };
+    Frame { marker: frame.marker, width, height, components, max_h, max_v }
+}
+
+// 系数值所需的位数（JPEG 中的 SSSS 类别）及其附加位
+fn magnitude(value: i32) -> (u32, u32) {
+    let size = 32 - value.unsigned_abs().leading_zeros();
+    let bits = if value < 0 { value - 1 } else { value };
+    (size, (bits as u32) & ((1u32 << size) - 1))
+}
+
+// 按 MCU 顺序生成每个块的 Huffman 符号：(分量序号, 是否 AC, 符号, 附加位, 附加位长度)
+fn for_each_symbol(frame: &Frame, mut emit: impl FnMut(usize, bool, u8, u32, u32)) {
+    let mut predictions = vec![0i32; frame.components.len()];
+    let _ = for_each_block(frame, |_, index, block| {
+        let coefficients = &frame.components[index].blocks[block];
+        let dc = coefficients[0] as i32;
+        let (size, bits) = magnitude(dc - predictions[index]);
+        predictions[index] = dc;
+        emit(index, false, size as u8, bits, size);
+
+        let mut run = 0;
+        for &position in &ZIGZAG[1..] {
+            let value = coefficients[position] as i32;
+            if value == 0 {
+                run += 1;
+                continue;
+            }
+            while run > 15 {
+                emit(index, true, 0xF0, 0, 0);
+                run -= 16;
+            }
+            let (size, bits) = magnitude(value);
+            emit(index, true, ((run << 4) | size) as u8, bits, size);
+            run = 0;
+        }
+        if run > 0 {
+            emit(index, true, 0x00, 0, 0);
+        }
+        Ok(())
+    });
+}
+
+/// 按符号频率生成码长不超过 16 位的最优 Huffman 表（JPEG 标准附录 K.2），返回各码长的码字数和符号
+fn optimal_table(frequencies: &[u32; 256]) -> ([u8; 16], Vec<u8>) {
+    // 保留一个频率为 1 的哨兵符号，保证不会出现全为 1 的码字
+    let mut freq = [0u64; 257];
+    for (slot, &count) in freq.iter_mut().zip(frequencies.iter()) {
+        *slot = count as u64;
+    }
+    freq[256] = 1;
+    let mut code_size = [0usize; 257];
+    let mut others = [usize::MAX; 257];
+
+    loop {
+        // 频率最小的两个符号，相同时取下标较大者
+        let (mut c1, mut c2): (Option<usize>, Option<usize>) = (None, None);
+        for i in (0..257).filter(|&i| freq[i] > 0) {
+            match c1 {
+                Some(c) if freq[i] > freq[c] => match c2 {
+                    Some(c) if freq[i] > freq[c] => {}
+                    _ => c2 = Some(i),
+                },
+                _ => {
+                    c2 = c1;
+                    c1 = Some(i);
+                }
+            }
+        }
+        let (mut c1, mut c2) = match (c1, c2) {
+            (Some(c1), Some(c2)) => (c1, c2),
+            _ => break,
+        };
+        freq[c1] += freq[c2];
+        freq[c2] = 0;
+        code_size[c1] += 1;
+        while others[c1] != usize::MAX {
+            c1 = others[c1];
+            code_size[c1] += 1;
+        }
+        others[c1] = c2;
+        code_size[c2] += 1;
+        while others[c2] != usize::MAX {
+            c2 = others[c2];
+            code_size[c2] += 1;
+        }
+    }
+
+    let mut bits = [0usize; 33];
+    for &size in code_size.iter().filter(|&&size| size > 0) {
+        bits[size] += 1;
+    }
+    for i in (17..=32).rev() {
+        while bits[i] > 0 {
+            let mut j = i - 2;
+            while bits[j] == 0 {
+                j -= 1;
+            }
+            bits[i] -= 2;
+            bits[i - 1] += 1;
+            bits[j + 1] += 2;
+            bits[j] -= 1;
+        }
+    }
+    // 去掉哨兵占用的最长码字
+    let mut longest = 16;
+    while bits[longest] == 0 {
+        longest -= 1;
+    }
+    bits[longest] -= 1;
+
+    let mut counts = [0u8; 16];
+    for (count, &value) in counts.iter_mut().zip(&bits[1..=16]) {
+        *count = value as u8;
+    }
+    let mut values = Vec::new();
+    for size in 1..=32 {
+        values.extend((0..256).filter(|&symbol| code_size[symbol] == size).map(|symbol| symbol as u8));
+    }
+    (counts, values)
+}
+
+// 由码长分布生成每个符号的 (码字, 码长)
+fn encode_table(counts: &[u8; 16], values: &[u8]) -> [(u32, u32); 256] {
+    let mut table = [(0, 0); 256];
+    let mut code = 0u32;
+    let mut values = values.iter();
+    for (length, &count) in (1..=16).zip(counts.iter()) {
+        for _ in 0..count {
+            if let Some(&symbol) = values.next() {
+                table[symbol as usize] = (code, length);
+            }
+            code += 1;
+        }
+        code <<= 1;
+    }
+    table
+}
+
+struct BitWriter<'a> {
+    output: &'a mut Vec<u8>,
+    bits: u32,
+    count: u32,
+}
+
+impl BitWriter<'_> {
+    fn write(&mut self, value: u32, length: u32) {
+        for shift in (0..length).rev() {
+            self.bits = (self.bits << 1) | ((value >> shift) & 1);
+            self.count += 1;
+            if self.count == 8 {
+                self.flush_byte();
+            }
+        }
+    }
+
+    fn flush_byte(&mut self) {
+        let byte = self.bits as u8;
+        self.output.push(byte);
+        if byte == 0xFF {
+            self.output.push(0x00);
+        }
+        self.bits = 0;
+        self.count = 0;
+    }
+
+    // 最后不足一个字节的部分用 1 填充
+    fn finish(&mut self) {
+        if self.count > 0 {
+            let padding = 8 - self.count;
+            self.write((1 << padding) - 1, padding);
+        }
+    }
+}
+
+// 写出 SOF、DHT、SOS、熵编码数据和 EOI；亮度（第一个分量）使用 0 号表，其余分量共用 1 号表
+fn encode_frame(frame: &Frame, output: &mut Vec<u8>) {
+    let table_for = |index: usize| usize::from(index > 0);
+    let mut frequencies = [[[0u32; 256]; 2]; 2];
+    for_each_symbol(frame, |index, is_ac, symbol, _, _| {
+        frequencies[table_for(index)][is_ac as usize][symbol as usize] += 1;
+    });
+
+    let mut sof = vec![8];
+    sof.extend_from_slice(&(frame.height as u16).to_be_bytes());
+    sof.extend_from_slice(&(frame.width as u16).to_be_bytes());
+    sof.push(frame.components.len() as u8);
+    for component in &frame.components {
+        sof.extend_from_slice(&[component.id, ((component.h << 4) | component.v) as u8, component.quant_table]);
+    }
+    write_segment(output, frame.marker, &sof);
+
+    let used_tables = if frame.components.len() > 1 { 2 } else { 1 };
+    let mut dht = Vec::new();
+    let mut codes = Vec::new();
+    for (table, table_frequencies) in frequencies.iter().enumerate().take(used_tables) {
+        let mut table_codes = Vec::new();
+        for (class, class_frequencies) in table_frequencies.iter().enumerate() {
+            let (counts, values) = optimal_table(class_frequencies);
+            dht.push(((class << 4) | table) as u8);
+            dht.extend_from_slice(&counts);
+            dht.extend_from_slice(&values);
+            table_codes.push(encode_table(&counts, &values));
+        }
+        codes.push(table_codes);
+    }
+    write_segment(output, 0xC4, &dht);
+
+    let mut sos = vec![frame.components.len() as u8];
+    for (index, component) in frame.components.iter().enumerate() {
+        let table = table_for(index) as u8;
+        sos.extend_from_slice(&[component.id, (table << 4) | table]);
+    }
+    sos.extend_from_slice(&[0, 63, 0]);
+    write_segment(output, 0xDA, &sos);
+
+    let mut writer = BitWriter { output: &mut *output, bits: 0, count: 0 };
+    for_each_symbol(frame, |index, is_ac, symbol, bits, length| {
+        let (code, code_length) = codes[table_for(index)][is_ac as usize][symbol as usize];
+        writer.write(code, code_length);
+        writer.write(bits, length);
+    });
+    writer.finish();
+    output.extend_from_slice(&[0xFF, 0xD9]);
+}
+
+fn write_segment(output: &mut Vec<u8>, marker: u8, body: &[u8]) {
+    output.extend_from_slice(&[0xFF, marker]);
+    output.extend_from_slice(&((body.len() + 2) as u16).to_be_bytes());
+    output.extend_from_slice(body);
+}
+
+/// 原地转置 DQT 段中的每张量化表，表项按 zigzag 顺序存储，8 位或 16 位精度
+fn transpose_quant_tables(body: &mut [u8]) -> Result<(), String> {
+    let mut natural_to_zigzag = [0usize; 64];
+    for (k, &position) in ZIGZAG.iter().enumerate() {
+        natural_to_zigzag[position] = k;
+    }
+
+    let mut pos = 0;
+    while pos < body.len() {
+        let entry_size = if body[pos] >> 4 == 0 { 1 } else { 2 };
+        let table = body.get_mut(pos + 1..pos + 1 + 64 * entry_size).ok_or("truncated DQT segment")?;
+        let original = table.to_vec();
+        for (k, &position) in ZIGZAG.iter().enumerate() {
+            let transposed = (position % 8) * 8 + position / 8;
+            let source = natural_to_zigzag[transposed] * entry_size;
+            table[k * entry_size..(k + 1) * entry_size].copy_from_slice(&original[source..source + entry_size]);
+        }
+        pos += 1 + 64 * entry_size;
+    }
+    Ok(())
+}
+
+/// 在 EXIF 的 TIFF 数据中把方向改为 1，`swap_dimensions` 时交换 Exif IFD 中的像素宽高
+///
+/// 原地修改取值，不改变任何偏移，无法识别的结构保持不变。
+fn reset_exif_orientation(tiff: &mut [u8], swap_dimensions: bool) {
+    let little_endian = match tiff.get(0..2) {
+        Some(b"II") => true,
+        Some(b"MM") => false,
+        _ => return,
+    };
+    let read_u16 = |tiff: &[u8], at: usize| {
+        tiff.get(at..at + 2).map(|b| if little_endian { u16::from_le_bytes([b[0], b[1]]) } else { u16::from_be_bytes([b[0], b[1]]) })
+    };
+    let read_u32 = |tiff: &[u8], at: usize| {
+        tiff.get(at..at + 4).map(|b| {
+            let b = [b[0], b[1], b[2], b[3]];
+            if little_endian { u32::from_le_bytes(b) } else { u32::from_be_bytes(b) }
+        })
+    };
+    // IFD 中每项 12 字节：标签(2) 类型(2) 数量(4) 值或偏移(4)，返回 (标签, 类型, 值字段位置)
+    let entries = |tiff: &[u8], offset: usize| -> Vec<(u16, u16, usize)> {
+        let count = read_u16(tiff, offset).unwrap_or(0) as usize;
+        (0..count)
+            .map(|i| offset + 2 + 12 * i)
+            .filter_map(|entry| Some((read_u16(tiff, entry)?, read_u16(tiff, entry + 2)?, entry + 8)))
+            .filter(|&(_, _, value)| value + 4 <= tiff.len())
+            .collect()
+    };
+
+    let ifd0 = match read_u32(tiff, 4) {
+        Some(offset) => offset as usize,
+        None => return,
+    };
+    let mut exif_ifd = None;
+    for (tag, kind, value) in entries(tiff, ifd0) {
+        match (tag, kind) {
+            // SHORT 类型的 Orientation
+            (0x0112, 3) => {
+                let one = if little_endian { 1u16.to_le_bytes() } else { 1u16.to_be_bytes() };
+                tiff[value..value + 2].copy_from_slice(&one);
+            }
+            (0x8769, _) => exif_ifd = read_u32(tiff, value),
+            _ => {}
+        }
+    }
+
+    let exif_entries = match exif_ifd {
+        Some(offset) if swap_dimensions => entries(tiff, offset as usize),
+        _ => return,
+    };
+    let width = exif_entries.iter().find(|(tag, _, _)| *tag == 0xA002);
+    let height = exif_entries.iter().find(|(tag, _, _)| *tag == 0xA003);
+    if let (Some(&(_, width_kind, width_at)), Some(&(_, height_kind, height_at))) = (width, height) {
+        if width_kind == height_kind {
+            let mut width_value = [0u8; 4];
+            width_value.copy_from_slice(&tiff[width_at..width_at + 4]);
+            tiff.copy_within(height_at..height_at + 4, width_at);
+            tiff[height_at..height_at + 4].copy_from_slice(&width_value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, GenericImageView};
+
+    fn gradient(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(image::RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([(x * 7 % 256) as u8, (y * 5 % 256) as u8, ((x * y) % 256) as u8])
+        }))
+    }
+
+    fn encode_jpeg(img: &DynamicImage, quality: u8) -> Vec<u8> {
+        let mut output = Vec::new();
+        let rgb = img.to_rgb8();
+        jpeg_encoder::Encoder::new(&mut output, quality)
+            .encode(rgb.as_raw(), rgb.width() as u16, rgb.height() as u16, jpeg_encoder::ColorType::Rgb)
+            .unwrap();
+        output
+    }
+
+    // 指定色度抽样和重启间隔（0 为不写重启标记）编码
+    fn encode_jpeg_with(img: &DynamicImage, sampling: jpeg_encoder::SamplingFactor, restart_interval: u16) -> Vec<u8> {
+        let mut output = Vec::new();
+        let rgb = img.to_rgb8();
+        let mut encoder = jpeg_encoder::Encoder::new(&mut output, 85);
+        encoder.set_sampling_factor(sampling);
+        encoder.set_restart_interval(restart_interval);
+        encoder
+            .encode(rgb.as_raw(), rgb.width() as u16, rgb.height() as u16, jpeg_encoder::ColorType::Rgb)
+            .unwrap();
+        output
+    }
+
+    fn transpose(img: &DynamicImage) -> DynamicImage {
+        img.rotate90().fliph()
+    }
+
+    fn max_difference(a: &DynamicImage, b: &DynamicImage) -> u8 {
+        a.to_rgb8()
+            .as_raw()
+            .iter()
+            .zip(b.to_rgb8().as_raw())
+            .map(|(x, y)| x.abs_diff(*y))
+            .max()
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn test_transform_composition() {
+        assert_eq!(Transform::ROTATE_90.then(Transform::ROTATE_90), Transform::ROTATE_180);
+        assert_eq!(Transform::ROTATE_90.then(Transform::ROTATE_180), Transform::ROTATE_270);
+        assert_eq!(Transform::FLIP_HORIZONTAL.then(Transform::FLIP_VERTICAL), Transform::ROTATE_180);
+        assert_eq!(Transform::ROTATE_270.then(Transform::ROTATE_90), Transform::IDENTITY);
+        assert_eq!(Transform::for_orientation(6).name(), "rotate-90");
+        assert_eq!(Transform::for_orientation(1), Transform::IDENTITY);
+        assert_eq!(Transform::ROTATE_90.output_size(40, 30), (30, 40));
+    }
+
+    #[test]
+    fn test_lossless_transform_matches_pixel_transform() {
+        // 质量 100 为 4:4:4，质量 80 为 4:2:0
+        for quality in [100, 80] {
+            let source = encode_jpeg(&gradient(48, 32), quality);
+            let decoded = image::load_from_memory(&source).unwrap();
+            let cases: [(Transform, fn(&DynamicImage) -> DynamicImage); 5] = [
+                (Transform::ROTATE_90, DynamicImage::rotate90),
+                (Transform::ROTATE_180, DynamicImage::rotate180),
+                (Transform::ROTATE_270, DynamicImage::rotate270),
+                (Transform::FLIP_HORIZONTAL, DynamicImage::fliph),
+                (Transform::FLIP_VERTICAL, DynamicImage::flipv),
+            ];
+            for (transform, expected) in cases {
+                let output = super::transform(&source, transform).unwrap();
+                let rotated = image::load_from_memory(&output).unwrap();
+                let expected = expected(&decoded);
+                assert_eq!(rotated.dimensions(), expected.dimensions(), "{}", transform.name());
+                assert!(max_difference(&rotated, &expected) <= 2, "q{} {}", quality, transform.name());
+            }
+        }
+    }
+
+    #[test]
+    fn test_lossless_transform_sampling_and_restarts() {
+        use jpeg_encoder::SamplingFactor;
+
+        let cases: [(Transform, fn(&DynamicImage) -> DynamicImage); 4] = [
+            (Transform::ROTATE_90, DynamicImage::rotate90),
+            (Transform::ROTATE_180, DynamicImage::rotate180),
+            (Transform::TRANSPOSE, transpose),
+            (Transform::FLIP_HORIZONTAL, DynamicImage::fliph),
+        ];
+        for (sampling, restart_interval) in
+            [(SamplingFactor::R_4_2_2, 0), (SamplingFactor::R_4_2_0, 2), (SamplingFactor::R_4_4_4, 1), (SamplingFactor::R_4_4_0, 3)]
+        {
+            let source = encode_jpeg_with(&gradient(48, 32), sampling, restart_interval);
+            let decoded = image::load_from_memory(&source).unwrap();
+            for (transform, expected) in cases {
+                let output = super::transform(&source, transform).unwrap();
+                let rotated = image::load_from_memory(&output).unwrap();
+                let expected = expected(&decoded);
+                assert_eq!(rotated.dimensions(), expected.dimensions(), "{:?} {}", sampling, transform.name());
+                // 整数 IDCT 的行、列舍入不对称，交换宽高的变换误差略大
+                assert!(max_difference(&rotated, &expected) <= 3, "{:?} restart {} {}", sampling, restart_interval, transform.name());
+            }
+        }
+    }
+
+    #[test]
+    fn test_lossless_transform_partial_mcu_edges() {
+        // 4:2:0 的 MCU 为 16x16；不翻转的方向上边缘不完整的 MCU 保持在末尾，仍可无损变换
+        let source = encode_jpeg_with(&gradient(45, 27), jpeg_encoder::SamplingFactor::R_4_2_0, 0);
+        let decoded = image::load_from_memory(&source).unwrap();
+        let output = transform(&source, Transform::TRANSPOSE).unwrap();
+        let transposed = image::load_from_memory(&output).unwrap();
+        assert_eq!(transposed.dimensions(), (27, 45));
+        assert!(max_difference(&transposed, &transpose(&decoded)) <= 3);
+
+        // 旋转 90 度只翻转源图的垂直方向，宽度可以不是 MCU 的整数倍
+        let source = encode_jpeg_with(&gradient(45, 32), jpeg_encoder::SamplingFactor::R_4_2_0, 0);
+        let decoded = image::load_from_memory(&source).unwrap();
+        let output = transform(&source, Transform::ROTATE_90).unwrap();
+        let rotated = image::load_from_memory(&output).unwrap();
+        assert_eq!(rotated.dimensions(), (32, 45));
+        assert!(max_difference(&rotated, &decoded.rotate90()) <= 3);
+        assert!(transform(&source, Transform::ROTATE_270).is_err());
+    }
+
+    #[test]
+    fn test_lossless_transform_rejects_malformed_input() {
+        // SOF0 之后是一个长度为 0 的 COM 段
+        let mut data = vec![0xFF, 0xD8, 0xFF, 0xC0, 0x00, 0x0B, 8, 0, 16, 0, 16, 1, 1, 0x11, 0];
+        data.extend_from_slice(&[0xFF, 0xFE, 0x00, 0x00]);
+        assert_eq!(transform(&data, Transform::ROTATE_90), Err("invalid segment length".to_string()));
+
+        // 截断或改写任意字节都只返回 Err，不会 panic
+        let source = encode_jpeg_with(&gradient(32, 16), jpeg_encoder::SamplingFactor::R_4_2_0, 1);
+        for length in 0..source.len() {
+            let _ = transform(&source[..length], Transform::ROTATE_90);
+        }
+        for position in 2..source.len() {
+            for value in [0x00, 0x01, 0x7F, 0xFF] {
+                let mut corrupted = source.clone();
+                corrupted[position] = value;
+                let _ = transform(&corrupted, Transform::TRANSPOSE);
+            }
+        }
+    }
+
+    #[test]
+    fn test_lossless_transform_rejects_partial_mcus() {
+        // 4:2:0 的 MCU 为 16x16，宽度 40 翻转后边缘的块会错位
+        let source = encode_jpeg(&gradient(40, 32), 80);
+        assert!(transform(&source, Transform::FLIP_HORIZONTAL).is_err());
+        // 只翻转垂直方向时宽度不受限制
+        assert!(transform(&source, Transform::FLIP_VERTICAL).is_ok());
+        assert!(transform(b"\x89PNG\r\n\x1a\n", Transform::ROTATE_90).is_err());
+    }
+
+    #[test]
+    fn test_reset_exif_orientation() {
+        // 小端 TIFF：IFD0 含 Orientation=6 和指向 Exif IFD 的指针，Exif IFD 含像素宽高
+        let mut tiff = b"II*\0\x08\0\0\0".to_vec();
+        tiff.extend_from_slice(&[2, 0]);
+        tiff.extend_from_slice(&[0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0]);
+        tiff.extend_from_slice(&[0x69, 0x87, 4, 0, 1, 0, 0, 0, 38, 0, 0, 0]);
+        tiff.extend_from_slice(&[0, 0, 0, 0]);
+        tiff.extend_from_slice(&[2, 0]);
+        tiff.extend_from_slice(&[0x02, 0xA0, 4, 0, 1, 0, 0, 0, 40, 0, 0, 0]);
+        tiff.extend_from_slice(&[0x03, 0xA0, 4, 0, 1, 0, 0, 0, 30, 0, 0, 0]);
+        tiff.extend_from_slice(&[0, 0, 0, 0]);
+
+        reset_exif_orientation(&mut tiff, true);
+        assert_eq!(tiff[18], 1);
+        assert_eq!((tiff[48], tiff[60]), (30, 40));
+    }
+}
@@ -2,6 +2,24 @@ pub mod compression;
 pub mod handlers;
 pub mod errors;
 pub mod config;
+pub mod storage;
+pub mod cli;
+pub mod quality;
+pub mod animation;
+pub mod logging;
+pub mod color;
+pub mod static_files;
+pub mod queue;
+pub mod metrics;
+pub mod archive;
+pub mod streaming;
+pub mod magic;
+pub mod cors;
+pub mod negotiation;
+pub mod heif;
+pub mod pipeline;
+pub mod jpeg_transform;
+pub mod sprite;
 
 // Re-export commonly used items for easier testing
 #[allow(unused_imports)]
@@ -9,3 +27,15 @@ pub use compression::*;
 pub use handlers::*;
 pub use errors::*;
 pub use config::*;
+pub use storage::*;
+pub use cli::*;
+pub use quality::*;
+pub use animation::*;
+pub use logging::*;
+pub use color::*;
+pub use static_files::*;
+pub use queue::*;
+pub use metrics::*;
+pub use archive::*;
+pub use streaming::*;
+pub use magic::*;
@@ -0,0 +1,144 @@
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use std::future::Future;
+use std::io::Write;
+use tracing::Instrument;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
+
+use crate::config::LoggingConfig;
+
+/// 请求 ID 的请求/响应头
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// 可用的日志输出格式
+pub const LOG_FORMATS: [&str; 2] = ["text", "json"];
+
+/// 覆盖 span 过滤规则的环境变量，语法同 `RUST_LOG`
+pub const TRACE_FILTER_ENV: &str = "IMG_SERVER_TRACE";
+
+tokio::task_local! {
+    // 当前请求的 ID，JSON 日志在处理请求期间输出时附带
+    static REQUEST_ID: String;
+}
+
+/// 按配置初始化 env_logger，`json` 格式下每行输出一个 JSON 对象
+pub fn init(config: &LoggingConfig) {
+    let mut builder = env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or(&config.level)
+    );
+
+    if config.format.eq_ignore_ascii_case("json") {
+        builder.format(|buf, record| {
+            let line = serde_json::json!({
+                "timestamp": buf.timestamp_millis().to_string(),
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+                "request_id": current_request_id(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+
+    builder.init();
+    init_tracing(config);
+}
+
+/// 安装输出到标准输出的 tracing 订阅者，span 关闭时输出一行，附带 busy/idle 耗时
+///
+/// 日志记录仍由 env_logger 输出，这里只处理 span。需要导出到 OpenTelemetry 时，
+/// 在此叠加 `tracing-opentelemetry` 层即可导出同样的请求和阶段 span。
+fn init_tracing(config: &LoggingConfig) {
+    if !config.trace_spans {
+        return;
+    }
+
+    let filter = EnvFilter::try_from_env(TRACE_FILTER_ENV).unwrap_or_else(|_| EnvFilter::new(&config.level));
+    let builder = tracing_subscriber::fmt()
+        .with_writer(std::io::stdout)
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE);
+    // 不使用 try_init：它会接管 log 门面，与已初始化的 env_logger 冲突
+    let result = if config.format.eq_ignore_ascii_case("json") {
+        tracing::subscriber::set_global_default(builder.json().finish())
+    } else {
+        tracing::subscriber::set_global_default(builder.finish())
+    };
+    if let Err(e) = result {
+        eprintln!("Failed to install tracing subscriber: {}", e);
+    }
+}
+
+/// 覆盖整个请求的 span，解码、转换、编码阶段的 span 都挂在它下面
+pub fn request_span(req: &ServiceRequest, request_id: &str) -> tracing::Span {
+    tracing::info_span!(
+        "request",
+        method = %req.method(),
+        path = %req.path(),
+        request_id = %request_id,
+        status = tracing::field::Empty,
+    )
+}
+
+/// 在 `span` 内执行后续处理，并把响应状态码记录到 span
+pub async fn with_request_span<B, E>(
+    span: tracing::Span,
+    fut: impl Future<Output = Result<ServiceResponse<B>, E>>,
+) -> Result<ServiceResponse<B>, E> {
+    let response = fut.instrument(span.clone()).await?;
+    span.record("status", response.status().as_u16());
+    Ok(response)
+}
+
+/// 在阻塞线程池中沿用当前 span，阶段 span 才能挂到所属请求下
+pub fn in_current_span<F, R>(f: F) -> impl FnOnce() -> R
+where
+    F: FnOnce() -> R,
+{
+    let span = tracing::Span::current();
+    move || span.in_scope(f)
+}
+
+/// 当前任务所属请求的 ID，不在请求处理中时返回 `None`
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// 读取请求中的 X-Request-Id，没有时生成新的 UUID
+pub fn request_id_for(req: &ServiceRequest) -> String {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty() && value.len() <= 128)
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string())
+}
+
+/// 在请求 ID 作用域内执行后续处理，并在响应中回写 X-Request-Id
+pub async fn with_request_id<B, E>(
+    request_id: String,
+    fut: impl Future<Output = Result<ServiceResponse<B>, E>>,
+) -> Result<ServiceResponse<B>, E> {
+    let mut response = REQUEST_ID.scope(request_id.clone(), fut).await?;
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static("x-request-id"), value);
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_request_id_scope() {
+        assert_eq!(current_request_id(), None);
+        let id = REQUEST_ID
+            .scope("abc".to_string(), async { current_request_id() })
+            .await;
+        assert_eq!(id.as_deref(), Some("abc"));
+    }
+}
@@ -0,0 +1,133 @@
+/// `detect_magic_types` 能识别的类型，`denied_types` 只能使用这些名称
+pub const MAGIC_TYPES: [&str; 20] = [
+    "image/jpeg",
+    "image/png",
+    "image/gif",
+    "image/webp",
+    "image/bmp",
+    "image/tiff",
+    "image/x-icon",
+    "image/avif",
+    "image/heic",
+    "image/svg+xml",
+    "application/pdf",
+    "application/postscript",
+    "application/zip",
+    "application/gzip",
+    "application/x-rar-compressed",
+    "application/x-7z-compressed",
+    "application/x-msdownload",
+    "application/x-elf",
+    "application/x-mach-binary",
+    "text/html",
+];
+
+/// PDF 阅读器接受文件头前有垃圾数据，文本类特征也只在开头这段范围内查找
+const HEADER_SCAN_LEN: usize = 1024;
+
+/// ZIP 的中央目录结束记录位于文件末尾，最多带 65535 字节注释
+const ZIP_EOCD_SCAN_LEN: usize = 22 + 65535;
+
+/// 按魔数识别数据可能的全部类型
+///
+/// 一个文件可以同时是合法图片和其他格式（多语言文件，如文件头附近嵌入 `%PDF-` 或末尾追加 ZIP 的 PNG），
+/// 因此返回所有匹配的类型而不只是第一个。
+pub fn detect_magic_types(data: &[u8]) -> Vec<&'static str> {
+    let mut types = Vec::new();
+    let header = &data[..data.len().min(HEADER_SCAN_LEN)];
+    let header_lower = header.to_ascii_lowercase();
+
+    let prefixes: [(&[u8], &'static str); 19] = [
+        (b"\xFF\xD8\xFF", "image/jpeg"),
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"BM", "image/bmp"),
+        (b"II*\0", "image/tiff"),
+        (b"MM\0*", "image/tiff"),
+        (b"\0\0\x01\0", "image/x-icon"),
+        (b"%!PS", "application/postscript"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1F\x8B", "application/gzip"),
+        (b"Rar!\x1A\x07", "application/x-rar-compressed"),
+        (b"7z\xBC\xAF\x27\x1C", "application/x-7z-compressed"),
+        (b"MZ", "application/x-msdownload"),
+        (b"\x7FELF", "application/x-elf"),
+        (b"\xFE\xED\xFA\xCE", "application/x-mach-binary"),
+        (b"\xFE\xED\xFA\xCF", "application/x-mach-binary"),
+        (b"\xCE\xFA\xED\xFE", "application/x-mach-binary"),
+        (b"\xCF\xFA\xED\xFE", "application/x-mach-binary"),
+    ];
+    for (prefix, mime_type) in prefixes {
+        if data.starts_with(prefix) && !types.contains(&mime_type) {
+            types.push(mime_type);
+        }
+    }
+
+    if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        types.push("image/webp");
+    }
+    if data.len() >= 12 && &data[4..8] == b"ftyp" {
+        match &data[8..12] {
+            b"avif" | b"avis" => types.push("image/avif"),
+            _ if crate::heif::is_heif(data) => types.push("image/heic"),
+            _ => {}
+        }
+    }
+
+    if contains(header, b"%PDF-") {
+        types.push("application/pdf");
+    }
+    if contains(&header_lower, b"<svg") {
+        types.push("image/svg+xml");
+    }
+    if [&b"<!doctype html"[..], b"<html", b"<script"].iter().any(|needle| contains(&header_lower, needle)) {
+        types.push("text/html");
+    }
+
+    // 末尾追加的 ZIP 只能通过中央目录结束记录发现
+    let tail = &data[data.len().saturating_sub(ZIP_EOCD_SCAN_LEN)..];
+    if contains(tail, b"PK\x05\x06") && !types.contains(&"application/zip") {
+        types.push("application/zip");
+    }
+
+    types
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_detect_magic_types() {
+        let mut png = Vec::new();
+        image::DynamicImage::new_rgb8(4, 4)
+            .write_to(&mut Cursor::new(&mut png), image::ImageOutputFormat::Png)
+            .unwrap();
+        assert_eq!(detect_magic_types(&png), vec!["image/png"]);
+
+        assert_eq!(detect_magic_types(b"%PDF-1.7\n%\xE2\xE3"), vec!["application/pdf"]);
+        assert_eq!(detect_magic_types(b"RIFF\0\0\0\0WEBPVP8 "), vec!["image/webp"]);
+        assert_eq!(detect_magic_types(b"\0\0\0\x18ftypheic\0\0\0\0"), vec!["image/heic"]);
+        assert_eq!(detect_magic_types(b"\n<!DOCTYPE html><html>"), vec!["text/html"]);
+        assert!(detect_magic_types(b"plain text").is_empty());
+
+        // 多语言文件：PNG 后追加 ZIP，或文件头附近嵌入 PDF 头
+        let mut polyglot = png.clone();
+        polyglot.extend_from_slice(b"PK\x05\x06\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0");
+        assert_eq!(detect_magic_types(&polyglot), vec!["image/png", "application/zip"]);
+
+        let mut polyglot = b"\xFF\xD8\xFF\xE0\0\x10JFIF\0".to_vec();
+        polyglot.extend_from_slice(b"%PDF-1.4");
+        assert_eq!(detect_magic_types(&polyglot), vec!["image/jpeg", "application/pdf"]);
+
+        for mime_type in detect_magic_types(&polyglot) {
+            assert!(MAGIC_TYPES.contains(&mime_type));
+        }
+    }
+}
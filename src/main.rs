@@ -1,11 +1,15 @@
-mod compression;
-mod handlers;
-mod errors;
-mod config;
-
-use actix_web::{middleware::Logger, web, App, HttpServer};
-use config::Config;
-use log::info;
+use actix_web::{dev::Service, http::Method, middleware::{Compress, Logger}, web, App, HttpServer};
+use img_server_rs::cli;
+use img_server_rs::compression;
+use img_server_rs::config::Config;
+use img_server_rs::cors;
+use img_server_rs::handlers;
+use img_server_rs::logging;
+use img_server_rs::metrics::CompressionMetrics;
+use img_server_rs::queue::JobQueue;
+use img_server_rs::static_files;
+use img_server_rs::storage;
+use log::{info, warn};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -16,10 +20,21 @@ async fn main() -> std::io::Result<()> {
         Config::default()
     });
 
-    // Initialize logger with configured level
-    env_logger::Builder::from_env(
-        env_logger::Env::default().default_filter_or(&config.logging.level)
-    ).init();
+    // Initialize logger with configured level and format
+    logging::init(&config.logging);
+
+    // `compress` 子命令：直接压缩本地文件后退出，不启动 HTTP 服务
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("compress") {
+        let result = cli::CompressArgs::parse(&args[1..], &config.compression.default_algorithm)
+            .map_err(|e| format!("{}\n{}", e, cli::COMPRESS_USAGE))
+            .and_then(|compress_args| cli::run_compress(&compress_args));
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
 
     info!("Starting Image Compression Server v{}", env!("CARGO_PKG_VERSION"));
     info!("Server will listen on http://{}", config.bind_address());
@@ -27,26 +42,111 @@ async fn main() -> std::io::Result<()> {
     info!("Default compression quality: {}", config.compression.default_quality);
     info!("Default compression algorithm: {}", config.compression.default_algorithm);
 
+    // 预热原生编码器，避免部署后第一个请求承担初始化开销
+    let warmup_start = std::time::Instant::now();
+    for (algorithm, result) in compression::warm_up_encoders() {
+        match result {
+            Ok(duration) => info!("Warmed up {} in {:.2}ms", algorithm, duration.as_secs_f64() * 1000.0),
+            Err(e) => warn!("Warmup failed for {}: {}", algorithm, e),
+        }
+    }
+    info!("Encoder warmup finished in {:.2}ms", warmup_start.elapsed().as_secs_f64() * 1000.0);
+
+    let storage = match storage::create_storage(&config.storage) {
+        Ok(storage) => storage,
+        Err(e) => {
+            eprintln!("Failed to initialize storage: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // 所有 worker 共享同一个任务队列
+    let job_queue = web::Data::new(JobQueue::from_config(&config.compression));
+    // 按格式和算法统计的压缩收益，同样在所有 worker 间共享
+    let compression_metrics = web::Data::new(CompressionMetrics::new());
+    info!(
+        "Max concurrent jobs: {}, queue wait timeout: {}ms",
+        config.compression.max_concurrent_jobs, config.compression.queue_wait_timeout_ms
+    );
+
     let bind_address = config.bind_address();
     let max_payload_size = config.max_file_size_bytes();
     let worker_threads = config.server.worker_threads;
+    let json_logs = config.logging.format.eq_ignore_ascii_case("json");
+
+    // 静态目录不存在时不注册静态文件服务，`/` 按 root_response 响应，其他未匹配的路径直接返回 404
+    let serve_static = std::path::Path::new(&config.static_files.dir).is_dir();
+    if !serve_static {
+        warn!(
+            "Static directory {} not found, web UI disabled; API endpoints are unaffected",
+            config.static_files.dir
+        );
+    }
 
     let mut server = HttpServer::new(move || {
-        App::new()
+        let static_config = config.static_files.clone();
+        let server_config = config.server.clone();
+        let mut app = App::new()
             .app_data(web::PayloadConfig::new(max_payload_size))
             .app_data(web::Data::new(config.clone()))
-            .wrap(Logger::default())
-            .wrap(
-                actix_web::middleware::DefaultHeaders::new()
-                    .add(("Access-Control-Allow-Origin", "*"))
-                    .add(("Access-Control-Allow-Methods", "GET, POST, OPTIONS"))
-                    .add(("Access-Control-Allow-Headers", "Content-Type, Authorization")),
-            )
+            .app_data(job_queue.clone())
+            .app_data(compression_metrics.clone());
+
+        if let Some(storage) = storage.clone() {
+            app = app.app_data(web::Data::from(storage));
+        }
+
+        let mut app = app
+            // JSON 响应按 Accept-Encoding 压缩，图片响应自身标记为 identity 不会被重复压缩
+            .wrap(Compress::default())
+            .wrap_fn(|req, srv| {
+                let request_id = logging::request_id_for(&req);
+                let span = logging::request_span(&req, &request_id);
+                logging::with_request_span(span, logging::with_request_id(request_id, srv.call(req)))
+            })
+            .wrap(if json_logs {
+                // 访问日志在请求作用域外输出，通过响应头带上请求 ID
+                Logger::new(r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T request_id=%{X-Request-Id}o"#)
+            } else {
+                Logger::default()
+            })
+            // 只有 cors_endpoints 中的接口带 CORS 响应头，/metrics 等运维接口默认不允许跨域读取
+            .wrap_fn(move |req, srv| {
+                let enabled = cors::allows(req.path(), &server_config);
+                cors::with_cors_headers(enabled, srv.call(req))
+            })
             .route("/health", web::get().to(handlers::health_check))
             .route("/info", web::get().to(handlers::info_endpoint))
+            .route("/metrics", web::get().to(handlers::metrics_endpoint))
             .route("/compress", web::post().to(handlers::compress_endpoint))
-            // 静态文件服务 - 放在最后以避免拦截API路由
-            .service(actix_files::Files::new("/", "./static").index_file("index.html"))
+            .route("/pyramid", web::post().to(handlers::pyramid_endpoint))
+            .route("/analyze", web::post().to(handlers::analyze_endpoint))
+            .route("/estimate-quality", web::post().to(handlers::estimate_quality_endpoint))
+            .route("/compare", web::post().to(handlers::compare_endpoint))
+            .route("/sprite", web::post().to(handlers::sprite_endpoint))
+            .route("/compress/raw", web::post().to(handlers::compress_raw_endpoint))
+            .route("/compress/zip", web::post().to(handlers::compress_zip_endpoint));
+
+        // 不允许跨域的上传接口不响应预检，浏览器随即拒绝跨域请求
+        for path in cors::PREFLIGHT_ENDPOINTS {
+            if cors::allows(path, &config.server) {
+                app = app.route(path, web::method(Method::OPTIONS).to(handlers::preflight));
+            }
+        }
+
+        if !serve_static {
+            return app.route("/", web::get().to(handlers::root_endpoint));
+        }
+
+        // 静态文件服务 - 放在最后以避免拦截API路由
+        app.service(
+            web::scope("")
+                .wrap_fn(move |req, srv| {
+                    let cache_control = static_files::cache_control_for(req.path(), &static_config);
+                    static_files::with_cache_control(cache_control, srv.call(req))
+                })
+                .service(static_files::files(&config.static_files)),
+        )
     });
 
     // Set worker threads if specified
@@ -0,0 +1,70 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// 某个输出格式和算法组合累计的压缩字节数
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SavingsStats {
+    pub compressions: u64,
+    pub original_bytes: u64,
+    pub compressed_bytes: u64,
+}
+
+impl SavingsStats {
+    /// 累计节省的字节数，输出比输入大时为负
+    pub fn bytes_saved(&self) -> i64 {
+        self.original_bytes as i64 - self.compressed_bytes as i64
+    }
+}
+
+/// 按输出格式和算法统计的压缩收益，供 `/metrics` 输出
+#[derive(Debug, Default)]
+pub struct CompressionMetrics {
+    savings: Mutex<BTreeMap<(String, String), SavingsStats>>,
+}
+
+impl CompressionMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 记录一次成功的压缩，格式和算法按小写归并
+    pub fn record(&self, format: &str, algorithm: &str, original_size: usize, compressed_size: usize) {
+        let key = (format.to_lowercase(), algorithm.to_lowercase());
+        let mut savings = self.savings.lock().unwrap_or_else(|e| e.into_inner());
+        let stats = savings.entry(key).or_default();
+        stats.compressions += 1;
+        stats.original_bytes += original_size as u64;
+        stats.compressed_bytes += compressed_size as u64;
+    }
+
+    /// 按 (格式, 算法) 排序的快照
+    pub fn savings(&self) -> Vec<((String, String), SavingsStats)> {
+        let savings = self.savings.lock().unwrap_or_else(|e| e.into_inner());
+        savings.iter().map(|(key, stats)| (key.clone(), *stats)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_savings() {
+        let metrics = CompressionMetrics::new();
+        metrics.record("WebP", "mozjpeg", 1000, 400);
+        metrics.record("webp", "MozJPEG", 500, 300);
+        metrics.record("png", "png-quantized", 100, 150);
+
+        let savings = metrics.savings();
+        assert_eq!(savings.len(), 2);
+
+        let ((format, algorithm), png) = &savings[0];
+        assert_eq!((format.as_str(), algorithm.as_str()), ("png", "png-quantized"));
+        assert_eq!(png.bytes_saved(), -50);
+
+        let ((format, _), webp) = &savings[1];
+        assert_eq!(format, "webp");
+        assert_eq!(*webp, SavingsStats { compressions: 2, original_bytes: 1500, compressed_bytes: 700 });
+        assert_eq!(webp.bytes_saved(), 800);
+    }
+}
@@ -0,0 +1,112 @@
+use actix_web::http::header::{HeaderName, HeaderValue, ACCEPT, USER_AGENT, VARY};
+use actix_web::{HttpRequest, HttpResponse};
+
+use crate::config::CompressionConfig;
+
+/// 请求此格式名时按 `Accept` 和 `User-Agent` 选择输出格式
+pub const AUTO_FORMAT: &str = "auto";
+
+/// `format=auto` 的选择结果
+#[derive(Debug, Clone, PartialEq)]
+pub struct AutoFormat {
+    /// 最终使用的输出格式
+    pub format: &'static str,
+    /// 选择原因：`accept`、`not-accepted` 或 `legacy-user-agent`
+    pub reason: &'static str,
+    /// 结果依赖的请求头，写入 `Vary` 以免共享缓存把 WebP 返回给旧客户端
+    pub vary: &'static str,
+}
+
+/// 为 `format=auto` 选择输出格式
+///
+/// `Accept` 明确列出 `image/webp` 时使用 WebP，否则使用 `fallback`（按输入格式的默认格式，
+/// 通常为 JPEG）。`User-Agent` 命中 `legacy_user_agents` 的客户端即使声明支持也回退，
+/// 该表为空时只依据 `Accept`。
+pub fn select_format(req: &HttpRequest, fallback: &'static str, config: &CompressionConfig) -> AutoFormat {
+    let header = |name: HeaderName| req.headers().get(name).and_then(|value| value.to_str().ok()).unwrap_or("");
+    let vary = if config.legacy_user_agents.is_empty() { "Accept" } else { "Accept, User-Agent" };
+
+    let (format, reason) = if !accepts(header(ACCEPT), "image/webp") {
+        (fallback, "not-accepted")
+    } else if is_legacy_user_agent(header(USER_AGENT), &config.legacy_user_agents) {
+        (fallback, "legacy-user-agent")
+    } else {
+        ("webp", "accept")
+    };
+
+    AutoFormat { format, reason, vary }
+}
+
+/// `Accept` 是否明确列出 `media_type`（`q=0` 表示拒绝），通配符 `*/*` 和 `image/*` 不算
+pub fn accepts(accept: &str, media_type: &str) -> bool {
+    accept.split(',').any(|entry| {
+        let mut parts = entry.split(';').map(str::trim);
+        let matches = parts.next().is_some_and(|name| name.eq_ignore_ascii_case(media_type));
+        let rejected = parts.any(|param| {
+            param
+                .strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .is_some_and(|q| q <= 0.0)
+        });
+        matches && !rejected
+    })
+}
+
+/// `User-Agent` 是否包含 `patterns` 中任一子串（不区分大小写）
+pub fn is_legacy_user_agent(user_agent: &str, patterns: &[String]) -> bool {
+    let user_agent = user_agent.to_ascii_lowercase();
+    patterns.iter().any(|pattern| user_agent.contains(&pattern.to_ascii_lowercase()))
+}
+
+/// 写入 `X-Auto-Format`、`X-Auto-Format-Reason` 和 `Vary`，未使用 `format=auto` 时原样返回
+pub fn with_auto_format_headers(mut response: HttpResponse, selection: Option<&AutoFormat>) -> HttpResponse {
+    if let Some(selection) = selection {
+        let headers = response.headers_mut();
+        headers.insert(
+            HeaderName::from_static("x-auto-format"),
+            HeaderValue::from_static(selection.format),
+        );
+        headers.insert(
+            HeaderName::from_static("x-auto-format-reason"),
+            HeaderValue::from_static(selection.reason),
+        );
+        headers.insert(VARY, HeaderValue::from_static(selection.vary));
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_accepts() {
+        assert!(accepts("image/avif,image/webp,*/*;q=0.8", "image/webp"));
+        assert!(accepts("IMAGE/WEBP;q=0.5", "image/webp"));
+        assert!(!accepts("image/webp;q=0", "image/webp"));
+        assert!(!accepts("*/*", "image/webp"));
+        assert!(!accepts("image/*", "image/webp"));
+        assert!(!accepts("", "image/webp"));
+    }
+
+    #[test]
+    fn test_select_format() {
+        let mut config = Config::default().compression;
+        let modern = TestRequest::default()
+            .insert_header((ACCEPT, "image/webp,*/*"))
+            .insert_header((USER_AGENT, "Mozilla/5.0 (Macintosh) Version/13.1 Safari/605.1.15"))
+            .to_http_request();
+        let old = TestRequest::default().insert_header((ACCEPT, "*/*")).to_http_request();
+
+        let selection = select_format(&modern, "jpeg", &config);
+        assert_eq!((selection.format, selection.reason, selection.vary), ("webp", "accept", "Accept"));
+        assert_eq!(select_format(&old, "jpeg", &config).reason, "not-accepted");
+
+        config.legacy_user_agents = vec!["version/13.".to_string()];
+        let selection = select_format(&modern, "jpeg", &config);
+        assert_eq!((selection.format, selection.reason), ("jpeg", "legacy-user-agent"));
+        assert_eq!(selection.vary, "Accept, User-Agent");
+    }
+}
@@ -0,0 +1,339 @@
+use image::DynamicImage;
+use tracing::info_span;
+
+use crate::compression::{self, CompressionOptions, ExifInfo, ResizeFilter};
+use crate::errors::ImageServerError;
+use crate::jpeg_transform::Transform;
+
+/// 单个请求最多可指定的操作数
+pub const MAX_OPERATIONS: usize = 16;
+
+/// `ops` 参数可使用的操作名
+pub const OPERATION_NAMES: [&str; 8] = ["auto-orient", "rotate", "flip", "crop", "resize", "sharpen", "grayscale", "flatten"];
+
+/// 编码前按请求顺序作用于解码后图片的操作
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operation {
+    /// 按 EXIF 方向旋转像素；JPEG 输出在解码时已经旋转，此时不再处理
+    AutoOrient,
+    /// 顺时针旋转 90、180 或 270 度
+    Rotate { degrees: u16 },
+    /// 水平（左右）或垂直（上下）翻转
+    Flip { horizontal: bool },
+    /// 裁剪出左上角为 (`x`, `y`) 的 `width`x`height` 区域，超出图片范围时返回 400
+    Crop { x: u32, y: u32, width: u32, height: u32 },
+    /// 等比缩小到不超过给定的宽和高，只给一边时按该边缩放，不放大
+    Resize { width: Option<u32>, height: Option<u32> },
+    /// 反锐化掩模：`sigma` 为模糊半径，差值不超过 `threshold` 的像素不处理
+    Sharpen { sigma: f32, threshold: i32 },
+    /// 转为灰度，保留 alpha 通道
+    Grayscale,
+    /// 把透明区域合成到纯色背景上
+    Flatten { background: [u8; 3] },
+}
+
+impl Operation {
+    /// 解析 `name` 或 `name:args` 形式的单个操作，如 `crop:10,10,200,100`、`resize:400x`
+    pub fn parse(spec: &str) -> Result<Self, ImageServerError> {
+        let (name, args) = spec.split_once(':').unwrap_or((spec, ""));
+        let name = name.trim().to_ascii_lowercase();
+        let args = args.trim();
+        let invalid = |usage: &str| {
+            ImageServerError::InvalidParameters(format!("Invalid operation {}: expected {}", spec.trim(), usage))
+        };
+
+        match name.as_str() {
+            "auto-orient" if args.is_empty() => Ok(Self::AutoOrient),
+            "grayscale" if args.is_empty() => Ok(Self::Grayscale),
+            "auto-orient" | "grayscale" => Err(invalid(&format!("{} without arguments", name))),
+            "rotate" => match args.parse::<u16>() {
+                Ok(degrees @ (90 | 180 | 270)) => Ok(Self::Rotate { degrees }),
+                _ => Err(invalid("rotate:90, rotate:180 or rotate:270")),
+            },
+            "flip" => match args.to_ascii_lowercase().as_str() {
+                "h" | "horizontal" => Ok(Self::Flip { horizontal: true }),
+                "v" | "vertical" => Ok(Self::Flip { horizontal: false }),
+                _ => Err(invalid("flip:h or flip:v")),
+            },
+            "crop" => {
+                let usage = "crop:x,y,width,height with a non-zero width and height";
+                let values = args
+                    .split(',')
+                    .map(|value| value.trim().parse::<u32>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| invalid(usage))?;
+                match values[..] {
+                    [x, y, width, height] if width > 0 && height > 0 => Ok(Self::Crop { x, y, width, height }),
+                    _ => Err(invalid(usage)),
+                }
+            }
+            "resize" => {
+                let usage = "resize:WIDTHxHEIGHT, resize:WIDTHx or resize:xHEIGHT with non-zero sizes";
+                let (width, height) = args.split_once(['x', 'X']).ok_or_else(|| invalid(usage))?;
+                let parse_side = |side: &str| match side.trim() {
+                    "" => Ok(None),
+                    side => side.parse::<u32>().ok().filter(|&size| size > 0).map(Some).ok_or_else(|| invalid(usage)),
+                };
+                match (parse_side(width)?, parse_side(height)?) {
+                    (None, None) => Err(invalid(usage)),
+                    (width, height) => Ok(Self::Resize { width, height }),
+                }
+            }
+            "sharpen" => {
+                let usage = "sharpen, sharpen:SIGMA or sharpen:SIGMA,THRESHOLD with SIGMA between 0 and 10";
+                let mut parts = args.split(',').map(str::trim).filter(|part| !part.is_empty());
+                let sigma = match parts.next() {
+                    Some(sigma) => sigma.parse::<f32>().map_err(|_| invalid(usage))?,
+                    None => 1.0,
+                };
+                let threshold = match parts.next() {
+                    Some(threshold) => threshold.parse::<i32>().ok().filter(|&t| t >= 0).ok_or_else(|| invalid(usage))?,
+                    None => 1,
+                };
+                if parts.next().is_some() || !(sigma > 0.0 && sigma <= 10.0) {
+                    return Err(invalid(usage));
+                }
+                Ok(Self::Sharpen { sigma, threshold })
+            }
+            "flatten" => {
+                let background = if args.is_empty() {
+                    [255, 255, 255]
+                } else {
+                    crate::handlers::parse_hex_color(args).ok_or_else(|| invalid("flatten or flatten:RRGGBB"))?
+                };
+                Ok(Self::Flatten { background })
+            }
+            _ => Err(ImageServerError::InvalidParameters(format!(
+                "Unknown operation {}. Must be one of: {:?}",
+                name, OPERATION_NAMES
+            ))),
+        }
+    }
+
+    /// 旋转、翻转对应的像素网格变换，其他操作为 `None`；只包含这些操作的 JPEG 可以无损变换
+    pub fn transform(&self) -> Option<Transform> {
+        match *self {
+            Self::Rotate { degrees: 90 } => Some(Transform::ROTATE_90),
+            Self::Rotate { degrees: 180 } => Some(Transform::ROTATE_180),
+            Self::Rotate { .. } => Some(Transform::ROTATE_270),
+            Self::Flip { horizontal: true } => Some(Transform::FLIP_HORIZONTAL),
+            Self::Flip { horizontal: false } => Some(Transform::FLIP_VERTICAL),
+            _ => None,
+        }
+    }
+
+    // 缩放操作作用于 `img` 时的目标尺寸，其他操作或不需要缩小时为 `None`
+    fn resize_target(&self, img: &DynamicImage) -> Option<(u32, u32)> {
+        let (width, height) = match *self {
+            Self::Resize { width, height } => (width, height),
+            _ => return None,
+        };
+        let (source_width, source_height) = (img.width(), img.height());
+        let scale = [
+            width.map(|w| w as f64 / source_width as f64),
+            height.map(|h| h as f64 / source_height as f64),
+        ]
+        .into_iter()
+        .flatten()
+        .fold(f64::INFINITY, f64::min);
+        if scale >= 1.0 {
+            return None;
+        }
+        let target_width = ((source_width as f64 * scale).round() as u32).max(1);
+        let target_height = ((source_height as f64 * scale).round() as u32).max(1);
+        Some((target_width, target_height))
+    }
+
+    /// 缩放操作作用于 `img` 时使用的滤镜，其他操作或不需要缩小时为 `None`
+    pub fn resize_filter(&self, img: &DynamicImage, options: &CompressionOptions) -> Option<ResizeFilter> {
+        self.resize_target(img)
+            .map(|(target_width, _)| options.resize_filter_for(img.width(), target_width))
+    }
+
+    /// 对 `img` 执行本操作，`auto-orient` 旋转后更新 `exif_info`
+    pub fn apply(
+        &self,
+        img: DynamicImage,
+        exif_info: &mut ExifInfo,
+        options: &CompressionOptions,
+    ) -> Result<DynamicImage, ImageServerError> {
+        match *self {
+            Self::AutoOrient => match exif_info.orientation {
+                Some(orientation) if !exif_info.orientation_applied => {
+                    exif_info.orientation_applied = true;
+                    exif_info.summary = format!("Applied EXIF orientation: {}", orientation);
+                    Ok(compression::apply_exif_orientation(img, orientation))
+                }
+                _ => Ok(img),
+            },
+            Self::Rotate { degrees: 90 } => Ok(img.rotate90()),
+            Self::Rotate { degrees: 180 } => Ok(img.rotate180()),
+            Self::Rotate { .. } => Ok(img.rotate270()),
+            Self::Flip { horizontal: true } => Ok(img.fliph()),
+            Self::Flip { horizontal: false } => Ok(img.flipv()),
+            Self::Crop { x, y, width, height } => {
+                let fits = x as u64 + width as u64 <= img.width() as u64
+                    && y as u64 + height as u64 <= img.height() as u64;
+                if !fits {
+                    return Err(ImageServerError::InvalidParameters(format!(
+                        "crop:{},{},{},{} is outside the {}x{} image",
+                        x, y, width, height, img.width(), img.height()
+                    )));
+                }
+                Ok(img.crop_imm(x, y, width, height))
+            }
+            Self::Resize { .. } => match self.resize_target(&img) {
+                Some((target_width, target_height)) => {
+                    let filter = options.resize_filter_for(img.width(), target_width);
+                    Ok(img.resize_exact(target_width, target_height, filter.filter_type()))
+                }
+                None => Ok(img),
+            },
+            Self::Sharpen { sigma, threshold } => Ok(img.unsharpen(sigma, threshold)),
+            Self::Grayscale => Ok(img.grayscale()),
+            Self::Flatten { background } if img.color().has_alpha() => Ok(compression::flatten_alpha(img, background)),
+            Self::Flatten { .. } => Ok(img),
+        }
+    }
+}
+
+/// 解析 `ops` 参数：`;` 分隔的操作（如 `crop:0,0,800,600;resize:400x;sharpen`），
+/// 或同样写法的 JSON 字符串数组（如 `["crop:0,0,800,600", "resize:400x"]`）
+pub fn parse_operations(value: &str) -> Result<Vec<Operation>, ImageServerError> {
+    let value = value.trim();
+    let specs: Vec<String> = if value.starts_with('[') {
+        serde_json::from_str(value).map_err(|e| {
+            ImageServerError::InvalidParameters(format!("ops must be a JSON array of operation strings: {}", e))
+        })?
+    } else {
+        value.split(';').map(str::trim).filter(|spec| !spec.is_empty()).map(String::from).collect()
+    };
+
+    if specs.len() > MAX_OPERATIONS {
+        return Err(ImageServerError::InvalidParameters(format!(
+            "ops allows at most {} operations, got {}",
+            MAX_OPERATIONS,
+            specs.len()
+        )));
+    }
+    specs.iter().map(|spec| Operation::parse(spec)).collect()
+}
+
+/// 按顺序执行全部操作，返回处理后的图片和各次缩放实际使用的滤镜（按执行顺序）
+pub fn apply(
+    img: DynamicImage,
+    operations: &[Operation],
+    exif_info: &mut ExifInfo,
+    options: &CompressionOptions,
+) -> Result<(DynamicImage, Vec<ResizeFilter>), ImageServerError> {
+    if operations.is_empty() {
+        return Ok((img, Vec::new()));
+    }
+    let _entered = info_span!("pipeline", operations = operations.len()).entered();
+    let mut resize_filters = Vec::new();
+    let img = operations.iter().try_fold(img, |img, operation| {
+        resize_filters.extend(operation.resize_filter(&img, options));
+        operation.apply(img, exif_info, options)
+    })?;
+    Ok((img, resize_filters))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgba8(image::RgbaImage::from_fn(width, height, |x, y| {
+            image::Rgba([(x * 2) as u8, (y * 2) as u8, 128, if x < width / 2 { 0 } else { 255 }])
+        }))
+    }
+
+    #[test]
+    fn test_parse_operations() {
+        let operations = parse_operations("crop:10,20,30,40; resize:100x ;sharpen;grayscale;flatten:#000;auto-orient;rotate:270;flip:h").unwrap();
+        assert_eq!(
+            operations,
+            vec![
+                Operation::Crop { x: 10, y: 20, width: 30, height: 40 },
+                Operation::Resize { width: Some(100), height: None },
+                Operation::Sharpen { sigma: 1.0, threshold: 1 },
+                Operation::Grayscale,
+                Operation::Flatten { background: [0, 0, 0] },
+                Operation::AutoOrient,
+                Operation::Rotate { degrees: 270 },
+                Operation::Flip { horizontal: true },
+            ]
+        );
+        assert_eq!(
+            parse_operations(r#"["resize:x50", "sharpen:2.5,4"]"#).unwrap(),
+            vec![
+                Operation::Resize { width: None, height: Some(50) },
+                Operation::Sharpen { sigma: 2.5, threshold: 4 },
+            ]
+        );
+        assert!(parse_operations("").unwrap().is_empty());
+
+        for invalid in ["rotate:45", "rotate", "flip:x", "crop:1,2,3", "crop:0,0,0,10", "resize:x", "resize:0x10", "sharpen:0", "grayscale:1", "flatten:zz", "[1, 2]"] {
+            assert!(
+                matches!(parse_operations(invalid), Err(ImageServerError::InvalidParameters(_))),
+                "{} should be rejected", invalid
+            );
+        }
+        assert!(parse_operations(&vec!["grayscale"; MAX_OPERATIONS + 1].join(";")).is_err());
+    }
+
+    #[test]
+    fn test_apply_operations_in_order() {
+        let options = CompressionOptions::new("png", 80, "png-quantized");
+        let mut exif_info = ExifInfo::unprocessed("");
+
+        // 先裁剪再缩放与先缩放再裁剪得到不同尺寸
+        let crop_then_resize = parse_operations("crop:0,0,100,50;resize:50x").unwrap();
+        let (img, filters) = apply(gradient(200, 100), &crop_then_resize, &mut exif_info, &options).unwrap();
+        assert_eq!(filters, vec![ResizeFilter::default()]);
+        assert_eq!((img.width(), img.height()), (50, 25));
+
+        let resize_then_crop = parse_operations("resize:50x;crop:0,0,20,20").unwrap();
+        let (img, _) = apply(gradient(200, 100), &resize_then_crop, &mut exif_info, &options).unwrap();
+        assert_eq!((img.width(), img.height()), (20, 20));
+
+        // 不放大，裁剪超出范围返回 400
+        let (img, filters) = apply(gradient(40, 20), &parse_operations("resize:400x400").unwrap(), &mut exif_info, &options).unwrap();
+        assert!(filters.is_empty());
+        assert_eq!((img.width(), img.height()), (40, 20));
+        assert!(apply(gradient(40, 20), &parse_operations("crop:30,0,20,20").unwrap(), &mut exif_info, &options).is_err());
+    }
+
+    #[test]
+    fn test_apply_pixel_operations() {
+        let options = CompressionOptions::new("png", 80, "png-quantized");
+        let mut exif_info = ExifInfo::unprocessed("");
+
+        let gray = Operation::Grayscale.apply(gradient(8, 8), &mut exif_info, &options).unwrap();
+        assert!(gray.color().has_alpha());
+        let pixel = gray.to_rgba8().get_pixel(6, 6).0;
+        assert_eq!((pixel[0], pixel[1]), (pixel[1], pixel[2]));
+
+        let flattened = Operation::Flatten { background: [255, 0, 0] }.apply(gradient(8, 8), &mut exif_info, &options).unwrap();
+        assert!(!flattened.color().has_alpha());
+        assert_eq!(flattened.to_rgb8().get_pixel(0, 0).0, [255, 0, 0]);
+
+        let sharpened = Operation::Sharpen { sigma: 1.0, threshold: 1 }.apply(gradient(8, 8), &mut exif_info, &options).unwrap();
+        assert_eq!((sharpened.width(), sharpened.height()), (8, 8));
+
+        // auto-orient 只在方向尚未应用时旋转
+        let mut exif_info = ExifInfo { orientation: Some(6), orientation_applied: false, summary: String::new() };
+        let rotated = Operation::AutoOrient.apply(gradient(8, 4), &mut exif_info, &options).unwrap();
+        assert_eq!((rotated.width(), rotated.height()), (4, 8));
+        assert!(exif_info.orientation_applied);
+        let unchanged = Operation::AutoOrient.apply(gradient(8, 4), &mut exif_info, &options).unwrap();
+        assert_eq!((unchanged.width(), unchanged.height()), (8, 4));
+
+        let rotated = Operation::Rotate { degrees: 90 }.apply(gradient(8, 4), &mut exif_info, &options).unwrap();
+        assert_eq!((rotated.width(), rotated.height()), (4, 8));
+        // 原图左半透明，顺时针旋转后位于上半部分，水平翻转后位于右半部分
+        assert_eq!(rotated.to_rgba8().get_pixel(0, 0).0[3], 0);
+        let flipped = Operation::Flip { horizontal: true }.apply(gradient(8, 4), &mut exif_info, &options).unwrap();
+        assert_eq!(flipped.to_rgba8().get_pixel(0, 0).0[3], 255);
+    }
+}
@@ -0,0 +1,568 @@
+use image::{DynamicImage, GrayImage};
+use log::info;
+
+use crate::compression::{self, encode_image, has_transparency, CompressionOptions};
+use crate::errors::ImageServerError;
+
+// SSIM 稳定常数，按 8 位动态范围计算
+const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+/// SSIM 计算使用的窗口大小
+const WINDOW: u32 = 8;
+
+/// 计算两张同尺寸灰度图的平均 SSIM（8x8 不重叠窗口）
+///
+/// 尺寸不一致时返回 `None`。
+pub fn ssim(a: &GrayImage, b: &GrayImage) -> Option<f64> {
+    if a.dimensions() != b.dimensions() {
+        return None;
+    }
+
+    let (width, height) = a.dimensions();
+    if width == 0 || height == 0 {
+        return None;
+    }
+
+    let mut total = 0.0;
+    let mut windows = 0u32;
+
+    let mut y = 0;
+    while y < height {
+        let window_h = WINDOW.min(height - y);
+        let mut x = 0;
+        while x < width {
+            let window_w = WINDOW.min(width - x);
+            total += window_ssim(a, b, x, y, window_w, window_h);
+            windows += 1;
+            x += WINDOW;
+        }
+        y += WINDOW;
+    }
+
+    Some(total / windows as f64)
+}
+
+fn window_ssim(a: &GrayImage, b: &GrayImage, x0: u32, y0: u32, w: u32, h: u32) -> f64 {
+    let n = (w * h) as f64;
+    let (mut sum_a, mut sum_b) = (0.0, 0.0);
+    for y in y0..y0 + h {
+        for x in x0..x0 + w {
+            sum_a += a.get_pixel(x, y)[0] as f64;
+            sum_b += b.get_pixel(x, y)[0] as f64;
+        }
+    }
+    let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+
+    let (mut var_a, mut var_b, mut cov) = (0.0, 0.0, 0.0);
+    for y in y0..y0 + h {
+        for x in x0..x0 + w {
+            let da = a.get_pixel(x, y)[0] as f64 - mean_a;
+            let db = b.get_pixel(x, y)[0] as f64 - mean_b;
+            var_a += da * da;
+            var_b += db * db;
+            cov += da * db;
+        }
+    }
+    var_a /= n;
+    var_b /= n;
+    cov /= n;
+
+    ((2.0 * mean_a * mean_b + C1) * (2.0 * cov + C2))
+        / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2))
+}
+
+/// 按目标 SSIM 搜索得到的压缩结果
+#[derive(Debug, Clone)]
+pub struct QualitySearchResult {
+    pub data: Vec<u8>,
+    pub quality: u8,
+    pub ssim: f64,
+}
+
+/// 二分查找满足目标 SSIM 的最低质量
+///
+/// 每个候选质量都会编码后重新解码并与原图比较；搜索不超过 `max_quality`，
+/// 即使最高质量也达不到目标时返回最高质量的结果。
+pub fn search_quality_for_ssim(
+    img: &DynamicImage,
+    options: &CompressionOptions,
+    target_ssim: f64,
+) -> Result<QualitySearchResult, ImageServerError> {
+    let reference = img.to_luma8();
+    let max_quality = options.max_quality.clamp(1, 100);
+    let (mut low, mut high) = (1u8, max_quality);
+    let mut best: Option<QualitySearchResult> = None;
+    let mut iterations = 0;
+
+    while low <= high {
+        let quality = low + (high - low) / 2;
+        let candidate = encode_and_measure(img, &reference, options, quality)?;
+        iterations += 1;
+        info!("SSIM 搜索 - 质量: {}, SSIM: {:.4}, 大小: {} bytes", quality, candidate.ssim, candidate.data.len());
+
+        if candidate.ssim >= target_ssim {
+            high = quality - 1;
+            best = Some(candidate);
+            if quality == 1 {
+                break;
+            }
+        } else {
+            low = quality + 1;
+        }
+    }
+
+    let result = match best {
+        Some(result) => result,
+        None => encode_and_measure(img, &reference, options, max_quality)?,
+    };
+
+    info!(
+        "SSIM 搜索完成 - 目标: {:.4}, 选择质量: {}, 实际 SSIM: {:.4}, 迭代次数: {}",
+        target_ssim, result.quality, result.ssim, iterations
+    );
+    Ok(result)
+}
+
+/// `size_search_max_iterations` 允许配置的上限
+pub const MAX_SIZE_SEARCH_ITERATIONS: u32 = 16;
+
+/// 按 `max_bytes` 搜索得到的压缩结果
+#[derive(Debug, Clone)]
+pub struct SizeSearchResult {
+    pub data: Vec<u8>,
+    pub quality: u8,
+    /// 实际编码的次数
+    pub iterations: u32,
+    /// 输出是否不超过 `max_bytes`；最低质量也放不下时为 `false`，返回搜索到的最小输出
+    pub fits: bool,
+}
+
+/// 二分查找输出不超过 `max_bytes` 的最高质量
+///
+/// 最多编码 `max_iterations` 次，保证单个请求的耗时可预期；某次输出落在目标以下 `tolerance_percent`
+/// 以内时立即停止。搜索不超过 `max_quality`，次数用完时返回已找到的最高质量结果。
+pub fn search_quality_for_size(
+    img: &DynamicImage,
+    options: &CompressionOptions,
+    max_bytes: usize,
+    max_iterations: u32,
+    tolerance_percent: f64,
+) -> Result<SizeSearchResult, ImageServerError> {
+    let max_quality = options.max_quality.clamp(1, 100);
+    let good_enough = max_bytes as f64 * (1.0 - tolerance_percent / 100.0);
+    let (mut low, mut high) = (1u8, max_quality);
+    // 放得下的最高质量结果，以及放不下时质量最低的结果
+    let mut best: Option<(Vec<u8>, u8)> = None;
+    let mut smallest: Option<(Vec<u8>, u8)> = None;
+    let mut iterations = 0;
+
+    while low <= high && iterations < max_iterations.max(1) {
+        let quality = low + (high - low) / 2;
+        let mut candidate_options = options.clone();
+        candidate_options.quality = quality;
+        let data = encode_image(img.clone(), &candidate_options)?;
+        iterations += 1;
+        info!("大小搜索 - 质量: {}, 大小: {} bytes, 目标: {} bytes", quality, data.len(), max_bytes);
+
+        if data.len() <= max_bytes {
+            let close_enough = data.len() as f64 >= good_enough;
+            best = Some((data, quality));
+            if close_enough {
+                break;
+            }
+            low = quality + 1;
+        } else {
+            smallest = Some((data, quality));
+            if quality == 1 {
+                break;
+            }
+            high = quality - 1;
+        }
+    }
+
+    let fits = best.is_some();
+    let (data, quality) = best.or(smallest).ok_or_else(|| {
+        ImageServerError::CompressionError("Size search produced no output".to_string())
+    })?;
+    info!(
+        "大小搜索完成 - 目标: {} bytes, 选择质量: {}, 大小: {} bytes, 迭代次数: {}",
+        max_bytes, quality, data.len(), iterations
+    );
+    Ok(SizeSearchResult { data, quality, iterations, fits })
+}
+
+fn encode_and_measure(
+    img: &DynamicImage,
+    reference: &GrayImage,
+    options: &CompressionOptions,
+    quality: u8,
+) -> Result<QualitySearchResult, ImageServerError> {
+    let mut candidate_options = options.clone();
+    candidate_options.quality = quality;
+    let data = encode_image(img.clone(), &candidate_options)?;
+
+    let decoded = image::load_from_memory(&data)
+        .map_err(|e| ImageServerError::CompressionError(format!("Failed to decode candidate: {}", e)))?
+        .to_luma8();
+    let ssim = ssim(reference, &decoded).unwrap_or(0.0);
+
+    Ok(QualitySearchResult { data, quality, ssim })
+}
+
+/// 推荐格式时认为画质可接受的最低 SSIM
+pub const RECOMMEND_MIN_SSIM: f64 = 0.95;
+
+/// 单个候选格式的试压缩结果
+#[derive(Debug, Clone)]
+pub struct FormatCandidate {
+    pub format: &'static str,
+    pub algorithm: &'static str,
+    pub size: usize,
+    pub ssim: f64,
+}
+
+/// 对 JPEG 和量化 PNG 分别试压缩后的对比结果
+#[derive(Debug, Clone)]
+pub struct FormatAnalysis {
+    pub width: u32,
+    pub height: u32,
+    pub has_transparency: bool,
+    pub candidates: Vec<FormatCandidate>,
+    pub recommended_format: &'static str,
+}
+
+/// 实际用 mozjpeg 和量化 PNG 各压缩一次，按体积和 SSIM 推荐存储格式
+///
+/// 带透明度的图片只推荐 PNG；否则在 SSIM 不低于 `RECOMMEND_MIN_SSIM` 的候选中选体积最小的，
+/// 都达不到时选 SSIM 最高的。
+pub fn analyze_formats(data: &[u8], quality: u8) -> Result<FormatAnalysis, ImageServerError> {
+    let img = image::load_from_memory(data)
+        .map_err(|e| ImageServerError::CompressionError(format!("Failed to decode image: {}", e)))?;
+    let reference = img.to_luma8();
+    let transparent = has_transparency(&img);
+
+    let mut candidates = Vec::with_capacity(2);
+    let formats: &[(&'static str, &'static str)] = if transparent {
+        &[("png", "png-quantized")]
+    } else {
+        &[("jpeg", "mozjpeg"), ("png", "png-quantized")]
+    };
+    for &(format, algorithm) in formats {
+        let options = CompressionOptions::new(format, quality, algorithm);
+        let measured = encode_and_measure(&img, &reference, &options, quality)?;
+        info!("格式分析 - {}: {} bytes, SSIM: {:.4}", format, measured.data.len(), measured.ssim);
+        candidates.push(FormatCandidate {
+            format,
+            algorithm,
+            size: measured.data.len(),
+            ssim: measured.ssim,
+        });
+    }
+
+    let recommended = candidates
+        .iter()
+        .filter(|c| c.ssim >= RECOMMEND_MIN_SSIM)
+        .min_by_key(|c| c.size)
+        .or_else(|| candidates.iter().max_by(|a, b| a.ssim.total_cmp(&b.ssim)))
+        .map(|c| c.format)
+        .unwrap_or("png");
+
+    Ok(FormatAnalysis {
+        width: img.width(),
+        height: img.height(),
+        has_transparency: transparent,
+        candidates,
+        recommended_format: recommended,
+    })
+}
+
+/// 两张图片的画质对比结果
+#[derive(Debug, Clone)]
+pub struct ImageComparison {
+    pub width: u32,
+    pub height: u32,
+    /// 候选图尺寸与原图不同，已缩放到原图尺寸后再比较
+    pub candidate_resized: bool,
+    /// RGB 通道的峰值信噪比（dB），两图像素完全相同时为 `None`（无穷大）
+    pub psnr: Option<f64>,
+    /// 亮度通道的平均 SSIM
+    pub ssim: f64,
+    /// R、G、B、A 各通道的平均绝对误差（0-255）
+    pub mean_error: [f64; 4],
+}
+
+/// 对比原图和候选图（通常是原图压缩后的结果），计算 PSNR、SSIM 和各通道平均误差
+///
+/// 尺寸不一致时，`resize_candidate` 为 `true` 则把候选图缩放到原图尺寸，否则返回 400。
+pub fn compare_images(
+    original: &[u8],
+    candidate: &[u8],
+    resize_candidate: bool,
+) -> Result<ImageComparison, ImageServerError> {
+    let decode = |data: &[u8], name: &str| {
+        image::load_from_memory(data)
+            .map_err(|e| ImageServerError::CompressionError(format!("Failed to decode {} image: {}", name, e)))
+    };
+    let original = decode(original, "original")?;
+    let mut candidate = decode(candidate, "candidate")?;
+
+    let (width, height) = (original.width(), original.height());
+    let candidate_resized = (candidate.width(), candidate.height()) != (width, height);
+    if candidate_resized {
+        if !resize_candidate {
+            return Err(ImageServerError::InvalidParameters(format!(
+                "Image dimensions differ: original is {}x{}, candidate is {}x{}; set resize=true to scale the candidate",
+                width, height, candidate.width(), candidate.height()
+            )));
+        }
+        candidate = candidate.resize_exact(width, height, image::imageops::FilterType::Lanczos3);
+    }
+
+    let ssim = ssim(&original.to_luma8(), &candidate.to_luma8()).unwrap_or(0.0);
+
+    let (a, b) = (original.to_rgba8(), candidate.to_rgba8());
+    let mut abs_sum = [0u64; 4];
+    let mut squared_sum = 0u64;
+    for (pa, pb) in a.pixels().zip(b.pixels()) {
+        for channel in 0..4 {
+            let diff = (pa[channel] as i64 - pb[channel] as i64).unsigned_abs();
+            abs_sum[channel] += diff;
+            if channel < 3 {
+                squared_sum += diff * diff;
+            }
+        }
+    }
+
+    let pixels = (width as u64 * height as u64).max(1) as f64;
+    let mean_error = abs_sum.map(|sum| sum as f64 / pixels);
+    let mse = squared_sum as f64 / (pixels * 3.0);
+    let psnr = (mse > 0.0).then(|| 10.0 * (255.0 * 255.0 / mse).log10());
+
+    info!("图片对比 {}x{}: PSNR {:?}, SSIM {:.4}", width, height, psnr, ssim);
+    Ok(ImageComparison {
+        width,
+        height,
+        candidate_resized,
+        psnr,
+        ssim,
+        mean_error,
+    })
+}
+
+/// ITU-T T.81 Annex K 标准亮度量化表（libjpeg 按质量缩放的基准）
+const STD_LUMINANCE_TABLE: [u16; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61,
+    12, 12, 14, 19, 26, 58, 60, 55,
+    14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62,
+    18, 22, 37, 56, 68, 109, 103, 77,
+    24, 35, 55, 64, 81, 104, 113, 92,
+    49, 64, 78, 87, 103, 121, 120, 101,
+    72, 92, 95, 98, 112, 100, 103, 99,
+];
+
+/// ITU-T T.81 Annex K 标准色度量化表
+const STD_CHROMINANCE_TABLE: [u16; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99,
+    18, 21, 26, 66, 99, 99, 99, 99,
+    24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+/// 根据量化表估算的 JPEG 编码质量
+#[derive(Debug, Clone)]
+pub struct JpegQualityEstimate {
+    /// 整体估算质量，取亮度表（编号 0）的结果
+    pub quality: u8,
+    /// 每张量化表的编号和估算质量
+    pub tables: Vec<(u8, u8)>,
+}
+
+/// 通过对比 DQT 量化表与标准表估算 JPEG 的原始编码质量（1-100）
+///
+/// 按 libjpeg 的质量缩放公式反推，使用非标准基准表的编码器（如 mozjpeg 默认表）只能得到近似值。
+pub fn estimate_jpeg_quality(data: &[u8]) -> Result<JpegQualityEstimate, ImageServerError> {
+    let tables = compression::read_jpeg_quant_tables(data).ok_or(ImageServerError::UnsupportedFormat)?;
+    if tables.is_empty() {
+        return Err(ImageServerError::InvalidParameters(
+            "JPEG has no quantization tables".to_string(),
+        ));
+    }
+
+    let tables: Vec<(u8, u8)> = tables
+        .iter()
+        .map(|(id, table)| {
+            let reference = if *id == 0 { &STD_LUMINANCE_TABLE } else { &STD_CHROMINANCE_TABLE };
+            (*id, estimate_table_quality(table, reference))
+        })
+        .collect();
+    let quality = tables
+        .iter()
+        .find(|(id, _)| *id == 0)
+        .unwrap_or(&tables[0])
+        .1;
+
+    info!("JPEG 质量估算: {}, 量化表: {:?}", quality, tables);
+    Ok(JpegQualityEstimate { quality, tables })
+}
+
+// libjpeg 以 scale = q < 50 ? 5000 / q : 200 - 2q 的百分比缩放标准表，这里按系数总和反推 scale
+fn estimate_table_quality(table: &[u16; 64], reference: &[u16; 64]) -> u8 {
+    if table.iter().all(|&value| value <= 1) {
+        return 100;
+    }
+
+    let sum: f64 = table.iter().map(|&value| value as f64).sum();
+    let reference_sum: f64 = reference.iter().map(|&value| value as f64).sum();
+    let scale = sum * 100.0 / reference_sum;
+    let quality = if scale <= 100.0 {
+        (200.0 - scale) / 2.0
+    } else {
+        5000.0 / scale
+    };
+
+    quality.round().clamp(1.0, 100.0) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(image::RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([(x * 3) as u8, (y * 5) as u8, ((x + y) * 2) as u8])
+        }))
+    }
+
+    #[test]
+    fn test_ssim_identical_images() {
+        let img = gradient(32, 24).to_luma8();
+        let value = ssim(&img, &img).unwrap();
+        assert!((value - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_ssim_dimension_mismatch() {
+        assert!(ssim(&gradient(8, 8).to_luma8(), &gradient(16, 8).to_luma8()).is_none());
+    }
+
+    #[test]
+    fn test_search_quality_for_ssim_meets_target() {
+        let img = gradient(64, 64);
+        let options = CompressionOptions::new("jpeg", 80, "mozjpeg");
+        let result = search_quality_for_ssim(&img, &options, 0.95).unwrap();
+        assert!(result.ssim >= 0.95);
+        assert!((1..=100).contains(&result.quality));
+    }
+
+    #[test]
+    fn test_search_quality_for_size_is_bounded() {
+        let img = gradient(64, 64);
+        let options = CompressionOptions::new("jpeg", 80, "jpeg-encoder");
+        // 第一次尝试的质量为 50，目标比它的输出多 1 字节
+        let target = {
+            let mut options = options.clone();
+            options.quality = 50;
+            encode_image(img.clone(), &options).unwrap().len() + 1
+        };
+
+        let result = search_quality_for_size(&img, &options, target, 7, 0.0).unwrap();
+        assert!(result.fits);
+        assert!(result.data.len() <= target);
+        assert!(result.quality >= 50);
+        assert!(result.iterations <= 7);
+
+        // 次数用完时返回已找到的放得下的结果
+        let result = search_quality_for_size(&img, &options, target, 2, 0.0).unwrap();
+        assert_eq!(result.iterations, 2);
+        assert!(result.fits && result.data.len() <= target);
+
+        // 宽松的容差在第一次放得下时就停止
+        let result = search_quality_for_size(&img, &options, target * 4, 7, 90.0).unwrap();
+        assert_eq!(result.iterations, 1);
+
+        // 目标小于最低质量的输出时返回最小的结果
+        let result = search_quality_for_size(&img, &options, 10, 16, 0.0).unwrap();
+        assert!(!result.fits);
+        assert_eq!(result.quality, 1);
+    }
+
+    #[test]
+    fn test_analyze_formats_recommends_png_for_flat_graphics() {
+        // 只有两种颜色的图形，量化 PNG 无损且体积更小
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, _| {
+            if x < 32 { image::Rgb([255, 0, 0]) } else { image::Rgb([0, 0, 255]) }
+        }));
+        let mut data = Vec::new();
+        img.write_to(&mut std::io::Cursor::new(&mut data), image::ImageOutputFormat::Png).unwrap();
+
+        let analysis = analyze_formats(&data, 80).unwrap();
+        assert_eq!(analysis.candidates.len(), 2);
+        assert_eq!(analysis.recommended_format, "png");
+    }
+
+    #[test]
+    fn test_analyze_formats_transparent_only_png() {
+        let img = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(16, 16, image::Rgba([0, 0, 0, 0])));
+        let mut data = Vec::new();
+        img.write_to(&mut std::io::Cursor::new(&mut data), image::ImageOutputFormat::Png).unwrap();
+
+        let analysis = analyze_formats(&data, 80).unwrap();
+        assert!(analysis.has_transparency);
+        assert_eq!(analysis.candidates.len(), 1);
+        assert_eq!(analysis.recommended_format, "png");
+    }
+
+    #[test]
+    fn test_estimate_jpeg_quality() {
+        let img = gradient(64, 64);
+        for quality in [30, 75, 95] {
+            let options = CompressionOptions::new("jpeg", quality, "jpeg-encoder");
+            let jpeg = encode_image(img.clone(), &options).unwrap();
+            let estimate = estimate_jpeg_quality(&jpeg).unwrap();
+            assert!(
+                (estimate.quality as i32 - quality as i32).abs() <= 2,
+                "quality {} estimated as {}", quality, estimate.quality
+            );
+            assert_eq!(estimate.tables.len(), 2);
+        }
+
+        assert!(matches!(estimate_jpeg_quality(b"not a jpeg"), Err(ImageServerError::UnsupportedFormat)));
+    }
+
+    #[test]
+    fn test_compare_images() {
+        let encode = |img: &DynamicImage| {
+            let mut data = Vec::new();
+            img.write_to(&mut std::io::Cursor::new(&mut data), image::ImageOutputFormat::Png).unwrap();
+            data
+        };
+        let original = encode(&gradient(32, 32));
+
+        let identical = compare_images(&original, &original, false).unwrap();
+        assert_eq!(identical.psnr, None);
+        assert!((identical.ssim - 1.0).abs() < 1e-9);
+        assert_eq!(identical.mean_error, [0.0; 4]);
+
+        let jpeg = encode_image(gradient(32, 32), &CompressionOptions::new("jpeg", 30, "jpeg-encoder")).unwrap();
+        let degraded = compare_images(&original, &jpeg, false).unwrap();
+        assert!(degraded.psnr.unwrap() > 20.0);
+        assert!(degraded.ssim < 1.0);
+        assert!(degraded.mean_error[..3].iter().any(|&error| error > 0.0));
+        assert_eq!(degraded.mean_error[3], 0.0);
+
+        let smaller = encode(&gradient(16, 16));
+        assert!(matches!(
+            compare_images(&original, &smaller, false),
+            Err(ImageServerError::InvalidParameters(_))
+        ));
+        let resized = compare_images(&original, &smaller, true).unwrap();
+        assert!(resized.candidate_resized);
+        assert_eq!((resized.width, resized.height), (32, 32));
+    }
+}
@@ -0,0 +1,110 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::warn;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::CompressionConfig;
+use crate::errors::ImageServerError;
+
+/// 压缩任务并发队列
+///
+/// 同时运行的任务数受 `max_concurrent_jobs` 限制，名额占满时请求最多排队
+/// `queue_wait_timeout_ms`，超时返回 503：短时突发排队消化，持续过载直接拒绝。
+pub struct JobQueue {
+    semaphore: Arc<Semaphore>,
+    max_jobs: usize,
+    wait_timeout: Duration,
+    waiting: AtomicUsize,
+    rejected: AtomicU64,
+}
+
+/// `/metrics` 使用的队列快照
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueStats {
+    pub depth: usize,
+    pub in_flight: usize,
+    pub max_jobs: usize,
+    pub rejected: u64,
+}
+
+impl JobQueue {
+    pub fn new(max_jobs: usize, wait_timeout: Duration) -> Self {
+        let max_jobs = max_jobs.max(1);
+        Self {
+            semaphore: Arc::new(Semaphore::new(max_jobs)),
+            max_jobs,
+            wait_timeout,
+            waiting: AtomicUsize::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    pub fn from_config(config: &CompressionConfig) -> Self {
+        Self::new(
+            config.max_concurrent_jobs,
+            Duration::from_millis(config.queue_wait_timeout_ms),
+        )
+    }
+
+    /// 获取一个任务名额，持有返回的 permit 期间占用名额
+    pub async fn acquire(&self) -> Result<OwnedSemaphorePermit, ImageServerError> {
+        // 有空闲名额时不计入排队
+        if let Ok(permit) = self.semaphore.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+
+        let started = Instant::now();
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+        let result = tokio::time::timeout(self.wait_timeout, self.semaphore.clone().acquire_owned()).await;
+        self.waiting.fetch_sub(1, Ordering::Relaxed);
+
+        match result {
+            Ok(Ok(permit)) => Ok(permit),
+            _ => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                let waited_ms = started.elapsed().as_millis() as u64;
+                warn!("Compression queue full, rejected after waiting {}ms", waited_ms);
+                Err(ImageServerError::Overloaded { waited_ms })
+            }
+        }
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        QueueStats {
+            depth: self.waiting.load(Ordering::Relaxed),
+            in_flight: self.max_jobs - self.semaphore.available_permits(),
+            max_jobs: self.max_jobs,
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_queue_waits_then_rejects() {
+        let queue = Arc::new(JobQueue::new(1, Duration::from_millis(50)));
+        let permit = queue.acquire().await.unwrap();
+        assert_eq!(queue.stats().in_flight, 1);
+
+        // 名额占满且超过等待时间时返回 503
+        let err = queue.acquire().await.unwrap_err();
+        assert!(matches!(err, ImageServerError::Overloaded { .. }));
+        assert_eq!(queue.stats().rejected, 1);
+
+        // 等待期间名额释放则继续执行
+        let waiter = {
+            let queue = queue.clone();
+            tokio::spawn(async move { queue.acquire().await.map(|_| ()) })
+        };
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(queue.stats().depth, 1);
+        drop(permit);
+        assert!(waiter.await.unwrap().is_ok());
+        assert_eq!(queue.stats(), QueueStats { depth: 0, in_flight: 0, max_jobs: 1, rejected: 1 });
+    }
+}
@@ -0,0 +1,177 @@
+use image::{imageops, DynamicImage, RgbaImage};
+use serde::Serialize;
+
+/// 单个雪碧图最多合并的图片数
+pub const MAX_SPRITE_IMAGES: usize = 256;
+
+/// 图片之间允许的最大间距（像素）
+pub const MAX_SPRITE_PADDING: u32 = 256;
+
+/// 雪碧图中图片的排列方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SpriteLayout {
+    /// 按上传顺序从左到右排成一行，顶部对齐
+    #[default]
+    Horizontal,
+    /// 按上传顺序从上到下排成一列，左侧对齐
+    Vertical,
+    /// 按上传顺序逐行填入等大的格子，格子取所有图片的最大宽高，图片位于格子左上角
+    Grid,
+}
+
+impl SpriteLayout {
+    pub const NAMES: [&'static str; 3] = ["horizontal", "vertical", "grid"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "horizontal" => Some(Self::Horizontal),
+            "vertical" => Some(Self::Vertical),
+            "grid" => Some(Self::Grid),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Horizontal => "horizontal",
+            Self::Vertical => "vertical",
+            Self::Grid => "grid",
+        }
+    }
+}
+
+/// 一张源图在雪碧图中的位置和尺寸
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SpriteRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// 雪碧图的尺寸和每张源图的位置，顺序与输入相同
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpritePlan {
+    pub width: u32,
+    pub height: u32,
+    pub rects: Vec<SpriteRect>,
+}
+
+/// 按 `layout` 排列宽高为 `sizes` 的图片，只计算位置不分配像素，调用方可先检查雪碧图尺寸再合成
+///
+/// 相邻图片之间留 `padding` 像素的透明间距。`columns` 只用于 `Grid`，为 `None` 时取 ⌈√n⌉ 使雪碧图接近正方形，
+/// 超过图片数时按图片数计算。尺寸按饱和加法计算，超大的结果由调用方的尺寸上限拒绝。
+pub fn plan(sizes: &[(u32, u32)], layout: SpriteLayout, columns: Option<u32>, padding: u32) -> SpritePlan {
+    let count = sizes.len() as u32;
+    let max_width = sizes.iter().map(|&(width, _)| width).max().unwrap_or(0);
+    let max_height = sizes.iter().map(|&(_, height)| height).max().unwrap_or(0);
+    let total_padding = padding.saturating_mul(count.saturating_sub(1));
+    let rect = |x: u32, y: u32, (width, height): (u32, u32)| SpriteRect { x, y, width, height };
+
+    match layout {
+        SpriteLayout::Horizontal => {
+            let mut x = 0u32;
+            let rects = sizes
+                .iter()
+                .map(|&size| {
+                    let placed = rect(x, 0, size);
+                    x = x.saturating_add(size.0).saturating_add(padding);
+                    placed
+                })
+                .collect();
+            let width = sizes.iter().fold(total_padding, |sum, &(width, _)| sum.saturating_add(width));
+            SpritePlan { width, height: max_height, rects }
+        }
+        SpriteLayout::Vertical => {
+            let mut y = 0u32;
+            let rects = sizes
+                .iter()
+                .map(|&size| {
+                    let placed = rect(0, y, size);
+                    y = y.saturating_add(size.1).saturating_add(padding);
+                    placed
+                })
+                .collect();
+            let height = sizes.iter().fold(total_padding, |sum, &(_, height)| sum.saturating_add(height));
+            SpritePlan { width: max_width, height, rects }
+        }
+        SpriteLayout::Grid => {
+            let columns = columns
+                .unwrap_or_else(|| (count as f64).sqrt().ceil() as u32)
+                .clamp(1, count.max(1));
+            let rows = count.div_ceil(columns);
+            let cell_width = max_width.saturating_add(padding);
+            let cell_height = max_height.saturating_add(padding);
+            let rects = sizes
+                .iter()
+                .enumerate()
+                .map(|(index, &size)| {
+                    let (column, row) = (index as u32 % columns, index as u32 / columns);
+                    rect(column.saturating_mul(cell_width), row.saturating_mul(cell_height), size)
+                })
+                .collect();
+            SpritePlan {
+                width: columns.saturating_mul(cell_width).saturating_sub(padding),
+                height: rows.saturating_mul(cell_height).saturating_sub(padding),
+                rects,
+            }
+        }
+    }
+}
+
+/// 按 `plan` 把图片合成到透明背景的雪碧图上
+pub fn compose(images: &[DynamicImage], plan: &SpritePlan) -> RgbaImage {
+    let mut sheet = RgbaImage::new(plan.width, plan.height);
+    for (image, rect) in images.iter().zip(&plan.rects) {
+        imageops::replace(&mut sheet, &image.to_rgba8(), rect.x as i64, rect.y as i64);
+    }
+    sheet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan_layouts() {
+        let sizes = [(10, 20), (30, 5), (4, 4)];
+
+        let horizontal = plan(&sizes, SpriteLayout::Horizontal, None, 2);
+        assert_eq!((horizontal.width, horizontal.height), (48, 20));
+        let positions: Vec<(u32, u32)> = horizontal.rects.iter().map(|r| (r.x, r.y)).collect();
+        assert_eq!(positions, vec![(0, 0), (12, 0), (44, 0)]);
+
+        let vertical = plan(&sizes, SpriteLayout::Vertical, None, 0);
+        assert_eq!((vertical.width, vertical.height), (30, 29));
+        assert_eq!(vertical.rects[2], SpriteRect { x: 0, y: 25, width: 4, height: 4 });
+
+        // 3 张图默认 2 列，格子为 30x20
+        let grid = plan(&sizes, SpriteLayout::Grid, None, 1);
+        assert_eq!((grid.width, grid.height), (61, 41));
+        let positions: Vec<(u32, u32)> = grid.rects.iter().map(|r| (r.x, r.y)).collect();
+        assert_eq!(positions, vec![(0, 0), (31, 0), (0, 21)]);
+
+        // 列数超过图片数时按图片数计算
+        let grid = plan(&sizes, SpriteLayout::Grid, Some(10), 0);
+        assert_eq!((grid.width, grid.height), (90, 20));
+    }
+
+    #[test]
+    fn test_compose() {
+        let red = DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 3, image::Rgba([255, 0, 0, 255])));
+        let blue = DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 1, image::Rgba([0, 0, 255, 255])));
+        let images = [red, blue];
+        let sizes: Vec<(u32, u32)> = images.iter().map(|img| (img.width(), img.height())).collect();
+        let sheet_plan = plan(&sizes, SpriteLayout::Horizontal, None, 1);
+
+        let sheet = compose(&images, &sheet_plan);
+        assert_eq!(sheet.dimensions(), (7, 3));
+        assert_eq!(sheet.get_pixel(1, 2).0, [255, 0, 0, 255]);
+        assert_eq!(sheet.get_pixel(2, 0).0, [0, 0, 0, 0]);
+        assert_eq!(sheet.get_pixel(6, 0).0, [0, 0, 255, 255]);
+        assert_eq!(sheet.get_pixel(6, 1).0, [0, 0, 0, 0]);
+
+        assert_eq!(SpriteLayout::from_name("Grid"), Some(SpriteLayout::Grid));
+        assert_eq!(SpriteLayout::from_name("diagonal"), None);
+    }
+}
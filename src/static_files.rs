@@ -0,0 +1,96 @@
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header::{HeaderValue, CACHE_CONTROL};
+use actix_web::http::StatusCode;
+use std::future::Future;
+
+use crate::config::StaticFilesConfig;
+
+/// 带内容哈希的资源文件名变化即内容变化，可缓存一年并标记为 immutable
+pub const IMMUTABLE_MAX_AGE_SECS: u64 = 31_536_000;
+
+/// 文件名中被视为内容哈希的最短十六进制片段长度
+const MIN_HASH_LEN: usize = 8;
+
+/// 按配置创建静态文件服务，开启时由 actix-files 处理 ETag/If-None-Match 和 Last-Modified/If-Modified-Since
+pub fn files(config: &StaticFilesConfig) -> actix_files::Files {
+    actix_files::Files::new("/", &config.dir)
+        .index_file("index.html")
+        .use_etag(config.use_etag)
+        .use_last_modified(config.use_last_modified)
+}
+
+/// 文件名（不含扩展名）中除第一段外是否有至少 8 位的十六进制片段，如 `app.3f2a9c1b.js`、`app-3f2a9c1b.js`
+pub fn is_hashed_asset(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let stem = match name.rsplit_once('.') {
+        Some((stem, _)) => stem,
+        None => return false,
+    };
+
+    stem.split(['.', '-', '_'])
+        .skip(1)
+        .any(|part| part.len() >= MIN_HASH_LEN && part.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// 静态资源的 Cache-Control
+///
+/// HTML 入口每次都向服务器验证，以便及时引用新的带哈希资源；
+/// 带哈希的资源长期缓存，其余资源按 `cache_max_age_secs` 缓存。
+pub fn cache_control_for(path: &str, config: &StaticFilesConfig) -> String {
+    let is_html = path.ends_with('/') || path.ends_with(".html") || path.ends_with(".htm");
+    if is_html || config.cache_max_age_secs == 0 {
+        "no-cache".to_string()
+    } else if config.immutable_hashed_assets && is_hashed_asset(path) {
+        format!("public, max-age={}, immutable", IMMUTABLE_MAX_AGE_SECS)
+    } else {
+        format!("public, max-age={}", config.cache_max_age_secs)
+    }
+}
+
+/// 为成功（含 304）的静态文件响应加上 Cache-Control
+pub async fn with_cache_control<B, E>(
+    cache_control: String,
+    fut: impl Future<Output = Result<ServiceResponse<B>, E>>,
+) -> Result<ServiceResponse<B>, E> {
+    let mut response = fut.await?;
+    let status = response.status();
+    if status.is_success() || status == StatusCode::NOT_MODIFIED {
+        if let Ok(value) = HeaderValue::from_str(&cache_control) {
+            response.headers_mut().insert(CACHE_CONTROL, value);
+        }
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_hashed_asset() {
+        assert!(is_hashed_asset("/assets/app.3f2a9c1b.js"));
+        assert!(is_hashed_asset("/app-3f2a9c1bde.css"));
+        assert!(!is_hashed_asset("/app.js"));
+        assert!(!is_hashed_asset("/app_new.js"));
+        assert!(!is_hashed_asset("/deadbeef99.js"));
+        assert!(!is_hashed_asset("/LICENSE"));
+    }
+
+    #[test]
+    fn test_cache_control_for() {
+        let mut config = StaticFilesConfig::default();
+        assert_eq!(cache_control_for("/", &config), "no-cache");
+        assert_eq!(cache_control_for("/index.html", &config), "no-cache");
+        assert_eq!(cache_control_for("/app.js", &config), "public, max-age=3600");
+        assert_eq!(
+            cache_control_for("/app.3f2a9c1b.js", &config),
+            "public, max-age=31536000, immutable"
+        );
+
+        config.immutable_hashed_assets = false;
+        assert_eq!(cache_control_for("/app.3f2a9c1b.js", &config), "public, max-age=3600");
+
+        config.cache_max_age_secs = 0;
+        assert_eq!(cache_control_for("/app.js", &config), "no-cache");
+    }
+}
@@ -0,0 +1,220 @@
+use futures::future::BoxFuture;
+use log::info;
+use sha1::{Digest, Sha1};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::compression::{self, CompressionOptions};
+use crate::config::StorageConfig;
+use crate::errors::ImageServerError;
+
+/// 压缩结果的持久化存储后端
+///
+/// 以内容哈希为键保存压缩输出，命中时可直接返回而无需重新压缩。
+pub trait StorageBackend: Send + Sync {
+    /// 读取已保存的压缩结果，不存在时返回 `None`
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, ImageServerError>>;
+
+    /// 保存压缩结果
+    fn put<'a>(&'a self, key: &'a str, data: &'a [u8]) -> BoxFuture<'a, Result<(), ImageServerError>>;
+}
+
+/// 根据输入内容的 SHA-256 和压缩参数生成存储键
+///
+/// 相同的输入在不同参数下会产生不同的输出，因此参数也参与哈希。输入摘要在上传时增量计算，
+/// 这里不再遍历整个输入。
+pub fn storage_key(input_sha256: &[u8; 32], options: &CompressionOptions) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(input_sha256);
+    // jpg 与 jpeg 命中同一缓存
+    let format = compression::normalize_format(&options.format);
+    hasher.update(format.map_or(options.format.to_lowercase(), |format| format.name().to_string()).as_bytes());
+    hasher.update([options.quality]);
+    hasher.update(options.algorithm.to_lowercase().as_bytes());
+    hasher.update([options.png_depth.unwrap_or(0)]);
+    hasher.update([options.png_quantize_speed.unwrap_or(0)]);
+    hasher.update([options.png_min_quantization_quality]);
+    hasher.update([options.progressive as u8]);
+    hasher.update(format!("{:?}", options.quant_table).as_bytes());
+    hasher.update([options.luma_quality.unwrap_or(0), options.chroma_quality.unwrap_or(0)]);
+    hasher.update(format!("{:?}", options.jpeg_mode).as_bytes());
+    hasher.update(format!("{:?}", options.dither).as_bytes());
+    hasher.update([options.preserve_exif as u8]);
+    hasher.update([options.thumbnail_quality_boost]);
+    hasher.update(options.thumbnail_max_dimension.to_le_bytes());
+    hasher.update(format!("{:?}", options.color_space).as_bytes());
+    hasher.update(options.target_ssim.map(f64::to_bits).unwrap_or(0).to_le_bytes());
+    hasher.update(options.background.map(|c| [1, c[0], c[1], c[2]]).unwrap_or([0; 4]));
+    hasher.update(options.comment.as_deref().unwrap_or("").as_bytes());
+    hasher.update(options.xmp.as_deref().map_or(0, str::len).to_le_bytes());
+    hasher.update(options.xmp.as_deref().unwrap_or("").as_bytes());
+    hasher.update([options.max_quality]);
+    hasher.update([options.deterministic as u8]);
+    hasher.update(format!("{:?}", options.operations).as_bytes());
+    hasher.update([options.lossless_rotate as u8]);
+    hasher.update(options.max_bytes.map_or(0, |max| max as u64 + 1).to_le_bytes());
+    hasher.update(options.size_search_max_iterations.to_le_bytes());
+    hasher.update(options.size_search_tolerance_percent.to_bits().to_le_bytes());
+    hasher.update(options.resize_filter.name().as_bytes());
+    hasher.update(options.fast_downscale_factor.to_le_bytes());
+    hasher.update([options.verify_output as u8]);
+
+    let hex: String = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+
+    let extension = format.map_or("bin", compression::Format::extension);
+
+    format!("{}.{}", hex, extension)
+}
+
+/// 本地文件系统存储
+pub struct FilesystemStorage {
+    root: PathBuf,
+}
+
+impl FilesystemStorage {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+
+    // 按键的前两个字符分目录，避免单个目录下文件过多
+    fn path_for(&self, key: &str) -> PathBuf {
+        let shard = key.get(..2).unwrap_or("00");
+        self.root.join(shard).join(key)
+    }
+}
+
+impl StorageBackend for FilesystemStorage {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, ImageServerError>> {
+        Box::pin(async move {
+            match tokio::fs::read(self.path_for(key)).await {
+                Ok(data) => Ok(Some(data)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(ImageServerError::StorageError(format!(
+                    "Failed to read {}: {}", key, e
+                ))),
+            }
+        })
+    }
+
+    fn put<'a>(&'a self, key: &'a str, data: &'a [u8]) -> BoxFuture<'a, Result<(), ImageServerError>> {
+        Box::pin(async move {
+            let path = self.path_for(key);
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await.map_err(|e| {
+                    ImageServerError::StorageError(format!(
+                        "Failed to create directory {:?}: {}", parent, e
+                    ))
+                })?;
+            }
+
+            // 先写临时文件再重命名，避免并发读取到写了一半的文件
+            let tmp_path = path.with_extension(format!("tmp-{}", uuid::Uuid::new_v4()));
+            tokio::fs::write(&tmp_path, data).await.map_err(|e| {
+                ImageServerError::StorageError(format!("Failed to write {}: {}", key, e))
+            })?;
+            tokio::fs::rename(&tmp_path, &path).await.map_err(|e| {
+                ImageServerError::StorageError(format!("Failed to rename {}: {}", key, e))
+            })?;
+
+            Ok(())
+        })
+    }
+}
+
+/// S3 兼容对象存储
+#[cfg(feature = "s3")]
+pub struct S3Storage {
+    bucket: s3::Bucket,
+    prefix: String,
+}
+
+#[cfg(feature = "s3")]
+impl S3Storage {
+    pub fn new(config: &StorageConfig) -> Result<Self, ImageServerError> {
+        let region = match config.s3_endpoint.as_deref() {
+            Some(endpoint) => s3::Region::Custom {
+                region: config.s3_region.clone(),
+                endpoint: endpoint.to_string(),
+            },
+            None => config.s3_region.parse().map_err(|e| {
+                ImageServerError::StorageError(format!("Invalid S3 region: {}", e))
+            })?,
+        };
+
+        let credentials = s3::creds::Credentials::default().map_err(|e| {
+            ImageServerError::StorageError(format!("Failed to load S3 credentials: {}", e))
+        })?;
+
+        let bucket = s3::Bucket::new(&config.s3_bucket, region, credentials).map_err(|e| {
+            ImageServerError::StorageError(format!("Failed to open S3 bucket: {}", e))
+        })?;
+
+        Ok(Self {
+            bucket,
+            prefix: config.s3_prefix.clone(),
+        })
+    }
+
+    fn object_path(&self, key: &str) -> String {
+        format!("{}/{}", self.prefix.trim_end_matches('/'), key)
+    }
+}
+
+#[cfg(feature = "s3")]
+impl StorageBackend for S3Storage {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, ImageServerError>> {
+        Box::pin(async move {
+            match self.bucket.get_object(self.object_path(key)).await {
+                Ok(response) if response.status_code() == 200 => {
+                    Ok(Some(response.bytes().to_vec()))
+                }
+                Ok(_) => Ok(None),
+                Err(s3::error::S3Error::HttpFailWithBody(404, _)) => Ok(None),
+                Err(e) => Err(ImageServerError::StorageError(format!(
+                    "Failed to get {} from S3: {}", key, e
+                ))),
+            }
+        })
+    }
+
+    fn put<'a>(&'a self, key: &'a str, data: &'a [u8]) -> BoxFuture<'a, Result<(), ImageServerError>> {
+        Box::pin(async move {
+            self.bucket
+                .put_object(self.object_path(key), data)
+                .await
+                .map_err(|e| {
+                    ImageServerError::StorageError(format!("Failed to put {} to S3: {}", key, e))
+                })?;
+            Ok(())
+        })
+    }
+}
+
+/// 根据配置创建存储后端，未启用时返回 `None`
+pub fn create_storage(
+    config: &StorageConfig,
+) -> Result<Option<Arc<dyn StorageBackend>>, ImageServerError> {
+    match config.backend.to_lowercase().as_str() {
+        "none" | "" => Ok(None),
+        "filesystem" => {
+            info!("Using filesystem storage at {}", config.path);
+            Ok(Some(Arc::new(FilesystemStorage::new(&config.path))))
+        }
+        #[cfg(feature = "s3")]
+        "s3" => {
+            info!("Using S3 storage in bucket {}", config.s3_bucket);
+            Ok(Some(Arc::new(S3Storage::new(config)?)))
+        }
+        #[cfg(not(feature = "s3"))]
+        "s3" => Err(ImageServerError::StorageError(
+            "S3 storage requires building with the `s3` feature".to_string(),
+        )),
+        other => Err(ImageServerError::StorageError(format!(
+            "Unknown storage backend: {}", other
+        ))),
+    }
+}
@@ -0,0 +1,186 @@
+use actix_web::web::Bytes;
+use image::codecs::jpeg::JpegDecoder;
+use image::codecs::png::PngDecoder;
+use image::{DynamicImage, ImageDecoder, ImageFormat};
+use log::info;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc as std_mpsc, Arc};
+use std::time::Instant;
+use tokio::sync::mpsc;
+
+use crate::errors::ImageServerError;
+
+/// 上传与解码之间最多缓冲的分块数，解码跟不上时上传等待
+pub const STREAMING_DECODE_BUFFER_CHUNKS: usize = 16;
+
+/// 识别格式前至少读取的字节数
+pub const STREAMING_SNIFF_LEN: usize = 16;
+
+/// 解码器可以从输入流逐步读取的格式
+pub fn supports_streaming(format: ImageFormat) -> bool {
+    matches!(format, ImageFormat::Jpeg | ImageFormat::Png)
+}
+
+/// 边上传边解码：上传分块经通道送入阻塞线程池中的解码器
+///
+/// 解码和上传重叠进行，上传结束时像素通常已经解码完成。丢弃时取消解码，已排队的分块不再读取。
+pub struct StreamingDecode {
+    chunks: Option<mpsc::Sender<Bytes>>,
+    result: std_mpsc::Receiver<Result<DynamicImage, ImageServerError>>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl StreamingDecode {
+    /// 启动 `format` 的解码，`check_dimensions` 在读到图片头之后、分配像素之前调用
+    pub fn start<F>(format: ImageFormat, check_dimensions: F) -> Self
+    where
+        F: FnOnce(u32, u32) -> Result<(), ImageServerError> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel(STREAMING_DECODE_BUFFER_CHUNKS);
+        let (result_sender, result) = std_mpsc::sync_channel(1);
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let reader = ChunkReader {
+            chunks: receiver,
+            current: Bytes::new(),
+            cancelled: cancelled.clone(),
+        };
+
+        // 在调用方（请求）的 span 下创建，解码线程中进入
+        let span = tracing::info_span!("decode", format = ?format, streaming = true);
+        tokio::task::spawn_blocking(move || {
+            let _entered = span.enter();
+            let start = Instant::now();
+            let decoded = decode_from_reader(reader, format, check_dimensions);
+            if decoded.is_ok() {
+                info!("流式解码完成 - 格式: {:?}, 耗时: {:?}", format, start.elapsed());
+            }
+            // 接收端已丢弃说明请求不再需要结果
+            let _ = result_sender.send(decoded);
+        });
+
+        Self {
+            chunks: Some(sender),
+            result,
+            cancelled,
+        }
+    }
+
+    /// 送入一个上传分块，缓冲已满时等待；解码已结束（失败或取消）时忽略
+    pub async fn push(&self, chunk: Bytes) {
+        if let Some(chunks) = &self.chunks {
+            let _ = chunks.send(chunk).await;
+        }
+    }
+
+    /// 上传结束，解码器读完已缓冲的分块后遇到 EOF
+    pub fn finish_input(&mut self) {
+        self.chunks = None;
+    }
+
+    /// 等待解码结果，会阻塞当前线程，须在阻塞线程池中调用
+    pub fn wait(mut self) -> Result<DynamicImage, ImageServerError> {
+        self.finish_input();
+        self.result.recv().unwrap_or_else(|_| {
+            Err(ImageServerError::ProcessingError("Streaming decode task failed".to_string()))
+        })
+    }
+}
+
+impl Drop for StreamingDecode {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+/// 从通道读取上传分块的阻塞 reader，发送端关闭即 EOF
+struct ChunkReader {
+    chunks: mpsc::Receiver<Bytes>,
+    current: Bytes,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.current.is_empty() {
+            if self.cancelled.load(Ordering::Relaxed) {
+                return Err(io::Error::other("streaming decode cancelled"));
+            }
+            match self.chunks.blocking_recv() {
+                Some(chunk) => self.current = chunk,
+                None => return Ok(0),
+            }
+        }
+
+        let len = buf.len().min(self.current.len());
+        buf[..len].copy_from_slice(&self.current.split_to(len));
+        Ok(len)
+    }
+}
+
+fn decode_from_reader<R, F>(reader: R, format: ImageFormat, check_dimensions: F) -> Result<DynamicImage, ImageServerError>
+where
+    R: Read,
+    F: FnOnce(u32, u32) -> Result<(), ImageServerError>,
+{
+    let reader = io::BufReader::new(reader);
+    match format {
+        ImageFormat::Jpeg => decode_checked(JpegDecoder::new(reader).map_err(decode_error)?, check_dimensions),
+        ImageFormat::Png => decode_checked(PngDecoder::new(reader).map_err(decode_error)?, check_dimensions),
+        _ => Err(ImageServerError::InvalidParameters(format!(
+            "Streaming decode is not supported for {:?}", format
+        ))),
+    }
+}
+
+fn decode_checked<'a, D, F>(decoder: D, check_dimensions: F) -> Result<DynamicImage, ImageServerError>
+where
+    D: ImageDecoder<'a>,
+    F: FnOnce(u32, u32) -> Result<(), ImageServerError>,
+{
+    let (width, height) = decoder.dimensions();
+    check_dimensions(width, height)?;
+    DynamicImage::from_decoder(decoder).map_err(decode_error)
+}
+
+fn decode_error(e: image::ImageError) -> ImageServerError {
+    ImageServerError::CompressionError(format!("Failed to decode image: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[actix_web::test]
+    async fn test_streaming_decode() {
+        let mut png = Vec::new();
+        DynamicImage::new_rgb8(64, 48)
+            .write_to(&mut Cursor::new(&mut png), image::ImageOutputFormat::Png)
+            .unwrap();
+
+        let mut decode = StreamingDecode::start(ImageFormat::Png, |_, _| Ok(()));
+        for chunk in png.chunks(100) {
+            decode.push(Bytes::copy_from_slice(chunk)).await;
+        }
+        decode.finish_input();
+        let img = tokio::task::spawn_blocking(move || decode.wait()).await.unwrap().unwrap();
+        assert_eq!((img.width(), img.height()), (64, 48));
+
+        // 尺寸检查在分配像素前拒绝，之后送入的分块被忽略
+        let decode = StreamingDecode::start(ImageFormat::Png, |width, _| {
+            Err(ImageServerError::InvalidParameters(format!("width {} too large", width)))
+        });
+        for chunk in png.chunks(100) {
+            decode.push(Bytes::copy_from_slice(chunk)).await;
+        }
+        let result = tokio::task::spawn_blocking(move || decode.wait()).await.unwrap();
+        assert!(matches!(result, Err(ImageServerError::InvalidParameters(message)) if message == "width 64 too large"));
+
+        // 输入被截断时返回解码错误
+        let decode = StreamingDecode::start(ImageFormat::Png, |_, _| Ok(()));
+        decode.push(Bytes::copy_from_slice(&png[..png.len() / 2])).await;
+        let result = tokio::task::spawn_blocking(move || decode.wait()).await.unwrap();
+        assert!(matches!(result, Err(ImageServerError::CompressionError(_))));
+    }
+}
@@ -1,7 +1,11 @@
 #[cfg(test)]
 mod api_tests {
-    use actix_web::{test, web, App};
-    use img_server_rs::handlers::{compress_endpoint, health_check, info_endpoint};
+    use actix_web::{dev::Service, test, web, App};
+    use img_server_rs::config::Config;
+    use img_server_rs::cors;
+    use img_server_rs::handlers::{compare_endpoint, compress_endpoint, compress_raw_endpoint, compress_zip_endpoint, health_check, info_endpoint, metrics_endpoint, preflight, root_endpoint, sprite_endpoint};
+    use img_server_rs::metrics::CompressionMetrics;
+    use img_server_rs::queue::JobQueue;
 
     #[actix_web::test]
     async fn test_health_endpoint() {
@@ -26,42 +30,1687 @@ mod api_tests {
     #[actix_web::test]
     async fn test_info_endpoint() {
         let app = test::init_service(
-            App::new().route("/info", web::get().to(info_endpoint))
+            App::new()
+                .app_data(web::Data::new(Config::default()))
+                .route("/info", web::get().to(info_endpoint))
+        ).await;
+
+        let req = test::TestRequest::get()
+            .uri("/info")
+            .to_request();
+        
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let body = test::read_body(resp).await;
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        
+        assert_eq!(json["service"], "Image Compression Server");
+        assert!(json["supported_algorithms"].is_array());
+    }
+
+    #[actix_web::test]
+    async fn test_root_endpoint() {
+        let mut config = Config::default();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(config.clone()))
+                .route("/", web::get().to(root_endpoint))
+        ).await;
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+        assert!(resp.status().is_success());
+        let json: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(json["service"], "image-compression-server");
+        assert_eq!(json["endpoints"]["compress"], "/compress");
+
+        config.static_files.root_response = "redirect".to_string();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(config.clone()))
+                .route("/", web::get().to(root_endpoint))
+        ).await;
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(resp.status(), 302);
+        assert_eq!(resp.headers().get("Location").unwrap(), "/info");
+
+        config.static_files.root_response = "not_found".to_string();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .route("/", web::get().to(root_endpoint))
+        ).await;
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(resp.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn test_compress_endpoint_no_file() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::PayloadConfig::new(100 * 1024 * 1024))
+                .app_data(web::Data::new(Config::default()))
+                .route("/compress", web::post().to(compress_endpoint))
+        ).await;
+
+        // Test request without file
+        let req = test::TestRequest::post()
+            .uri("/compress")
+            .set_form([("quality", "80")])
+            .to_request();
+        
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400); // Bad request - no file provided
+    }
+
+    #[actix_web::test]
+    async fn test_compress_preflight() {
+        let app = test::init_service(
+            App::new().route("/compress", web::method(actix_web::http::Method::OPTIONS).to(preflight))
+        ).await;
+
+        let req = test::TestRequest::default()
+            .method(actix_web::http::Method::OPTIONS)
+            .uri("/compress")
+            .insert_header(("Origin", "http://example.com"))
+            .insert_header(("Access-Control-Request-Method", "POST"))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 204);
+        assert!(resp.headers().contains_key("access-control-max-age"));
+        assert!(resp.headers().contains_key("access-control-allow-origin"));
+    }
+
+    #[actix_web::test]
+    async fn test_cors_only_on_configured_endpoints() {
+        let server_config = Config::default().server;
+        let app = test::init_service(
+            App::new()
+                .wrap_fn(move |req, srv| {
+                    let enabled = cors::allows(req.path(), &server_config);
+                    cors::with_cors_headers(enabled, srv.call(req))
+                })
+                .route("/health", web::get().to(health_check))
+                .route("/metrics", web::get().to(metrics_endpoint))
+        ).await;
+
+        let req = test::TestRequest::get().uri("/health").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get("access-control-allow-origin").unwrap(), "*");
+        assert!(resp.headers().contains_key("access-control-allow-methods"));
+
+        // 运维接口默认不允许跨域读取
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert!(!resp.headers().contains_key("access-control-allow-origin"));
+    }
+
+    // Helper function to build a multipart/form-data request body
+    fn multipart_body(file: &[u8], filename: &str, fields: &[(&str, &str)]) -> (String, Vec<u8>) {
+        let boundary = "----img-server-test-boundary";
+        let mut body = Vec::new();
+
+        for (name, value) in fields {
+            body.extend_from_slice(format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                boundary, name, value
+            ).as_bytes());
+        }
+
+        body.extend_from_slice(format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+            boundary, filename
+        ).as_bytes());
+        body.extend_from_slice(file);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+        (format!("multipart/form-data; boundary={}", boundary), body)
+    }
+
+    fn compress_app() -> App<
+        impl actix_web::dev::ServiceFactory<
+            actix_web::dev::ServiceRequest,
+            Config = (),
+            Response = actix_web::dev::ServiceResponse,
+            Error = actix_web::Error,
+            InitError = (),
+        >,
+    > {
+        App::new()
+            .app_data(web::PayloadConfig::new(100 * 1024 * 1024))
+            .app_data(web::Data::new(Config::default()))
+            .route("/compress", web::post().to(compress_endpoint))
+    }
+
+    #[actix_web::test]
+    async fn test_compress_passthrough_returns_original() {
+        let app = test::init_service(compress_app()).await;
+        let png_data = create_simple_png();
+        let (content_type, body) = multipart_body(&png_data, "test.png", &[("algorithm", "none")]);
+
+        let req = test::TestRequest::post()
+            .uri("/compress")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(resp.headers().get("x-passthrough").unwrap(), "true");
+        assert_eq!(resp.headers().get("content-type").unwrap(), "image/png");
+
+        let body = test::read_body(resp).await;
+        assert_eq!(body.as_ref(), png_data.as_slice());
+    }
+
+    #[actix_web::test]
+    async fn test_compress_passthrough_rejects_invalid_image() {
+        let app = test::init_service(compress_app()).await;
+        let (content_type, body) = multipart_body(b"not an image", "test.png", &[]);
+
+        let req = test::TestRequest::post()
+            .uri("/compress?format=original")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 415);
+    }
+
+    #[actix_web::test]
+    async fn test_compress_unsupported_format_is_415() {
+        // APNG 不预先解码，无法识别的输入在压缩阶段才被发现，仍应返回 415 而不是 500
+        let app = test::init_service(compress_app()).await;
+        let (content_type, body) = multipart_body(b"not an image", "test.png", &[]);
+
+        let req = test::TestRequest::post()
+            .uri("/compress?format=apng")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 415);
+        let json: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+        assert_eq!(json["error"], "unsupported_format");
+    }
+
+    #[actix_web::test]
+    async fn test_compress_file_path_header_rejects_traversal() {
+        let allowed = std::env::temp_dir().join(format!("img-server-proxy-{}", std::process::id()));
+        std::fs::create_dir_all(&allowed).unwrap();
+        std::fs::write(allowed.join("test.png"), create_simple_png()).unwrap();
+
+        let mut config = Config::default();
+        config.server.proxy_upload_dir = Some(allowed.to_string_lossy().to_string());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .route("/compress", web::post().to(compress_endpoint))
+        ).await;
+
+        // File inside the allowed directory is read from disk
+        let req = test::TestRequest::post()
+            .uri("/compress?format=png")
+            .insert_header(("X-File-Path", "test.png"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        // Escaping the allowed directory is forbidden
+        let req = test::TestRequest::post()
+            .uri("/compress")
+            .insert_header(("X-File-Path", "../../../../etc/passwd"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 403);
+
+        std::fs::remove_dir_all(&allowed).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_compress_rejects_oversized_dimensions() {
+        let mut config = Config::default();
+        config.server.max_image_pixels = 8;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .route("/compress", web::post().to(compress_endpoint))
+        ).await;
+
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[]);
+        let req = test::TestRequest::post()
+            .uri("/compress")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 413);
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "dimensions_too_large");
+        assert_eq!(body["limit_name"], "max_image_pixels");
+        assert_eq!(body["limit"], 8);
+        assert!(body["axis"].is_null());
+        assert!(body["width"].as_u64().unwrap() * body["height"].as_u64().unwrap() > 8);
+    }
+
+    #[actix_web::test]
+    async fn test_compress_skips_small_inputs() {
+        let png = create_simple_png();
+        let mut config = Config::default();
+        config.compression.skip_below_bytes = png.len() + 1;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .route("/compress", web::post().to(compress_endpoint))
+        ).await;
+
+        let (content_type, body) = multipart_body(&png, "test.png", &[]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=jpeg")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(resp.headers().get("X-Skipped").unwrap(), "too-small");
+
+        let body = test::read_body(resp).await;
+        assert_eq!(body.as_ref(), png.as_slice());
+    }
+
+    #[actix_web::test]
+    async fn test_compress_include_palette() {
+        let app = test::init_service(compress_app()).await;
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[("include_palette", "true")]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=png")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        let palette = body["palette"].as_array().unwrap();
+        assert!(!palette.is_empty() && palette.len() <= 256);
+        let color = palette[0]["color"].as_str().unwrap();
+        assert!(color.starts_with('#') && color.len() == 7);
+        assert!(palette[0]["alpha"].is_u64());
+        assert!(body["data"].is_string());
+        assert!(body["quantization_quality"].as_u64().unwrap() <= 100);
+
+        // 非 JSON 模式通过响应头返回量化质量
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=png")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let quality: u8 = resp.headers().get("X-Quantization-Quality").unwrap().to_str().unwrap().parse().unwrap();
+        assert!(quality <= 100);
+        assert_eq!(resp.headers().get("X-PNG-Path").unwrap(), "quantized");
+
+        // JPEG 输出没有调色板
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=jpeg&include_palette=true")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_compress_auto_format() {
+        let app = test::init_service(compress_app()).await;
+        let jpeg = {
+            let mut data = Vec::new();
+            image::load_from_memory(&create_simple_png())
+                .unwrap()
+                .write_to(&mut std::io::Cursor::new(&mut data), image::ImageOutputFormat::Jpeg(90))
+                .unwrap();
+            data
+        };
+
+        for (accept, content_type, reason) in [
+            ("image/avif,image/webp,*/*;q=0.8", "image/webp", "accept"),
+            ("*/*", "image/jpeg", "not-accepted"),
+        ] {
+            let (multipart_type, body) = multipart_body(&jpeg, "photo.jpg", &[]);
+            let req = test::TestRequest::post()
+                .uri("/compress?format=auto")
+                .insert_header(("Content-Type", multipart_type))
+                .insert_header(("Accept", accept))
+                .set_payload(body)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert!(resp.status().is_success());
+            assert_eq!(resp.headers().get("content-type").unwrap(), content_type);
+            assert_eq!(resp.headers().get("x-auto-format-reason").unwrap(), reason);
+            assert_eq!(resp.headers().get("vary").unwrap(), "Accept");
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_compress_clamp_to_source_quality() {
+        let mut jpeg = Vec::new();
+        image::load_from_memory(&create_simple_png())
+            .unwrap()
+            .write_to(&mut std::io::Cursor::new(&mut jpeg), image::ImageOutputFormat::Jpeg(60))
+            .unwrap();
+
+        let mut config = Config::default();
+        config.compression.clamp_to_source_quality = true;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .route("/compress", web::post().to(compress_endpoint))
+        ).await;
+
+        let (content_type, body) = multipart_body(&jpeg, "test.jpg", &[]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=jpeg&quality=95")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        let header = |name: &str| -> u8 { resp.headers().get(name).unwrap().to_str().unwrap().parse().unwrap() };
+        let source_quality = header("X-Source-Quality");
+        assert!((55..=65).contains(&source_quality), "{}", source_quality);
+        // 缩略图质量提升同样受上限约束
+        assert_eq!(header("X-Effective-Quality"), source_quality + 5);
+
+        // PNG 输入没有量化表，不做限制
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=jpeg&quality=70")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.headers().get("X-Source-Quality").is_none());
+        assert_eq!(resp.headers().get("X-Effective-Quality").unwrap(), "80");
+    }
+
+    #[actix_web::test]
+    async fn test_compress_param_precedence() {
+        let mut config = Config::default();
+        config.compression.format_quality.insert("png".to_string(), 40);
+        config.compression.thumbnail_quality_boost = 0;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .route("/compress", web::post().to(compress_endpoint))
+        ).await;
+
+        // (查询参数, 请求头, 表单字段, 预期格式, 预期质量)
+        type Case<'a> = (&'a str, Option<&'a str>, &'a [(&'a str, &'a str)], &'a str, &'a str);
+        let cases: [Case; 4] = [
+            ("format=jpeg&quality=70", Some("60"), &[("format", "png"), ("quality", "50")], "image/jpeg", "70"),
+            ("format=jpeg", Some("60"), &[("quality", "50")], "image/jpeg", "60"),
+            ("", None, &[("format", "jpeg"), ("quality", "50")], "image/jpeg", "50"),
+            ("format=png", None, &[], "image/png", "40"),
+        ];
+        for (query, header, fields, content_type, quality) in cases {
+            let (multipart_type, body) = multipart_body(&create_simple_png(), "test.png", fields);
+            let mut req = test::TestRequest::post()
+                .uri(&format!("/compress?{}", query))
+                .insert_header(("Content-Type", multipart_type));
+            if let Some(header) = header {
+                req = req.insert_header(("X-Param-Quality", header));
+            }
+            let resp = test::call_service(&app, req.set_payload(body).to_request()).await;
+            assert!(resp.status().is_success(), "{}", query);
+            assert_eq!(resp.headers().get("Content-Type").unwrap(), content_type, "{}", query);
+            assert_eq!(resp.headers().get("X-Effective-Quality").unwrap(), quality, "{}", query);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_compress_input_sha256() {
+        use sha2::{Digest, Sha256};
+
+        let app = test::init_service(compress_app()).await;
+        let png = create_simple_png();
+        let expected: String = Sha256::digest(&png).iter().map(|b| format!("{:02x}", b)).collect();
+
+        for uri in ["/compress?format=jpeg", "/compress?format=original"] {
+            let (content_type, body) = multipart_body(&png, "test.png", &[]);
+            let req = test::TestRequest::post()
+                .uri(uri)
+                .insert_header(("Content-Type", content_type))
+                .set_payload(body)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert!(resp.status().is_success(), "{}", uri);
+            assert_eq!(resp.headers().get("X-Content-SHA256").unwrap().to_str().unwrap(), expected, "{}", uri);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_compress_rejects_multiple_file_fields() {
+        let app = test::init_service(compress_app()).await;
+        let png = create_simple_png();
+        let (content_type, mut body) = multipart_body(&png, "first.png", &[]);
+
+        // 在结束分隔符前追加第二个文件部分
+        let boundary = "----img-server-test-boundary";
+        body.truncate(body.len() - format!("--{}--\r\n", boundary).len());
+        body.extend_from_slice(format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"second.png\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+            boundary
+        ).as_bytes());
+        body.extend_from_slice(&png);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+        let req = test::TestRequest::post()
+            .uri("/compress?format=png")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+        let json: serde_json::Value = test::read_body_json(resp).await;
+        assert!(json["message"].as_str().unwrap().contains("Multiple file fields"));
+    }
+
+    #[actix_web::test]
+    async fn test_compress_field_too_large() {
+        let app = test::init_service(compress_app()).await;
+        let max_size = Config::default().server.max_text_field_bytes;
+
+        // 超过字段大小上限返回 413，与文件过大一致
+        let comment = "x".repeat(max_size + 1);
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[("comment", comment.as_str())]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=png")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 413);
+        let json: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(json["error"], "field_too_large");
+        assert_eq!(json["field"], "comment");
+        assert_eq!(json["max_size_bytes"], max_size);
+
+        // 未超过字段上限但取值无效仍是 400
+        let comment = "x".repeat(2048);
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[("comment", comment.as_str())]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=png")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_compress_upload_interrupted() {
+        let app = test::init_service(compress_app()).await;
+
+        // 请求体在结束分隔符前被截断，相当于上传中途断开
+        let (content_type, mut body) = multipart_body(&create_simple_png(), "test.png", &[]);
+        body.truncate(body.len() / 2);
+        let req = test::TestRequest::post()
+            .uri("/compress")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+        let json: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(json["error"], "upload_interrupted");
+        assert_eq!(json["retryable"], true);
+    }
+
+    #[actix_web::test]
+    async fn test_compress_source_dimensions() {
+        let app = test::init_service(compress_app()).await;
+
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=jpeg")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get("x-source-width").unwrap(), "50");
+        assert_eq!(resp.headers().get("x-source-height").unwrap(), "50");
+        assert_eq!(resp.headers().get("x-image-width").unwrap(), "50");
+    }
+
+    #[actix_web::test]
+    async fn test_compress_ops_pipeline() {
+        let app = test::init_service(compress_app()).await;
+
+        for (ops, status, dimensions) in [
+            ("crop:0,0,40,20;resize:20x", 200, Some(("20", "10"))),
+            (r#"["resize:20x", "crop:0,0,10,10", "grayscale"]"#, 200, Some(("10", "10"))),
+            ("crop:40,0,20,20", 400, None),
+            ("crop:0,0,40,20;rotate:90", 200, Some(("20", "40"))),
+            ("rotate:45", 400, None),
+        ] {
+            let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[("ops", ops)]);
+            let req = test::TestRequest::post()
+                .uri("/compress?format=png")
+                .insert_header(("Content-Type", content_type))
+                .set_payload(body)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status, "{}", ops);
+            if let Some((width, height)) = dimensions {
+                assert_eq!(resp.headers().get("x-image-width").unwrap(), width, "{}", ops);
+                assert_eq!(resp.headers().get("x-image-height").unwrap(), height, "{}", ops);
+                assert_eq!(resp.headers().get("x-source-width").unwrap(), "50");
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_compress_ops_resize_filter() {
+        let mut config = Config::default();
+        config.compression.resize_filter_default = "catmull-rom".to_string();
+        config.compression.resize_fast_downscale_factor = 4;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .route("/compress", web::post().to(compress_endpoint))
+        ).await;
+
+        // 50 -> 40 用配置的滤镜，40 -> 10 达到 1/4 改用 triangle；不缩小时不返回响应头
+        for (ops, filters) in [("resize:40x;resize:10x", Some("catmull-rom,triangle")), ("resize:100x;grayscale", None)] {
+            let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[("ops", ops)]);
+            let req = test::TestRequest::post()
+                .uri("/compress?format=png")
+                .insert_header(("Content-Type", content_type))
+                .set_payload(body)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), 200, "{}", ops);
+            let header = resp.headers().get("x-resize-filter").map(|value| value.to_str().unwrap());
+            assert_eq!(header, filters, "{}", ops);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_compress_lossless_rotate() {
+        let app = test::init_service(compress_app()).await;
+        let jpeg = {
+            let img = image::RgbImage::from_fn(48, 32, |x, y| image::Rgb([(x * 5) as u8, (y * 7) as u8, 128]));
+            let mut data = Vec::new();
+            image::DynamicImage::ImageRgb8(img)
+                .write_to(&mut std::io::Cursor::new(&mut data), image::ImageOutputFormat::Jpeg(90))
+                .unwrap();
+            data
+        };
+
+        for (ops, lossless) in [("rotate:90", Some("rotate-90")), ("rotate:90;grayscale", None)] {
+            let (content_type, body) = multipart_body(&jpeg, "photo.jpg", &[("ops", ops), ("lossless_rotate", "true")]);
+            let req = test::TestRequest::post()
+                .uri("/compress?format=jpeg")
+                .insert_header(("Content-Type", content_type))
+                .set_payload(body)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), 200, "{}", ops);
+            assert_eq!(resp.headers().get("x-image-width").unwrap(), "32", "{}", ops);
+            assert_eq!(resp.headers().get("x-image-height").unwrap(), "48", "{}", ops);
+            assert_eq!(resp.headers().get("x-lossless-transform").map(|v| v.to_str().unwrap()), lossless, "{}", ops);
+            let encoder = resp.headers().get("x-encoder").unwrap().to_str().unwrap();
+            assert_eq!(encoder.starts_with("lossless-transform"), lossless.is_some(), "{}", ops);
+        }
+
+        // 只有 JPEG 输出能无损旋转
+        let (content_type, body) = multipart_body(&jpeg, "photo.jpg", &[("lossless_rotate", "true")]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=png")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_compress_max_bytes() {
+        let app = test::init_service(compress_app()).await;
+
+        for (max_bytes, met) in [("100000", "true"), ("10", "false")] {
+            let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[("max_bytes", max_bytes)]);
+            let req = test::TestRequest::post()
+                .uri("/compress?format=jpeg")
+                .insert_header(("Content-Type", content_type))
+                .set_payload(body)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), 200, "{}", max_bytes);
+            assert_eq!(resp.headers().get("x-max-bytes-met").unwrap(), met, "{}", max_bytes);
+            assert!(resp.headers().get("x-chosen-quality").is_some());
+            let iterations: u32 = resp.headers().get("x-size-search-iterations").unwrap().to_str().unwrap().parse().unwrap();
+            assert!((1..=7).contains(&iterations), "{}", max_bytes);
+        }
+
+        for uri in [
+            "/compress?format=png&max_bytes=1000",
+            "/compress?format=jpeg&max_bytes=0",
+            "/compress?format=jpeg&max_bytes=1000&target_ssim=0.9",
+        ] {
+            let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[]);
+            let req = test::TestRequest::post()
+                .uri(uri)
+                .insert_header(("Content-Type", content_type))
+                .set_payload(body)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), 400, "{}", uri);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_compress_validate_output() {
+        let app = test::init_service(compress_app()).await;
+
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[("validate_output", "true")]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=jpeg")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get("X-Output-Validated").unwrap(), "true");
+        assert!(resp.headers().get("X-Validation-Fallback").is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_compress_channel_qualities() {
+        let app = test::init_service(compress_app()).await;
+
+        for (uri, status) in [
+            ("/compress?format=jpeg&luma_quality=90&chroma_quality=40", 200),
+            ("/compress?format=jpeg&chroma_quality=0", 400),
+            ("/compress?format=jpeg&luma_quality=101", 400),
+        ] {
+            let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[]);
+            let req = test::TestRequest::post()
+                .uri(uri)
+                .insert_header(("Content-Type", content_type))
+                .set_payload(body)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status, "{}", uri);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_compress_png_quantize_speed() {
+        let app = test::init_service(compress_app()).await;
+
+        for (uri, status) in [
+            ("/compress?format=png&png_quantize_speed=10", 200),
+            ("/compress?format=png&png_quantize_speed=0", 400),
+            ("/compress?format=png&png_quantize_speed=11", 400),
+        ] {
+            let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[]);
+            let req = test::TestRequest::post()
+                .uri(uri)
+                .insert_header(("Content-Type", content_type))
+                .set_payload(body)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status, "{}", uri);
+        }
+
+        // 表单字段同样生效
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[("png_quantize_speed", "12")]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=png")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_compress_raw_validates_dimensions() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(Config::default()))
+                .route("/compress/raw", web::post().to(compress_raw_endpoint))
+        ).await;
+
+        let pixels = vec![128u8; 8 * 4 * 3];
+        let req = test::TestRequest::post()
+            .uri("/compress/raw?width=8&height=4&channels=3&format=png")
+            .set_payload(pixels.clone())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(resp.headers().get("content-type").unwrap(), "image/png");
+        assert_eq!(resp.headers().get("x-image-width").unwrap(), "8");
+
+        // 声明的尺寸与字节数不一致
+        let req = test::TestRequest::post()
+            .uri("/compress/raw?width=8&height=8&channels=3")
+            .set_payload(pixels.clone())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+
+        // 声明超大尺寸
+        let req = test::TestRequest::post()
+            .uri("/compress/raw?width=100000&height=100000&channels=3")
+            .set_payload(pixels)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "invalid_parameters");
+        assert!(body["message"].as_str().unwrap().contains("max_raw_pixels"));
+    }
+
+    #[actix_web::test]
+    async fn test_compress_keeps_input_format_without_extension() {
+        let app = test::init_service(compress_app()).await;
+
+        // 带透明度的 PNG 以无扩展名的 "blob" 上传，不应被转成 JPEG
+        let mut img = image::RgbaImage::from_pixel(16, 16, image::Rgba([255, 0, 0, 255]));
+        img.put_pixel(0, 0, image::Rgba([0, 0, 0, 0]));
+        let mut png_data = Vec::new();
+        img.write_to(&mut std::io::Cursor::new(&mut png_data), image::ImageOutputFormat::Png).unwrap();
+
+        let (content_type, body) = multipart_body(&png_data, "blob", &[]);
+        let req = test::TestRequest::post()
+            .uri("/compress")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(resp.headers().get("content-type").unwrap(), "image/png");
+
+        let output = image::load_from_memory(&test::read_body(resp).await).unwrap().to_rgba8();
+        assert_eq!(output.get_pixel(0, 0)[3], 0);
+    }
+
+    #[actix_web::test]
+    async fn test_anonymous_quality_cap() {
+        let mut config = Config::default();
+        config.server.api_keys = vec!["secret-key".to_string()];
+        config.server.anonymous_max_quality = 60;
+        config.compression.thumbnail_quality_boost = 0;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::PayloadConfig::new(100 * 1024 * 1024))
+                .app_data(web::Data::new(config))
+                .route("/compress", web::post().to(compress_endpoint))
+        ).await;
+
+        // 没有 API Key 的请求被限制在 anonymous_max_quality
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=jpeg&quality=95")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(resp.headers().get("x-effective-quality").unwrap(), "60");
+
+        // 错误的 API Key 按匿名请求处理
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=jpeg&quality=95")
+            .insert_header(("Content-Type", content_type))
+            .insert_header(("X-Api-Key", "wrong-key"))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get("x-effective-quality").unwrap(), "60");
+
+        // 有效的 API Key 不受限制
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=jpeg&quality=95")
+            .insert_header(("Content-Type", content_type))
+            .insert_header(("Authorization", "Bearer secret-key"))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(resp.headers().get("x-effective-quality").unwrap(), "95");
+    }
+
+    #[actix_web::test]
+    async fn test_compress_fast_jpeg_encoder_min_bytes() {
+        let mut config = Config::default();
+        config.compression.fast_jpeg_encoder_min_bytes = 1;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::PayloadConfig::new(100 * 1024 * 1024))
+                .app_data(web::Data::new(config))
+                .route("/compress", web::post().to(compress_endpoint))
+        ).await;
+
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=jpeg")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get("x-algorithm-override").unwrap(), "jpeg-encoder");
+
+        // 显式指定的算法不被替换
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[("algorithm", "mozjpeg")]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=jpeg")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        assert!(resp.headers().get("x-algorithm-override").is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_compress_load_shedding() {
+        let mut config = Config::default();
+        config.load_shedding.enabled = true;
+        config.load_shedding.min_active_jobs = 1;
+        let queue = web::Data::new(JobQueue::new(2, std::time::Duration::from_millis(20)));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::PayloadConfig::new(100 * 1024 * 1024))
+                .app_data(web::Data::new(config))
+                .app_data(queue.clone())
+                .route("/compress", web::post().to(compress_endpoint))
         ).await;
 
-        let req = test::TestRequest::get()
-            .uri("/info")
+        let request = || {
+            let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[]);
+            test::TestRequest::post()
+                .uri("/compress?format=jpeg&quality=90")
+                .insert_header(("Content-Type", content_type))
+                .set_payload(body)
+                .to_request()
+        };
+
+        // 空闲时不降级
+        let resp = test::call_service(&app, request()).await;
+        assert_eq!(resp.status(), 200);
+        assert!(resp.headers().get("x-load-shed").is_none());
+
+        // 已有任务在运行时降低质量并改用 jpeg-encoder
+        let permit = queue.acquire().await.unwrap();
+        let resp = test::call_service(&app, request()).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get("x-load-shed").unwrap(), "true");
+        assert_eq!(resp.headers().get("x-effective-quality").unwrap(), "70");
+        assert!(resp.headers().get("x-encoder").unwrap().to_str().unwrap().starts_with("jpeg-encoder"));
+        drop(permit);
+    }
+
+    #[actix_web::test]
+    async fn test_compress_rejects_when_queue_full() {
+        let queue = web::Data::new(JobQueue::new(1, std::time::Duration::from_millis(20)));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::PayloadConfig::new(100 * 1024 * 1024))
+                .app_data(web::Data::new(Config::default()))
+                .app_data(queue.clone())
+                .route("/compress", web::post().to(compress_endpoint))
+                .route("/metrics", web::get().to(metrics_endpoint))
+        ).await;
+
+        // 唯一的名额被占用，请求等待超时后返回 503
+        let permit = queue.acquire().await.unwrap();
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[]);
+        let req = test::TestRequest::post()
+            .uri("/compress")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 503);
+        assert_eq!(resp.headers().get("retry-after").unwrap(), "1");
+        let json: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(json["error"], "overloaded");
+
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let metrics = String::from_utf8(test::read_body(test::call_service(&app, req).await).await.to_vec()).unwrap();
+        assert!(metrics.contains("img_server_queue_depth 0\n"));
+        assert!(metrics.contains("img_server_jobs_in_flight 1\n"));
+        assert!(metrics.contains("img_server_queue_rejected_total 1\n"));
+
+        // 名额释放后恢复正常
+        drop(permit);
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[]);
+        let req = test::TestRequest::post()
+            .uri("/compress")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
             .to_request();
-        
         let resp = test::call_service(&app, req).await;
         assert!(resp.status().is_success());
+    }
 
-        let body = test::read_body(resp).await;
-        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
-        
-        assert_eq!(json["service"], "Image Compression Server");
-        assert!(json["supported_algorithms"].is_array());
+    #[actix_web::test]
+    async fn test_metrics_bytes_saved_by_format() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::PayloadConfig::new(100 * 1024 * 1024))
+                .app_data(web::Data::new(Config::default()))
+                .app_data(web::Data::new(CompressionMetrics::new()))
+                .route("/compress", web::post().to(compress_endpoint))
+                .route("/metrics", web::get().to(metrics_endpoint))
+        ).await;
+
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=jpeg&algorithm=jpeg-encoder")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        let original_size: i64 = resp.headers().get("x-original-size").unwrap().to_str().unwrap().parse().unwrap();
+        let compressed_size: i64 = resp.headers().get("x-compressed-size").unwrap().to_str().unwrap().parse().unwrap();
+
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let metrics = String::from_utf8(test::read_body(test::call_service(&app, req).await).await.to_vec()).unwrap();
+        let labels = r#"{format="jpeg",algorithm="jpeg-encoder"}"#;
+        assert!(metrics.contains(&format!("img_server_compressions_total{} 1\n", labels)));
+        assert!(metrics.contains(&format!("img_server_original_bytes_total{} {}\n", labels, original_size)));
+        assert!(metrics.contains(&format!("img_server_bytes_saved{} {}\n", labels, original_size - compressed_size)));
     }
 
     #[actix_web::test]
-    async fn test_compress_endpoint_no_file() {
+    async fn test_default_algorithm_by_input_format() {
+        let mut config = Config::default();
+        config.compression.default_algorithm_by_input_format.insert("png".to_string(), "jpeg-encoder".to_string());
         let app = test::init_service(
             App::new()
                 .app_data(web::PayloadConfig::new(100 * 1024 * 1024))
+                .app_data(web::Data::new(config))
                 .route("/compress", web::post().to(compress_endpoint))
         ).await;
 
-        // Test request without file
+        // PNG 输入未指定算法时使用按输入格式配置的算法
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=jpeg")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        let encoder = resp.headers().get("x-encoder").unwrap().to_str().unwrap();
+        assert!(encoder.starts_with("jpeg-encoder "), "{}", encoder);
+
+        // 请求中显式指定的算法优先
+        if cfg!(feature = "mozjpeg") {
+            let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[("algorithm", "mozjpeg")]);
+            let req = test::TestRequest::post()
+                .uri("/compress?format=jpeg")
+                .insert_header(("Content-Type", content_type))
+                .set_payload(body)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            let encoder = resp.headers().get("x-encoder").unwrap().to_str().unwrap();
+            assert!(encoder.starts_with("mozjpeg "), "{}", encoder);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_compress_sidecar() {
+        let app = test::init_service(compress_app()).await;
+        let (content_type, body) = multipart_body(&create_simple_png(), "photo.png", &[("sidecar", "true")]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=jpeg")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        let content_type = resp.headers().get("content-type").unwrap().to_str().unwrap().to_string();
+        let boundary = content_type.strip_prefix("multipart/mixed; boundary=").unwrap().to_string();
+        let body = test::read_body(resp).await.to_vec();
+
+        // 按分隔符拆出图片和 JSON 两个部分
+        let delimiter = format!("\r\n--{}", boundary);
+        let body = &body[boundary.len() + 4..];
+        let split = body.windows(delimiter.len()).position(|w| w == delimiter.as_bytes()).unwrap();
+        let (image_part, json_part) = (&body[..split], &body[split + delimiter.len()..]);
+
+        let header_end = image_part.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        assert!(std::str::from_utf8(&image_part[..header_end]).unwrap().contains("Content-Type: image/jpeg"));
+        let image_data = &image_part[header_end + 4..];
+        assert!(image::load_from_memory(image_data).is_ok());
+
+        let json_part = std::str::from_utf8(json_part).unwrap();
+        assert!(json_part.contains("Content-Type: application/json"));
+        let json_start = json_part.find("\r\n\r\n").unwrap() + 4;
+        let json_end = json_part.rfind(&format!("\r\n--{}--", boundary)).unwrap();
+        let metadata: serde_json::Value = serde_json::from_str(&json_part[json_start..json_end]).unwrap();
+        assert_eq!(metadata["filename"], "photo_compressed.jpg");
+        assert_eq!(metadata["compressed_size"], image_data.len());
+        assert_eq!(metadata["width"], 50);
+        assert!(metadata["encoder"].as_str().is_some());
+        assert_eq!(metadata["exif"]["orientation_applied"], false);
+        assert_eq!(metadata["sha1"].as_str().unwrap().len(), 40);
+    }
+
+    #[actix_web::test]
+    async fn test_disabled_algorithms() {
+        let mut config = Config::default();
+        config.compression.disabled_algorithms = vec!["jpeg-encoder".to_string()];
+        let app = test::init_service(
+            App::new()
+                .app_data(web::PayloadConfig::new(100 * 1024 * 1024))
+                .app_data(web::Data::new(config.clone()))
+                .route("/compress", web::post().to(compress_endpoint))
+                .route("/info", web::get().to(info_endpoint))
+        ).await;
+
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[("algorithm", "jpeg-encoder")]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=jpeg")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+
+        // /info 不列出被禁用的算法
+        let req = test::TestRequest::get().uri("/info").to_request();
+        let info: serde_json::Value = test::read_body_json(test::call_service(&app, req).await).await;
+        let names: Vec<&str> = info["supported_algorithms"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|algorithm| algorithm["name"].as_str().unwrap())
+            .collect();
+        assert!(!names.contains(&"jpeg-encoder"));
+        assert!(names.contains(&"png-quantized"));
+
+        // 开启回退时改用 default_algorithm
+        config.compression.disabled_algorithm_fallback = true;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::PayloadConfig::new(100 * 1024 * 1024))
+                .app_data(web::Data::new(config))
+                .route("/compress", web::post().to(compress_endpoint))
+        ).await;
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[("algorithm", "jpeg-encoder")]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=jpeg")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        if cfg!(feature = "mozjpeg") {
+            let encoder = resp.headers().get("x-encoder").unwrap().to_str().unwrap();
+            assert!(encoder.starts_with("mozjpeg "), "{}", encoder);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_compress_embeds_xmp() {
+        let app = test::init_service(compress_app()).await;
+
+        // XMP 包通常超过 1024 字节，仍在 max_text_field_bytes 的默认上限之内
+        let xmp = format!(
+            r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#"><rdf:Description>{}</rdf:Description></rdf:RDF></x:xmpmeta>"#,
+            " ".repeat(2048)
+        );
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[("xmp", xmp.as_str())]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=png")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        let data = test::read_body(resp).await;
+        let reader = png::Decoder::new(std::io::Cursor::new(data.to_vec())).read_info().unwrap();
+        let chunk = &reader.info().utf8_text[0];
+        assert_eq!(chunk.keyword, "XML:com.adobe.xmp");
+        assert_eq!(chunk.get_text().unwrap(), xmp);
+
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[("xmp", "<x:xmpmeta>")]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=png")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_compress_zip() {
+        use std::io::{Cursor, Read, Write};
+
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        writer.start_file("photos/2024/a.png", zip::write::FileOptions::default()).unwrap();
+        writer.write_all(&create_simple_png()).unwrap();
+        writer.start_file("notes.txt", zip::write::FileOptions::default()).unwrap();
+        writer.write_all(b"not an image").unwrap();
+        let input = writer.finish().unwrap().into_inner();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::PayloadConfig::new(100 * 1024 * 1024))
+                .app_data(web::Data::new(Config::default()))
+                .route("/compress/zip", web::post().to(compress_zip_endpoint))
+        ).await;
+        let req = test::TestRequest::post()
+            .uri("/compress/zip?format=jpeg&quality=70")
+            .insert_header(("Content-Type", "application/zip"))
+            .set_payload(input)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(resp.headers().get("x-zip-compressed").unwrap(), "1");
+        assert_eq!(resp.headers().get("x-zip-skipped").unwrap(), "1");
+        assert_eq!(resp.headers().get("x-zip-deduplicated").unwrap(), "0");
+
+        let body = test::read_body(resp).await;
+        let mut archive = zip::ZipArchive::new(Cursor::new(body.to_vec())).unwrap();
+        let mut jpeg = Vec::new();
+        archive.by_name("photos/2024/a.jpg").unwrap().read_to_end(&mut jpeg).unwrap();
+        assert_eq!(image::guess_format(&jpeg).unwrap(), image::ImageFormat::Jpeg);
+
+        let mut report = String::new();
+        archive.by_name("_report.json").unwrap().read_to_string(&mut report).unwrap();
+        let report: serde_json::Value = serde_json::from_str(&report).unwrap();
+        assert_eq!(report["entries"][1]["name"], "notes.txt");
+        assert_eq!(report["entries"][1]["status"], "skipped");
+
+        // 未指定 format 时与 /compress 相同，WebP 条目保持为 WebP
+        let mut webp = Vec::new();
+        image::RgbaImage::new(8, 8).write_to(&mut Cursor::new(&mut webp), image::ImageOutputFormat::WebP).unwrap();
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        writer.start_file("icons/clear.webp", zip::write::FileOptions::default()).unwrap();
+        writer.write_all(&webp).unwrap();
+        let req = test::TestRequest::post()
+            .uri("/compress/zip")
+            .insert_header(("Content-Type", "application/zip"))
+            .set_payload(writer.finish().unwrap().into_inner())
+            .to_request();
+        let body = test::read_body(test::call_service(&app, req).await).await;
+        let mut archive = zip::ZipArchive::new(Cursor::new(body.to_vec())).unwrap();
+        let mut output = Vec::new();
+        archive.by_name("icons/clear.webp").unwrap().read_to_end(&mut output).unwrap();
+        assert_eq!(image::guess_format(&output).unwrap(), image::ImageFormat::WebP);
+
+        let req = test::TestRequest::post()
+            .uri("/compress/zip")
+            .set_payload("not a zip")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_compress_zip_spools_to_temp_dir() {
+        use std::io::{Cursor, Write};
+
+        let temp_dir = std::env::temp_dir().join(format!("img-server-zip-api-{}", std::process::id()));
+        std::fs::create_dir_all(&temp_dir).unwrap();
+        let mut config = Config::default();
+        config.server.zip_temp_dir = Some(temp_dir.to_string_lossy().into_owned());
+
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        writer.start_file("a.png", zip::write::FileOptions::default()).unwrap();
+        writer.write_all(&create_simple_png()).unwrap();
+        let input = writer.finish().unwrap().into_inner();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .route("/compress/zip", web::post().to(compress_zip_endpoint))
+        ).await;
+        let req = test::TestRequest::post()
+            .uri("/compress/zip")
+            .insert_header(("Content-Type", "application/zip"))
+            .set_payload(input)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(resp.headers().get("content-type").unwrap(), "application/zip");
+        let body = test::read_body(resp).await;
+        let archive = zip::ZipArchive::new(Cursor::new(body.to_vec())).unwrap();
+        assert!(archive.file_names().any(|name| name == "a.png"));
+
+        // 响应发送后临时文件已删除
+        assert_eq!(std::fs::read_dir(&temp_dir).unwrap().count(), 0);
+        std::fs::remove_dir(&temp_dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_compress_denied_types() {
+        let mut config = Config::default();
+        config.server.denied_types = vec!["application/pdf".to_string(), "application/zip".to_string()];
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .route("/compress", web::post().to(compress_endpoint))
+        ).await;
+
+        // PNG 末尾追加 ZIP 中央目录，仍能作为图片解码
+        let mut polyglot = create_simple_png();
+        polyglot.extend_from_slice(b"PK\x05\x06\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0");
+        let uploads: [(&[u8], &str); 2] = [(b"%PDF-1.4\n%\xE2\xE3\xCF\xD3\n", "application/pdf"), (&polyglot, "application/zip")];
+        for (file, mime_type) in uploads {
+            let (content_type, body) = multipart_body(file, "upload.png", &[]);
+            let req = test::TestRequest::post()
+                .uri("/compress")
+                .insert_header(("Content-Type", content_type))
+                .set_payload(body)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), 415);
+            let json: serde_json::Value = serde_json::from_slice(&test::read_body(resp).await).unwrap();
+            assert_eq!(json["error"], "denied_type");
+            assert_eq!(json["type"], mime_type);
+        }
+
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[]);
         let req = test::TestRequest::post()
             .uri("/compress")
-            .set_form(&[("quality", "80")])
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+
+    #[actix_web::test]
+    async fn test_compress_streaming_decode() {
+        let mut config = Config::default();
+        config.compression.streaming_decode_min_bytes = 1;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .route("/compress", web::post().to(compress_endpoint))
+        ).await;
+
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=jpeg")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
             .to_request();
-        
         let resp = test::call_service(&app, req).await;
-        assert_eq!(resp.status(), 400); // Bad request - no file provided
+        assert!(resp.status().is_success());
+        let data = test::read_body(resp).await;
+        let img = image::load_from_memory(&data).unwrap();
+        assert_eq!((img.width(), img.height()), (50, 50));
+    }
+
+    #[actix_web::test]
+    async fn test_compress_streams_progressive_scans() {
+        let app = test::init_service(compress_app()).await;
+
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=jpeg&stream=true")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert!(resp.headers().get("content-length").is_none());
+        let scans: usize = resp.headers().get("x-progressive-scans").unwrap().to_str().unwrap().parse().unwrap();
+        assert!(scans > 1);
+        let header = |name: &str| resp.headers().get(name).unwrap().to_str().unwrap().to_string();
+        let (compressed_size, ratio) = (header("x-compressed-size"), header("x-compression-ratio"));
+        let data = test::read_body(resp).await;
+        // 流式响应的统计信息与实际发送的字节一致
+        assert_eq!(compressed_size, data.len().to_string());
+        assert!(ratio.parse::<f64>().unwrap() > 0.0);
+        assert_eq!(img_server_rs::compression::detect_progressive_jpeg(&data), Some(true));
+        assert!(image::load_from_memory(&data).is_ok());
+
+        // 流式返回只支持渐进式 JPEG
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=png&stream=true")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 400);
+
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=jpeg&stream=true&jpeg_mode=baseline")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_compress_profile() {
+        let mut config = Config::default();
+        config.compression.thumbnail_quality_boost = 0;
+        config.compression.profiles.insert(
+            "web-thumbnail".to_string(),
+            img_server_rs::config::CompressionProfile {
+                format: Some("jpg".to_string()),
+                quality: Some(60),
+                algorithm: Some("jpeg-encoder".to_string()),
+                ..Default::default()
+            },
+        );
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .route("/compress", web::post().to(compress_endpoint))
+        ).await;
+
+        let cases = [
+            ("/compress?profile=web-thumbnail", vec![], "image/jpeg", "60"),
+            // 查询参数和表单字段覆盖组合中的值
+            ("/compress?profile=web-thumbnail&format=png", vec![("quality", "80")], "image/png", "80"),
+            ("/compress", vec![("profile", "web-thumbnail")], "image/jpeg", "60"),
+        ];
+        for (uri, fields, content_type_expected, quality) in cases {
+            let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &fields);
+            let req = test::TestRequest::post()
+                .uri(uri)
+                .insert_header(("Content-Type", content_type))
+                .set_payload(body)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert!(resp.status().is_success(), "{}", uri);
+            assert_eq!(resp.headers().get("Content-Type").unwrap(), content_type_expected, "{}", uri);
+            assert_eq!(resp.headers().get("X-Effective-Quality").unwrap(), quality, "{}", uri);
+        }
+
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[]);
+        let req = test::TestRequest::post()
+            .uri("/compress?profile=print")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+        let json: serde_json::Value = test::read_body_json(resp).await;
+        assert!(json["message"].as_str().unwrap().contains("web-thumbnail"), "{}", json);
+    }
+
+    #[actix_web::test]
+    async fn test_compress_reject_animated() {
+        use image::codecs::gif::GifEncoder;
+
+        let mut gif = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut gif);
+            for value in [0, 128, 255] {
+                let frame = image::Frame::new(image::RgbaImage::from_pixel(8, 8, image::Rgba([value, 0, 0, 255])));
+                encoder.encode_frame(frame).unwrap();
+            }
+        }
+
+        let app = test::init_service(compress_app()).await;
+        let (content_type, body) = multipart_body(&gif, "anim.gif", &[("reject_animated", "true")]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=png")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+        let json: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(json["error"], "animated_input");
+        assert_eq!(json["frames"], 3);
+
+        // 静态图片不受影响
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[("reject_animated", "true")]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=png")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+
+    #[actix_web::test]
+    async fn test_compress_apng_rejects_oversized_animation() {
+        use image::codecs::gif::GifEncoder;
+
+        // 20 帧 50x50：单帧 2500 像素，全部帧共 50000 像素
+        let mut gif = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut gif);
+            for value in 0..20u8 {
+                let frame = image::Frame::new(image::RgbaImage::from_pixel(50, 50, image::Rgba([value * 10, 0, 0, 255])));
+                encoder.encode_frame(frame).unwrap();
+            }
+        }
+
+        async fn post_apng(config: Config, gif: &[u8]) -> (u16, serde_json::Value) {
+            let app = test::init_service(
+                App::new()
+                    .app_data(web::Data::new(config))
+                    .route("/compress", web::post().to(compress_endpoint))
+            ).await;
+            let (content_type, body) = multipart_body(gif, "anim.gif", &[]);
+            let req = test::TestRequest::post()
+                .uri("/compress?format=apng")
+                .insert_header(("Content-Type", content_type))
+                .set_payload(body)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            let status = resp.status().as_u16();
+            let body = test::read_body(resp).await;
+            (status, serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null))
+        }
+
+        // 单帧未超出像素限制，但全部帧的像素总和超出
+        let mut config = Config::default();
+        config.server.max_image_pixels = 10_000;
+        let (status, json) = post_apng(config, &gif).await;
+        assert_eq!(status, 413);
+        assert_eq!(json["error"], "animation_too_large");
+        assert_eq!(json["limit_name"], "max_image_pixels");
+        assert_eq!(json["frames"], 20);
+        assert_eq!(json["width"], 50);
+
+        let mut config = Config::default();
+        config.server.max_animation_frames = 5;
+        let (status, json) = post_apng(config, &gif).await;
+        assert_eq!(status, 413);
+        assert_eq!(json["limit_name"], "max_animation_frames");
+        assert_eq!(json["limit"], 5);
+
+        // 默认限制下正常输出
+        let (status, _) = post_apng(Config::default(), &gif).await;
+        assert_eq!(status, 200);
+    }
+
+    #[actix_web::test]
+    async fn test_strict_algorithm() {
+        let mut config = Config::default();
+        config.compression.strict_algorithm = true;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::PayloadConfig::new(100 * 1024 * 1024))
+                .app_data(web::Data::new(config))
+                .route("/compress", web::post().to(compress_endpoint))
+        ).await;
+
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[("algorithm", "mozjepg")]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=jpeg")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+        let json: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(json["error"], "invalid_parameters");
+        assert!(json["message"].as_str().unwrap().contains("jpeg-encoder"), "{}", json);
+
+        // 已知算法不区分大小写
+        let (content_type, body) = multipart_body(&create_simple_png(), "test.png", &[("algorithm", "JPEG-Encoder")]);
+        let req = test::TestRequest::post()
+            .uri("/compress?format=jpeg")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
     }
 
     // Helper function to create test image data
+    #[actix_web::test]
+    async fn test_compare_images() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(Config::default()))
+                .route("/compare", web::post().to(compare_endpoint)),
+        )
+        .await;
+
+        let compare_body = |original: &[u8], candidate: &[u8]| {
+            let boundary = "----img-server-test-boundary";
+            let mut body = Vec::new();
+            for (name, data) in [("original", original), ("candidate", candidate)] {
+                body.extend_from_slice(format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"{}.png\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+                    boundary, name, name
+                ).as_bytes());
+                body.extend_from_slice(data);
+                body.extend_from_slice(b"\r\n");
+            }
+            body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+            (format!("multipart/form-data; boundary={}", boundary), body)
+        };
+
+        let png = create_simple_png();
+        let (content_type, body) = compare_body(&png, &png);
+        let req = test::TestRequest::post()
+            .uri("/compare")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        let json: serde_json::Value = test::read_body_json(resp).await;
+        assert!(json["psnr"].is_null());
+        assert_eq!(json["ssim"], 1.0);
+        assert_eq!(json["mean_error"]["r"], 0.0);
+        assert_eq!(json["candidate_resized"], false);
+
+        // 尺寸不同的候选图默认拒绝，resize=true 时缩放后比较
+        let mut smaller = Vec::new();
+        image::load_from_memory(&png)
+            .unwrap()
+            .thumbnail(25, 25)
+            .write_to(&mut std::io::Cursor::new(&mut smaller), image::ImageOutputFormat::Png)
+            .unwrap();
+        for (uri, status) in [("/compare", 400), ("/compare?resize=true", 200)] {
+            let (content_type, body) = compare_body(&png, &smaller);
+            let req = test::TestRequest::post()
+                .uri(uri)
+                .insert_header(("Content-Type", content_type))
+                .set_payload(body)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), status, "{}", uri);
+        }
+
+        // 缺少候选图
+        let (content_type, body) = multipart_body(&png, "test.png", &[]);
+        let req = test::TestRequest::post()
+            .uri("/compare")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_sprite_sheet() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(Config::default()))
+                .route("/sprite", web::post().to(sprite_endpoint)),
+        )
+        .await;
+
+        let sprite_body = |files: &[(&str, &[u8])]| {
+            let boundary = "----img-server-test-boundary";
+            let mut body = Vec::new();
+            for (filename, data) in files {
+                body.extend_from_slice(format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+                    boundary, filename
+                ).as_bytes());
+                body.extend_from_slice(data);
+                body.extend_from_slice(b"\r\n");
+            }
+            body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+            (format!("multipart/form-data; boundary={}", boundary), body)
+        };
+
+        let png = create_simple_png();
+        let mut small = Vec::new();
+        image::DynamicImage::new_rgb8(20, 10)
+            .write_to(&mut std::io::Cursor::new(&mut small), image::ImageOutputFormat::Png)
+            .unwrap();
+        let files: [(&str, &[u8]); 3] = [("a.png", &png), ("b.png", &small), ("c.png", &png)];
+
+        let (content_type, body) = sprite_body(&files);
+        let req = test::TestRequest::post()
+            .uri("/sprite?layout=grid&padding=2")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        let json: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(json["width"], 102);
+        assert_eq!(json["height"], 102);
+        assert_eq!(json["sprites"][1]["name"], "b.png");
+        assert_eq!(json["sprites"][1]["x"], 52);
+        assert_eq!(json["sprites"][1]["width"], 20);
+        assert_eq!(json["sprites"][2]["y"], 52);
+        let data = base64::Engine::decode(&base64::engine::general_purpose::STANDARD, json["data"].as_str().unwrap()).unwrap();
+        let sheet = image::load_from_memory(&data).unwrap();
+        assert_eq!((sheet.width(), sheet.height()), (102, 102));
+
+        for uri in ["/sprite?layout=diagonal", "/sprite?columns=2", "/sprite?padding=1000", "/sprite?format=gif"] {
+            let (content_type, body) = sprite_body(&files);
+            let req = test::TestRequest::post()
+                .uri(uri)
+                .insert_header(("Content-Type", content_type))
+                .set_payload(body)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), 400, "{}", uri);
+        }
+    }
+
     fn create_simple_png() -> Vec<u8> {
         use image::{ImageBuffer, Rgb};
         
@@ -90,8 +1739,8 @@ mod api_tests {
     // more sophisticated testing with actual multipart data, which is typically
     // done with integration tests using a real HTTP client.
     
-    #[test]
-    fn test_image_creation_helper() {
+    #[actix_web::test]
+    async fn test_image_creation_helper() {
         let png_data = create_simple_png();
         assert!(!png_data.is_empty());
         